@@ -0,0 +1,113 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}};
+use crate::{ApplicationError, StorageError, config::{AuditConfig, AuditLevel}, group::CommitSummary};
+
+use chrono::{SecondsFormat, Utc};
+
+/// An append-only record of what happened to the user's group, kept in a local file so that incidents can be
+/// reconstructed after the fact. Each line is one event: a UTC timestamp, the event's name, and its details as
+/// `key=value` pairs. Identities are written as quoted, escaped strings, so that a member can't forge extra lines by
+/// putting a line break in their name.
+pub struct AuditLog {
+    /// None if the log is turned off
+    file: Option<File>,
+    level: AuditLevel,
+}
+
+impl AuditLog {
+    /// Opens the audit log configured in `config` for appending, creating it and its parent directories if needed.
+    /// With no path configured, returns a log that records nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file can't be opened.
+    pub fn open(config: &AuditConfig) -> Result<AuditLog, ApplicationError> {
+        let Some(path) = &config.path else { return Ok(AuditLog::disabled()) };
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| StorageError::Config(format!("{}: {}", parent.display(), e)))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)))?;
+
+        Ok(AuditLog { file: Some(file), level: config.level })
+    }
+
+    /// Returns a log that records nothing.
+    pub fn disabled() -> AuditLog {
+        AuditLog { file: None, level: AuditLevel::default() }
+    }
+
+    /// Appends an event to the log if the configured level includes `level`. `details` is written as is, and
+    /// should already be in `key=value` form.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `io::Error` from writing to the file.
+    pub fn record(&mut self, level: AuditLevel, event: &str, details: &str) -> io::Result<()> {
+        let Some(file) = &mut self.file else { return Ok(()) };
+        if level > self.level {
+            return Ok(());
+        }
+
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        // written in one call, so that a line is never split by another process appending to the same file
+        file.write_all(format!("{} {} {}\n", time, event, details).as_bytes())
+    }
+
+    /// Appends a merged commit to the log: at `AuditLevel::Membership` if it added or removed anyone, and at
+    /// `AuditLevel::Commits` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `io::Error` from writing to the file.
+    pub fn record_commit(&mut self, summary: &CommitSummary) -> io::Result<()> {
+        let level = if summary.added.is_empty() && summary.removed.is_empty() {
+            AuditLevel::Commits
+        } else {
+            AuditLevel::Membership
+        };
+
+        self.record(level, "commit", &format!(
+            "epoch={} committer={:?} added={:?} removed={:?} updated={:?}",
+            summary.epoch,
+            summary.committer,
+            summary.added,
+            summary.removed,
+            summary.updated,
+        ))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_up_to_level() {
+        let path = std::env::temp_dir().join(format!("mls_chat_audit_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = AuditConfig { path: Some(path.clone()), level: AuditLevel::Membership };
+        let mut log = AuditLog::open(&config).unwrap();
+
+        let summary = CommitSummary {
+            committer: String::from("alice"),
+            epoch: 2,
+            added: vec![String::from("bob\nforged")],
+            removed: vec![],
+            updated: vec![],
+        };
+        log.record_commit(&summary).unwrap();
+        log.record_commit(&CommitSummary { added: vec![], updated: vec![String::from("alice")], ..summary }).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", contents);
+        assert!(lines[0].ends_with(r#" commit epoch=2 committer="alice" added=["bob\nforged"] removed=[] updated=[]"#));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{fs, path::Path};
+use crate::{ApplicationError, StorageError, PROVIDER, session::{self, Session}};
+
+use openmls_traits::{crypto::OpenMlsCrypto, random::OpenMlsRand, types::{AeadType, HashType}, OpenMlsCryptoProvider};
+use serde::{Deserialize, Serialize};
+
+/// PBKDF2 rounds used for new backups. Old backups keep whatever count they were made with.
+const ITERATIONS: u32 = 600_000;
+/// Most PBKDF2 rounds a backup can ask for, leaving room for `ITERATIONS` to grow, so that a doctored backup can't keep
+/// the client deriving a key for hours.
+const MAX_ITERATIONS: u32 = ITERATIONS * 16;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Bound as associated data, so that a backup can't be passed off as some other file encrypted with the same key.
+const AAD: &[u8] = b"mls_chat identity backup v1";
+
+/// A saved `Session` encrypted under a passphrase, for moving an identity and its place in a group to another machine.
+/// The key is derived with PBKDF2-HMAC-SHA256 and the session sealed with ChaCha20-Poly1305, both through the crypto
+/// provider the rest of the client uses.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    version: u8,
+    iterations: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Backup {
+    /// Encrypts `session` under `passphrase`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if the session can't be serialized or encrypted.
+    pub fn seal(session: &Session, passphrase: &str) -> Result<Backup, ApplicationError> {
+        Self::seal_with(session, passphrase, ITERATIONS)
+    }
+
+    fn seal_with(session: &Session, passphrase: &str, iterations: u32) -> Result<Backup, ApplicationError> {
+        let error = |msg: &str| ApplicationError::Storage(StorageError::Session(format!("unable to encrypt the backup: {}", msg)));
+
+        let plaintext = serde_json::to_vec(session).map_err(|e| error(&e.to_string()))?;
+        let salt = (*PROVIDER).rand().random_vec(SALT_LEN).map_err(|e| error(&format!("{:?}", e)))?;
+        let nonce = (*PROVIDER).rand().random_vec(NONCE_LEN).map_err(|e| error(&format!("{:?}", e)))?;
+        let key = derive_key(passphrase, &salt, iterations)?;
+        let ciphertext = (*PROVIDER).crypto()
+            .aead_encrypt(AeadType::ChaCha20Poly1305, &key, &plaintext, &nonce, AAD)
+            .map_err(|e| error(&format!("{:?}", e)))?;
+
+        Ok(Backup { version: 1, iterations, salt, nonce, ciphertext })
+    }
+
+    /// Decrypts the backup with `passphrase`, returning the session inside.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if the passphrase is wrong, the backup was tampered with (including asking for
+    /// more than `MAX_ITERATIONS` rounds of PBKDF2), or it's from a newer version of mls_chat.
+    pub fn open(&self, passphrase: &str) -> Result<Session, ApplicationError> {
+        if self.version != 1 {
+            return Err(StorageError::Session(format!(
+                "the backup is version {}, which this version of mls_chat can't read", self.version,
+            )).into());
+        }
+        if self.iterations > MAX_ITERATIONS {
+            return Err(StorageError::Session(format!(
+                "the backup asks for {} rounds of key derivation, more than the {} allowed, so it's been tampered with",
+                self.iterations,
+                MAX_ITERATIONS,
+            )).into());
+        }
+
+        let key = derive_key(passphrase, &self.salt, self.iterations)?;
+        let plaintext = (*PROVIDER).crypto()
+            .aead_decrypt(AeadType::ChaCha20Poly1305, &key, &self.ciphertext, &self.nonce, AAD)
+            .map_err(|_| StorageError::Session(String::from("wrong passphrase, or the backup is damaged")))?;
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| StorageError::Session(format!("the backup's contents are damaged: {}", e)).into())
+    }
+
+    /// Reads a backup from `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if the file can't be read or isn't a backup.
+    pub fn read(path: &Path) -> Result<Backup, ApplicationError> {
+        let contents = fs::read(path)
+            .map_err(|e| StorageError::Session(format!("{}: {}", path.display(), e)))?;
+
+        serde_json::from_slice(&contents)
+            .map_err(|e| StorageError::Session(format!("{} isn't an identity backup: {}", path.display(), e)).into())
+    }
+
+    /// Writes the backup to `path` with `session::write_private()`, refusing to replace an existing file unless `force`
+    /// is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if the file exists and `force` isn't set, or if writing fails.
+    pub fn write(&self, path: &Path, force: bool) -> Result<(), ApplicationError> {
+        if path.exists() && !force {
+            return Err(StorageError::Session(format!(
+                "{} already exists. Pass --force to overwrite it.", path.display(),
+            )).into());
+        }
+
+        let contents = serde_json::to_vec(self)
+            .map_err(|e| StorageError::Session(e.to_string()))?;
+        session::write_private(path, &contents)
+            .map_err(|e| StorageError::Session(format!("{}: {}", path.display(), e)).into())
+    }
+}
+
+/// Derives a 32-byte key from `passphrase` with PBKDF2-HMAC-SHA256. HKDF-Extract is HMAC keyed with its salt, so it
+/// stands in for the HMAC PBKDF2 is built on; a single block is all a 32-byte key needs.
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>, ApplicationError> {
+    let hmac = |data: &[u8]| {
+        (*PROVIDER).crypto()
+            .hkdf_extract(HashType::Sha2_256, passphrase.as_bytes(), data)
+            .map(|mac| mac.as_slice().to_vec())
+            .map_err(|e| StorageError::Session(format!("unable to derive the backup key: {:?}", e)))
+    };
+
+    let mut block = hmac(&[salt, &1u32.to_be_bytes()].concat())?;
+    let mut key = block.clone();
+    for _ in 1..iterations.max(1) {
+        block = hmac(&block)?;
+        key.iter_mut().zip(&block).for_each(|(k, b)| *k ^= b);
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::User;
+
+    #[test]
+    fn derive_pbkdf2_key() {
+        // RFC 7914 section 11's PBKDF2-HMAC-SHA256 test vector, cut to 32 bytes
+        let key = derive_key("passwd", b"salt", 1).unwrap();
+        assert_eq!(key[..8], [0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f]);
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let session = Session::capture(&mut alice, Some(3), vec![]).unwrap();
+        let backup = Backup::seal_with(&session, "correct horse", 10).unwrap();
+
+        assert!(backup.open("battery staple").is_err());
+        let opened = backup.open("correct horse").unwrap();
+        assert_eq!(opened.user.id(), "alice");
+        assert_eq!(opened.last_seq, Some(3));
+    }
+
+    #[test]
+    fn refuse_excessive_iterations() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let session = Session::capture(&mut alice, None, vec![]).unwrap();
+        let mut backup = Backup::seal_with(&session, "correct horse", 10).unwrap();
+        backup.iterations = u32::MAX;
+
+        let Err(ApplicationError::Storage(StorageError::Session(msg))) = backup.open("correct horse") else {
+            panic!("Backup asking for u32::MAX iterations was opened");
+        };
+        assert!(msg.contains("tampered"), "{}", msg);
+    }
+
+    #[test]
+    fn write_privately_without_replacing() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let session = Session::capture(&mut alice, None, vec![]).unwrap();
+        let backup = Backup::seal_with(&session, "correct horse", 10).unwrap();
+        let path = std::env::temp_dir().join(format!("mls_chat_backup_{}.json", std::process::id()));
+
+        backup.write(&path, false).unwrap();
+        assert!(backup.write(&path, false).is_err());
+        backup.write(&path, true).unwrap();
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        assert_eq!(Backup::read(&path).unwrap().open("correct horse").unwrap().user.id(), "alice");
+        fs::remove_file(&path).unwrap();
+    }
+}
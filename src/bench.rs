@@ -0,0 +1,453 @@
+use std::{
+    fmt,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+use crate::{
+    errors::{ApplicationError, MlsError, NetworkError},
+    metrics::format_bytes,
+    network::{address::ServerAddr, client::{Client, ConnectionState}, frame::Frame},
+    User,
+};
+
+use openmls::prelude::*;
+use tokio::{task::JoinSet, time::{self, Instant}};
+
+/// Marks the messages the bench clients send, so anything else relayed to them is left out of the report.
+const MAGIC: &[u8; 5] = b"bench";
+
+/// Shortest message a bench client can send: `MAGIC` and the time it was sent.
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+/// How long the clients keep waiting for messages still on their way once they've stopped sending.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long each bench client gets to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What `run()` does: how many clients to connect, and how much traffic they send between them.
+#[derive(Clone, Copy, Debug)]
+pub struct BenchOptions {
+    pub clients: usize,
+    /// Messages per second sent across every client, each sending its share at an even pace.
+    pub rate: f64,
+    /// How long the clients send for.
+    pub duration: Duration,
+    /// Size in bytes of each message, padded out from the smallest one that fits a timestamp.
+    pub size: usize,
+}
+
+/// How a run of `run()` went.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BenchReport {
+    pub clients: usize,
+    /// How long the clients sent for.
+    pub duration: Duration,
+    /// Messages sent by every client together.
+    pub sent: u64,
+    /// Deliveries the messages sent should have made: one to every client other than its sender.
+    pub expected: u64,
+    /// Deliveries actually made before the clients gave up waiting.
+    pub delivered: u64,
+    /// Times the server told a client it had fallen behind (`Frame::Lagged`).
+    pub lagged: u64,
+    /// Times the server couldn't replay what a client had missed (`Frame::Resync`).
+    pub resyncs: u64,
+    /// Clients whose connection closed before the run finished.
+    pub disconnected: u64,
+    /// Time from sending each message to each delivery of it, shortest first.
+    pub latencies: Vec<Duration>,
+}
+
+/// What MLS costs in a group of one size, as measured by `group_costs()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GroupCosts {
+    /// Members in the group once the last has been added.
+    pub size: usize,
+    /// Whether the Welcome carried the ratchet tree, rather than the new member being given it separately.
+    pub ratchet_tree_in_welcome: bool,
+    /// Time to commit adding the last member, and merge the commit.
+    pub add: Duration,
+    pub add_commit_bytes: usize,
+    pub welcome_bytes: usize,
+    /// Size of the ratchet tree, whether it's in the Welcome or fetched from the server.
+    pub tree_bytes: usize,
+    /// Time for the last member to join from the Welcome.
+    pub join: Duration,
+    /// Time to commit an update of the committer's keys, and merge it.
+    pub update: Duration,
+    pub update_commit_bytes: usize,
+    /// Time for another member to process the update and merge it.
+    pub process: Duration,
+    /// Time to commit removing a member, and merge it.
+    pub remove: Duration,
+    pub remove_commit_bytes: usize,
+}
+
+/// The schedule every bench client keeps to, along with the counters they share.
+struct Run {
+    start: Instant,
+    /// when the clients stop sending
+    stop: Instant,
+    /// time between the messages each client sends
+    interval: Duration,
+    size: usize,
+    clients: u64,
+    sent: AtomicU64,
+    delivered: AtomicU64,
+    lagged: AtomicU64,
+    resyncs: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl BenchReport {
+    /// Returns the latency at or below which `percentile` percent of deliveries were made, or None if there weren't
+    /// any.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let rank = (percentile / 100.0 * last as f64).round() as usize;
+        self.latencies.get(rank.min(last)).copied()
+    }
+
+    /// Returns the share of expected deliveries that were never made, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        match self.expected {
+            0 => 0.0,
+            expected => expected.saturating_sub(self.delivered) as f64 / expected as f64,
+        }
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |latency: Option<Duration>| match latency {
+            Some(latency) => format!("{:.1} ms", latency.as_secs_f64() * 1000.0),
+            None => String::from("-"),
+        };
+        let secs = self.duration.as_secs_f64().max(f64::EPSILON);
+        writeln!(f, "Clients:   {}", self.clients)?;
+        writeln!(f, "Sent:      {} messages ({:.1}/s)", self.sent, self.sent as f64 / secs)?;
+        writeln!(
+            f,
+            "Delivered: {} of {} ({:.2}% lost, {:.1}/s)",
+            self.delivered,
+            self.expected,
+            self.loss() * 100.0,
+            self.delivered as f64 / secs,
+        )?;
+        writeln!(
+            f,
+            "Latency:   p50 {}, p90 {}, p99 {}, max {}",
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            millis(self.latencies.last().copied()),
+        )?;
+        write!(f, "Lagged:    {}, resynced {}, disconnected {}", self.lagged, self.resyncs, self.disconnected)
+    }
+}
+
+impl fmt::Display for GroupCosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |time: Duration| format!("{:.1} ms", time.as_secs_f64() * 1000.0);
+        let bytes = |bytes: usize| format_bytes(bytes as u64);
+        let tree = match self.ratchet_tree_in_welcome {
+            true => "in the Welcome",
+            false => "fetched from the server",
+        };
+        writeln!(f, "Members:   {}, ratchet tree {} ({})", self.size, tree, bytes(self.tree_bytes))?;
+        writeln!(
+            f,
+            "Add:       {}, commit {}, Welcome {}",
+            millis(self.add),
+            bytes(self.add_commit_bytes),
+            bytes(self.welcome_bytes),
+        )?;
+        writeln!(f, "Join:      {}", millis(self.join))?;
+        writeln!(
+            f,
+            "Update:    {}, commit {}, processed in {}",
+            millis(self.update),
+            bytes(self.update_commit_bytes),
+            millis(self.process),
+        )?;
+        write!(f, "Remove:    {}, commit {}", millis(self.remove), bytes(self.remove_commit_bytes))
+    }
+}
+
+/// Load-tests the server at `address` for `mls_chat bench`: connects `options.clients` clients to it (entering `room`
+/// first, on a rendezvous server), has them send messages to each other at `options.rate` for `options.duration`, and
+/// reports how long each took to be delivered and how many never were. The clients don't run MLS, and send messages
+/// the server relays without being able to read, so it's the server alone that's measured; it never takes them for
+/// chat messages, so they aren't held to its rate limit either.
+///
+/// The clients register as `bench-1`, `bench-2`, and so on, so the rest of the room sees them come and go, and is
+/// relayed everything they send.
+///
+/// # Errors
+///
+/// Returns whichever error a client failed to connect or register with, as `Client::build()` and
+/// `Client::handshake()` would.
+pub async fn run(address: &ServerAddr, room: Option<&str>, options: BenchOptions) -> Result<BenchReport, ApplicationError> {
+    let clients = options.clients.max(1);
+    let mut connecting = JoinSet::new();
+    for n in 1..=clients {
+        let (address, room) = (address.clone(), room.map(str::to_string));
+        connecting.spawn(async move {
+            let mut client = Client::build(address, CONNECT_TIMEOUT, 0).await?;
+            client.handshake(room.as_deref(), &format!("bench-{}", n)).await?;
+            client.handle_stream().await?;
+            Ok::<_, ApplicationError>(client)
+        });
+    }
+    let mut connected = Vec::with_capacity(clients);
+    while let Some(client) = connecting.join_next().await {
+        match client {
+            Ok(client) => connected.push(client?),
+            Err(_) => return Err(NetworkError::ConnectionFailed.into()),
+        }
+    }
+
+    let start = Instant::now();
+    let run = Arc::new(Run {
+        start,
+        stop: start + options.duration,
+        // each client sends its share of the rate
+        interval: Duration::from_secs_f64(clients as f64 / options.rate.max(f64::EPSILON)),
+        size: options.size,
+        clients: clients as u64,
+        sent: AtomicU64::new(0),
+        delivered: AtomicU64::new(0),
+        lagged: AtomicU64::new(0),
+        resyncs: AtomicU64::new(0),
+        disconnected: AtomicU64::new(0),
+    });
+    let mut running = JoinSet::new();
+    for (n, client) in connected.into_iter().enumerate() {
+        // offset from the others, so they don't all send at once
+        let first = start + run.interval.mul_f64(n as f64 / clients as f64);
+        running.spawn(drive(client, first, Arc::clone(&run)));
+    }
+
+    let mut latencies = Vec::new();
+    while let Some(client) = running.join_next().await {
+        latencies.extend(client.unwrap_or_default());
+    }
+    latencies.sort();
+
+    let sent = run.sent.load(Ordering::Relaxed);
+    Ok(BenchReport {
+        clients,
+        duration: options.duration,
+        sent,
+        expected: sent * (run.clients - 1),
+        delivered: run.delivered.load(Ordering::Relaxed),
+        lagged: run.lagged.load(Ordering::Relaxed),
+        resyncs: run.resyncs.load(Ordering::Relaxed),
+        disconnected: run.disconnected.load(Ordering::Relaxed),
+        latencies,
+    })
+}
+
+/// Helper function for `run()`. Has `client` send a message every `Run::interval` from `first` until `Run::stop`,
+/// then keeps it reading until every message sent has been delivered to every other client, or `DRAIN_TIMEOUT` has
+/// passed. Returns the latency of every delivery to it.
+async fn drive(mut client: Client, first: Instant, run: Arc<Run>) -> Vec<Duration> {
+    let incoming = client.incoming();
+    let mut ticker = time::interval_at(first, run.interval);
+    let mut latencies = Vec::new();
+    let (start, stop) = (run.start, run.stop);
+    let drained = || run.delivered.load(Ordering::Relaxed) >= run.sent.load(Ordering::Relaxed) * (run.clients - 1);
+
+    loop {
+        let sending = Instant::now() < stop;
+        tokio::select! {
+            _ = ticker.tick(), if sending => {
+                if Instant::now() < stop {
+                    client.send(message(start.elapsed(), run.size)).await;
+                    run.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            _ = incoming.notified() => (),
+            _ = time::sleep_until(stop), if sending => continue,
+            _ = time::sleep_until(stop + DRAIN_TIMEOUT), if !sending => break,
+            // the other clients' deliveries don't wake this one
+            _ = time::sleep(Duration::from_millis(100)), if !sending => (),
+        }
+
+        for frame in client.get_input().await {
+            match frame {
+                Frame::Relay { data, .. } => {
+                    if let Some(sent) = sent_at(&data) {
+                        latencies.push(start.elapsed().saturating_sub(sent));
+                        run.delivered.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Frame::Lagged { .. } => {
+                    run.lagged.fetch_add(1, Ordering::Relaxed);
+                }
+                Frame::Resync => {
+                    run.resyncs.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => (),
+            }
+        }
+        if client.state() == ConnectionState::Disconnected {
+            run.disconnected.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+        if Instant::now() >= stop && drained() {
+            break;
+        }
+    }
+    latencies
+}
+
+/// Measures what MLS costs in a group of `size` members, for `mls_chat bench --groups`: builds the group by having
+/// one member add each of the others in turn, then times adding the last, their joining from the Welcome, and an
+/// update and a removal, along with the size of each commit and of the Welcome. Runs locally, without a server. The
+/// members in between never process anything, since only the committer and the last member are measured.
+///
+/// # Errors
+///
+/// Returns any `ApplicationError` returned by `User` while building the group or taking the measurements, e.g. an
+/// `MlsError::Welcome` if the last member can't join.
+pub fn group_costs(size: usize, ratchet_tree_in_welcome: bool) -> Result<GroupCosts, ApplicationError> {
+    let size = size.max(2);
+    let mut committer = User::build(String::from("bench-1"))?;
+    committer.set_ratchet_tree_extension(ratchet_tree_in_welcome);
+    for n in 2..size {
+        let key_package = User::build(format!("bench-{}", n))?.generate_key_package()?;
+        committer.add_member(key_package.into())?;
+        committer.merge_pending_commit()?;
+    }
+
+    let mut joiner = User::build(format!("bench-{}", size))?;
+    let key_package = joiner.generate_key_package()?;
+    let start = Instant::now();
+    let (commit, welcome) = committer.add_member(key_package.into())?;
+    committer.merge_pending_commit()?;
+    let add = start.elapsed();
+    let (add_commit, welcome) = (serialize(&commit)?, serialize(&welcome)?);
+    let tree = committer.ratchet_tree()?;
+
+    let MlsMessageInBody::Welcome(welcome_in) = deserialize(&welcome)? else { return Err(MlsError::InvalidMessage.into()) };
+    let start = Instant::now();
+    joiner.join_group(welcome_in, (!ratchet_tree_in_welcome).then_some(&tree[..]))?;
+    let join = start.elapsed();
+
+    let start = Instant::now();
+    let commit = committer.update_keys()?;
+    committer.merge_pending_commit()?;
+    let update = start.elapsed();
+    let update_commit = serialize(&commit)?;
+    let commit = match deserialize(&update_commit)? {
+        MlsMessageInBody::PrivateMessage(msg) => ProtocolMessage::from(msg),
+        MlsMessageInBody::PublicMessage(msg) => ProtocolMessage::from(msg),
+        _ => return Err(MlsError::InvalidMessage.into()),
+    };
+    let start = Instant::now();
+    joiner.process_message(commit)?;
+    let process = start.elapsed();
+
+    // leaf 1 is the first member added, which has never taken part
+    let start = Instant::now();
+    let commit = committer.remove_member(1)?;
+    committer.merge_pending_commit()?;
+    let remove = start.elapsed();
+
+    Ok(GroupCosts {
+        size,
+        ratchet_tree_in_welcome,
+        add,
+        add_commit_bytes: add_commit.len(),
+        welcome_bytes: welcome.len(),
+        tree_bytes: tree.len(),
+        join,
+        update,
+        update_commit_bytes: update_commit.len(),
+        process,
+        remove,
+        remove_commit_bytes: serialize(&commit)?.len(),
+    })
+}
+
+/// Helper function for `group_costs()`. Serializes `msg` as it would be sent.
+fn serialize(msg: &MlsMessageOut) -> Result<Vec<u8>, ApplicationError> {
+    msg.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()).into())
+}
+
+/// Helper function for `group_costs()`. Deserializes a message serialized by `serialize()`, as it would be received.
+fn deserialize(data: &[u8]) -> Result<MlsMessageInBody, ApplicationError> {
+    MlsMessageIn::tls_deserialize(&mut &data[..]).map(MlsMessageIn::extract).map_err(|_| MlsError::InvalidMessage.into())
+}
+
+/// Returns a bench message sent `elapsed` into the run, padded out to `size` bytes.
+fn message(elapsed: Duration, size: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size.max(HEADER_LEN));
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX).to_be_bytes());
+    data.resize(size.max(HEADER_LEN), 0);
+    data
+}
+
+/// Returns how far into the run the bench message `data` was sent, or None if it isn't one.
+fn sent_at(data: &[u8]) -> Option<Duration> {
+    let micros = data.strip_prefix(MAGIC)?.get(..8)?;
+    Some(Duration::from_micros(u64::from_be_bytes(micros.try_into().ok()?)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn messages_carry_their_send_time() {
+        let data = message(Duration::from_micros(1234), 64);
+        assert_eq!(data.len(), 64);
+        assert_eq!(sent_at(&data), Some(Duration::from_micros(1234)));
+        assert_eq!(message(Duration::ZERO, 0).len(), HEADER_LEN);
+        assert_eq!(sent_at(b"not a bench message"), None);
+
+        let report = BenchReport {
+            expected: 4,
+            delivered: 3,
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..BenchReport::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.loss(), 0.25);
+    }
+
+    #[tokio::test]
+    async fn delivers_everything_on_a_quiet_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddr::from(listener.local_addr().unwrap());
+        let mut server = Server::new(address.port, 8);
+        server.set_quiet(true);
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+
+        let options = BenchOptions { clients: 3, rate: 30.0, duration: Duration::from_millis(500), size: 100 };
+        let report = run(&address, None, options).await.unwrap();
+        assert!(report.sent > 0);
+        assert_eq!(report.expected, report.sent * 2);
+        assert_eq!(report.delivered, report.expected, "{}", report);
+        assert_eq!(report.latencies.len() as u64, report.delivered);
+
+        serving.abort();
+    }
+
+    #[test]
+    fn welcomes_without_the_tree_stay_small() {
+        let with_tree = group_costs(50, true).unwrap();
+        let without_tree = group_costs(50, false).unwrap();
+        assert!(with_tree.welcome_bytes > with_tree.tree_bytes, "{}", with_tree);
+        assert!(without_tree.welcome_bytes + without_tree.tree_bytes / 2 < with_tree.welcome_bytes, "{}", without_tree);
+        assert!(without_tree.update_commit_bytes > 0 && without_tree.remove_commit_bytes > 0);
+    }
+}
@@ -0,0 +1,94 @@
+use std::{env, path::PathBuf, process};
+use clap::Parser;
+use mls_chat::{
+    matrix::{BridgeView, Homeserver},
+    network::address::{ServerAddr, Target},
+    ApplicationError,
+    NetworkError,
+    StorageError,
+    ClientConfig,
+    Controller,
+};
+use tokio::runtime;
+
+/// relay messages between an mls_chat group and a Matrix room. The Matrix access token is read from the
+/// MATRIX_ACCESS_TOKEN environment variable, so it doesn't show up in the process list.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// homeserver to reach the Matrix room through, as an http:// URL (e.g. http://localhost:8008)
+    #[arg(long, value_name="URL")]
+    homeserver: String,
+
+    /// ID of the Matrix room to bridge (e.g. !abcdef:example.org), which the token's user has already joined
+    #[arg(long, value_name="ROOM_ID")]
+    room: String,
+
+    /// hostname or IP address of the mls_chat server, optionally with the port (defaults to the config file's server
+    /// address)
+    #[arg(short, long, value_name="HOST[:PORT]")]
+    target: Option<Target>,
+
+    /// network port of the mls_chat server (defaults to the config file's server port)
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// user id the bridge joins the group as (defaults to the config file's username)
+    #[arg(short, long)]
+    id: Option<String>,
+
+    /// config file to use instead of ~/.config/mls_chat/config.toml
+    #[arg(short, long, value_name="PATH")]
+    config: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let config = match args.config.or_else(ClientConfig::default_path) {
+        Some(path) => ClientConfig::load(&path).unwrap_or_else(|err| fail(err)),
+        None => ClientConfig::default(),
+    };
+    let (Some(target), Some(port), Some(id)) = (
+        args.target.as_ref().map(|target| target.host.clone()).or(config.server.address.clone()),
+        args.port.or(args.target.and_then(|target| target.port)).or(config.server.port),
+        args.id.or_else(|| config.username.clone()),
+    ) else {
+        eprintln!("Error: a target, port, and id are required, either as flags or in the config file.");
+        process::exit(1)
+    };
+    let Ok(token) = env::var("MATRIX_ACCESS_TOKEN") else {
+        eprintln!("Error: set MATRIX_ACCESS_TOKEN to the access token of the bridge's Matrix user.");
+        process::exit(1)
+    };
+
+    let homeserver = Homeserver::new(&args.homeserver, token).unwrap_or_else(|err| fail(err));
+    let view = BridgeView::start(homeserver, args.room).unwrap_or_else(|err| fail(err));
+
+    let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap_or_else(|err| {
+        eprintln!("Error: unable to start the async runtime: {}", err);
+        process::exit(1)
+    });
+    runtime.block_on(async {
+        let address = ServerAddr::new(target, port);
+        let mut controller = Controller::with_view(address, id, config, Box::new(view)).await.unwrap_or_else(|err| fail(err));
+        let shutdown = controller.shutdown_token();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                shutdown.cancel();
+            }
+        });
+        if let Err(err) = controller.run().await {
+            fail(err)
+        }
+    });
+}
+
+/// Prints `err` and exits.
+fn fail(err: ApplicationError) -> ! {
+    match err {
+        ApplicationError::Network(NetworkError::Bridge(msg)) | ApplicationError::Storage(StorageError::Config(msg)) => eprintln!("Error: {}", msg),
+        err => eprintln!("Error: {}", err),
+    }
+    process::exit(1)
+}
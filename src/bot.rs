@@ -0,0 +1,166 @@
+use std::{collections::VecDeque, io::{self, Write}, str::FromStr, sync::Arc};
+use crate::{
+    ApplicationError,
+    chat_log::{find_matches, ChatLog, EntryKind, LogEntry},
+    frontend::{ChatView, UserInput},
+    plain,
+};
+
+use chrono::Utc;
+use tokio::sync::Notify;
+
+/// How far back a message's timestamp can be from when the bot started before it's taken for history shared with the
+/// bot on joining, rather than something said since, and left unanswered.
+const HISTORY_SLACK: i64 = 60;
+
+/// A chat message from another member, as passed to `MessageHandler::on_message()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatEvent {
+    /// Local number the message is shown with, which replies like `Reply::React` refer to.
+    pub number: usize,
+    pub sender: String,
+    pub text: String,
+    /// Unix timestamp (in seconds) the sender gave the message.
+    pub time: i64,
+    /// whether the message mentions the bot by `@name`
+    pub mentioned: bool,
+}
+
+/// What a `MessageHandler` does in answer to a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// Sends a chat message. It's always sent as text, even if it starts with `/`.
+    Message(String),
+    /// Reacts to the message being answered with an emoji.
+    React(String),
+}
+
+/// Something a bot does with the messages in its group: answering them, logging them, passing them on elsewhere.
+/// Handlers only see chat messages from other members, so a bot can't end up answering itself.
+pub trait MessageHandler: Send {
+    /// Called with each chat message from another member as it arrives, returning what to do in answer, if anything.
+    fn on_message(&mut self, event: &ChatEvent) -> Option<Reply>;
+}
+
+/// Replies with a fixed message to every message containing a trigger phrase, ignoring case. Parses from the
+/// `<trigger>=<reply>` form `join --respond` takes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AutoResponder {
+    pub trigger: String,
+    pub reply: String,
+}
+
+impl MessageHandler for AutoResponder {
+    fn on_message(&mut self, event: &ChatEvent) -> Option<Reply> {
+        (!find_matches(&event.text, &self.trigger).is_empty()).then(|| Reply::Message(self.reply.clone()))
+    }
+}
+
+impl FromStr for AutoResponder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((trigger, reply)) if !trigger.trim().is_empty() && !reply.trim().is_empty() => Ok(AutoResponder {
+                trigger: trigger.trim().to_string(),
+                reply: reply.trim().to_string(),
+            }),
+            _ => Err(String::from("expected <trigger>=<reply>, e.g. \"!ping=pong\"")),
+        }
+    }
+}
+
+/// A view for running the client as a bot: each chat message from another member is passed to every handler in turn,
+/// and their replies are sent back to the group. Log entries are printed to stdout like `PlainView` does. The bot
+/// runs until it's stopped.
+pub struct BotView {
+    handlers: Vec<Box<dyn MessageHandler>>,
+    /// notified as replies are queued, so the Controller comes back for them
+    ready: Arc<Notify>,
+    replies: VecDeque<String>,
+    started: i64,
+}
+
+impl BotView {
+    pub fn new(handlers: Vec<Box<dyn MessageHandler>>) -> BotView {
+        BotView { handlers, ready: Arc::new(Notify::new()), replies: VecDeque::new(), started: Utc::now().timestamp() }
+    }
+
+    /// Passes a log entry to the handlers, if it's a message they should see, and queues their replies.
+    fn dispatch(&mut self, entry: &LogEntry) {
+        let EntryKind::Chat { number, sender, author: Some(_), mentioned, .. } = &entry.kind else { return };
+        if entry.deleted || entry.time < self.started - HISTORY_SLACK {
+            return;
+        }
+
+        let event = ChatEvent {
+            number: *number,
+            sender: sender.clone(),
+            text: entry.text.clone(),
+            time: entry.time,
+            mentioned: *mentioned,
+        };
+        for handler in &mut self.handlers {
+            match handler.on_message(&event) {
+                // a leading / would otherwise be run as a command; // sends it as a message starting with /
+                Some(Reply::Message(text)) if text.starts_with('/') => self.replies.push_back(format!("/{}", text)),
+                Some(Reply::Message(text)) => self.replies.push_back(text),
+                Some(Reply::React(emoji)) => self.replies.push_back(format!("/react {} {}", number, emoji)),
+                None => (),
+            }
+        }
+    }
+}
+
+impl ChatView for BotView {
+    /// Entries are printed by `BotView::push_message()` as they arrive, so there's nothing to redraw.
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.replies.pop_front().map(UserInput::Line))
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        let mut stdout = io::stdout().lock();
+        // stdout going away (e.g. a closed pipe) leaves nothing to report the error to
+        let _ = writeln!(stdout, "{}", plain::format_entry(entry)).and_then(|_| stdout.flush());
+
+        self.dispatch(entry);
+        if !self.replies.is_empty() {
+            self.ready.notify_one();
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        Arc::clone(&self.ready)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_to_other_members_only() {
+        let responder: AutoResponder = "!ping = /pong".parse().unwrap();
+        assert!("no reply".parse::<AutoResponder>().is_err());
+
+        let mut bot = BotView::new(vec![Box::new(responder)]);
+        let mut log = ChatLog::new(10);
+        let now = Utc::now().timestamp();
+        log.push_chat(1, now, String::from("bot"), None, "bot", String::from("!PING"));
+        log.push_chat(2, now, String::from("alice"), Some(1), "bot", String::from("!PING"));
+        log.push_chat(3, now - 3600, String::from("alice"), Some(1), "bot", String::from("!ping from history"));
+        log.iter().for_each(|entry| bot.dispatch(entry));
+
+        assert_eq!(bot.poll_input().unwrap(), Some(UserInput::Line(String::from("//pong"))));
+        assert_eq!(bot.poll_input().unwrap(), None);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use crate::message::MessageId;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// What a `LogEntry` records.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A chat message from a member of the group, including the local user.
+    Chat {
+        id: MessageId,
+        /// Local number the user refers to the message by in commands like `/react`.
+        number: usize,
+        sender: String,
+        /// Leaf index of the member who sent the message, or None for the local user's own messages.
+        author: Option<u32>,
+        /// whether the message mentions the local user by `@name`
+        mentioned: bool,
+        /// whether another member shared the message after the local user joined, rather than its sender sending it.
+        /// `sender` then names the member who shared it too, and `author` is theirs, since nothing vouches for what
+        /// they say about the message; nobody can edit or delete it.
+        #[serde(default)]
+        shared: bool,
+    },
+    /// A notice from the application itself.
+    System,
+    /// A notice from the application about something that could compromise the group's security.
+    Warning,
+}
+
+/// A single line of the chat log, along with any reactions it has received.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub kind: EntryKind,
+    /// Unix timestamp (in seconds) the entry was sent or created at.
+    pub time: i64,
+    pub text: String,
+    /// Names of the members who reacted to the message, by emoji.
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+    /// whether the sender has edited the message since sending it
+    pub edited: bool,
+    /// whether the sender has deleted the message, leaving only a tombstone with no text
+    pub deleted: bool,
+    /// whether the message is the local user's, written while disconnected and not sent yet
+    #[serde(default)]
+    pub pending: bool,
+    /// whether the message is the local user's and couldn't be sent, waiting to be retried
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl LogEntry {
+    /// Returns true if this is a chat message sent by the local user.
+    pub fn is_own(&self) -> bool {
+        matches!(self.kind, EntryKind::Chat { author: None, .. })
+    }
+
+    /// Formats the entry's timestamp for display.
+    pub fn timestamp(&self) -> String {
+        DateTime::from_timestamp(self.time, 0).unwrap_or_default().time().format("%H:%M:%S").to_string()
+    }
+}
+
+/// The messages shown in the chat window, capped at a maximum number of entries.
+pub struct ChatLog {
+    entries: VecDeque<LogEntry>,
+    max_entries: usize,
+    next_number: usize,
+    /// number of entries ever added, including those since dropped
+    total: usize,
+}
+
+impl ChatLog {
+    pub fn new(max_entries: usize) -> ChatLog {
+        ChatLog {
+            entries: VecDeque::new(),
+            max_entries: max_entries.max(1),
+            next_number: 1,
+            total: 0,
+        }
+    }
+
+    /// Adds a chat message to the log and returns the local number assigned to it. `author` is the sender's leaf
+    /// index, or None for the local user's own messages. `local_name` is the local user's current name, used to flag
+    /// messages that mention them.
+    pub fn push_chat(
+        &mut self,
+        id: MessageId,
+        time: i64,
+        sender: String,
+        author: Option<u32>,
+        local_name: &str,
+        text: String,
+    ) -> usize {
+        let number = self.next_number;
+        self.next_number += 1;
+        let mentioned = author.is_some() && mentions(&text, local_name);
+        self.push(LogEntry {
+            kind: EntryKind::Chat { id, number, sender, author, mentioned, shared: false },
+            time,
+            text,
+            reactions: BTreeMap::new(),
+            edited: false,
+            deleted: false,
+            pending: false,
+            failed: false,
+        });
+        number
+    }
+
+    /// Adds a chat message another member shared from their log (see `SharedEntry`) and returns the local number
+    /// assigned to it, as `ChatLog::push_chat()` does. `sender` should name both who the member says sent it and the
+    /// member themselves, and `sharer` is their leaf index.
+    pub fn push_shared(&mut self, id: MessageId, time: i64, sender: String, sharer: u32, local_name: &str, text: String) -> usize {
+        let number = self.push_chat(id, time, sender, Some(sharer), local_name, text);
+        if let Some(LogEntry { kind: EntryKind::Chat { shared, .. }, .. }) = self.entries.back_mut() {
+            *shared = true;
+        }
+        number
+    }
+
+    /// Adds a notice from the application, timestamped with `time`.
+    pub fn push_system(&mut self, time: i64, text: String) {
+        self.push(LogEntry {
+            kind: EntryKind::System,
+            time,
+            text,
+            reactions: BTreeMap::new(),
+            edited: false,
+            deleted: false,
+            pending: false,
+            failed: false,
+        });
+    }
+
+    /// Adds a warning from the application, timestamped with `time`.
+    pub fn push_warning(&mut self, time: i64, text: String) {
+        self.push(LogEntry {
+            kind: EntryKind::Warning,
+            time,
+            text,
+            reactions: BTreeMap::new(),
+            edited: false,
+            deleted: false,
+            pending: false,
+            failed: false,
+        });
+    }
+
+    /// Adds entries saved from an earlier log, e.g. with a resumed session, numbering new messages after theirs.
+    pub fn restore(&mut self, entries: Vec<LogEntry>) {
+        for entry in entries {
+            if let EntryKind::Chat { number, .. } = entry.kind {
+                self.next_number = self.next_number.max(number + 1);
+            }
+            self.push(entry);
+        }
+    }
+
+    fn push(&mut self, entry: LogEntry) {
+        self.entries.push_back(entry);
+        self.total += 1;
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Records `reactor`'s reaction to the message with the given ID. Returns false if the message
+    /// isn't in the log (it may be older than the log's history) or has been deleted.
+    pub fn react(&mut self, id: MessageId, emoji: String, reactor: String) -> bool {
+        let Some(entry) = self.entries.iter_mut()
+            .find(|entry| !entry.deleted && matches!(entry.kind, EntryKind::Chat { id: entry_id, .. } if entry_id == id))
+        else { return false };
+
+        entry.reactions.entry(emoji).or_default().insert(reactor);
+        true
+    }
+
+    /// Replaces the text of the message with the given ID, if it's in the log, was sent by `author` (as passed to
+    /// `ChatLog::push_chat()`), and hasn't been deleted. Returns false otherwise.
+    pub fn edit(&mut self, id: MessageId, author: Option<u32>, text: String) -> bool {
+        let Some(entry) = self.find_by(id, author) else { return false };
+        entry.text = text;
+        entry.edited = true;
+        true
+    }
+
+    /// Marks the local user's message with the given ID as pending or sent, if it's in the log.
+    pub fn set_pending(&mut self, id: MessageId, pending: bool) {
+        if let Some(entry) = self.find_by(id, None) {
+            entry.pending = pending;
+        }
+    }
+
+    /// Marks the local user's message with the given ID as failed to send or not, if it's in the log.
+    pub fn set_failed(&mut self, id: MessageId, failed: bool) {
+        if let Some(entry) = self.find_by(id, None) {
+            entry.failed = failed;
+        }
+    }
+
+    /// Tombstones the message with the given ID, clearing its text and reactions, if it's in the log and was sent
+    /// by `author` (as passed to `ChatLog::push_chat()`). Returns false otherwise.
+    pub fn delete(&mut self, id: MessageId, author: Option<u32>) -> bool {
+        let Some(entry) = self.find_by(id, author) else { return false };
+        entry.text.clear();
+        entry.reactions.clear();
+        entry.deleted = true;
+        true
+    }
+
+    /// Moves the authors of the messages from other members to their new leaf indices, as `leaves` maps them from
+    /// the old ones, once the group has moved to a successor. Messages from members who didn't move can no longer be
+    /// edited or deleted by anyone.
+    pub fn move_authors(&mut self, leaves: &HashMap<u32, u32>) {
+        for entry in &mut self.entries {
+            if let EntryKind::Chat { author: Some(author), .. } = &mut entry.kind {
+                // no member's leaf index is ever this high, so nobody can claim the message
+                *author = leaves.get(author).copied().unwrap_or(u32::MAX);
+            }
+        }
+    }
+
+    /// Returns the message with the given ID if it was sent by `author`, hasn't been deleted, and wasn't shared.
+    fn find_by(&mut self, id: MessageId, author: Option<u32>) -> Option<&mut LogEntry> {
+        self.entries.iter_mut().find(|entry| !entry.deleted && matches!(
+            entry.kind,
+            EntryKind::Chat { id: entry_id, author: entry_author, shared: false, .. } if entry_id == id && entry_author == author
+        ))
+    }
+
+    /// Returns the ID of the chat message with the given local number, if it's still in the log.
+    pub fn id_of(&self, number: usize) -> Option<MessageId> {
+        self.entries.iter().find_map(|entry| match entry.kind {
+            EntryKind::Chat { id, number: n, .. } if n == number => Some(id),
+            _ => None,
+        })
+    }
+
+    /// Returns the number of entries ever added to the log, including those that have since been dropped. The
+    /// oldest entry still in the log was the `total() - len()`th added.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the entry at `index`, counting every entry ever added as `ChatLog::total()` does, if it's still in
+    /// the log.
+    pub fn get(&self, index: usize) -> Option<&LogEntry> {
+        self.entries.get(index.checked_sub(self.total - self.entries.len())?)
+    }
+
+    /// Returns the most recently added entry.
+    pub fn last(&self) -> Option<&LogEntry> {
+        self.entries.back()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &LogEntry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Returns true if `text` contains `@name` as a whole word, ignoring case.
+pub fn mentions(text: &str, name: &str) -> bool {
+    mention_spans(text).any(|(start, end)| text[start + 1..end].eq_ignore_ascii_case(name))
+}
+
+/// Returns the byte ranges of every `@name` token in `text`, including the `@`. A token runs from an `@` at the
+/// start of the text or after whitespace up to the next character that can't be part of a name.
+pub fn mention_spans(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    text.char_indices()
+        .filter(move |&(i, c)| c == '@' && text[..i].chars().next_back().is_none_or(char::is_whitespace))
+        .filter_map(move |(start, _)| {
+            let len = text[start + 1..]
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(text.len() - start - 1);
+            (len > 0).then_some((start, start + 1 + len))
+        })
+}
+
+/// Returns the byte ranges of every non-overlapping occurrence of `query` in `text`, ignoring case.
+pub fn find_matches(text: &str, query: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    if query.is_empty() {
+        return found;
+    }
+
+    let mut start = 0;
+    while let Some(c) = text[start..].chars().next() {
+        match match_len(&text[start..], query) {
+            Some(len) => {
+                found.push((start, start + len));
+                start += len;
+            }
+            None => start += c.len_utf8(),
+        }
+    }
+    found
+}
+
+/// Returns the length in bytes of the prefix of `text` that matches `query` ignoring case, if there is one.
+fn match_len(text: &str, query: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    for q in query.chars() {
+        let (_, c) = chars.next()?;
+        if !c.to_lowercase().eq(q.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.next().map_or(text.len(), |(i, _)| i))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_aggregate_per_reactor() {
+        let mut log = ChatLog::new(10);
+        let number = log.push_chat(7, 0, String::from("alice"), Some(1), "bob", String::from("hi"));
+
+        assert_eq!(log.id_of(number), Some(7));
+        assert!(log.react(7, String::from("👍"), String::from("bob")));
+        assert!(log.react(7, String::from("👍"), String::from("bob")));
+        assert!(log.react(7, String::from("👍"), String::from("carol")));
+        assert!(!log.react(8, String::from("👍"), String::from("carol")));
+
+        let entry = log.iter().next().unwrap();
+        assert_eq!(entry.reactions["👍"].len(), 2);
+    }
+
+    #[test]
+    fn only_the_author_can_edit_or_delete() {
+        let mut log = ChatLog::new(10);
+        log.push_chat(7, 0, String::from("alice"), Some(1), "bob", String::from("hi"));
+
+        assert!(!log.edit(7, Some(2), String::from("forged")));
+        assert!(!log.edit(7, None, String::from("forged")));
+        log.move_authors(&HashMap::from([(1, 2), (2, 1)]));
+        assert!(!log.edit(7, Some(1), String::from("forged")));
+        log.move_authors(&HashMap::from([(2, 1)]));
+        assert!(log.edit(7, Some(1), String::from("hello")));
+        assert!(log.delete(7, Some(1)));
+        assert!(!log.edit(7, Some(1), String::from("revived")));
+
+        let entry = log.iter().next().unwrap();
+        assert!(entry.edited && entry.deleted && entry.text.is_empty());
+
+        log.push_shared(8, 0, String::from("alice (shared by bob)"), 2, "carol", String::from("hi"));
+        assert!(!log.edit(8, Some(2), String::from("forged")));
+        assert!(!log.delete(8, Some(2)));
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let mut log = ChatLog::new(2);
+        let first = log.push_chat(1, 0, String::from("alice"), Some(1), "bob", String::from("one"));
+        log.push_system(0, String::from("two"));
+        log.push_system(0, String::from("three"));
+
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.total(), 3);
+        assert_eq!(log.id_of(first), None);
+        assert!(log.get(0).is_none());
+        assert_eq!(log.get(2).map(|entry| entry.text.as_str()), Some("three"));
+    }
+
+    #[test]
+    fn restored_entries_keep_their_numbers() {
+        let mut log = ChatLog::new(10);
+        log.push_chat(1, 0, String::from("alice"), Some(1), "bob", String::from("one"));
+        log.push_chat(2, 0, String::from("alice"), Some(1), "bob", String::from("two"));
+
+        let mut resumed = ChatLog::new(10);
+        resumed.restore(log.iter().cloned().collect());
+        assert_eq!(resumed.id_of(2), Some(2));
+        assert_eq!(resumed.push_chat(3, 0, String::from("alice"), Some(1), "bob", String::from("three")), 3);
+    }
+
+    #[test]
+    fn detect_mentions() {
+        assert!(mentions("hey @Bob, lunch?", "bob"));
+        assert!(mentions("@bob", "bob"));
+        assert!(!mentions("hey @bobby", "bob"));
+        assert!(!mentions("mail bob@example.org", "bob"));
+        assert_eq!(mention_spans("@a b @c_d!").collect::<Vec<_>>(), vec![(0, 2), (5, 9)]);
+    }
+
+    #[test]
+    fn find_matches_ignores_case() {
+        assert_eq!(find_matches("Lunch? lunch!", "LUNCH"), vec![(0, 5), (7, 12)]);
+        assert_eq!(find_matches("aaa", "aa"), vec![(0, 2)]);
+        assert_eq!(find_matches("Ünïcode ünï", "üNÏ"), vec![(0, 5), (10, 15)]);
+        assert!(find_matches("hello", "").is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// Where the `Controller` gets the time from: `Instant`s for its timers (key rotation, key package refreshes, cover
+/// traffic, link codes) and wall-clock time for timestamping the chat log. Swapping in a `ManualClock` lets tests move
+/// time forward themselves instead of sleeping.
+pub trait Clock: Send {
+    /// Returns the current point in monotonic time.
+    fn now(&self) -> Instant;
+
+    /// Returns the current wall-clock time.
+    fn utc(&self) -> DateTime<Utc>;
+
+    /// Returns the time passed since `earlier`, or zero if it's in the future.
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// The system's own clocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until `ManualClock::advance()` moves it. Clones share the same time, so a test can keep
+/// one to advance while the `Controller` reads another.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    origin: Instant,
+    start: DateTime<Utc>,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Returns a clock stopped at wall-clock time `start`.
+    pub fn new(start: DateTime<Utc>) -> ManualClock {
+        ManualClock { origin: Instant::now(), start, elapsed: Arc::default() }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Returns the time passed since the clock was started.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let elapsed = TimeDelta::from_std(self.elapsed()).unwrap_or(TimeDelta::max_value());
+        self.start.checked_add_signed(elapsed).unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let handle = clock.clone();
+        let before = clock.now();
+
+        handle.advance(Duration::from_secs(90));
+        assert_eq!(clock.since(before), Duration::from_secs(90));
+        assert_eq!(clock.utc().timestamp(), 1_700_000_090);
+        assert_eq!(handle.since(clock.now() + Duration::from_secs(1)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,220 @@
+use std::path::PathBuf;
+
+/// A line of input from the chat window, either plain text for the group or a `/command`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Text to send to the group as a chat message.
+    Chat(String),
+    /// `/nick <name>`: change the name this user is displayed under.
+    Nick(String),
+    /// `/react <number> <emoji>`: react to the message shown with the given number in the log.
+    React { number: usize, emoji: String },
+    /// `/topic <text>`: set the group's topic.
+    Topic(String),
+    /// `/edit <number> <text>`: replace the text of one of the user's own messages.
+    Edit { number: usize, text: String },
+    /// `/delete <number>`: delete one of the user's own messages.
+    Delete(usize),
+    /// `/keys`: list the active key bindings.
+    Keys,
+    /// `/copy-last`: copy the most recent message from another member to the clipboard.
+    CopyLast,
+    /// `/retry`: try again to send the user's messages that failed.
+    Retry,
+    /// `/share-history`: send the recent chat history to the members who've asked for it.
+    ShareHistory,
+    /// `/verify-group`: show a code to compare with the other members over another channel.
+    VerifyGroup,
+    /// `/debug`: show the group ID, epoch, own leaf index, and other protocol state.
+    Debug,
+    /// `/tree`: show every node of the group's ratchet tree, including blank ones.
+    Tree,
+    /// `/stats`: show traffic, commit, reconnection and latency counters for this session.
+    Stats,
+    /// `/away [message]`: mark the user as away with an optional message, or as back if they already were.
+    Away(Option<String>),
+    /// `/link`: show a one-time code to add another of the user's devices to the group with.
+    Link,
+    /// `/reinit <ciphersuite>`: move the group to a successor using the ciphersuite with the given code point.
+    ReInit(u16),
+    /// `/wipe`: list what `/wipe confirm` would delete; `/wipe confirm`: delete it and quit.
+    Wipe { confirmed: bool },
+    /// `/create <room> [--invite-only]`: create a room on the server, owned by the user.
+    Create { room: String, invite_only: bool },
+    /// `/export <path>`: write the chat log to a file, as JSON, Markdown, or plain text depending on its extension.
+    Export(PathBuf),
+    /// `/dump-state [--redact] <path>`: write the group's public state to a file as JSON, for bug reports, with
+    /// members' identities left out if `--redact` is given.
+    DumpState { path: PathBuf, redact: bool },
+}
+
+impl Command {
+    /// Parses a line typed into the chat window. Lines that don't start with `/` are chat messages;
+    /// a leading `//` sends the rest of the line as a chat message starting with a single `/`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message to show to the user if the command is unknown or its arguments are invalid.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let Some(command) = line.strip_prefix('/') else { return Ok(Command::Chat(line.to_string())) };
+        if command.starts_with('/') {
+            return Ok(Command::Chat(command.to_string()));
+        }
+
+        let (name, args) = match command.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "nick" => {
+                if args.is_empty() || args.contains(char::is_whitespace) {
+                    return Err(String::from("Usage: /nick <name> (names can't contain spaces)"));
+                }
+                Ok(Command::Nick(args.to_string()))
+            }
+            "react" => {
+                let usage = || String::from("Usage: /react <message number> <emoji>");
+                let Some((number, emoji)) = args.split_once(char::is_whitespace) else { return Err(usage()) };
+                let (number, emoji) = (number.trim_start_matches('#'), emoji.trim());
+                let Ok(number) = number.parse() else { return Err(usage()) };
+                if emoji.is_empty() || emoji.contains(char::is_whitespace) {
+                    return Err(usage());
+                }
+                Ok(Command::React { number, emoji: emoji.to_string() })
+            }
+            "topic" => {
+                if args.is_empty() {
+                    return Err(String::from("Usage: /topic <text>"));
+                }
+                Ok(Command::Topic(args.to_string()))
+            }
+            "edit" => {
+                let usage = || String::from("Usage: /edit <message number> <new text>");
+                let Some((number, text)) = args.split_once(char::is_whitespace) else { return Err(usage()) };
+                let Ok(number) = number.trim_start_matches('#').parse() else { return Err(usage()) };
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(usage());
+                }
+                Ok(Command::Edit { number, text: text.to_string() })
+            }
+            "delete" => {
+                let Ok(number) = args.trim_start_matches('#').parse() else {
+                    return Err(String::from("Usage: /delete <message number>"));
+                };
+                Ok(Command::Delete(number))
+            }
+            "keys" => Ok(Command::Keys),
+            "copy-last" => Ok(Command::CopyLast),
+            "retry" => Ok(Command::Retry),
+            "share-history" => Ok(Command::ShareHistory),
+            "verify-group" => Ok(Command::VerifyGroup),
+            "debug" => Ok(Command::Debug),
+            "tree" => Ok(Command::Tree),
+            "stats" => Ok(Command::Stats),
+            "link" => Ok(Command::Link),
+            "reinit" => {
+                let Ok(ciphersuite) = args.parse() else {
+                    return Err(String::from("Usage: /reinit <ciphersuite> (its code point, e.g. 1)"));
+                };
+                Ok(Command::ReInit(ciphersuite))
+            }
+            "away" => Ok(Command::Away((!args.is_empty()).then(|| args.to_string()))),
+            "wipe" => match args {
+                "" => Ok(Command::Wipe { confirmed: false }),
+                "confirm" => Ok(Command::Wipe { confirmed: true }),
+                _ => Err(String::from("Usage: /wipe, then /wipe confirm")),
+            },
+            "create" => {
+                let usage = || String::from("Usage: /create <room code> [--invite-only]");
+                let mut room = None;
+                let mut invite_only = false;
+                for arg in args.split_whitespace() {
+                    match arg {
+                        "--invite-only" => invite_only = true,
+                        _ if room.is_none() => room = Some(arg.to_string()),
+                        _ => return Err(usage()),
+                    }
+                }
+                let Some(room) = room else { return Err(usage()) };
+                crate::invite::check_room_code(&room).map_err(|e| format!("Invalid room code: {}", e))?;
+                Ok(Command::Create { room, invite_only })
+            }
+            "export" => {
+                if args.is_empty() {
+                    return Err(String::from("Usage: /export <path> (.json, .md, or anything else for plain text)"));
+                }
+                Ok(Command::Export(PathBuf::from(args)))
+            }
+            "dump-state" => {
+                let (redact, path) = match args.strip_prefix("--redact") {
+                    Some(path) if path.is_empty() || path.starts_with(char::is_whitespace) => (true, path.trim()),
+                    _ => (false, args),
+                };
+                if path.is_empty() {
+                    return Err(String::from("Usage: /dump-state [--redact] <path>"));
+                }
+                Ok(Command::DumpState { path: PathBuf::from(path), redact })
+            }
+            _ => Err(format!("Unknown command /{}", name)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("hello"), Ok(Command::Chat(String::from("hello"))));
+        assert_eq!(Command::parse("//shrug"), Ok(Command::Chat(String::from("/shrug"))));
+        assert_eq!(Command::parse("/nick  bobby "), Ok(Command::Nick(String::from("bobby"))));
+        assert!(Command::parse("/nick").is_err());
+        assert!(Command::parse("/nick two words").is_err());
+        assert_eq!(Command::parse("/react #3 👍"), Ok(Command::React { number: 3, emoji: String::from("👍") }));
+        assert!(Command::parse("/react 👍").is_err());
+        assert_eq!(Command::parse("/topic book club"), Ok(Command::Topic(String::from("book club"))));
+        assert_eq!(Command::parse("/edit #2 fixed  typo"), Ok(Command::Edit { number: 2, text: String::from("fixed  typo") }));
+        assert!(Command::parse("/edit 2").is_err());
+        assert_eq!(Command::parse("/delete 4"), Ok(Command::Delete(4)));
+        assert!(Command::parse("/delete four").is_err());
+        assert_eq!(Command::parse("/keys"), Ok(Command::Keys));
+        assert_eq!(Command::parse("/copy-last"), Ok(Command::CopyLast));
+        assert_eq!(Command::parse("/retry"), Ok(Command::Retry));
+        assert_eq!(Command::parse("/share-history"), Ok(Command::ShareHistory));
+        assert_eq!(Command::parse("/verify-group"), Ok(Command::VerifyGroup));
+        assert_eq!(Command::parse("/debug"), Ok(Command::Debug));
+        assert_eq!(Command::parse("/tree"), Ok(Command::Tree));
+        assert_eq!(Command::parse("/stats"), Ok(Command::Stats));
+        assert_eq!(Command::parse("/link"), Ok(Command::Link));
+        assert_eq!(Command::parse("/reinit 1"), Ok(Command::ReInit(1)));
+        assert!(Command::parse("/reinit chacha").is_err());
+        assert_eq!(Command::parse("/away lunch"), Ok(Command::Away(Some(String::from("lunch")))));
+        assert_eq!(Command::parse("/away"), Ok(Command::Away(None)));
+        assert_eq!(Command::parse("/wipe"), Ok(Command::Wipe { confirmed: false }));
+        assert_eq!(Command::parse("/wipe confirm"), Ok(Command::Wipe { confirmed: true }));
+        assert!(Command::parse("/wipe now").is_err());
+        assert_eq!(Command::parse("/export minutes.md"), Ok(Command::Export(PathBuf::from("minutes.md"))));
+        assert!(Command::parse("/export").is_err());
+        assert_eq!(
+            Command::parse("/dump-state --redact state.json"),
+            Ok(Command::DumpState { path: PathBuf::from("state.json"), redact: true }),
+        );
+        assert_eq!(
+            Command::parse("/dump-state --redacted.json"),
+            Ok(Command::DumpState { path: PathBuf::from("--redacted.json"), redact: false }),
+        );
+        assert!(Command::parse("/dump-state --redact").is_err());
+        assert_eq!(Command::parse("/create book-club"), Ok(Command::Create { room: String::from("book-club"), invite_only: false }));
+        assert_eq!(
+            Command::parse("/create --invite-only book-club"),
+            Ok(Command::Create { room: String::from("book-club"), invite_only: true }),
+        );
+        assert!(Command::parse("/create --invite-only").is_err());
+        assert!(Command::parse("/create two rooms").is_err());
+        assert!(Command::parse("/frobnicate").is_err());
+    }
+}
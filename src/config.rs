@@ -0,0 +1,1026 @@
+use std::{env, fmt, fs, io::ErrorKind, net::SocketAddr, path::{Path, PathBuf}};
+use crate::{ApplicationError, StorageError, link::LinkCode, theme::{ThemeColors, ThemeName}};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use openmls::prelude::{Capabilities, Ciphersuite, ExtensionType, ProposalType, ProtocolVersion};
+use serde::Deserialize;
+
+/// The commented template written out by `mls_chat config init`. Every setting is commented out, so
+/// the file starts out behaving exactly like the built-in defaults.
+const TEMPLATE: &str = r##"# mls_chat client configuration.
+#
+# Every setting is optional; flags passed to `mls_chat join` take priority over this file.
+
+# Username/id to join with.
+# username = "alice"
+
+# Use the line-based interface instead of the full-screen one, like `mls_chat join --plain`.
+# plain = false
+
+# Color theme for the chat window: "dark", "light", or "solarized".
+# theme = "dark"
+
+# Minimum number of seconds between updates of your key material. Keys are always updated after
+# sending a message once at least this much time has passed since the last update; 0 updates them
+# after every message.
+# key_rotation_interval = 0
+
+[server]
+# Default server to join, by hostname or IP address.
+# address = "127.0.0.1"
+# port = 8080
+# Seconds to wait for a server to accept the connection before giving up on that attempt.
+# connect_timeout = 10
+# Number of times to try connecting again after a failed attempt, waiting a little longer before each.
+# connect_retries = 2
+# Whether to offer to compress large frames (e.g. Welcomes for big groups), if the server supports it.
+# compress = true
+
+[tuning]
+# Capacities of the client's queues and buffers. The defaults are fine for most groups; in a busy group of a few
+# hundred members, raising inbound_queue to 4096 lets bursts of commits through without stalling the connection.
+# Frames waiting to be written to the server before sending waits for room in the queue; 0 never waits.
+# outbound_queue = 256
+# Frames read from the server but not yet handled before reading stops until they are; 0 never stops.
+# inbound_queue = 1024
+# Sizes in bytes of the buffers the connection is read through and written through.
+# read_buffer = 8192
+# write_buffer = 8192
+# Whether Welcomes into groups you start carry the group's ratchet tree. In groups of hundreds of members it makes
+# every Welcome large; turned off, new members fetch the tree from the server instead.
+# ratchet_tree_in_welcome = true
+
+[history]
+# Number of lines kept in the chat log before the oldest are dropped.
+# max_lines = 1000
+# Whether to share recent messages with members who join later: "ask" notes each request in the log and waits for
+# /share-history, "always" shares without asking, and "never" ignores requests.
+# share = "ask"
+# Number of the most recent messages shared.
+# share_window = 50
+
+[display]
+# Render *bold*, _italic_, and `code` in messages. Set to false to show messages exactly as typed.
+# formatting = true
+
+[notifications]
+# What to do when someone mentions you with @username.
+# bell = false        # ring the terminal bell
+# desktop = false     # send a desktop notification (requires notify-send)
+
+[privacy]
+# Pad every message to a multiple of this many bytes before it's encrypted, so that anyone watching the traffic
+# (including the server) can't tell messages apart by their length. 0 turns padding off.
+# padding = 0
+# Paranoid mode: send dummy messages (which other members discard) at random around every cover_interval seconds,
+# and hold back everything sent by a random delay of up to max_send_delay milliseconds, to blur when you're
+# actually active. Costs bandwidth, and makes messages arrive a little later.
+# paranoid = false
+# cover_interval = 30
+# max_send_delay = 500
+
+[moderation]
+# Who may add and remove members: "open" lets anyone, "warn" lets anyone but warns when someone who isn't listed
+# below does, and "enforce" refuses their commits outright. Unless it's "open", this client only adds new members
+# if you're listed yourself. Everyone in the group should use the same settings, since a refused commit leaves this
+# client out of step with the members who accepted it.
+# policy = "open"
+# moderators = ["alice", "bob"]
+
+[security]
+# What to do with chat messages that arrive signed but not encrypted, which no mls_chat client sends: "strict" drops
+# them with a warning in the chat (and the audit log), since they mean a member's client is broken or someone is
+# trying to slip messages past the group's encryption, while "lenient" drops them with only a notice, as for any
+# other message that can't be decrypted. They're never shown either way.
+# plaintext = "strict"
+# Which commits from other members are refused rather than merged: "permissive" only refuses what the moderation
+# settings do, while "strict" also refuses removals from anyone who isn't a moderator (even with policy = "warn"),
+# additions to an invite-only room from anyone but its owner, and commits that remove a member and add them back
+# under a different key. As with moderation, everyone in the group should use the same setting.
+# commits = "permissive"
+
+[capabilities]
+# What your key packages tell other clients you support, for MLS deployments that check it. Each list is of IANA
+# code points, and a list that's left out keeps the openmls default. The protocol version (1) and ciphersuite (3)
+# mls_chat uses can't be left out of their lists. Only applies to the groups you're added to: a group you create
+# starts with openmls's defaults.
+# versions = [1]
+# ciphersuites = [1, 2, 3]
+# extensions = [1]
+# proposals = [1, 2, 3, 4, 5, 6, 7]
+
+[session]
+# Save your place in the group to this file as you chat, and pick up from it when you start mls_chat again: you stay
+# in the same group at the same leaf instead of being added back as a new member, and catch up on whatever the
+# server relayed while you were away, as long as it still has it. The file holds your private keys, so keep it
+# somewhere only you can read. Off unless a path is set. `mls_chat export-identity` and `import-identity` move it to
+# another machine, encrypted under a passphrase.
+# path = "/home/alice/.local/share/mls_chat/session.json"
+# Also save the chat log with the session, so it's back after a restart and `mls_chat export-log` can write it out.
+# It's kept in the clear, so anyone who can read the session file can read the chat.
+# save_log = false
+
+[audit]
+# Append a record of every commit merged, members joining and leaving, and key updates to this file, with
+# timestamps and the identities involved, so that incidents can be reconstructed later. Off unless a path is set.
+# path = "/home/alice/.local/share/mls_chat/audit.log"
+# What's recorded: "membership" for members being added and removed (along with anything suspicious), "commits"
+# for every commit and epoch change as well, and "all" for the senders of application messages too (never what
+# they said).
+# level = "commits"
+
+[colors]
+# Override parts of the theme. Colors are names ("red", "lightblue"), hex codes ("#268bd2"), or
+# 256-color indices ("33"). Each sets the text color of its element, except search_match,
+# current_match, and selection, which set the highlight's background.
+# own_message = "cyan"      # your name on your own messages
+# mention = "yellow"        # lines that mention you
+# system = "darkgray"       # notices from mls_chat
+# error = "red"             # errors and the disconnected state
+# status = "gray"           # the status line
+# input = "yellow"          # the input box while typing
+# search_match = "blue"     # search matches
+# current_match = "yellow"  # the search match last jumped to
+# unread = "red"            # the unread divider and new message count
+# online = "green"          # presence markers and the connected state
+# away = "yellow"           # presence markers of members who are away
+# selection = "darkgray"    # the message selected for copying
+# code = "lightgreen"       # `code` in messages
+
+[keybindings]
+# Keys are written as e.g. "Enter", "Esc", "Tab", "Up", "PageDown", "F2", "q", or with
+# modifiers as "Ctrl+q" or "Alt+Enter".
+# edit = "Enter"      # start typing a message
+# send = "Enter"      # send the typed message
+# normal = "Esc"      # stop typing
+# quit = "Esc"        # exit mls_chat
+# members = "Tab"     # show or hide the member list
+# scroll_up = "PageUp"        # scroll back through the chat log
+# scroll_down = "PageDown"    # scroll forward through the chat log
+# search = "/"        # search the chat log
+# search_next = "n"   # jump to the next older match
+# search_prev = "N"   # jump to the next newer match
+# select_prev = "Up"  # select the previous message in the chat log
+# select_next = "Down"        # select the next message in the chat log
+# copy = "y"          # copy the selected message to the clipboard
+# retry = "r"         # retry sending messages that failed
+# next_group = "Ctrl+Right"   # switch to the next group
+# prev_group = "Ctrl+Left"    # switch to the previous group
+#
+# Keys that are active in the same mode can't share a binding, and keys that work while typing
+# need a modifier or a non-character key. Type /keys in the chat to list the active bindings.
+"##;
+
+/// The client's settings, loaded from `config.toml` in the user's config directory. Missing settings
+/// take their default values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server: DefaultServer,
+    pub tuning: ClientTuning,
+    pub username: Option<String>,
+    /// Use the line-based `PlainView` instead of the full-screen `ChatWindow`.
+    pub plain: bool,
+    pub theme: ThemeName,
+    pub colors: ThemeColors,
+    /// Minimum number of seconds between key updates; 0 updates after every message sent.
+    pub key_rotation_interval: u64,
+    pub history: HistoryConfig,
+    pub display: DisplayConfig,
+    pub keybindings: KeyBindings,
+    pub notifications: NotificationConfig,
+    pub privacy: PrivacyConfig,
+    pub moderation: ModerationConfig,
+    pub security: SecurityConfig,
+    pub capabilities: CapabilitiesConfig,
+    pub session: SessionConfig,
+    pub audit: AuditConfig,
+    /// Code from `/link` on another device, to join as another device of that user. Only ever set by `join --link`.
+    #[serde(skip)]
+    pub link: Option<LinkCode>,
+    /// Address to take messages to send from as a `WebhookView`, instead of the terminal. Only ever set by
+    /// `join --webhook`.
+    #[serde(skip)]
+    pub webhook: Option<SocketAddr>,
+    /// Code of the room to enter on a rendezvous server. Only ever set by `join --room` or an invite naming a room.
+    #[serde(skip)]
+    pub room: Option<String>,
+    /// File to trace the MLS messages sent and received to, as an `MlsTrace`. Only ever set by `join --debug-mls`.
+    #[serde(skip)]
+    pub debug_mls: Option<PathBuf>,
+    /// The file the config was loaded from, if it was.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// The server's settings, loaded from `server.toml` in the user's config directory, or the file passed to `mls_chat
+/// host --config`. Missing settings take their default values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub access_log: AccessLogConfig,
+    pub rate_limit: RateLimitConfig,
+    pub tuning: ServerTuning,
+}
+
+/// Where the server's access log is kept, and when it's rotated. See `AccessLog`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// File the log is appended to; None turns it off.
+    pub path: Option<PathBuf>,
+    /// Size in bytes past which the log is rotated; 0 never rotates it.
+    pub max_size: u64,
+    /// Number of rotated logs kept, as `<path>.1` (the newest) to `<path>.<keep>`.
+    pub keep: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig { path: None, max_size: 10 * 1024 * 1024, keep: 5 }
+    }
+}
+
+/// How fast each identity in a room may send chat messages before the server slows it down, and when it's muted for
+/// flooding. See `FloodControl`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Chat messages per second an identity can keep sending; 0 turns rate limiting off.
+    pub rate: f64,
+    /// Chat messages an identity can send at once before it's held to `rate`.
+    pub burst: u32,
+    /// Chat messages in a row an identity can have held back before it's muted; 0 never mutes anyone.
+    pub mute_after: u32,
+    /// Seconds a mute lasts.
+    pub mute_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { rate: 2.0, burst: 20, mute_after: 20, mute_secs: 60 }
+    }
+}
+
+/// The server `mls_chat join` connects to when no target is passed on the command line, and how patiently it connects
+/// to whichever server it's given.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultServer {
+    /// Hostname or IP address of the server.
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    /// Seconds each attempt to connect gets before it's given up on.
+    pub connect_timeout: u64,
+    /// Number of attempts to connect made after the first fails, unless the server's address can't be found at all.
+    pub connect_retries: u32,
+    /// Whether to offer the server compression of large frames when connecting.
+    pub compress: bool,
+}
+
+impl Default for DefaultServer {
+    fn default() -> Self {
+        DefaultServer {
+            address: None,
+            port: None,
+            connect_timeout: 10,
+            connect_retries: 2,
+            compress: true,
+        }
+    }
+}
+
+/// Capacities of the queues and buffers a `Client` passes frames through. A full queue holds the connection back
+/// rather than dropping anything: sending waits for room in the outbound queue, and reading from the server stops
+/// until the inbound queue has been handled, which in turn holds back the server's relay to the client.
+///
+/// The defaults suit groups of up to a few dozen members. In a group of a few hundred, a single change of membership
+/// can mean a burst of a commit from each member that noticed it, so an `inbound_queue` of around 4096 keeps the
+/// connection from stalling while they're handled. The buffers rarely need changing: frames are small, and a
+/// larger buffer only helps when the Welcome of a big group has to be read in one go.
+///
+/// Welcomes carry the whole ratchet tree by default, which grows with the group (to around 250 bytes a member), and
+/// is sent again for every member added. Turning off `ratchet_tree_in_welcome` keeps them under a kilobyte at any
+/// size, with the new member fetching the tree from the server instead. It's settled by whoever starts the group,
+/// and everyone welcomed into it keeps to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTuning {
+    /// Frames queued to be written to the server before sending waits; 0 never waits.
+    pub outbound_queue: usize,
+    /// Frames read from the server but not yet handled before reading stops; 0 never stops.
+    pub inbound_queue: usize,
+    /// Size in bytes of the buffer the connection is read through.
+    pub read_buffer: usize,
+    /// Size in bytes of the buffer the connection is written through.
+    pub write_buffer: usize,
+    /// Include the group's ratchet tree in the Welcomes sent to new members of the groups this client starts, rather
+    /// than publishing it to the server for them to fetch.
+    pub ratchet_tree_in_welcome: bool,
+}
+
+impl Default for ClientTuning {
+    fn default() -> Self {
+        ClientTuning {
+            outbound_queue: 256,
+            inbound_queue: 1024,
+            read_buffer: 8192,
+            write_buffer: 8192,
+            ratchet_tree_in_welcome: true,
+        }
+    }
+}
+
+/// Capacities of the queues and buffers a `Server` passes frames through, independent of how many connections it
+/// takes. Each connection has a queue of the messages relayed to its room, which every message sent to the room is
+/// put in; a connection that lets its queue fill up falls behind and is caught up from the room's history once it's
+/// drained, so a bigger queue costs memory for each connection but means fewer catch-ups.
+///
+/// The defaults suit rooms of up to a few dozen members. For rooms of a few hundred, where a change of membership
+/// can set off a commit from each member at once, a `relay_queue` of 1024 and a `direct_queue` of 128 (for the key
+/// packages and Welcomes sent to a single connection) keep members from falling behind on every burst. For a small
+/// server on a constrained host, a `relay_queue` of 32 is plenty. The buffers rarely need changing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerTuning {
+    /// Relayed messages queued for each connection before it falls behind.
+    pub relay_queue: usize,
+    /// Frames addressed only to a connection queued for it.
+    pub direct_queue: usize,
+    /// Size in bytes of the buffer each connection is read through.
+    pub read_buffer: usize,
+    /// Size in bytes of the buffer each connection is written through.
+    pub write_buffer: usize,
+}
+
+impl Default for ServerTuning {
+    fn default() -> Self {
+        ServerTuning { relay_queue: 256, direct_queue: 32, read_buffer: 8192, write_buffer: 8192 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Number of lines kept in the chat log before the oldest are dropped.
+    pub max_lines: usize,
+    /// Whether recent messages are shared with members who join later.
+    pub share: HistoryShare,
+    /// Number of the most recent chat messages shared with a new member.
+    pub share_window: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_lines: 1000,
+            share: HistoryShare::default(),
+            share_window: 50,
+        }
+    }
+}
+
+/// How the client answers a new member's request for the recent chat history.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryShare {
+    /// Note the request in the log, and only share once the user runs `/share-history`.
+    #[default]
+    Ask,
+    /// Share without asking.
+    Always,
+    /// Ignore requests.
+    Never,
+}
+
+/// How messages are shown in the chat window.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// Render `*bold*`, `_italic_`, and `` `code` `` spans in messages.
+    pub formatting: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig { formatting: true }
+    }
+}
+
+/// How the user is alerted when they're mentioned in a message.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Ring the terminal bell.
+    pub bell: bool,
+    /// Send a desktop notification through `notify-send`.
+    pub desktop: bool,
+}
+
+/// Settings that make the client's traffic harder to analyze.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// Block size, in bytes, that message plaintexts are padded to a multiple of before encryption; 0 for none.
+    pub padding: usize,
+    /// Send cover traffic and delay outgoing messages, as configured below.
+    pub paranoid: bool,
+    /// Average number of seconds between cover messages in paranoid mode.
+    pub cover_interval: u64,
+    /// Longest random delay, in milliseconds, added to each outgoing message in paranoid mode.
+    pub max_send_delay: u64,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        PrivacyConfig {
+            padding: 0,
+            paranoid: false,
+            cover_interval: 30,
+            max_send_delay: 500,
+        }
+    }
+}
+
+/// Which members may change the group's membership.
+///
+/// Ideally the moderator roster would live in a group context extension, so that every member (including later
+/// joiners) agrees on it as part of the authenticated group state, with moderators granted through a custom proposal
+/// type. openmls 0.5 can't create or update group context extensions outside its own tests, and has no support for
+/// custom proposals, so for now each client keeps its own copy of the roster in its config.
+///
+/// # TODO
+///
+/// Grant moderators through a custom proposal type once openmls is upgraded to a version that has them (0.6 or later),
+/// so that changes to the roster are committed and every member applies them in the same order.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    pub policy: ModerationPolicy,
+    /// Identities allowed to add and remove members.
+    pub moderators: Vec<String>,
+}
+
+impl ModerationConfig {
+    /// Returns true if the member with the given identity is allowed to add and remove members without a warning.
+    pub fn allows(&self, identity: &str) -> bool {
+        self.policy == ModerationPolicy::Open || self.moderators.iter().any(|moderator| moderator == identity)
+    }
+}
+
+/// What happens when a member who isn't a moderator adds or removes someone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationPolicy {
+    /// Anyone may add and remove members.
+    #[default]
+    Open,
+    /// The commit is merged, with a warning in the log.
+    Warn,
+    /// The commit is refused.
+    Enforce,
+}
+
+/// How the client treats messages that break the rules of the group's protocol.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub plaintext: PlaintextPolicy,
+    pub commits: CommitPolicyName,
+}
+
+/// What happens to an application message that arrives as a `PublicMessage`, signed but not encrypted. Chat content
+/// should only ever arrive as `PrivateMessage` ciphertext, and openmls refuses to process it otherwise, so either way
+/// it's dropped; the policy decides how loudly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaintextPolicy {
+    /// The message is dropped before it's processed, with a security warning in the log and the audit log.
+    #[default]
+    Strict,
+    /// The message is processed like any other, and dropped with a notice when openmls refuses it.
+    Lenient,
+}
+
+/// Which `CommitPolicy` judges the commits from other members before they're merged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitPolicyName {
+    /// `policy::Permissive`.
+    #[default]
+    Permissive,
+    /// `policy::Strict`.
+    Strict,
+}
+
+/// Where the client saves its session to resume after a restart. See `Session`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// File the session is saved to; None turns saving and resuming off.
+    pub path: Option<PathBuf>,
+    /// Whether the chat log is saved along with the session.
+    pub save_log: bool,
+}
+
+/// Where the local audit log of the group's history is kept, and how much goes into it. See `AuditLog`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// File the log is appended to; None turns it off.
+    pub path: Option<PathBuf>,
+    pub level: AuditLevel,
+}
+
+/// How much goes into the audit log. Each level records everything the ones before it do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditLevel {
+    /// Members being added and removed, joining and leaving the group, and refused or suspicious commits.
+    Membership,
+    /// Every commit merged, with the epoch it moved the group into and the keys it updated.
+    #[default]
+    Commits,
+    /// The sender and epoch of every application message, but not its contents.
+    All,
+}
+
+/// The capabilities advertised in the leaf nodes of the user's key packages, as IANA code points. None keeps the
+/// openmls default for that list.
+///
+/// openmls 0.5 builds a group creator's own leaf from the group's required capabilities, which `MlsGroupConfig`
+/// can't set, so these only reach the groups the user joins from a Welcome.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapabilitiesConfig {
+    pub versions: Option<Vec<u16>>,
+    pub ciphersuites: Option<Vec<u16>>,
+    pub extensions: Option<Vec<u16>>,
+    pub proposals: Option<Vec<u16>>,
+}
+
+impl CapabilitiesConfig {
+    /// Builds the `Capabilities` to put in key packages.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if a version or ciphersuite isn't one openmls knows, or if the lists
+    /// leave out the protocol version or ciphersuite the application uses.
+    pub fn capabilities(&self) -> Result<Capabilities, String> {
+        let defaults = Capabilities::default();
+
+        let versions = match &self.versions {
+            Some(versions) => versions.iter()
+                .map(|&version| ProtocolVersion::try_from(version).map_err(|_| format!("unknown protocol version {}", version)))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => defaults.versions().to_vec(),
+        };
+        if !versions.contains(&ProtocolVersion::Mls10) {
+            return Err(String::from("capabilities.versions must include MLS 1.0 (1)"));
+        }
+
+        // left as None for the default list, since openmls can't turn the default's entries back into `Ciphersuite`s
+        let ciphersuites = match &self.ciphersuites {
+            Some(ciphersuites) => Some(ciphersuites.iter()
+                .map(|&suite| Ciphersuite::try_from(suite).map_err(|_| format!("unknown ciphersuite {}", suite)))
+                .collect::<Result<Vec<_>, _>>()?),
+            None => None,
+        };
+        if ciphersuites.as_ref().is_some_and(|ciphersuites| !ciphersuites.contains(&crate::CIPHERSUITE)) {
+            return Err(format!("capabilities.ciphersuites must include the one mls_chat uses ({})", crate::CIPHERSUITE as u16));
+        }
+
+        let extensions: Vec<ExtensionType> = match &self.extensions {
+            Some(extensions) => extensions.iter().map(|&extension| extension.into()).collect(),
+            None => defaults.extensions().to_vec(),
+        };
+        let proposals: Vec<ProposalType> = match &self.proposals {
+            Some(proposals) => proposals.iter().map(|&proposal| proposal.into()).collect(),
+            None => defaults.proposals().to_vec(),
+        };
+
+        Ok(Capabilities::new(
+            Some(&versions),
+            ciphersuites.as_deref(),
+            Some(&extensions),
+            Some(&proposals),
+            Some(defaults.credentials()),
+        ))
+    }
+}
+
+/// The keys the chat window responds to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    /// Switches from Normal mode to Editing mode.
+    pub edit: KeyBinding,
+    /// Sends the message being typed in Editing mode.
+    pub send: KeyBinding,
+    /// Switches from Editing mode back to Normal mode.
+    pub normal: KeyBinding,
+    /// Exits the application from Normal mode.
+    pub quit: KeyBinding,
+    /// Shows or hides the member list from Normal mode.
+    pub members: KeyBinding,
+    /// Scrolls the chat log back by half a page.
+    pub scroll_up: KeyBinding,
+    /// Scrolls the chat log forward by half a page, following new messages again once it reaches the bottom.
+    pub scroll_down: KeyBinding,
+    /// Starts typing a search of the chat log from Normal mode.
+    pub search: KeyBinding,
+    /// Jumps to the next older match of the current search.
+    pub search_next: KeyBinding,
+    /// Jumps to the next newer match of the current search.
+    pub search_prev: KeyBinding,
+    /// Selects the previous entry of the chat log from Normal mode.
+    pub select_prev: KeyBinding,
+    /// Selects the next entry of the chat log from Normal mode.
+    pub select_next: KeyBinding,
+    /// Copies the selected entry's text to the clipboard from Normal mode.
+    pub copy: KeyBinding,
+    /// Retries sending the user's messages that failed from Normal mode.
+    pub retry: KeyBinding,
+    /// Switches to the next group from Normal mode.
+    pub next_group: KeyBinding,
+    /// Switches to the previous group from Normal mode.
+    pub prev_group: KeyBinding,
+}
+
+/// The chat window modes a key binding can be active in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindingMode {
+    Normal,
+    Editing,
+    Search,
+}
+
+/// A key binding along with what it does, as listed by `KeyBindings::list()`.
+#[derive(Clone, Copy, Debug)]
+pub struct BindingInfo {
+    /// Name of the binding in the config file.
+    pub name: &'static str,
+    pub key: KeyBinding,
+    pub modes: &'static [BindingMode],
+    pub description: &'static str,
+}
+
+impl KeyBindings {
+    /// Lists every binding with the modes it's active in and a short description.
+    pub fn list(&self) -> Vec<BindingInfo> {
+        use BindingMode::*;
+
+        let info = |name, key, modes, description| BindingInfo { name, key, modes, description };
+        vec![
+            info("edit", self.edit, &[Normal], "start typing a message"),
+            info("send", self.send, &[Editing, Search], "send the message or search"),
+            info("normal", self.normal, &[Editing, Search], "stop typing"),
+            info("quit", self.quit, &[Normal], "exit mls_chat"),
+            info("members", self.members, &[Normal], "show or hide the member list"),
+            info("scroll_up", self.scroll_up, &[Normal, Editing], "scroll back through the chat log"),
+            info("scroll_down", self.scroll_down, &[Normal, Editing], "scroll forward through the chat log"),
+            info("search", self.search, &[Normal], "search the chat log"),
+            info("search_next", self.search_next, &[Normal], "jump to the next older match"),
+            info("search_prev", self.search_prev, &[Normal], "jump to the next newer match"),
+            info("select_prev", self.select_prev, &[Normal], "select the previous message"),
+            info("select_next", self.select_next, &[Normal], "select the next message"),
+            info("copy", self.copy, &[Normal], "copy the selected message"),
+            info("retry", self.retry, &[Normal], "retry sending messages that failed"),
+            info("next_group", self.next_group, &[Normal], "switch to the next group"),
+            info("prev_group", self.prev_group, &[Normal], "switch to the previous group"),
+        ]
+    }
+
+    /// Returns a description of every conflict between the bindings: two bindings active in the same mode on
+    /// the same key, or a binding that's active while typing on a key that would otherwise be typed.
+    pub fn conflicts(&self) -> Vec<String> {
+        let bindings = self.list();
+        let mut conflicts = Vec::new();
+
+        for (i, a) in bindings.iter().enumerate() {
+            for b in &bindings[i + 1..] {
+                if a.key == b.key && a.modes.iter().any(|mode| b.modes.contains(mode)) {
+                    conflicts.push(format!("\"{}\" and \"{}\" are both bound to {}", a.name, b.name, a.key));
+                }
+            }
+
+            let typed = matches!(a.key.code, KeyCode::Char(_))
+                && (a.key.modifiers - KeyModifiers::SHIFT).is_empty();
+            let while_typing = a.modes.contains(&BindingMode::Editing) || a.modes.contains(&BindingMode::Search);
+            if typed && while_typing {
+                conflicts.push(format!("\"{}\" is bound to {}, which couldn't be typed in messages", a.name, a.key));
+            }
+        }
+
+        conflicts
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            edit: KeyBinding::new(KeyCode::Enter),
+            send: KeyBinding::new(KeyCode::Enter),
+            normal: KeyBinding::new(KeyCode::Esc),
+            quit: KeyBinding::new(KeyCode::Esc),
+            members: KeyBinding::new(KeyCode::Tab),
+            scroll_up: KeyBinding::new(KeyCode::PageUp),
+            scroll_down: KeyBinding::new(KeyCode::PageDown),
+            search: KeyBinding::new(KeyCode::Char('/')),
+            search_next: KeyBinding::new(KeyCode::Char('n')),
+            search_prev: KeyBinding::new(KeyCode::Char('N')),
+            select_prev: KeyBinding::new(KeyCode::Up),
+            select_next: KeyBinding::new(KeyCode::Down),
+            copy: KeyBinding::new(KeyCode::Char('y')),
+            retry: KeyBinding::new(KeyCode::Char('r')),
+            next_group: KeyBinding { code: KeyCode::Right, modifiers: KeyModifiers::CONTROL },
+            prev_group: KeyBinding { code: KeyCode::Left, modifiers: KeyModifiers::CONTROL },
+        }
+    }
+}
+
+/// A key plus the modifiers that must be held with it, parsed from strings like `"Ctrl+q"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    pub fn new(code: KeyCode) -> KeyBinding {
+        KeyBinding { code, modifiers: KeyModifiers::NONE }
+    }
+
+    /// Returns true if the key event is this binding's key with exactly this binding's modifiers.
+    /// Shift is ignored for character keys, since it's already reflected in the character itself.
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let mut modifiers = key.modifiers;
+        if let KeyCode::Char(_) = key.code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        key.code == self.code && modifiers == self.modifiers
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut parts: Vec<&str> = s.split('+').collect();
+        // "Ctrl++" binds the plus key itself
+        if s.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let Some(key) = parts.pop() else { return Err(String::from("empty key binding")) };
+
+        let mut modifiers = KeyModifiers::NONE;
+        for modifier in parts {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier \"{}\" in key binding \"{}\"", modifier, s)),
+            };
+        }
+
+        let code = match key.to_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            lower => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => match lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                        Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                        _ => return Err(format!("unknown key \"{}\" in key binding \"{}\"", key, s)),
+                    },
+                }
+            }
+        };
+
+        Ok(KeyBinding { code, modifiers })
+    }
+}
+
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}", name)?;
+            }
+        }
+
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Returns the default location of the config file: `$XDG_CONFIG_HOME/mls_chat/config.toml`,
+    /// falling back to `~/.config/mls_chat/config.toml`. Returns None if neither variable is set.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(base.join("mls_chat").join("config.toml"))
+    }
+
+    /// Loads the config file at `path`. A missing file isn't an error, and just results in the
+    /// default configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file exists but can't be read or parsed, or if its key
+    /// bindings conflict.
+    pub fn load(path: &Path) -> Result<ClientConfig, ApplicationError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ClientConfig::default()),
+            Err(e) => return Err(StorageError::Config(format!("{}: {}", path.display(), e)).into()),
+        };
+
+        let mut config = Self::parse(&contents)
+            .map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)))?;
+        config.source = Some(path.to_path_buf());
+
+        let conflicts = config.keybindings.conflicts();
+        if !conflicts.is_empty() {
+            return Err(StorageError::Config(
+                format!("{}: conflicting key bindings:\n  {}", path.display(), conflicts.join("\n  "))
+            ).into());
+        }
+        if let Err(e) = config.capabilities.capabilities() {
+            return Err(StorageError::Config(format!("{}: {}", path.display(), e)).into());
+        }
+        Ok(config)
+    }
+
+    /// Parses the contents of a config file.
+    pub fn parse(contents: &str) -> Result<ClientConfig, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// Writes the commented config template to `path`, creating its parent directories if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file already exists and `overwrite` is
+    /// false, or if writing fails.
+    pub fn write_template(path: &Path, overwrite: bool) -> Result<(), ApplicationError> {
+        if path.exists() && !overwrite {
+            return Err(StorageError::Config(
+                format!("{} already exists (pass --force to overwrite it)", path.display())
+            ).into());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| StorageError::Config(format!("{}: {}", parent.display(), e)))?;
+        }
+        fs::write(path, TEMPLATE)
+            .map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)).into())
+    }
+}
+
+impl ServerConfig {
+    /// Returns the default location of the server's config file: `server.toml` next to the client's `config.toml`
+    /// (see `ClientConfig::default_path()`).
+    pub fn default_path() -> Option<PathBuf> {
+        ClientConfig::default_path().map(|path| path.with_file_name("server.toml"))
+    }
+
+    /// Loads the server config file at `path`. A missing file isn't an error, and just results in the default
+    /// configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file exists but can't be read or parsed.
+    pub fn load(path: &Path) -> Result<ServerConfig, ApplicationError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ServerConfig::default()),
+            Err(e) => return Err(StorageError::Config(format!("{}: {}", path.display(), e)).into()),
+        };
+
+        toml::from_str(&contents).map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_parses_to_defaults() {
+        let config = ClientConfig::parse(TEMPLATE);
+        assert!(config.is_ok(), "Template returns error: {:?}", config);
+
+        let config = config.unwrap();
+        assert_eq!(config.history.max_lines, HistoryConfig::default().max_lines);
+        assert_eq!(config.history.share, HistoryShare::Ask);
+        assert_eq!(config.server.connect_timeout, DefaultServer::default().connect_timeout);
+        assert_eq!(config.tuning, ClientTuning::default());
+        assert_eq!(config.security.plaintext, PlaintextPolicy::Strict);
+        assert_eq!(config.security.commits, CommitPolicyName::Permissive);
+        assert_eq!(config.keybindings.quit, KeyBinding::new(KeyCode::Esc));
+    }
+
+    #[test]
+    fn parse_moderation() {
+        assert!(ClientConfig::default().moderation.allows("mallory"));
+
+        let config = ClientConfig::parse("[moderation]\npolicy = \"enforce\"\nmoderators = [\"alice\"]\n").unwrap();
+        assert_eq!(config.moderation.policy, ModerationPolicy::Enforce);
+        assert!(config.moderation.allows("alice"));
+        assert!(!config.moderation.allows("mallory"));
+    }
+
+    #[test]
+    fn parse_capabilities() {
+        assert_eq!(CapabilitiesConfig::default().capabilities(), Ok(Capabilities::default()));
+
+        let config = ClientConfig::parse("[capabilities]\nciphersuites = [3]\nproposals = [1, 2, 3]\n").unwrap();
+        let capabilities = config.capabilities.capabilities().unwrap();
+        assert_eq!(capabilities.ciphersuites(), &[crate::CIPHERSUITE.into()]);
+        assert_eq!(capabilities.proposals(), &[ProposalType::Add, ProposalType::Update, ProposalType::Remove]);
+
+        let config = ClientConfig::parse("[capabilities]\nciphersuites = [1]\n").unwrap();
+        assert!(config.capabilities.capabilities().is_err());
+        let config = ClientConfig::parse("[capabilities]\nversions = [9]\n").unwrap();
+        assert!(config.capabilities.capabilities().is_err());
+    }
+
+    #[test]
+    fn parse_audit() {
+        let config = ClientConfig::parse("[audit]\npath = \"audit.log\"\nlevel = \"membership\"\n").unwrap();
+        assert_eq!(config.audit.path, Some(PathBuf::from("audit.log")));
+        assert_eq!(config.audit.level, AuditLevel::Membership);
+        assert_eq!(ClientConfig::default().audit.path, None);
+        assert!(ClientConfig::parse("[audit]\nlevel = \"everything\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_session() {
+        let config = ClientConfig::parse("[session]\npath = \"session.json\"\n").unwrap();
+        assert_eq!(config.session.path, Some(PathBuf::from("session.json")));
+        assert_eq!(ClientConfig::default().session.path, None);
+    }
+
+    #[test]
+    fn parse_key_bindings() {
+        let config = ClientConfig::parse("[keybindings]\nquit = \"Ctrl+q\"\nedit = \"F2\"\n").unwrap();
+
+        assert_eq!(config.keybindings.quit, KeyBinding { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL });
+        assert_eq!(config.keybindings.edit, KeyBinding::new(KeyCode::F(2)));
+        assert_eq!(config.keybindings.quit.to_string(), "Ctrl+q");
+        assert!(ClientConfig::parse("[keybindings]\nquit = \"Hyper+q\"\n").is_err());
+    }
+
+    #[test]
+    fn detect_key_binding_conflicts() {
+        assert!(KeyBindings::default().conflicts().is_empty());
+
+        let config = ClientConfig::parse("[keybindings]\nmembers = \"/\"\nscroll_up = \"k\"\n").unwrap();
+        let conflicts = config.keybindings.conflicts();
+        assert_eq!(conflicts.len(), 2, "{:?}", conflicts);
+        assert!(conflicts[0].contains("\"members\" and \"search\""));
+        assert!(conflicts[1].contains("\"scroll_up\""));
+    }
+
+    #[test]
+    fn parse_theme() {
+        let config = ClientConfig::parse("theme = \"solarized\"\n[colors]\nmention = \"#ff8800\"\n").unwrap();
+
+        assert_eq!(config.theme, ThemeName::Solarized);
+        assert_eq!(config.colors.mention.map(|color| color.0), Some(ratatui::style::Color::Rgb(0xff, 0x88, 0x00)));
+        assert!(ClientConfig::parse("theme = \"neon\"\n").is_err());
+        assert!(ClientConfig::parse("[colors]\nmention = \"not-a-color\"\n").is_err());
+    }
+}
@@ -1,161 +1,3099 @@
-use crate::{
-    errors::ApplicationError,
-    network::client::Client,
-    user::User,
-    view::ChatWindow
-};
-use chrono::Utc;
-use openmls::prelude::*;
-
-
-pub struct Controller {
-    log: Vec<String>,
-    network: Client,
-    user: User,
-    window: ChatWindow,
-}
-
-impl Controller {
-    pub async fn build(address: String, uid: String) -> Result<Controller, ApplicationError> {
-        let network = Client::build(address).await?;
-        let user = User::build(uid)?;
-
-        Ok(Controller {
-            log: Vec::new(),
-            network,
-            user,
-            window: ChatWindow::build().unwrap(),
-        })
-    }
-
-    /// The primary functionality loop for the client application. Continually updates the user interface
-    /// with the log of messages sent, as well as sending messages input by the user and spawning/joining the
-    /// network stream thread and pulling incoming messages to handle.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace instances of `unwrap()` with more robust error handling.
-    /// 
-    /// Reconfigure to recover from/continue past non-fatal errors.
-    pub async fn run(&mut self) -> Result<(), ApplicationError> {
-        let kp = self.user.generate_key_package();
-        self.serialize_and_send(kp).await?;
-
-        let Ok(_network_handle) = self.network.handle_stream().await else { return Err(ApplicationError::IOError) };
-
-        loop {
-            self.window.draw(&self.log).unwrap();
-            if !self.window.run()? {
-                break;
-            }
-
-            match self.window.get_output() {
-                Some(s) => {
-                    if !s.is_empty() { self.send_chat_msg(s).await?; }
-                }
-                None => ()
-            }      
-
-            for msg in self.network.get_input().await {
-                self.handle_messages(msg).await?;
-            }
-        }
-
-        Ok(())
-    }
-
-    /// Helper function for `Controller::run()`. Deserializes and processes incoming messages, then executes
-    /// the necessary tasks for each.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns any `ApplicationError` types returned by `User::add_member()`, `User::update_keys()`,
-    /// `User::process_message()`, or `Controller::serialize_and_send()`.
-    /// 
-    /// Could also return an `ApplicationError::InvalidMessage` if the input doesn't match any expected types.
-    /// 
-    /// # TODO
-    /// 
-    /// Suspected that MLS key packages must be deserialized as `KeyPackageIn::tls_deserialize()` rather than
-    /// `MlsMessageIn::tls_deserialize()` extracted to an `MlsMessageInBody::KeyPackage`. Test this more thoroughly
-    /// and refactor accordingly if any other types also can't be deserialized as `MlsMessageIn`.
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    async fn handle_messages(&mut self, msg: Vec<u8>) -> Result<(), ApplicationError> {
-        if let Ok(msg) = MlsMessageIn::tls_deserialize(&mut msg.as_slice()) {
-            match msg.extract() {
-                MlsMessageInBody::Welcome(w) => {
-                    if !self.user.has_group() {
-                        self.user.join_group(w)?;
-                        let msg = self.user.update_keys()?;
-                        self.serialize_and_send(msg).await?;
-                    }
-                }
-                MlsMessageInBody::KeyPackage(kp) => {
-                    let (commit, welcome) = self.user.add_member(kp)?;
-                    self.serialize_and_send(commit).await?;
-                    self.serialize_and_send(welcome).await?;
-                }
-                MlsMessageInBody::GroupInfo(_) => (),
-                MlsMessageInBody::PrivateMessage(msg) => {
-                    let protocol_message = msg.into();
-                    match self.user.process_message(protocol_message)? {
-                        Some(msg) => self.log.push(String::from_utf8(msg).unwrap()),
-                        None => (),
-                    }
-                }
-                MlsMessageInBody::PublicMessage(msg) => {
-                    let protocol_message = msg.into();
-                    match self.user.process_message(protocol_message)? {
-                        Some(msg) => self.log.push(String::from_utf8(msg).unwrap()),
-                        None => (),
-                    }
-                }
-            }
-
-            Ok(())
-        } else if let Ok(kp) = KeyPackageIn::tls_deserialize(&mut msg.as_slice()) {
-            let (commit, welcome) = self.user.add_member(kp)?;
-            self.serialize_and_send(commit).await?;
-            self.serialize_and_send(welcome).await?;
-            Ok(())
-        }
-        else { Err(ApplicationError::InvalidMessage) }
-    }
-
-    /// Helper function for `Controller::run()`. Takes the user's input text, adds a timestamp and username to the
-    /// message as a prefix, encrypts it, and calls `Controller::serialize_and_send()`. Updates the user's key material
-    /// after encryption as required by the MLS protocol, and sends the resulting key update message as well.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns any `ApplicationError` types returned from `User::encrypt_message()`, `User::update_keys()`, and
-    /// `Controller::serialize_and_send()`.
-    async fn send_chat_msg(&mut self, msg: String) -> Result<(), ApplicationError> {
-        let time = Utc::now().time().format("%H:%M:%S");
-        let msg = format!("[{}] {}: {}", time, self.user.get_id(), msg);
-
-        self.log.push(msg.clone());
-        let msg = self.user.encrypt_message(&msg)?;
-        self.serialize_and_send(msg).await?;
-
-        let msg = self.user.update_keys()?;
-        self.serialize_and_send(msg).await?;
-
-        Ok(())
-    }
-
-    /// Helper function to remove repetition of the message serialize and send operations.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::TlsSerializeError` if `tls_serialize_detached()` fails.
-    async fn serialize_and_send<T>(&mut self, msg: T) -> Result<(), ApplicationError> where T: TlsSerializeTrait  {
-        if let Ok(msg) = msg.tls_serialize_detached() {
-            self.network.send(msg).await;
-            Ok(())
-        } else {
-            Err(ApplicationError::TlsSerializeError)
-        }
-    }
-}
\ No newline at end of file
+use crate::{
+    audit::AuditLog,
+    chat_log::{mentions, ChatLog, EntryKind},
+    clock::{Clock, SystemClock},
+    command::Command,
+    config::{
+        AuditLevel, ClientConfig, HistoryConfig, HistoryShare, ModerationConfig, ModerationPolicy, NotificationConfig,
+        PlaintextPolicy, PrivacyConfig, SecurityConfig,
+    },
+    errors::{ApplicationError, MlsError, NetworkError, StorageError},
+    frontend::{ChatView, UserInput},
+    group::{self, CommitSummary, RosterEntry, TreeNode},
+    link::LinkCode,
+    message::{
+        sanitize, MessageId, Payload, Received, SeenIds, SentIds, Sequences, SharedEntry, MAX_NAME_CHARS, MAX_TEXT_CHARS,
+    },
+    metrics::{format_bytes, Metrics},
+    network::{address::ServerAddr, client::{Client, ConnectionState}, frame::Frame, wire::{self, Envelope, Feature}},
+    plain::PlainView,
+    policy,
+    session::{InFlight, Sent, Session},
+    theme::Theme,
+    trace::MlsTrace,
+    transcript::{self, Format},
+    user::{Processed, User},
+    view::{ChatWindow, MemberLine, Presence, Progress, Status},
+    webhook::WebhookView,
+    wipe,
+    PROVIDER,
+};
+use std::{collections::{HashMap, VecDeque}, io, mem, path::PathBuf, process, sync::Arc, time::{Duration, Instant}};
+use arboard::Clipboard;
+use openmls::prelude::*;
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// Number of key packages kept published in the server's directory, besides the last resort.
+const KEY_PACKAGE_POOL_SIZE: u64 = 5;
+
+/// How long after publishing its key packages the client replaces them with fresh ones, if it still hasn't been
+/// added to a group: halfway through their lifetime.
+const KEY_PACKAGE_REFRESH: Duration = Duration::from_secs(crate::KEY_PACKAGE_LIFETIME / 2);
+
+/// How long a code from `/link` can be used for.
+const LINK_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How long each moderator waits, per moderator ranked ahead of them, for a newcomer to be added before claiming the
+/// newcomer's key package itself. See `Controller::claim_delay()`.
+const ADD_FALLBACK: Duration = Duration::from_secs(15);
+
+/// How long a user with nobody else in their group waits before adding a newcomer, so that a group the user is about
+/// to be welcomed into themselves gets to add the newcomer first.
+const LONE_ADD_DELAY: Duration = Duration::from_secs(2);
+
+/// Most MLS messages a user waiting to be welcomed into a group keeps to catch up on once they are.
+const EARLY_MESSAGES: usize = 256;
+
+/// How long the "server is shutting down" notice stays on screen before the client exits.
+const CLOSING_NOTICE_DELAY: Duration = Duration::from_millis(1500);
+
+/// Longest the main loop sleeps without input or frames arriving, so the status line and timers that aren't tracked
+/// to the instant, like key package refreshes, stay current.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the client waits on exit for frames it's queued to be written to the server.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the client waits after a failed attempt to reconnect before the next, doubling each time up to
+/// `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest the client waits between attempts to reconnect.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a user moving the group with `/reinit` waits for the other members' key packages before moving it with
+/// whoever has answered.
+const REINIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long after the group moves the client still reads messages sent in the group it left, by members who hadn't
+/// followed it yet.
+const DRAIN_TIME: Duration = Duration::from_secs(30);
+
+/// A move of the user's group to a successor with another ciphersuite, started with `/reinit`, while the other
+/// members' key packages for it come in.
+struct Migration {
+    /// code point of the successor's ciphersuite
+    ciphersuite: u16,
+    /// leaf indices of the members who haven't answered yet
+    waiting: Vec<u32>,
+    key_packages: Vec<KeyPackageIn>,
+    started: Instant,
+}
+
+
+pub struct Controller {
+    audit: AuditLog,
+    /// message the user gave with `/away`, while they're away
+    away: Option<String>,
+    /// set after joining a group until another member shares the recent chat history
+    awaiting_history: bool,
+    /// capabilities advertised in the user's key packages, kept to reapply after a resync
+    capabilities: Capabilities,
+    /// opened on first use, and kept open since on some platforms the copied text only lasts as long as it is
+    clipboard: Option<Clipboard>,
+    /// where timers and chat log timestamps get the time from
+    clock: Box<dyn Clock>,
+    closing: bool,
+    /// id the server knows the user's connection by
+    connection_id: Option<u64>,
+    /// leaf index of each member whose connection id is known, by connection id
+    connections: HashMap<u64, u32>,
+    /// key packages of members to add once the user's pending commit is resolved
+    deferred_adds: VecDeque<KeyPackageIn>,
+    /// payloads to send once the user's pending commit is resolved, since they'd be encrypted for an epoch the rest of
+    /// the group is about to leave
+    deferred_payloads: Vec<Payload>,
+    /// messages relayed while the user waits to be welcomed, since the server may order some from the group's new
+    /// epoch ahead of the Welcome
+    early_messages: VecDeque<ProtocolMessage>,
+    /// the user's chat messages that couldn't be sent, kept to be retried with `/retry`
+    failed: Vec<Payload>,
+    /// leaf index of the member moving the group with `/reinit`, and the successor's group ID, once the user has sent
+    /// them a key package for it
+    following: Option<(u32, Vec<u8>)>,
+    history: HistoryConfig,
+    /// leaf indices of the members who've asked for the chat history and are waiting on `/share-history`
+    history_requests: Vec<u32>,
+    /// leaves added by someone other than the user they claim to be a device of, with the user ID they claim
+    impostors: HashMap<u32, String>,
+    /// MLS messages sent that the server hasn't sequenced yet, oldest first
+    in_flight: VecDeque<InFlight>,
+    /// number of key packages published since the last resort, to cycle them through `group::key_package_ciphersuites()`
+    key_package_turn: usize,
+    key_packages_published: Instant,
+    key_rotation_interval: Duration,
+    last_key_rotation: Instant,
+    /// sequence number of the last relayed message handled, once any has been
+    last_seq: Option<u64>,
+    /// code from `/link` waiting for the new device's key package, and when it was shown
+    link: Option<(LinkCode, Instant)>,
+    /// owner of the room, if the server says it's invite-only
+    locked_by: Option<String>,
+    log: ChatLog,
+    /// counters for `/stats`, shared with the `Client`
+    metrics: Arc<Metrics>,
+    moderation: ModerationConfig,
+    /// when the group last moved to a successor, until `DRAIN_TIME` has passed and the group it left is dropped
+    moved: Option<Instant>,
+    network: Client,
+    /// connection ids of newcomers another moderator was elected to add, with when their key packages were announced
+    newcomers: HashMap<u64, Instant>,
+    /// when the next cover message is due, in paranoid mode
+    next_cover: Option<Instant>,
+    /// sequence number of the last chat message the user sent
+    next_seq: u64,
+    /// display names members have chosen with `/nick`, by leaf index
+    nicknames: HashMap<u32, String>,
+    notifications: NotificationConfig,
+    /// payloads written while the connection was down or catching up, sent once it's back, oldest first
+    outbox: Vec<Payload>,
+    /// Welcome into a group the user is waiting on the ratchet tree for, with the reference of the key package it's
+    /// for, since it may have been sent without one (see `ClientTuning::ratchet_tree_in_welcome`)
+    pending_welcome: Option<(Vec<u8>, Welcome)>,
+    /// presence of the members who've announced it or gone offline, by leaf index
+    presence: HashMap<u32, Presence>,
+    privacy: PrivacyConfig,
+    /// whether Welcomes into the groups the user creates include the ratchet tree
+    ratchet_tree_in_welcome: bool,
+    /// number of key packages claimed from each newcomer in a row that were for another ciphersuite than the group's
+    reclaims: HashMap<u64, usize>,
+    /// when the next attempt to reconnect is due, after one has failed
+    reconnect_at: Option<Instant>,
+    /// how long to wait after the next failed attempt to reconnect
+    reconnect_delay: Duration,
+    /// the move the user started with `/reinit`, until it's done or called off
+    reinit: Option<Migration>,
+    /// set after connecting until the server answers the `Frame::Ping` sent after resuming, by which point it's
+    /// replayed everything the client missed, to the number of MLS messages in flight from before connecting that the
+    /// server hasn't sequenced yet
+    restoring: Option<usize>,
+    /// set while a resumed session waits for the server to replay what it missed
+    resuming: bool,
+    /// code of the room entered on a rendezvous server, to enter again on reconnecting
+    room: Option<String>,
+    /// whether the chat log is saved with the session
+    save_log: bool,
+    security: SecurityConfig,
+    /// ids of the MLS messages processed in the last few epochs, to drop exact duplicates of them
+    seen_ids: SeenIds,
+    sequences: Sequences,
+    /// when each of the last `sent_at.len()` messages in `in_flight` was sent, for measuring how long the server takes
+    /// to sequence them (the ones before them came from a saved session)
+    sent_at: VecDeque<Instant>,
+    /// ids of the user's own recent MLS messages, to drop them if they come back
+    sent_ids: SentIds,
+    /// set when there's something new to save since the session was last saved
+    session_dirty: bool,
+    /// where the session is saved, unless saving it is off
+    session_path: Option<PathBuf>,
+    /// cancelled to stop the main loop from outside, e.g. on Ctrl-C
+    shutdown: CancellationToken,
+    /// where the MLS messages sent and received are traced to with `join --debug-mls`
+    trace: MlsTrace,
+    user: User,
+    window: Box<dyn ChatView>,
+    /// local files `/wipe confirm` deletes
+    wipe_targets: Vec<PathBuf>,
+    /// set by `/wipe confirm`, to quit once the current input has been handled
+    wiped: bool,
+}
+
+impl Controller {
+    /// Connects to the server at `address` and sets up a `User` with the id `uid`, taking the rest of its settings
+    /// from the client's `ClientConfig`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::connect()`, `Controller::load_user()`,
+    /// `AuditLog::open()`, `MlsTrace::open()`, `WebhookView::bind()`, or `ChatWindow::build()`.
+    pub async fn build(address: ServerAddr, uid: String, config: ClientConfig) -> Result<Controller, ApplicationError> {
+        let (network, assigned) = Self::connect(address, &uid, &config).await?;
+        let (user, session) = Self::load_user(assigned, &config)?;
+        let audit = AuditLog::open(&config.audit)?;
+        let trace = MlsTrace::open(config.debug_mls.as_deref())?;
+        // the terminal is only taken over once the connection is up, so a failure to connect leaves it untouched
+        let window: Box<dyn ChatView> = if let Some(address) = config.webhook {
+            Box::new(WebhookView::bind(address)?)
+        } else if config.plain {
+            Box::new(PlainView::new())
+        } else {
+            Box::new(ChatWindow::build(config.keybindings.clone(), Theme::new(config.theme, &config.colors), config.display)?)
+        };
+
+        let mut controller = Self::new(network, user, session, audit, &config, window, Box::new(SystemClock));
+        controller.trace = trace;
+        controller.note_renamed(&uid);
+        Ok(controller)
+    }
+
+    /// Like `Controller::build()`, but drives the given `ChatView` instead of choosing one from the config.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::connect()`, `Controller::load_user()`, or
+    /// `AuditLog::open()`.
+    pub async fn with_view(
+        address: ServerAddr,
+        uid: String,
+        config: ClientConfig,
+        window: Box<dyn ChatView>,
+    ) -> Result<Controller, ApplicationError> {
+        let (network, assigned) = Self::connect(address, &uid, &config).await?;
+        let mut controller = Self::with_client(network, assigned, config, window, Box::new(SystemClock))?;
+        controller.note_renamed(&uid);
+        Ok(controller)
+    }
+
+    /// Connects to the server at `address` and registers `uid` with it, in the configured room if there is one.
+    /// Returns the connection along with the id the server registered it under, which differs from `uid` if someone
+    /// in the room already goes by that.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if `uid` is taken but there's a session saved for it, which joining
+    /// under another id would replace, or any `ApplicationError` types returned by `Client::build()` or
+    /// `Client::handshake()`.
+    async fn connect(address: ServerAddr, uid: &str, config: &ClientConfig) -> Result<(Client, String), ApplicationError> {
+        let timeout = Duration::from_secs(config.server.connect_timeout);
+        let mut network = Client::build(address, timeout, config.server.connect_retries).await?;
+        network.set_compression(config.server.compress);
+        network.set_tuning(config.tuning);
+        let assigned = network.handshake(config.room.as_deref(), uid).await?;
+
+        if assigned != uid {
+            if let Some(path) = &config.session.path {
+                if Session::load(path)?.is_some_and(|session| session.user.id() == uid) {
+                    return Err(StorageError::Session(format!(
+                        "someone on the server already goes by {}. If it's you in another client, or a connection \
+                        that hasn't timed out yet, quit that first: joining as {} would replace your saved session.",
+                        uid, assigned,
+                    )).into());
+                }
+            }
+        }
+        Ok((network, assigned))
+    }
+
+    /// Tells the user the server registered them under another id than the `requested` one, if it did.
+    fn note_renamed(&mut self, requested: &str) {
+        if self.user.get_id() != requested {
+            let text = format!("Someone here already goes by {}, so you've joined as {}.", requested, self.user.get_id());
+            self.log.push_system(self.clock.utc().timestamp(), text);
+            self.push_last();
+        }
+    }
+
+    /// Like `Controller::with_view()`, but over the given `Client`, e.g. one from `Client::in_memory()`, and reading
+    /// the time from `clock`, e.g. a `ManualClock`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::load_user()`, `AuditLog::open()`, or
+    /// `MlsTrace::open()`.
+    pub fn with_client(
+        network: Client,
+        uid: String,
+        config: ClientConfig,
+        window: Box<dyn ChatView>,
+        clock: Box<dyn Clock>,
+    ) -> Result<Controller, ApplicationError> {
+        let (user, session) = Self::load_user(uid, &config)?;
+        let audit = AuditLog::open(&config.audit)?;
+        let trace = MlsTrace::open(config.debug_mls.as_deref())?;
+
+        let mut controller = Self::new(network, user, session, audit, &config, window, clock);
+        controller.trace = trace;
+        Ok(controller)
+    }
+
+    /// Builds a `User` with the id `uid` as another device of the user who showed the configured link code, if there
+    /// is one. Otherwise, resumes the session saved at the configured path, if there is one and it belongs to `uid`,
+    /// or else builds a fresh `User`. Returns the `User` along with the session it was resumed from.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Session::load()`, `Session::resume_user()`, or
+    /// `User::build()`.
+    fn load_user(uid: String, config: &ClientConfig) -> Result<(User, Option<Session>), ApplicationError> {
+        if let Some(code) = &config.link {
+            return Ok((User::build_linked(uid, code)?, None));
+        }
+        if let Some(path) = &config.session.path {
+            if let Some(mut session) = Session::load(path)?.filter(|session| session.user.id() == uid) {
+                return Ok((session.resume_user()?, Some(session)));
+            }
+        }
+        Ok((User::build(uid)?, None))
+    }
+
+    fn new(
+        mut network: Client,
+        mut user: User,
+        session: Option<Session>,
+        audit: AuditLog,
+        config: &ClientConfig,
+        window: Box<dyn ChatView>,
+        clock: Box<dyn Clock>,
+    ) -> Controller {
+        // `ClientConfig::load()` has already rejected invalid capabilities
+        let capabilities = config.capabilities.capabilities().unwrap_or_default();
+        user.set_capabilities(capabilities.clone());
+        user.set_moderation(config.moderation.clone());
+        user.set_commit_policy(policy::named(config.security.commits));
+        user.set_padding(config.privacy.padding);
+        user.set_ratchet_tree_extension(config.tuning.ratchet_tree_in_welcome);
+        if config.privacy.paranoid {
+            network.set_max_delay(Duration::from_millis(config.privacy.max_send_delay));
+        }
+        let mut log = ChatLog::new(config.history.max_lines);
+        let (last_seq, in_flight, outbox) = match session {
+            Some(session) => {
+                log.restore(session.log);
+                (session.last_seq, session.in_flight.into(), session.outbox)
+            }
+            None => (None, VecDeque::new(), Vec::new()),
+        };
+        let now = clock.now();
+
+        Controller {
+            audit,
+            away: None,
+            awaiting_history: false,
+            capabilities,
+            clipboard: None,
+            clock,
+            closing: false,
+            connection_id: None,
+            connections: HashMap::new(),
+            deferred_adds: VecDeque::new(),
+            deferred_payloads: Vec::new(),
+            failed: Vec::new(),
+            following: None,
+            early_messages: VecDeque::new(),
+            history: config.history,
+            history_requests: Vec::new(),
+            impostors: HashMap::new(),
+            in_flight,
+            key_package_turn: 0,
+            key_packages_published: now,
+            key_rotation_interval: Duration::from_secs(config.key_rotation_interval),
+            last_key_rotation: now,
+            last_seq,
+            link: None,
+            locked_by: None,
+            log,
+            metrics: network.metrics(),
+            moderation: config.moderation.clone(),
+            moved: None,
+            network,
+            newcomers: HashMap::new(),
+            next_cover: config.privacy.paranoid.then(|| now + cover_delay(config.privacy.cover_interval)),
+            next_seq: 0,
+            nicknames: HashMap::new(),
+            presence: HashMap::new(),
+            notifications: config.notifications,
+            outbox,
+            pending_welcome: None,
+            privacy: config.privacy,
+            ratchet_tree_in_welcome: config.tuning.ratchet_tree_in_welcome,
+            reclaims: HashMap::new(),
+            reconnect_at: None,
+            reconnect_delay: RECONNECT_DELAY,
+            reinit: None,
+            restoring: None,
+            resuming: last_seq.is_some(),
+            room: config.room.clone(),
+            save_log: config.session.save_log,
+            security: config.security,
+            seen_ids: SeenIds::default(),
+            sequences: Sequences::default(),
+            sent_at: VecDeque::new(),
+            sent_ids: SentIds::default(),
+            session_dirty: false,
+            session_path: config.session.path.clone(),
+            shutdown: CancellationToken::new(),
+            trace: MlsTrace::disabled(),
+            user,
+            window,
+            wipe_targets: wipe::targets(config),
+            wiped: false,
+        }
+    }
+
+    /// The primary functionality loop for the client application. Spawns the network stream tasks, then goes
+    /// through `Controller::tick()` each time the view has input, frames arrive from the server, or a timer comes
+    /// due, sleeping in between. Stops when the user quits, the server shuts down, or the token from
+    /// `Controller::shutdown_token()` is cancelled, then waits up to `FLUSH_TIMEOUT` for the frames it's queued to be
+    /// sent before tearing down the view.
+    ///
+    /// # TODO
+    ///
+    /// Replace instances of `unwrap()` with more robust error handling.
+    ///
+    /// Reconfigure to recover from/continue past non-fatal errors.
+    pub async fn run(&mut self) -> Result<(), ApplicationError> {
+        self.start().await?;
+        let Ok(_network_handle) = self.network.handle_stream().await else { return Err(NetworkError::Io.into()) };
+        let (input, incoming) = (self.window.input_ready(), self.network.incoming());
+        let shutdown = self.shutdown.clone();
+        while self.tick().await? {
+            tokio::select! {
+                _ = input.notified() => (),
+                _ = incoming.notified() => (),
+                _ = time::sleep(self.next_wake()) => (),
+                _ = shutdown.cancelled() => break,
+            }
+        }
+        self.network.flush(FLUSH_TIMEOUT).await;
+        self.finish()
+    }
+
+    /// Returns a token that stops `Controller::run()` cleanly when cancelled, as if the user had quit.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Helper function for `Controller::run()`. Returns how long the main loop can sleep before a timer comes due, if
+    /// nothing wakes it first.
+    fn next_wake(&self) -> Duration {
+        let now = self.clock.now();
+        [self.next_cover, self.next_claim(), self.reconnect_at].into_iter()
+            .flatten()
+            .map(|due| due.saturating_duration_since(now))
+            .chain(self.window.redraw_interval())
+            .fold(HOUSEKEEPING_INTERVAL, Duration::min)
+    }
+
+    /// Returns the `User` the client acts as.
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// Gets the client going before the main loop: a resumed session asks the server for what it missed, and a user
+    /// with nobody else in their group publishes key packages to be added with.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::publish_key_packages()`.
+    pub async fn start(&mut self) -> Result<(), ApplicationError> {
+        self.log_system(&format!("Connected to {} as {}.", self.network.address(), self.user.get_id()));
+        self.note_downgrade();
+        // a resumed session asks for what it missed before anything else, so the replay comes first
+        match self.last_seq {
+            Some(after) if self.resuming => {
+                self.network.send_frame(Frame::Resume { after }).await;
+                self.await_replay().await;
+                self.audit_group(AuditLevel::Membership, "resumed");
+                self.log_system(&format!(
+                    "Resumed your session at epoch {}. Catching up on what was sent while you were away...",
+                    self.user.epoch().unwrap_or_default(),
+                ));
+            }
+            _ => self.audit_group(AuditLevel::Membership, "started"),
+        }
+        // a resumed member of a group with anyone else in it is already where key packages would get them
+        if self.user.roster().len() <= 1 {
+            self.publish_key_packages(true, KEY_PACKAGE_POOL_SIZE).await?;
+            self.log_system(
+                "Waiting to be added to the group. Nothing sent before you're added can be read here; if nobody else \
+                is on the server yet, whoever joins next is added to your group instead.",
+            );
+        }
+        Ok(())
+    }
+
+    /// Tells the user if the server speaks an older version of the protocol than the client, and so what's missing,
+    /// e.g. after it was rolled back, or if something in between kept the versions it offers from getting through.
+    fn note_downgrade(&mut self) {
+        let version = self.network.version();
+        if version >= wire::CURRENT_VERSION {
+            return;
+        }
+        self.trace.decision("downgraded", &format!("version={}", version));
+        self.log_system(&format!(
+            "The server speaks an older version of the protocol ({}, where this client speaks up to {}), so large \
+            messages aren't compressed, rooms can't be created, and other members aren't shown going offline.",
+            version,
+            wire::CURRENT_VERSION,
+        ));
+    }
+
+    /// Goes once through the main loop: handles all the user's input and every frame received since the last time,
+    /// sends whatever's due, and redraws the window. Returns false once the client should stop.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `ChatView::poll_input()`, `Controller::handle_input()`,
+    /// `Controller::handle_frame()`, `Controller::send_cover()`, or `Controller::publish_key_packages()`.
+    pub async fn tick(&mut self) -> Result<bool, ApplicationError> {
+        while let Some(input) = self.window.poll_input()? {
+            match input {
+                UserInput::Quit => return Ok(false),
+                UserInput::Line(s) if !s.is_empty() => self.handle_input(s).await?,
+                UserInput::Copy(index) => self.copy_entry(index),
+                UserInput::Retry => self.retry_failed().await,
+                _ => (),
+            }
+            if self.wiped {
+                return Ok(false);
+            }
+        }
+
+        for frame in self.network.get_input().await {
+            self.handle_frame(frame).await?;
+        }
+        match self.network.take_dropped() {
+            0 => (),
+            1 => self.window.show_notice("Skipped a frame from the server that couldn't be read."),
+            dropped => self.window.show_notice(&format!("Skipped {} frames from the server that couldn't be read.", dropped)),
+        }
+        if self.network.take_corrupted() > 0 {
+            self.trace.decision("retransmit", "reason=corrupted_inbound");
+            self.window.show_notice("A frame from the server arrived corrupted. Asking for it again...");
+            self.request_retransmit().await;
+        }
+        if self.network.state() == ConnectionState::Disconnected && !self.closing {
+            self.reconnect().await?;
+        }
+        if !self.outbox.is_empty() && self.online() && self.user.epoch().is_some() {
+            self.flush_outbox().await?;
+        }
+        if self.session_dirty {
+            self.save_session();
+        }
+
+        if self.next_cover.is_some_and(|due| due <= self.clock.now()) {
+            self.send_cover().await?;
+        }
+
+        // once the user is in a group with anyone else, nobody needs their key packages any more
+        if self.clock.since(self.key_packages_published) >= KEY_PACKAGE_REFRESH && self.user.roster().len() <= 1 {
+            self.publish_key_packages(true, KEY_PACKAGE_POOL_SIZE).await?;
+        }
+        self.claim_overdue_newcomers().await;
+        self.advance_reinit().await?;
+        if self.moved.is_some_and(|moved| self.clock.since(moved) >= DRAIN_TIME) {
+            self.moved = None;
+            self.user.drop_predecessor();
+        }
+
+        self.update_members();
+        self.update_status();
+        self.window.draw(&self.log).unwrap();
+        if self.closing {
+            // leave the notice on screen for a moment before tearing down the terminal
+            time::sleep(CLOSING_NOTICE_DELAY).await;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Tears the client down after the main loop: saves the session one last time, restores the terminal, and
+    /// finishes a `/wipe`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `ChatView::shutdown()`.
+    pub fn finish(&mut self) -> Result<(), ApplicationError> {
+        if !self.wiped {
+            self.log_system("Disconnected from the server.");
+        }
+        if self.session_dirty {
+            self.save_session();
+        }
+        self.window.shutdown()?;
+        if self.closing {
+            println!("Disconnected: the server shut down.");
+        }
+        if self.wiped {
+            self.finish_wipe();
+        }
+
+        Ok(())
+    }
+
+    /// Helper function for `Controller::run()`. Passes relayed MLS messages on to `Controller::handle_messages()`,
+    /// and reacts to the server's control frames.
+    ///
+    /// A `Frame::Lagged` is only reported to the user, since the server follows it with a replay of the missed
+    /// messages. A `Frame::Resync` means the missed messages are gone, so the local group state is discarded and
+    /// fresh key packages are published to be re-added to the group. A `Frame::ServerClosing` ends the main loop
+    /// once the current batch of frames has been handled.
+    ///
+    /// When another client publishes key packages, one is claimed from the directory to add them with; when one of
+    /// the user's own is claimed, the pool is topped back up. A `Frame::Sequenced` is handled by
+    /// `Controller::handle_sequenced()`, as is a relayed message that turns out to be one of the user's own, sent
+    /// before a restart. Relayed and sequenced messages are skipped if `Controller::advance_seq()` says so.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::handle_messages()`, `Controller::resync()`,
+    /// `Controller::add_member()`, `Controller::publish_key_packages()`, or `Controller::handle_sequenced()`.
+    async fn handle_frame(&mut self, frame: Frame) -> Result<(), ApplicationError> {
+        match frame {
+            Frame::Relay { seq, data } => {
+                if !self.advance_seq(seq) {
+                    return Ok(());
+                }
+                self.trace.inbound(seq, &data);
+                if self.in_flight.front().is_some_and(|sent| sent.data == data) {
+                    return self.handle_sequenced().await;
+                }
+                self.metrics.message_received();
+                self.handle_messages(data).await
+            }
+            Frame::Lagged { missed } => {
+                self.window.show_notice(&format!("Fell behind the server by {} messages. Catching up...", missed));
+                Ok(())
+            }
+            Frame::Resync => {
+                self.log_system("Missed messages could no longer be recovered. Rejoining the group...");
+                self.resync().await
+            }
+            Frame::ServerClosing => {
+                self.log_system("The server is shutting down. Disconnecting...");
+                self.closing = true;
+                Ok(())
+            }
+            Frame::Kicked => {
+                self.log_system("The server's host disconnected you.");
+                self.closing = true;
+                Ok(())
+            }
+            Frame::Announcement { text } => {
+                self.log_system(&format!("Announcement from the server: {}", sanitize(&text, MAX_TEXT_CHARS)));
+                Ok(())
+            }
+            Frame::Muted { secs, data } => {
+                self.handle_muted(secs, &data);
+                Ok(())
+            }
+            Frame::Retransmit => {
+                self.trace.decision("retransmit", "reason=corrupted_outbound");
+                self.request_retransmit().await;
+                Ok(())
+            }
+            Frame::Connected { id, .. } => {
+                self.connection_id = Some(id);
+                // a resumed session tells the rest of the group which connection it's on now
+                if self.user.roster().len() > 1 {
+                    self.announce_presence().await?;
+                }
+                Ok(())
+            }
+            Frame::Disconnected { id } => {
+                if let Some(leaf) = self.connections.remove(&id) {
+                    // a member who's left the group is no longer in the roster to be named
+                    let member = self.user.roster().into_iter().find(|member| member.index == leaf && !member.own);
+                    let previous = self.presence.insert(leaf, Presence::Offline);
+                    if let (Some(member), false) = (member, previous == Some(Presence::Offline)) {
+                        let name = self.member_name(leaf, &member.identity);
+                        self.log_system(&format!("{} went offline.", name));
+                    }
+                }
+                self.newcomers.remove(&id);
+                self.reclaims.remove(&id);
+                Ok(())
+            }
+            Frame::KeyPackagesAvailable { owner } => {
+                if self.claim_delay() == Some(Duration::ZERO) {
+                    self.network.send_frame(Frame::ClaimKeyPackage { owner }).await;
+                } else if self.user.is_moderator() {
+                    self.newcomers.insert(owner, self.clock.now());
+                }
+                Ok(())
+            }
+            Frame::KeyPackage { owner, data } => match KeyPackageIn::tls_deserialize(&mut data.as_slice()) {
+                Ok(kp) if self.reclaim(owner, &kp) => {
+                    self.network.send_frame(Frame::ClaimKeyPackage { owner }).await;
+                    Ok(())
+                }
+                Ok(kp) => self.add_member(kp).await,
+                Err(_) => {
+                    self.window.show_notice("Received a key package that couldn't be read.");
+                    Ok(())
+                }
+            }
+            Frame::RoomCreated { room } => {
+                self.log_system(&format!(
+                    "Created room {}, owned by you. Anyone joining with --room {} will enter it.",
+                    sanitize(&room, MAX_NAME_CHARS),
+                    sanitize(&room, MAX_NAME_CHARS),
+                ));
+                Ok(())
+            }
+            Frame::RoomRefused { room } => {
+                self.window.show_notice(&format!(
+                    "Couldn't create room {}: it's already taken, or the server doesn't have rooms.",
+                    sanitize(&room, MAX_NAME_CHARS),
+                ));
+                Ok(())
+            }
+            Frame::RoomOwner { owner, invite_only } => {
+                self.set_room_owner(owner, invite_only);
+                Ok(())
+            }
+            Frame::KeyPackageClaimed { remaining } => {
+                self.publish_key_packages(false, KEY_PACKAGE_POOL_SIZE.saturating_sub(remaining)).await
+            }
+            Frame::Sequenced { seq } => {
+                if !self.advance_seq(seq) {
+                    return Ok(());
+                }
+                self.handle_sequenced().await
+            }
+            Frame::RatchetTree { key_package, tree } => {
+                match self.pending_welcome.take() {
+                    Some((pending, welcome)) if pending == key_package => {
+                        self.join(welcome, (!tree.is_empty()).then_some(tree)).await
+                    }
+                    pending => {
+                        self.pending_welcome = pending;
+                        Ok(())
+                    }
+                }
+            }
+            // only ever sent by clients, or by servers to each other
+            Frame::Mls(_)
+            | Frame::PublishKeyPackage { .. }
+            | Frame::ClaimKeyPackage { .. }
+            | Frame::PublishRatchetTree { .. }
+            | Frame::FetchRatchetTree { .. }
+            | Frame::Resume { .. }
+            | Frame::Enter { .. }
+            | Frame::Forwarded { .. }
+            | Frame::Register { .. }
+            | Frame::CreateRoom { .. }
+            | Frame::Version { .. }
+            | Frame::Ping => Ok(()),
+            // only ever sent in answer to what `Client::handshake()` sends, which waits for it itself
+            Frame::Registered { .. } | Frame::Compression { .. } => Ok(()),
+            // only ever sent in place of that answer, which `Client::handshake()` fails on
+            Frame::ServerFull => Ok(()),
+            Frame::Pong { .. } if self.restoring.is_some() => {
+                self.restored().await;
+                Ok(())
+            }
+            // the answer to the keepalive `Client::handle_stream()` sends, which only has to reach the server
+            Frame::Pong { .. } => Ok(()),
+        }
+    }
+
+    /// Helper function for `Controller::handle_frame()`. Makes the owner of the room the server put the user in a
+    /// moderator, and if the room is invite-only, enforces moderation so that nobody else adds members, and locks it
+    /// to the owner for `policy::Strict`. The server says so again on every reconnection, which only changes anything
+    /// the first time.
+    fn set_room_owner(&mut self, owner: String, invite_only: bool) {
+        let policy = if invite_only { ModerationPolicy::Enforce } else { self.moderation.policy };
+        let locked_by = invite_only.then(|| owner.clone());
+        if self.moderation.moderators.contains(&owner)
+            && self.moderation.policy == policy
+            && self.locked_by == locked_by
+        {
+            return;
+        }
+        let name = sanitize(&owner, MAX_NAME_CHARS);
+        if !self.moderation.moderators.contains(&owner) {
+            self.moderation.moderators.push(owner);
+        }
+        self.moderation.policy = policy;
+        self.user.set_moderation(self.moderation.clone());
+        self.user.set_locked_by(locked_by.clone());
+        self.locked_by = locked_by;
+        self.log_system(&if invite_only {
+            format!("This room is owned by {}, and only they can add members to it.", name)
+        } else {
+            format!("This room is owned by {}.", name)
+        });
+    }
+
+    /// Helper function for `Controller::tick()`. Tries to reconnect to the server once the connection's been lost,
+    /// waiting longer after each failed attempt, up to `MAX_RECONNECT_DELAY`. Once an attempt succeeds, the client
+    /// resumes from the last relayed message it handled, as a resumed session does. Whatever the user writes in the
+    /// meantime waits in the outbox.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::publish_key_packages()`.
+    async fn reconnect(&mut self) -> Result<(), ApplicationError> {
+        match self.network.finish_reconnect().await {
+            Some(Ok(())) => {
+                self.reconnect_at = None;
+                self.reconnect_delay = RECONNECT_DELAY;
+                if let Some(after) = self.last_seq {
+                    self.resuming = true;
+                    self.network.send_frame(Frame::Resume { after }).await;
+                }
+                self.await_replay().await;
+                self.log_system("Reconnected to the server. Catching up...");
+                self.note_downgrade();
+                // the server forgets a connection's key packages along with it
+                if self.user.roster().len() <= 1 {
+                    self.publish_key_packages(true, KEY_PACKAGE_POOL_SIZE).await?;
+                }
+                // the answer to a fetch may have been lost along with the connection
+                if let Some((key_package, _)) = &self.pending_welcome {
+                    self.network.send_frame(Frame::FetchRatchetTree { key_package: key_package.clone() }).await;
+                }
+            }
+            Some(Err(_)) => {
+                self.reconnect_at = Some(self.clock.now() + self.reconnect_delay);
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+            None if self.network.reconnecting() => (),
+            None => {
+                match self.reconnect_at {
+                    None => self.log_system(
+                        "Lost the connection to the server. Reconnecting... Messages you write meanwhile will be sent \
+                        once it's back.",
+                    ),
+                    Some(due) if due > self.clock.now() => return Ok(()),
+                    Some(_) => (),
+                }
+                self.network.reconnect(self.room.clone(), self.user.get_id().clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the server for a `Frame::Pong`, which it sends once it's replayed what a `Frame::Resume` asked for, and
+    /// holds payloads back in the outbox until it arrives. See `Controller::restored()`.
+    async fn await_replay(&mut self) {
+        self.restoring = Some(self.in_flight.len());
+        self.network.send_frame(Frame::Ping).await;
+    }
+
+    /// Recovers from a frame corrupted on the way to or from the server, which was dropped: asks the server to replay
+    /// everything relayed after the last message handled, and once it has, sends again whatever it never got, as
+    /// after reconnecting. Does nothing if the client is already catching up, or if nothing has been relayed yet to
+    /// resume from.
+    async fn request_retransmit(&mut self) {
+        let Some(after) = self.last_seq else { return };
+        if self.restoring.is_some() {
+            return;
+        }
+        self.resuming = true;
+        self.network.send_frame(Frame::Resume { after }).await;
+        self.await_replay().await;
+    }
+
+    /// Helper function for `Controller::handle_frame()`. Called once the server has answered the `Frame::Ping` sent by
+    /// `Controller::await_replay()`, so everything the client missed has been replayed. The MLS messages from before
+    /// connecting that the replay didn't sequence never reached the server, so they're sent again, in order.
+    async fn restored(&mut self) {
+        let unsent = self.restoring.take().unwrap_or_default();
+        self.resuming = false;
+        let unsent: Vec<Vec<u8>> = self.in_flight.iter().take(unsent).map(|sent| sent.data.clone()).collect();
+        for data in unsent {
+            self.network.send(data).await;
+        }
+    }
+
+    /// Returns whether payloads can be sent now: the client is connected, and has caught up since it last connected.
+    fn online(&self) -> bool {
+        self.network.state() == ConnectionState::Connected && self.restoring.is_none()
+    }
+
+    /// Sends the payloads written while the client was offline, encrypting each for the group's current epoch, and
+    /// marks the chat messages among them as sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::send_payload()`.
+    async fn flush_outbox(&mut self) -> Result<(), ApplicationError> {
+        let mut messages = 0;
+        for payload in mem::take(&mut self.outbox) {
+            if let Payload::Chat { id, .. } = payload {
+                self.log.set_pending(id, false);
+                messages += 1;
+                self.send_chat_payload(id, payload).await;
+            } else {
+                self.send_payload(&payload).await?;
+            }
+        }
+        if messages > 0 {
+            self.log_system(&format!("Sent {} message(s) written while you were disconnected.", messages));
+        }
+        self.session_dirty = true;
+        Ok(())
+    }
+
+    /// Records that the relayed message `seq` arrived, returning false if it should be skipped: because it was already
+    /// handled, or because it came ahead of the replay a resumed session is waiting on, which sends it again in order.
+    fn advance_seq(&mut self, seq: u64) -> bool {
+        if let Some(last) = self.last_seq {
+            if seq <= last || (self.resuming && seq > last + 1) {
+                return false;
+            }
+        }
+        self.resuming = false;
+        self.last_seq = Some(seq);
+        self.session_dirty = true;
+        true
+    }
+
+    /// Publishes `count` fresh key packages to the server's directory, preceded by a new last resort if `last_resort`
+    /// is set (which starts the user's pool over). The last resort is for the user's own ciphersuite, and the rest take
+    /// turns through `group::key_package_ciphersuites()`, since the directory can't tell whoever claims them which
+    /// ones a group that's moved to another ciphersuite could use (see `Controller::reclaim()`).
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `User::generate_key_package()` or
+    /// `User::successor_key_package()`, or an `MlsError::TlsSerialize` if a key package can't be serialized.
+    async fn publish_key_packages(&mut self, last_resort: bool, count: u64) -> Result<(), ApplicationError> {
+        let ciphersuites = group::key_package_ciphersuites(self.user.ciphersuite());
+        if last_resort {
+            self.key_package_turn = 0;
+        }
+        let flags = std::iter::once(true).filter(|_| last_resort).chain((0..count).map(|_| false));
+        for last_resort in flags {
+            let key_package = match last_resort {
+                true => self.user.generate_key_package()?,
+                false => {
+                    let ciphersuite = ciphersuites[self.key_package_turn % ciphersuites.len()];
+                    self.key_package_turn += 1;
+                    match ciphersuite == self.user.ciphersuite() {
+                        true => self.user.generate_key_package()?,
+                        false => self.user.successor_key_package(u16::from(ciphersuite))?,
+                    }
+                }
+            };
+            let data = key_package.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+            self.network.send_frame(Frame::PublishKeyPackage { last_resort, data }).await;
+        }
+        if last_resort {
+            self.key_packages_published = self.clock.now();
+        }
+        // the private keys behind them have to be saved to open a Welcome that arrives after a restart
+        self.session_dirty = true;
+        Ok(())
+    }
+
+    /// Replaces the `User` with a fresh one under the same identity and ID, and publishes its key packages so that it
+    /// can be added back into the group.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `User::build()` or `Controller::publish_key_packages()`.
+    async fn resync(&mut self) -> Result<(), ApplicationError> {
+        self.audit_group(AuditLevel::Membership, "resynced");
+        let id = self.user.get_id().clone();
+        let ciphersuite = self.user.ciphersuite();
+        self.user = User::build(self.user.identity())?;
+        if *self.user.get_id() != id {
+            self.user.set_id(id);
+        }
+        // the group may have moved off `CIPHERSUITE`, and the user's key packages are for getting back into it
+        self.user.set_ciphersuite(ciphersuite);
+        // the server picks up from wherever it is now, so whatever was in flight may never be sequenced, and would
+        // take the place of the new user's own messages if it stayed queued
+        self.in_flight.clear();
+        self.sent_at.clear();
+        self.last_seq = None;
+        self.resuming = false;
+        self.user.set_capabilities(self.capabilities.clone());
+        self.user.set_moderation(self.moderation.clone());
+        self.user.set_locked_by(self.locked_by.clone());
+        self.user.set_commit_policy(policy::named(self.security.commits));
+        self.user.set_padding(self.privacy.padding);
+        self.user.set_ratchet_tree_extension(self.ratchet_tree_in_welcome);
+        self.nicknames.clear();
+        self.impostors.clear();
+        self.presence.clear();
+        self.connections.clear();
+        self.newcomers.clear();
+        self.reclaims.clear();
+        self.early_messages.clear();
+        self.pending_welcome = None;
+        self.seen_ids = SeenIds::default();
+        self.window.set_topic(None);
+        self.publish_key_packages(true, KEY_PACKAGE_POOL_SIZE).await
+    }
+
+    /// Helper function for `Controller::run()`. Deserializes and processes incoming messages, then executes
+    /// the necessary tasks for each. The user's own messages coming back are dropped (see `SentIds`), and anything
+    /// that doesn't decode as an `Envelope` is shown as a notice and skipped, since it's up to whichever peer sent it.
+    ///
+    /// A Welcome for one of the user's key packages isn't used until the server has been asked for the ratchet tree
+    /// published for it, since it may have been sent without one, and openmls uses up the key package whether or not
+    /// it can join with it. See `Controller::join()`.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns any `ApplicationError` types returned by `User::add_member()`, `User::update_keys()`,
+    /// `User::process_message()`, or `Controller::serialize_and_send()`.
+    async fn handle_messages(&mut self, data: Vec<u8>) -> Result<(), ApplicationError> {
+        // the server should only ever acknowledge the user's own messages with a `Frame::Sequenced`
+        if self.sent_ids.contains(&data) {
+            self.trace.decision("dropped", "reason=own_message");
+            return Ok(());
+        }
+        let envelope = match Envelope::decode(&data) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                self.trace.decision("dropped", &format!("reason=unreadable error={:?}", e.to_string()));
+                self.window.show_notice("Received a message that couldn't be read.");
+                return Ok(());
+            }
+        };
+        match envelope.body {
+            MlsMessageInBody::Welcome(w) => {
+                // only a user who's still alone in the group they started with is waiting to be welcomed
+                if self.user.roster().len() > 1 {
+                    self.trace.decision("ignored", "reason=already_in_group");
+                } else if let Some(key_package) = self.user.welcome_for(&w) {
+                    self.trace.decision("fetching_tree", "");
+                    self.network.send_frame(Frame::FetchRatchetTree { key_package: key_package.clone() }).await;
+                    self.pending_welcome = Some((key_package, w));
+                } else {
+                    self.trace.decision("ignored", "reason=welcome_for_someone_else");
+                }
+            }
+            MlsMessageInBody::KeyPackage(kp) => {
+                self.trace.decision("adding_member", "");
+                self.add_member(kp).await?
+            }
+            MlsMessageInBody::GroupInfo(_) => self.trace.decision("ignored", "reason=group_info"),
+            MlsMessageInBody::PrivateMessage(msg) => self.process_message(msg.into(), &data).await?,
+            MlsMessageInBody::PublicMessage(msg)
+                if msg.content_type() == ContentType::Application && self.security.plaintext == PlaintextPolicy::Strict =>
+            {
+                self.drop_plaintext(msg.sender(), msg.epoch().as_u64())
+            }
+            MlsMessageInBody::PublicMessage(msg) => self.process_message(msg.into(), &data).await?,
+        }
+        Ok(())
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Drops an application message that arrived as a
+    /// `PublicMessage`, warning the user, since chat content is only ever sent encrypted: it means the sender's client
+    /// is broken, or someone is trying to get the group to show something it never encrypted. The sender named in it
+    /// is unverified, so it's only reported as claimed, and sanitized like any other identity a peer controls.
+    fn drop_plaintext(&mut self, sender: &Sender, epoch: u64) {
+        let claimed = match sender {
+            Sender::Member(leaf) => self.user.roster().into_iter()
+                .find(|member| member.index == leaf.u32())
+                .map(|member| sanitize(&member.identity, MAX_NAME_CHARS)),
+            _ => None,
+        };
+        let claimed = claimed.unwrap_or_else(|| String::from("someone outside the group"));
+        self.trace.decision("dropped", &format!("reason=plaintext_application epoch={} sender={:?}", epoch, claimed));
+        self.audit(AuditLevel::Membership, "plaintext_application", &format!("epoch={} sender={:?}", epoch, claimed));
+        self.log_warning(&format!(
+            "Dropped a message in epoch {} that was sent unencrypted, claiming to be from {}. No client of this group \
+             should send one, so it may have been forged.",
+            epoch,
+            claimed,
+        ));
+    }
+
+    /// Helper function for `Controller::handle_frame()`. Joins the group with a Welcome for one of the user's key
+    /// packages, once the server has answered with the ratchet tree published for it, if there was one, then catches
+    /// up on what was relayed meanwhile. Does nothing if the user has since been joined in their own group.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types other than `MlsError::KeyPackageDNE`, `MlsError::Welcome` and
+    /// `MlsError::InvalidMessage` returned by `User::join_group()`, or any returned by
+    /// `Controller::catch_up_early()`, `Controller::rotate_keys()`, `Controller::send_payload()` or
+    /// `Controller::announce_presence()`.
+    async fn join(&mut self, welcome: Welcome, ratchet_tree: Option<Vec<u8>>) -> Result<(), ApplicationError> {
+        if self.user.roster().len() > 1 {
+            self.trace.decision("ignored", "reason=already_in_group");
+            return Ok(());
+        }
+        match self.user.join_group(welcome, ratchet_tree.as_deref()) {
+            Ok(()) => {
+                self.trace.decision("joined", &format!(
+                    "epoch={} members={}",
+                    self.user.epoch().unwrap_or_default(),
+                    self.user.roster().len(),
+                ));
+                self.audit_group(AuditLevel::Membership, "joined");
+                self.log_system(&format!(
+                    "Joined the group at epoch {}, with {} members.",
+                    self.user.epoch().unwrap_or_default(),
+                    self.user.roster().len(),
+                ));
+                self.drop_in_flight_commits();
+                self.seen_ids = SeenIds::default();
+                self.catch_up_early().await?;
+                self.rotate_keys().await?;
+                self.awaiting_history = true;
+                self.send_payload(&Payload::HistoryRequest).await?;
+                self.announce_presence().await?;
+            }
+            Err(ApplicationError::Mls(MlsError::KeyPackageDNE)) => {
+                self.trace.decision("ignored", "reason=welcome_for_someone_else");
+            }
+            Err(ApplicationError::Mls(e @ (MlsError::Welcome(_) | MlsError::InvalidMessage))) => {
+                self.trace.decision("dropped", &format!("reason=join_failed error={:?}", e.to_string()));
+                self.audit(AuditLevel::Membership, "join_failed", &format!("error={:?}", e.to_string()));
+                self.log_warning(&format!("A Welcome into the group couldn't be used ({}).", e));
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Processes an MLS message sent to a group, or keeps it for
+    /// `Controller::catch_up_early()` while the user is still waiting to be welcomed into one. `data` is the message
+    /// as it arrived; an exact duplicate of one already processed is dropped (see `SeenIds`). A message that can't be
+    /// decrypted or verified, one from outside the group, or a commit that can't be merged, is shown as a notice and
+    /// skipped, rather than ending the session.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types other than `MlsError::ProcessMessage`, `MlsError::InvalidMessage` and
+    /// `MlsError::MergeCommit` returned by `User::process_message()`, or any returned by
+    /// `Controller::handle_processed()`.
+    async fn process_message(&mut self, msg: ProtocolMessage, data: &[u8]) -> Result<(), ApplicationError> {
+        let epoch = msg.epoch().as_u64();
+        if self.user.roster().len() <= 1 {
+            self.trace.decision("queued", &format!("reason=awaiting_welcome epoch={}", epoch));
+            if self.early_messages.len() == EARLY_MESSAGES {
+                self.early_messages.pop_front();
+            }
+            self.early_messages.push_back(msg);
+            return Ok(());
+        }
+        if self.seen_ids.contains(epoch, data) {
+            self.trace.decision("dropped", &format!("reason=duplicate epoch={}", epoch));
+            return Ok(());
+        }
+        let processed = match self.user.process_message(msg) {
+            Ok(processed) => processed,
+            Err(ApplicationError::Mls(MlsError::ProcessMessage(e))) => {
+                self.trace.decision("dropped", &format!("reason=undecryptable epoch={} error={:?}", epoch, e.to_string()));
+                self.audit(AuditLevel::Commits, "undecryptable", &format!("epoch={} error={:?}", epoch, e.to_string()));
+                self.window.show_notice(&format!("Couldn't decrypt a message in epoch {} ({}).", epoch, e));
+                return Ok(());
+            }
+            Err(ApplicationError::Mls(e @ (MlsError::InvalidMessage | MlsError::MergeCommit(_)))) => {
+                self.trace.decision("dropped", &format!("reason=rejected epoch={} error={:?}", epoch, e.to_string()));
+                self.audit(AuditLevel::Commits, "rejected", &format!("epoch={} error={:?}", epoch, e.to_string()));
+                self.window.show_notice(&format!("Skipped a message in epoch {} ({}).", epoch, e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.seen_ids.record(epoch, data);
+        let details = match &processed {
+            Processed::Application(msg) => format!("kind=application epoch={} sender={}", epoch, msg.sender),
+            Processed::Commit { summary, superseded, .. } => format!(
+                "kind=commit epoch={} committer={:?} own_commit_discarded={}",
+                summary.epoch,
+                summary.committer,
+                superseded,
+            ),
+            Processed::Refused { committer, reason } => {
+                format!("kind=refused_commit epoch={} committer={:?} reason={:?}", epoch, committer, reason)
+            }
+            Processed::ReInit(_) => format!("kind=reinit epoch={}", epoch),
+            Processed::Other => format!("kind=other epoch={}", epoch),
+        };
+        self.trace.decision("processed", &details);
+        self.handle_processed(processed).await
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Processes the messages relayed while the user waited to be
+    /// welcomed, now that they're in the group. Anything sent before the epoch they joined in can't be read, and is
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::handle_processed()`.
+    async fn catch_up_early(&mut self) -> Result<(), ApplicationError> {
+        for msg in mem::take(&mut self.early_messages) {
+            if let Ok(processed) = self.user.process_message(msg) {
+                self.handle_processed(processed).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how long after a newcomer's key packages are announced the user claims one to add them with, or None
+    /// if they leave it to others. If several members added the newcomer at once, the group would fork, so moderators
+    /// take turns by leaf index: the first who isn't known to be offline claims one right away, and each after them
+    /// gives those ahead `ADD_FALLBACK` apiece. Every member sees the same roster, and the server tells them all when
+    /// a connection closes, so they agree on the order.
+    ///
+    /// A user with nobody else in their group may be waiting on a group themselves, so they give it `LONE_ADD_DELAY`
+    /// to add the newcomer first, and leave it to the group altogether once it's relayed anything they've seen.
+    fn claim_delay(&self) -> Option<Duration> {
+        if !self.user.is_moderator() {
+            return None;
+        }
+        let roster = self.user.roster();
+        if roster.len() <= 1 {
+            return self.early_messages.is_empty().then_some(LONE_ADD_DELAY);
+        }
+        let own = self.user.own_index()?;
+        let ahead = roster.iter()
+            .filter(|member| member.index < own && self.moderation.allows(&member.identity))
+            .filter(|member| self.presence.get(&member.index) != Some(&Presence::Offline))
+            .count();
+        Some(ADD_FALLBACK * ahead as u32)
+    }
+
+    /// Returns when the user is next due to claim the key package of a newcomer they've left to others so far, if
+    /// they're waiting on any. See `Controller::claim_delay()`.
+    pub fn next_claim(&self) -> Option<Instant> {
+        let announced = self.newcomers.values().min()?;
+        Some(*announced + self.claim_delay()?)
+    }
+
+    /// Claims the key packages of newcomers that were left to others who still haven't added them, e.g. because the
+    /// moderator ranked first dropped off without the server noticing yet. Newcomers who've announced themselves from
+    /// inside the group are forgotten.
+    async fn claim_overdue_newcomers(&mut self) {
+        if self.newcomers.is_empty() {
+            return;
+        }
+        self.newcomers.retain(|owner, _| !self.connections.contains_key(owner));
+        let Some(delay) = self.claim_delay() else { return };
+        let overdue: Vec<u64> = self.newcomers.iter()
+            .filter(|(_, announced)| self.clock.since(**announced) >= delay)
+            .map(|(owner, _)| *owner)
+            .collect();
+        for owner in overdue {
+            self.newcomers.remove(&owner);
+            self.network.send_frame(Frame::ClaimKeyPackage { owner }).await;
+        }
+    }
+
+    /// Returns whether to claim another of the key packages the connection `owner` published in place of `kp`, because
+    /// it's for another ciphersuite than the group's, e.g. since the group moved off the one it was created with.
+    /// Newcomers cycle the key packages they publish through every ciphersuite a group could be using (see
+    /// `Controller::publish_key_packages()`), so one the group can use comes up within that many claims, unless only
+    /// their last resort is left.
+    fn reclaim(&mut self, owner: u64, kp: &KeyPackageIn) -> bool {
+        let ciphersuite = self.user.ciphersuite();
+        if !self.user.is_moderator() || group::key_package_ciphersuite(kp).is_none_or(|other| other == ciphersuite) {
+            self.reclaims.remove(&owner);
+            return false;
+        }
+        let claims = self.reclaims.entry(owner).or_default();
+        *claims += 1;
+        if *claims >= group::key_package_ciphersuites(ciphersuite).len() {
+            self.reclaims.remove(&owner);
+            return false;
+        }
+        true
+    }
+
+    /// Returns whether the owner of a key package is already in the group, e.g. because another moderator added them
+    /// while the user's own add was pending.
+    fn in_group(&self, kp: &KeyPackageIn) -> bool {
+        group::key_package_signature_key(kp)
+            .is_some_and(|key| self.user.roster().iter().any(|member| member.signature_key == key))
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Adds the owner of a key package to the group and sends out
+    /// the resulting Commit, unless the moderation settings leave adding members to someone else or they're already
+    /// in it. The Welcome goes out once the server sequences the commit (see `Controller::handle_sequenced()`). An
+    /// expired key package is only reported in the log, and one that doesn't verify, is for another ciphersuite or MLS
+    /// version, or that openmls won't add is shown as a notice. If one of the user's commits is already pending, the key
+    /// package waits its turn in `Controller::send_deferred()`.
+    ///
+    /// A key package claiming to be another device of someone in the group is only added by the device that showed
+    /// its link code, once the proof in it checks out; everyone else leaves it be.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `User::add_member()` other than the ones for a key package
+    /// that can't be added (`MlsError::KeyPackageExpired`, `KeyPackageVerify`, `KeyPackageVersion`,
+    /// `KeyPackageCiphersuite` and `AddMember`), or any returned by `Controller::serialize_and_send()`.
+    async fn add_member(&mut self, kp: KeyPackageIn) -> Result<(), ApplicationError> {
+        if !self.user.is_moderator() {
+            return Ok(());
+        }
+        let linking = match group::key_package_profile(&kp) {
+            Some((profile, signature_key)) if self.claims_member(&profile.user_id) || profile.link.is_some() => {
+                let linked = self.link.as_ref().is_some_and(|(code, shown)| {
+                    self.clock.since(*shown) < LINK_LIFETIME && code.verify(&profile, &signature_key)
+                });
+                if !linked {
+                    return Ok(());
+                }
+                true
+            }
+            _ => false,
+        };
+        if self.in_group(&kp) {
+            return Ok(());
+        }
+        if self.user.has_pending_commit() {
+            self.deferred_adds.push_back(kp);
+            return Ok(());
+        }
+
+        let key_package = kp.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+        let (commit, welcome) = match self.user.add_member(kp) {
+            Ok(messages) => messages,
+            Err(ApplicationError::Mls(MlsError::KeyPackageExpired)) => {
+                self.log_system("Couldn't add a new member: their key package has expired.");
+                return Ok(());
+            }
+            Err(ApplicationError::Mls(
+                e @ (MlsError::KeyPackageVerify(_)
+                | MlsError::KeyPackageVersion
+                | MlsError::KeyPackageCiphersuite(_)
+                | MlsError::AddMember(_))
+            )) => {
+                self.window.show_notice(&format!("Couldn't add a new member ({}).", e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let data = commit.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+        let welcome = welcome.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+        self.send_in_flight(InFlight { sent: Sent::Add, data, welcome: Some(welcome), key_package: Some(key_package) }).await;
+        if linking {
+            self.link = None;
+            self.audit(AuditLevel::Membership, "device_linked", &format!("user_id={:?}", self.user.profile().user_id));
+            self.log_system("Adding your new device to the group. It'll show up as you in the member list.");
+        }
+        Ok(())
+    }
+
+    /// Returns whether `user_id` is the user's own or that of a member already in the group.
+    fn claims_member(&self, user_id: &str) -> bool {
+        user_id == self.user.profile().user_id
+            || self.user.roster().iter().any(|member| member.profile.as_ref().is_some_and(|profile| profile.user_id == user_id))
+    }
+
+    /// Records the leaves a commit from `committer` added that claim to be another member's device, and warns about
+    /// them. Leaves recorded earlier are forgotten once they're emptied or handed to someone else.
+    fn record_impostors(&mut self, committer: &str, impostors: Vec<u32>) {
+        let roster = self.user.roster();
+        self.impostors.retain(|index, user_id| roster.iter().any(|member| {
+            member.index == *index && member.profile.as_ref().is_some_and(|profile| &profile.user_id == user_id)
+        }));
+
+        for index in impostors {
+            let Some(member) = roster.iter().find(|member| member.index == index) else { continue };
+            let Some(profile) = &member.profile else { continue };
+            self.impostors.insert(index, profile.user_id.clone());
+            self.audit(AuditLevel::Membership, "impostor_device", &format!(
+                "committer={:?} leaf={} identity={:?} user_id={:?}",
+                committer,
+                index,
+                member.identity,
+                profile.user_id,
+            ));
+            self.log_warning(&format!(
+                "{} added {} as another device of an existing member, but only that member's own devices can link one. \
+                It's listed separately; don't trust it as them.",
+                sanitize(committer, MAX_NAME_CHARS),
+                sanitize(&member.identity, MAX_NAME_CHARS),
+            ));
+        }
+    }
+
+    /// Helper function for `Controller::handle_frame()`. Drops a message the server wouldn't relay because the user is
+    /// muted for flooding from the ones in flight, since it'll never be sequenced, and tells the user it wasn't
+    /// delivered. It's found by its contents, since the `Frame::Muted` may overtake the `Sequenced` of messages sent
+    /// before it.
+    fn handle_muted(&mut self, secs: u64, data: &[u8]) {
+        let Some(index) = self.in_flight.iter().position(|msg| msg.data == data) else { return };
+        // `sent_at` lines up with the end of `in_flight`
+        let timed_from = self.in_flight.len().saturating_sub(self.sent_at.len());
+        if index >= timed_from {
+            self.sent_at.remove(index - timed_from);
+        }
+        if let Some(unsent) = &mut self.restoring {
+            if index < *unsent {
+                *unsent -= 1;
+            }
+        }
+        self.in_flight.remove(index);
+        self.session_dirty = true;
+        self.trace.decision("dropped", "reason=muted");
+        self.window.show_notice(&format!(
+            "Your message wasn't delivered: the server has muted you for flooding the room for another {} seconds.",
+            secs,
+        ));
+    }
+
+    /// Helper function for `Controller::handle_frame()`. Matches a `Frame::Sequenced` to the oldest MLS message still
+    /// in flight. If that was a commit which hasn't been discarded, no other commit was ordered ahead of it, so it's
+    /// merged, the Welcome for any member it added is sent, and whatever was held back while it was pending is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `User::merge_pending_commit()` or
+    /// `Controller::send_deferred()`.
+    async fn handle_sequenced(&mut self) -> Result<(), ApplicationError> {
+        if let Some(unsent) = &mut self.restoring {
+            *unsent = unsent.saturating_sub(1);
+        }
+        if self.sent_at.len() == self.in_flight.len() {
+            if let Some(sent_at) = self.sent_at.pop_front() {
+                self.metrics.record_latency(self.clock.since(sent_at));
+            }
+        }
+        if let Some(sent) = self.in_flight.front() {
+            self.trace.decision("sequenced", &format!("sent={:?}", sent.sent));
+        }
+        match self.in_flight.pop_front() {
+            Some(InFlight { sent: Sent::Update | Sent::Add, welcome, .. }) => {
+                if let Some(summary) = self.user.merge_pending_commit()? {
+                    self.metrics.commit_merged();
+                    if let Some(data) = welcome {
+                        if !self.user.includes_ratchet_tree() {
+                            self.publish_ratchet_tree(&data).await?;
+                        }
+                        self.send_in_flight(InFlight { sent: Sent::Message, data, welcome: None, key_package: None }).await;
+                    }
+                    self.audit_commit(&summary);
+                    if !summary.added.is_empty() {
+                        // the user was waiting to be added, and has been joined in their own group instead
+                        if self.user.roster().len() == summary.added.len() + 1 {
+                            let added: Vec<String> = summary.added.iter().map(|name| sanitize(name, MAX_NAME_CHARS)).collect();
+                            self.log_system(&format!(
+                                "{} joined your group. You're in the group at epoch {}, with {} members.",
+                                added.join(", "),
+                                summary.epoch,
+                                self.user.roster().len(),
+                            ));
+                        }
+                        self.announce_presence().await?;
+                    }
+                }
+                self.send_deferred().await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Helper function for `Controller::handle_sequenced()`. Publishes the group's ratchet tree to the server for each
+    /// member the serialized Welcome `welcome` is for, ahead of the Welcome itself, since it was sent without one.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `User::ratchet_tree()`.
+    async fn publish_ratchet_tree(&mut self, welcome: &[u8]) -> Result<(), ApplicationError> {
+        let Ok(Envelope { body: MlsMessageInBody::Welcome(welcome), .. }) = Envelope::decode(welcome) else { return Ok(()) };
+        let tree = self.user.ratchet_tree()?;
+        for secrets in welcome.secrets() {
+            let key_package = secrets.new_member().as_slice().to_vec();
+            self.network.send_frame(Frame::PublishRatchetTree { key_package, tree: tree.clone() }).await;
+        }
+        Ok(())
+    }
+
+    /// Sends what was held back while one of the user's commits was pending: the next member waiting to be added,
+    /// whose commit then holds back the rest in turn, or else every deferred payload.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned by `Controller::add_member()` or `Controller::send_payload()`.
+    async fn send_deferred(&mut self) -> Result<(), ApplicationError> {
+        while let Some(kp) = self.deferred_adds.pop_front() {
+            self.add_member(kp).await?;
+            if self.user.has_pending_commit() {
+                return Ok(());
+            }
+        }
+        for payload in mem::take(&mut self.deferred_payloads) {
+            self.send_payload(&payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks every commit still in flight as moot, so that the server sequencing it doesn't merge a later commit in
+    /// its place, and drops the Welcomes held back for them. Used when the commit was discarded or its group replaced.
+    fn drop_in_flight_commits(&mut self) {
+        for sent in &mut self.in_flight {
+            sent.sent = Sent::Message;
+            sent.welcome = None;
+            sent.key_package = None;
+        }
+    }
+
+    /// Puts the members that the user's discarded commits were adding back at the front of the queue, to be added
+    /// again in the group's new epoch, unless the commit that got in first already added them.
+    fn requeue_adds(&mut self) {
+        let key_packages: Vec<KeyPackageIn> = self.in_flight.iter()
+            .filter_map(|sent| sent.key_package.as_ref())
+            .filter_map(|data| KeyPackageIn::tls_deserialize(&mut data.as_slice()).ok())
+            .collect();
+        for kp in key_packages.into_iter().rev() {
+            if !self.in_group(&kp) {
+                self.deferred_adds.push_front(kp);
+            }
+        }
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Passes application messages on to
+    /// `Controller::handle_received()`, warns about any members whose keys a merged commit replaced unexpectedly, and
+    /// notes requests to re-initialize the group, which can't be acted on. If another member's commit superseded the
+    /// user's own, whatever was waiting on the user's is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::handle_received()` or
+    /// `Controller::send_deferred()`.
+    async fn handle_processed(&mut self, processed: Processed) -> Result<(), ApplicationError> {
+        match processed {
+            Processed::Application(msg) => {
+                let details = format!(
+                    "epoch={} leaf={} sender={:?}",
+                    self.user.epoch().unwrap_or_default(),
+                    msg.sender,
+                    msg.identity,
+                );
+                self.audit(AuditLevel::All, "message", &details);
+                self.handle_received(msg).await?
+            }
+            Processed::Commit { summary, key_changes, impostors, unmoderated, superseded } => {
+                self.metrics.commit_merged();
+                self.audit_commit(&summary);
+                if !summary.added.is_empty() {
+                    self.announce_presence().await?;
+                }
+                self.record_impostors(&summary.committer, impostors);
+                if superseded {
+                    self.audit(AuditLevel::Commits, "own_commit_discarded", &format!("epoch={}", summary.epoch));
+                    self.requeue_adds();
+                    self.drop_in_flight_commits();
+                    self.send_deferred().await?;
+                }
+                if let Some(identity) = unmoderated {
+                    self.audit(AuditLevel::Membership, "unmoderated_commit", &format!("committer={:?}", identity));
+                    self.log_warning(&format!(
+                        "{} added or removed members without being a moderator.",
+                        sanitize(&identity, MAX_NAME_CHARS),
+                    ));
+                }
+                for change in key_changes {
+                    self.audit(AuditLevel::Membership, "unexpected_key_change", &format!(
+                        "leaf={} before={:?} after={:?}",
+                        change.index,
+                        change.before,
+                        change.after,
+                    ));
+                    let before = sanitize(&change.before, MAX_NAME_CHARS);
+                    let after = sanitize(&change.after, MAX_NAME_CHARS);
+                    let who = if before == after { before } else { format!("{} (now claiming to be {})", before, after) };
+                    self.log_warning(&format!(
+                        "The keys of {} were replaced by another member's commit, not their own. Someone may be \
+                        impersonating them; check with /verify-group.",
+                        who,
+                    ));
+                }
+            }
+            Processed::Refused { committer, reason } => {
+                self.audit(AuditLevel::Membership, "refused_commit", &format!("committer={:?} reason={:?}", committer, reason));
+                self.log_warning(&format!(
+                    "Refused a commit from {}, who {}. Members who accepted it can no longer be read until the group \
+                    is rejoined.",
+                    sanitize(&committer, MAX_NAME_CHARS),
+                    sanitize(&reason, MAX_TEXT_CHARS),
+                ))
+            }
+            Processed::ReInit(identity) => {
+                self.audit(AuditLevel::Commits, "reinit_proposal", &format!("sender={:?}", identity));
+                self.log_system(&format!(
+                    "{} asked to re-initialize the group (e.g. to move it to a new ciphersuite) in a way this client \
+                    can't follow; a moderator can move it with /reinit instead. Messages keep flowing in the current \
+                    group.",
+                    sanitize(&identity, MAX_NAME_CHARS),
+                ))
+            }
+            Processed::Other => (),
+        }
+        Ok(())
+    }
+
+    /// Helper function for `Controller::handle_messages()`. Decodes the `Payload` of a decrypted application message
+    /// and updates the log accordingly. A message that can't be decoded is shown as a notice rather than treated as an
+    /// error, since it only means one member sent something this client doesn't understand.
+    ///
+    /// Everything the sender controls, including the identity in their credential, is sanitized before it's shown.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::share_history()`.
+    async fn handle_received(&mut self, mut msg: Received) -> Result<(), ApplicationError> {
+        msg.identity = sanitize(&msg.identity, MAX_NAME_CHARS);
+        let Ok(payload) = Payload::decode(&msg.content).map(Payload::sanitized) else {
+            self.window.show_notice(&format!("Received a message from {} that couldn't be read.", self.display_name(&msg)));
+            return Ok(());
+        };
+
+        match payload {
+            Payload::Chat { id, seq, time, text } => {
+                let sender = self.display_name(&msg);
+                match self.sequences.record(msg.sender, seq) {
+                    0 => (),
+                    1 => self.log_system(&format!("1 message from {} may have been lost.", sender)),
+                    missed => self.log_system(&format!("{} messages from {} may have been lost.", missed, sender)),
+                }
+                if mentions(&text, self.user.get_id()) {
+                    self.notify_mention(&sender, &text);
+                }
+                self.log.push_chat(id, time, sender, Some(msg.sender), self.user.get_id(), text);
+                self.push_last();
+            }
+            Payload::Nick { name } if self.name_taken(&name, Some(msg.sender)) => {
+                self.audit(AuditLevel::Membership, "nick_refused", &format!("leaf={} name={:?}", msg.sender, name));
+                self.log_warning(&format!(
+                    "{} tried to take the name {}, which someone else in the group goes by. Kept their old name.",
+                    self.display_name(&msg),
+                    name,
+                ));
+            }
+            Payload::Nick { name } => {
+                let before = self.display_name(&msg);
+                self.nicknames.insert(msg.sender, name);
+                self.log_system(&format!("{} is now known as {}.", before, self.display_name(&msg)));
+            }
+            Payload::Reaction { target, emoji } => {
+                // reactions to messages that have aged out of the log are dropped silently
+                self.log.react(target, emoji, self.display_name(&msg));
+            }
+            Payload::Edit { target, text } => {
+                // as are edits and deletions, along with any that don't come from the message's author
+                self.log.edit(target, Some(msg.sender), text);
+            }
+            Payload::Delete { target } => {
+                self.log.delete(target, Some(msg.sender));
+            }
+            Payload::Cover { .. } => (),
+            Payload::ReInit { group_id, ciphersuite } => self.answer_reinit(&msg, group_id, ciphersuite).await?,
+            Payload::Successor { group_id, key_package } => self.collect_successor(&msg, group_id, key_package),
+            Payload::Switch { group_id, welcome, ratchet_tree } => {
+                self.follow_switch(&msg, group_id, welcome, ratchet_tree).await?
+            }
+            Payload::HistoryRequest => match self.history.share {
+                HistoryShare::Never => (),
+                HistoryShare::Always => self.share_history(vec![msg.sender]).await?,
+                HistoryShare::Ask => {
+                    if !self.history_requests.contains(&msg.sender) {
+                        self.history_requests.push(msg.sender);
+                    }
+                    self.log_system(&format!(
+                        "{} asked for the recent chat history. Type /share-history to send them the last {} messages.",
+                        self.display_name(&msg),
+                        self.history.share_window,
+                    ));
+                }
+            }
+            Payload::History { to, entries } => {
+                // every member who's willing may answer, but only the first answer is used
+                if !self.awaiting_history || Some(to) != self.user.own_index() {
+                    return Ok(());
+                }
+                self.awaiting_history = false;
+                if entries.is_empty() {
+                    return Ok(());
+                }
+
+                let sharer = self.display_name(&msg);
+                self.log_system(&format!("{} shared the last {} messages:", sharer, entries.len()));
+                for entry in entries {
+                    // the entry's author is only what the sharing member claims, so it's never used
+                    let sender = format!("{} (shared by {})", entry.sender, sharer);
+                    self.log.push_shared(entry.id, entry.time, sender, msg.sender, self.user.get_id(), entry.text);
+                    self.push_last();
+                }
+            }
+            Payload::Topic { text } => {
+                self.log_system(&format!("{} set the topic to \"{}\".", self.display_name(&msg), text));
+                self.window.set_topic(Some(text));
+            }
+            Payload::Presence { connection, away, moderation } => {
+                if moderation.is_some_and(|digest| self.user.note_moderation(msg.sender, digest)) {
+                    self.audit(AuditLevel::Membership, "moderation_mismatch", &format!("leaf={}", msg.sender));
+                    self.log_warning(&format!(
+                        "{}'s moderation settings differ from yours, so their commits adding or removing members will \
+                        be refused. Check that you both have the same moderators and policy in your configs.",
+                        self.display_name(&msg),
+                    ));
+                }
+                if let Some(connection) = connection {
+                    self.connections.retain(|_, leaf| *leaf != msg.sender);
+                    self.connections.insert(connection, msg.sender);
+                }
+                let presence = away.map_or(Presence::Online, Presence::Away);
+                // only changes of a member who was already around are worth a line in the log
+                let previous = self.presence.insert(msg.sender, presence.clone());
+                if !matches!(previous, Some(Presence::Offline)) && previous.as_ref() != Some(&presence) {
+                    match presence {
+                        Presence::Away(text) if text.is_empty() => {
+                            self.log_system(&format!("{} is away.", self.display_name(&msg)));
+                        }
+                        Presence::Away(text) => {
+                            self.log_system(&format!("{} is away: {}", self.display_name(&msg), text));
+                        }
+                        Presence::Online if previous.is_some() => {
+                            self.log_system(&format!("{} is back.", self.display_name(&msg)));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Helper function for `Controller::run()`. Parses a line of the user's input as a `Command` and carries it out.
+    /// Invalid commands are reported in the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_chat_msg()` or `Controller::change_nick()`.
+    async fn handle_input(&mut self, line: String) -> Result<(), ApplicationError> {
+        match Command::parse(&line) {
+            Ok(Command::Chat(text)) => self.send_chat_msg(text).await,
+            Ok(Command::Nick(name)) => self.change_nick(name).await,
+            Ok(Command::React { number, emoji }) => self.react(number, emoji).await,
+            Ok(Command::Topic(text)) => {
+                self.log_system(&format!("You set the topic to \"{}\".", text));
+                self.window.set_topic(Some(text.clone()));
+                self.send_payload(&Payload::Topic { text }).await
+            }
+            Ok(Command::Edit { number, text }) => self.edit(number, text).await,
+            Ok(Command::Delete(number)) => self.delete(number).await,
+            Ok(Command::ShareHistory) => {
+                if self.history_requests.is_empty() {
+                    self.log_system("Nobody has asked for the chat history.");
+                    return Ok(());
+                }
+                let requests = std::mem::take(&mut self.history_requests);
+                self.share_history(requests).await
+            }
+            Ok(Command::VerifyGroup) => {
+                match (self.user.epoch(), self.user.verification_code()) {
+                    (Some(epoch), Some(code)) => self.log_system(&format!(
+                        "Verification code for epoch {}: {}. Compare it with the other members over a channel you \
+                        trust; if everyone reads out the same code, you're all in the same group. It changes whenever \
+                        someone joins, leaves, or updates their keys.",
+                        epoch,
+                        code,
+                    )),
+                    _ => self.log_system("You aren't in a group yet."),
+                }
+                Ok(())
+            }
+            Ok(Command::Debug) => {
+                match (self.user.group_id(), self.user.epoch(), self.user.own_index()) {
+                    (Some(group_id), Some(epoch), Some(leaf)) => self.log_system(&format!(
+                        "Group {}, epoch {}, leaf {}, {} members. Pending commit: {}. {} sent message(s) awaiting the \
+                        server, {} outgoing frame(s) queued.",
+                        hex(group_id.as_slice()),
+                        epoch,
+                        leaf,
+                        self.user.roster().len(),
+                        if self.user.has_pending_commit() { "yes" } else { "no" },
+                        self.in_flight.len(),
+                        self.network.pending(),
+                    )),
+                    _ => self.log_system("You aren't in a group yet."),
+                }
+                Ok(())
+            }
+            Ok(Command::Wipe { confirmed: false }) => {
+                let files: Vec<String> = self.wipe_targets.iter()
+                    .filter(|path| path.exists())
+                    .map(|path| path.display().to_string())
+                    .collect();
+                self.log_warning(&format!(
+                    "/wipe confirm overwrites and deletes {}, forgets your keys and the chat history, and quits. It \
+                    can't be undone.",
+                    if files.is_empty() { String::from("no local files (none are saved)") } else { files.join(", ") },
+                ));
+                Ok(())
+            }
+            Ok(Command::Wipe { confirmed: true }) => {
+                self.wipe();
+                Ok(())
+            }
+            Ok(Command::Export(path)) => {
+                match transcript::export(self.log.iter(), Format::from_path(&path), &path, false) {
+                    Ok(()) => self.log_system(&format!(
+                        "Exported {} lines of the chat to {}. It's unencrypted, so keep it somewhere safe.",
+                        self.log.len(),
+                        path.display(),
+                    )),
+                    Err(ApplicationError::Storage(StorageError::Export(msg))) => self.log_system(&format!("Couldn't export the chat: {}", msg)),
+                    Err(e) => return Err(e),
+                }
+                Ok(())
+            }
+            Ok(Command::DumpState { path, redact }) => {
+                let Some(mut snapshot) = self.user.snapshot()? else {
+                    self.log_system("You aren't in a group yet.");
+                    return Ok(());
+                };
+                if redact {
+                    snapshot.redact();
+                }
+                match snapshot.write(&path) {
+                    Ok(()) => self.log_system(&format!(
+                        "Wrote the group's state at epoch {} to {}.{}",
+                        snapshot.epoch,
+                        path.display(),
+                        if redact { "" } else { " It names every member, so check it before sharing it." },
+                    )),
+                    Err(ApplicationError::Storage(StorageError::Export(msg))) => self.log_system(&format!("Couldn't dump the group's state: {}", msg)),
+                    Err(e) => return Err(e),
+                }
+                Ok(())
+            }
+            Ok(Command::Away(text)) => {
+                let (away, note) = match (text, &self.away) {
+                    (Some(text), _) => (Some(text), "You're marked as away."),
+                    (None, Some(_)) => (None, "You're marked as back."),
+                    (None, None) => (Some(String::new()), "You're marked as away."),
+                };
+                self.away = away;
+                self.log_system(note);
+                self.announce_presence().await
+            }
+            Ok(Command::ReInit(ciphersuite)) => self.start_reinit(ciphersuite).await,
+            Ok(Command::Link) => {
+                if !self.user.is_moderator() {
+                    self.log_system("Only moderators can add members to this group, so you can't link a device to it.");
+                    return Ok(());
+                }
+                let code = LinkCode::generate(&self.user.profile().user_id);
+                self.log_system(&format!(
+                    "Link code: {}. On your new device, run `mls_chat join --link {}` with the same id within {} \
+                    minutes. Anyone with the code can join as you, so keep it to yourself.",
+                    code,
+                    code,
+                    LINK_LIFETIME.as_secs() / 60,
+                ));
+                self.link = Some((code, self.clock.now()));
+                Ok(())
+            }
+            Ok(Command::Tree) => {
+                let Some(epoch) = self.user.epoch() else {
+                    self.log_system("You aren't in a group yet.");
+                    return Ok(());
+                };
+                let tree = self.user.tree();
+                self.log_system(&format!("Ratchet tree at epoch {}, {} nodes:", epoch, tree.len()));
+                for (position, node) in tree.into_iter().enumerate() {
+                    let node = match node {
+                        TreeNode::Blank if position % 2 == 0 => format!("leaf {}: blank", position / 2),
+                        TreeNode::Blank => String::from("parent: blank"),
+                        TreeNode::Leaf { index, identity, own } => format!(
+                            "leaf {}: {}{}",
+                            index,
+                            sanitize(&identity, MAX_NAME_CHARS),
+                            if own { " (you)" } else { "" },
+                        ),
+                        TreeNode::Parent { unmerged } if unmerged.is_empty() => String::from("parent"),
+                        TreeNode::Parent { unmerged } => format!("parent, unmerged leaves {:?}", unmerged),
+                    };
+                    // a node's level above the leaves is the number of trailing 1 bits in its position
+                    self.log_system(&format!("node {} (level {}): {}", position, position.trailing_ones(), node));
+                }
+                Ok(())
+            }
+            Ok(Command::Stats) => {
+                let stats = self.metrics.stats();
+                self.log_system("Stats for this session:");
+                self.log_system(&format!("messages: {} sent, {} received", stats.messages_sent, stats.messages_received));
+                self.log_system(&format!(
+                    "over the wire: {} sent, {} received",
+                    format_bytes(stats.bytes_sent),
+                    format_bytes(stats.bytes_received),
+                ));
+                self.log_system(&match self.user.epoch() {
+                    Some(epoch) => format!("epoch: {}, after {} commits merged", epoch, stats.commits),
+                    None => format!("epoch: not in a group, after {} commits merged", stats.commits),
+                });
+                self.log_system(&format!("reconnects: {}", stats.reconnects));
+                self.log_system(&match stats.average_latency {
+                    Some(latency) => format!("average send latency: {} ms", latency.as_millis()),
+                    None => String::from("average send latency: nothing sent yet"),
+                });
+                self.log_system(&match stats.round_trip {
+                    Some(round_trip) => format!("round trip to the server: {} ms", round_trip.as_millis()),
+                    None => String::from("round trip to the server: not measured yet"),
+                });
+                self.log_system(&match (stats.upload_rate, stats.download_rate) {
+                    (Some(up), Some(down)) => format!(
+                        "throughput over the last few seconds: {}/s sent, {}/s received",
+                        format_bytes(up),
+                        format_bytes(down),
+                    ),
+                    _ => String::from("throughput: not measured yet"),
+                });
+                Ok(())
+            }
+            Ok(Command::Create { .. }) if !Feature::Rooms.available(self.network.version()) => {
+                self.log_system("The server speaks an older version of the protocol, without rooms to create.");
+                Ok(())
+            }
+            Ok(Command::Create { room, invite_only }) => {
+                self.network.send_frame(Frame::CreateRoom { room, invite_only }).await;
+                Ok(())
+            }
+            Ok(Command::Retry) => {
+                self.retry_failed().await;
+                Ok(())
+            }
+            Ok(Command::Keys) => {
+                self.window.show_keys();
+                Ok(())
+            }
+            Ok(Command::CopyLast) => {
+                let last = self.log.iter().enumerate().rev()
+                    .find(|(_, entry)| matches!(entry.kind, EntryKind::Chat { author: Some(_), .. }))
+                    .map(|(i, _)| self.log.total() - self.log.len() + i);
+                match last {
+                    Some(index) => self.copy_entry(index),
+                    None => self.log_system("There's no message from another member to copy."),
+                }
+                Ok(())
+            }
+            Err(msg) => {
+                self.log_system(&msg);
+                Ok(())
+            }
+        }
+    }
+
+    /// Helper function for `Controller::handle_input()`. Timestamps the user's chat message, adds it to the log, and
+    /// sends it to the group with `Controller::send_chat_payload()`, which marks it as failed rather than returning an
+    /// error if it can't be sent. Updates the user's key material afterwards if the configured key rotation interval
+    /// has passed since the last update.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns any `ApplicationError` types returned from `Controller::rotate_keys()`.
+    async fn send_chat_msg(&mut self, text: String) -> Result<(), ApplicationError> {
+        let id = rand::random();
+        let time = self.clock.utc().timestamp();
+        self.log.push_chat(id, time, self.user.get_id().clone(), None, self.user.get_id(), text.clone());
+        if !self.online() {
+            self.log.set_pending(id, true);
+        }
+        self.push_last();
+
+        self.next_seq += 1;
+        self.send_chat_payload(id, Payload::Chat { id, seq: self.next_seq, time, text }).await;
+
+        // a commit made offline could lose out to one made meanwhile by someone who isn't
+        if self.online() && self.clock.since(self.last_key_rotation) >= self.key_rotation_interval {
+            self.rotate_keys().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Helper function for `Controller::handle_input()`. Changes the user's ID and announces the new name to the group,
+    /// unless another member already goes by it. See `User::set_id()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn change_nick(&mut self, name: String) -> Result<(), ApplicationError> {
+        if self.name_taken(&name, None) {
+            self.log_system(&format!("Someone else in the group already goes by {}.", name));
+            return Ok(());
+        }
+        self.log_system(&format!("You are now known as {}.", name));
+        self.user.set_id(name.clone());
+
+        self.send_payload(&Payload::Nick { name }).await
+    }
+
+    /// Helper function for `Controller::handle_input()`. Adds the user's reaction to the message with the given local
+    /// number and sends it to the group. Unknown message numbers are reported in the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn react(&mut self, number: usize, emoji: String) -> Result<(), ApplicationError> {
+        let Some(target) = self.log.id_of(number) else {
+            self.log_system(&format!("There's no message #{} in the log.", number));
+            return Ok(());
+        };
+
+        self.log.react(target, emoji.clone(), self.user.get_id().clone());
+        self.send_payload(&Payload::Reaction { target, emoji }).await
+    }
+
+    /// Helper function for `Controller::handle_input()`. Replaces the text of the user's own message with the given
+    /// local number and sends the edit to the group. Unknown numbers and other members' messages are reported in the
+    /// log.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn edit(&mut self, number: usize, text: String) -> Result<(), ApplicationError> {
+        let Some(target) = self.log.id_of(number) else {
+            self.log_system(&format!("There's no message #{} in the log.", number));
+            return Ok(());
+        };
+        if !self.log.edit(target, None, text.clone()) {
+            self.log_system(&format!("Message #{} isn't one of yours.", number));
+            return Ok(());
+        }
+
+        self.send_payload(&Payload::Edit { target, text }).await
+    }
+
+    /// Helper function for `Controller::handle_input()`. Deletes the user's own message with the given local number
+    /// and sends the deletion to the group. Unknown numbers and other members' messages are reported in the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn delete(&mut self, number: usize) -> Result<(), ApplicationError> {
+        let Some(target) = self.log.id_of(number) else {
+            self.log_system(&format!("There's no message #{} in the log.", number));
+            return Ok(());
+        };
+        if !self.log.delete(target, None) {
+            self.log_system(&format!("Message #{} isn't one of yours.", number));
+            return Ok(());
+        }
+
+        self.send_payload(&Payload::Delete { target }).await
+    }
+
+    /// Sends the most recent chat messages, up to the configured history window, to each of the members at the given
+    /// leaf indices. Deleted messages are left out, and edited ones are sent as they read now.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn share_history(&mut self, members: Vec<u32>) -> Result<(), ApplicationError> {
+        let Some(own_index) = self.user.own_index() else { return Ok(()) };
+        let mut entries: Vec<SharedEntry> = self.log.iter().rev()
+            .filter(|entry| !entry.deleted)
+            .filter_map(|entry| match &entry.kind {
+                EntryKind::Chat { id, sender, author, .. } => Some(SharedEntry {
+                    id: *id,
+                    author: author.unwrap_or(own_index),
+                    sender: sender.clone(),
+                    time: entry.time,
+                    text: entry.text.clone(),
+                }),
+                EntryKind::System | EntryKind::Warning => None,
+            })
+            .take(self.history.share_window)
+            .collect();
+        entries.reverse();
+
+        for to in members.into_iter().filter(|&to| to != own_index) {
+            self.send_payload(&Payload::History { to, entries: entries.clone() }).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a cover message with a random amount of filler, and schedules the next one.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn send_cover(&mut self) -> Result<(), ApplicationError> {
+        let mut rng = rand::thread_rng();
+        let length = rng.gen_range(0..=200);
+        let filler = (&mut rng).sample_iter(Alphanumeric).take(length).map(char::from).collect();
+        self.next_cover = Some(self.clock.now() + cover_delay(self.privacy.cover_interval));
+        // held back until the client is back online, it would stand out instead of covering anything
+        if !self.online() {
+            return Ok(());
+        }
+
+        self.send_payload(&Payload::Cover { filler }).await
+    }
+
+    /// Starts moving the group to a successor with the ciphersuite `ciphersuite` (see `User::reinit()`), asking the
+    /// other members for key packages for it with a `Payload::ReInit`. `Controller::advance_reinit()` welcomes them
+    /// into it once they've answered.
+    ///
+    /// This isn't MLS's own re-initialization: openmls 0.5 can't propose a ReInit, and keeps the contents of one from
+    /// another client private, so the successor is created and announced by the application instead (see
+    /// `Processed::ReInit`).
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `User::reinit()`, besides an
+    /// `MlsError::UnsupportedCiphersuite`, or `Controller::send_payload()`.
+    async fn start_reinit(&mut self, ciphersuite: u16) -> Result<(), ApplicationError> {
+        if !self.user.is_moderator() {
+            self.log_system("Only moderators can move the group to another ciphersuite.");
+            return Ok(());
+        }
+        if self.reinit.is_some() {
+            self.log_system("The group is already being moved to another ciphersuite.");
+            return Ok(());
+        }
+        let waiting: Vec<u32> = self.user.roster().iter().filter(|member| !member.own).map(|member| member.index).collect();
+        if waiting.is_empty() {
+            self.log_system("There's nobody else in the group to move.");
+            return Ok(());
+        }
+
+        let group_id = match self.user.reinit(ciphersuite) {
+            Ok(group_id) => group_id,
+            Err(ApplicationError::Mls(e @ MlsError::UnsupportedCiphersuite(_))) => {
+                self.log_system(&format!(
+                    "Couldn't move the group: {}. It has to be one this client supports, other than the current one, \
+                    that signs with the same kind of key.",
+                    e,
+                ));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.reinit = Some(Migration { ciphersuite, waiting, key_packages: Vec::new(), started: self.clock.now() });
+        self.audit(AuditLevel::Membership, "reinit_started", &format!("ciphersuite={}", ciphersuite));
+        self.log_system(&format!(
+            "Moving the group to ciphersuite {}. Waiting up to {} seconds for the other members to answer...",
+            ciphersuite,
+            REINIT_TIMEOUT.as_secs(),
+        ));
+        self.send_payload(&Payload::ReInit { group_id, ciphersuite }).await
+    }
+
+    /// Answers a `Payload::ReInit` from the member who sent `msg` with a key package for the successor `group_id`, so
+    /// that they add the user to it, if they're a moderator and this client supports the successor's ciphersuite.
+    /// See `Controller::follow_switch()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `User::successor_key_package()`, besides an
+    /// `MlsError::UnsupportedCiphersuite`, or `Controller::send_payload()`.
+    async fn answer_reinit(&mut self, msg: &Received, group_id: Vec<u8>, ciphersuite: u16) -> Result<(), ApplicationError> {
+        let sender = self.display_name(msg);
+        let identity = self.user.roster().into_iter().find(|member| member.index == msg.sender).map(|member| member.identity);
+        if !identity.is_some_and(|identity| self.moderation.allows(&identity)) {
+            self.audit(AuditLevel::Membership, "unmoderated_reinit", &format!("leaf={}", msg.sender));
+            self.log_warning(&format!("{} tried to move the group to another ciphersuite without being a moderator.", sender));
+            return Ok(());
+        }
+
+        let key_package = match self.user.successor_key_package(ciphersuite) {
+            Ok(key_package) => key_package,
+            Err(ApplicationError::Mls(e @ MlsError::UnsupportedCiphersuite(_))) => {
+                self.log_warning(&format!("{} is moving the group, but {}, so you'll be left behind in this one.", sender, e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let key_package = key_package.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+        self.following = Some((msg.sender, group_id.clone()));
+        self.audit(AuditLevel::Membership, "reinit_answered", &format!("leaf={} ciphersuite={}", msg.sender, ciphersuite));
+        self.log_system(&format!("{} is moving the group to ciphersuite {}.", sender, ciphersuite));
+        self.send_payload(&Payload::Successor { group_id, key_package }).await
+    }
+
+    /// Keeps the key package the member who sent `msg` answered the user's `/reinit` with, if the move is waiting on
+    /// them and it's for the successor's ciphersuite, signed with the same key as their leaf in the current group.
+    fn collect_successor(&mut self, msg: &Received, group_id: Vec<u8>, key_package: Vec<u8>) {
+        let Some(reinit) = &self.reinit else { return };
+        if self.user.successor_id() != Some(group_id) || !reinit.waiting.contains(&msg.sender) {
+            return;
+        }
+        let ciphersuite = Ciphersuite::try_from(reinit.ciphersuite).ok();
+        let signature_key = self.user.roster().into_iter()
+            .find(|member| member.index == msg.sender)
+            .map(|member| member.signature_key);
+
+        let key_package = KeyPackageIn::tls_deserialize(&mut key_package.as_slice()).ok().filter(|kp| {
+            group::key_package_ciphersuite(kp) == ciphersuite
+                && group::key_package_signature_key(kp).is_some_and(|key| Some(key) == signature_key)
+        });
+        let Some(key_package) = key_package else {
+            self.audit(AuditLevel::Membership, "reinit_key_package_refused", &format!("leaf={}", msg.sender));
+            self.log_warning(&format!(
+                "{} answered the move with a key package that isn't theirs or is for the wrong ciphersuite, so \
+                they'll be left behind.",
+                self.display_name(msg),
+            ));
+            return;
+        };
+        if let Some(reinit) = &mut self.reinit {
+            reinit.waiting.retain(|&leaf| leaf != msg.sender);
+            reinit.key_packages.push(key_package);
+        }
+    }
+
+    /// Moves the group to the successor from the user's `/reinit` once every other member has answered, or
+    /// `REINIT_TIMEOUT` has passed, adding whoever answered and sending them the Welcome in a `Payload::Switch`. That
+    /// has to go out in the group being left, so it waits until the user is online with no commit of their own
+    /// pending. If nobody answered, or the successor can't be built, the move is called off and the group stays as it
+    /// is.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `User::welcome_to_successor()`, besides an `MlsError`,
+    /// `User::switch_to_successor()`, `Controller::send_payload()`, or `Controller::moved()`.
+    async fn advance_reinit(&mut self) -> Result<(), ApplicationError> {
+        let Some(reinit) = &self.reinit else { return Ok(()) };
+        if !reinit.waiting.is_empty() && self.clock.since(reinit.started) < REINIT_TIMEOUT {
+            return Ok(());
+        }
+        if !self.online() || self.user.has_pending_commit() {
+            return Ok(());
+        }
+        let Some(Migration { ciphersuite, key_packages, .. }) = self.reinit.take() else { return Ok(()) };
+        let Some(group_id) = self.user.successor_id() else { return Ok(()) };
+        if key_packages.is_empty() {
+            self.user.cancel_reinit();
+            self.audit(AuditLevel::Membership, "reinit_cancelled", &format!("ciphersuite={}", ciphersuite));
+            self.log_system("Nobody answered in time, so the group stays as it is.");
+            return Ok(());
+        }
+
+        let (welcome, ratchet_tree) = match self.user.welcome_to_successor(key_packages) {
+            Ok(welcome) => welcome,
+            Err(ApplicationError::Mls(e)) => {
+                self.audit(AuditLevel::Membership, "reinit_cancelled", &format!("ciphersuite={} error={:?}", ciphersuite, e.to_string()));
+                self.log_warning(&format!("Couldn't move the group to ciphersuite {} ({}), so it stays as it is.", ciphersuite, e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let welcome = welcome.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()))?;
+        // checked above, so this goes out right away rather than being deferred or held in the outbox
+        self.send_payload(&Payload::Switch { group_id, welcome, ratchet_tree }).await?;
+        let before = self.user.roster();
+        self.user.switch_to_successor()?;
+        self.moved(&before).await
+    }
+
+    /// Follows the member who sent `msg` into the successor `group_id` they moved the group to, joining it with the
+    /// Welcome from their `Payload::Switch`, if it's the move the user answered. Members it doesn't welcome are left
+    /// behind in the old group.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `User::join_successor()`, besides an `MlsError`, or
+    /// `Controller::moved()`.
+    async fn follow_switch(
+        &mut self,
+        msg: &Received,
+        group_id: Vec<u8>,
+        welcome: Vec<u8>,
+        ratchet_tree: Option<Vec<u8>>,
+    ) -> Result<(), ApplicationError> {
+        if self.following.as_ref() != Some(&(msg.sender, group_id)) {
+            return Ok(());
+        }
+        self.following = None;
+        let sender = self.display_name(msg);
+        let Ok(MlsMessageInBody::Welcome(welcome)) = Envelope::decode(&welcome).map(|envelope| envelope.body) else {
+            self.log_warning(&format!("The Welcome from {} couldn't be read, so you're left behind in the old group.", sender));
+            return Ok(());
+        };
+
+        let before = self.user.roster();
+        match self.user.join_successor(welcome, ratchet_tree.as_deref()) {
+            Ok(()) => self.moved(&before).await,
+            Err(ApplicationError::Mls(e)) => {
+                self.log_warning(&format!(
+                    "Couldn't follow {} into the moved group ({}), so you're left behind in the old one.",
+                    sender,
+                    e,
+                ));
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Carries the user over into the successor that replaced their group, whether they moved it or followed whoever
+    /// did. What's kept by leaf index follows each member from their leaf in `before`, the old group's roster, to the
+    /// one with the same signature key in the successor, and anyone left behind is forgotten. The old group is read
+    /// for another `DRAIN_TIME`, for what the members still in it send before they follow.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::announce_presence()`.
+    async fn moved(&mut self, before: &[RosterEntry]) -> Result<(), ApplicationError> {
+        let after = self.user.roster();
+        let leaves: HashMap<u32, u32> = before.iter()
+            .filter_map(|old| {
+                let new = after.iter().find(|new| new.signature_key == old.signature_key)?;
+                Some((old.index, new.index))
+            })
+            .collect();
+        self.nicknames = move_leaves(mem::take(&mut self.nicknames), &leaves);
+        self.presence = move_leaves(mem::take(&mut self.presence), &leaves);
+        self.impostors = move_leaves(mem::take(&mut self.impostors), &leaves);
+        self.connections = mem::take(&mut self.connections).into_iter()
+            .filter_map(|(connection, leaf)| Some((connection, *leaves.get(&leaf)?)))
+            .collect();
+        self.log.move_authors(&leaves);
+        self.history_requests.clear();
+        self.sequences = Sequences::default();
+        self.seen_ids = SeenIds::default();
+        self.following = None;
+        self.drop_in_flight_commits();
+        self.moved = Some(self.clock.now());
+        self.session_dirty = true;
+
+        self.audit_group(AuditLevel::Membership, "reinit");
+        self.log_system(&format!(
+            "The group moved to {:?}, with {} of its {} members.",
+            self.user.ciphersuite(),
+            after.len(),
+            before.len(),
+        ));
+        self.announce_presence().await
+    }
+
+    /// Tells the group the user's presence and the connection they're on, if they're in a group with anyone else.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Controller::send_payload()`.
+    async fn announce_presence(&mut self) -> Result<(), ApplicationError> {
+        if self.user.roster().len() <= 1 {
+            return Ok(());
+        }
+        self.send_payload(&Payload::Presence {
+            connection: self.connection_id,
+            away: self.away.clone(),
+            moderation: Some(self.user.moderation_digest()),
+        }).await
+    }
+
+    /// Encrypts a `Payload` for the group and sends it, or holds it in the outbox while the client is offline.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `Payload::encode()`, `User::encrypt_message()`, or
+    /// `Controller::serialize_and_send()`.
+    async fn send_payload(&mut self, payload: &Payload) -> Result<(), ApplicationError> {
+        if !self.online() {
+            self.outbox.push(payload.clone());
+            self.session_dirty = true;
+            return Ok(());
+        }
+        if self.user.has_pending_commit() {
+            self.deferred_payloads.push(payload.clone());
+            return Ok(());
+        }
+
+        let msg = self.user.encrypt_message(&payload.encode()?)?;
+        self.serialize_and_send(msg, Sent::Message).await
+    }
+
+    /// Sends the payload of the user's chat message with the given ID. If it can't be, the message is marked as failed
+    /// in the log and kept for `Controller::retry_failed()`, rather than the error ending the session.
+    async fn send_chat_payload(&mut self, id: MessageId, payload: Payload) {
+        if let Err(e) = self.send_payload(&payload).await {
+            self.log.set_failed(id, true);
+            self.failed.push(payload);
+            self.log_warning(&format!("Couldn't send your message ({}). Use /retry to try again.", e));
+        }
+    }
+
+    /// Tries again to send the user's chat messages that failed, in the order they were written. Any that fail again
+    /// are kept for the next retry.
+    async fn retry_failed(&mut self) {
+        if self.failed.is_empty() {
+            self.log_system("There are no failed messages to retry.");
+            return;
+        }
+        for payload in mem::take(&mut self.failed) {
+            let Payload::Chat { id, .. } = payload else { continue };
+            self.log.set_failed(id, false);
+            self.log.set_pending(id, !self.online());
+            self.send_chat_payload(id, payload).await;
+        }
+    }
+
+    /// Updates the user's key material and sends the resulting commit to the group, unless one of the user's commits
+    /// is already pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` types returned from `User::update_keys()` or `Controller::serialize_and_send()`.
+    async fn rotate_keys(&mut self) -> Result<(), ApplicationError> {
+        if self.user.has_pending_commit() {
+            return Ok(());
+        }
+
+        let msg = self.user.update_keys()?;
+        self.serialize_and_send(msg, Sent::Update).await?;
+        self.last_key_rotation = self.clock.now();
+        Ok(())
+    }
+
+    /// Refreshes the window's member list from the group's roster, so that it reflects every commit merged so far.
+    /// Leaves sharing a user ID are the same user's devices, and are listed once, unless they're recorded impostors.
+    /// The user's own leaf goes by their own user ID even where the group's creator has no profile in the tree.
+    fn update_members(&mut self) {
+        let mut members: Vec<MemberLine> = vec![];
+        let mut by_user: HashMap<String, usize> = HashMap::new();
+
+        for member in self.user.roster() {
+            let user_id = if member.own {
+                Some(self.user.profile().user_id.clone())
+            } else {
+                member.profile.as_ref().map(|profile| profile.user_id.clone())
+            };
+            let presence = if member.own {
+                self.away.clone().map_or(Presence::Online, Presence::Away)
+            } else {
+                self.presence.get(&member.index).cloned().unwrap_or_default()
+            };
+            let device_of = user_id.as_ref()
+                .filter(|_| !self.impostors.contains_key(&member.index))
+                .and_then(|user_id| by_user.get(user_id).copied());
+            if let Some(line) = device_of {
+                members[line].devices += 1;
+                members[line].own |= member.own;
+                members[line].presence = mem::take(&mut members[line].presence).or(presence);
+                if member.own {
+                    members[line].name = self.user.get_id().clone();
+                }
+                continue;
+            }
+
+            if let Some(user_id) = &user_id {
+                if !self.impostors.contains_key(&member.index) {
+                    by_user.insert(user_id.clone(), members.len());
+                }
+            }
+            members.push(MemberLine {
+                name: if member.own {
+                    self.user.get_id().clone()
+                } else if self.nicknames.contains_key(&member.index) {
+                    self.member_name(member.index, &member.identity)
+                } else {
+                    let identity = sanitize(&member.identity, MAX_NAME_CHARS);
+                    match member.profile.as_ref().and_then(|profile| profile.display_name.as_deref()) {
+                        Some(name) if name != member.identity => format!("{} ({})", sanitize(name, MAX_NAME_CHARS), identity),
+                        _ => identity,
+                    }
+                },
+                user_id: member.profile.map(|profile| sanitize(&profile.user_id, MAX_NAME_CHARS)),
+                own: member.own,
+                devices: 1,
+                presence,
+            });
+        }
+        self.window.set_members(members);
+    }
+
+    /// Refreshes the window's status line from the current state of the connection and the group.
+    fn update_status(&mut self) {
+        self.metrics.sample_throughput(self.clock.now());
+        let stats = self.metrics.stats();
+        let progress = if self.network.state() == ConnectionState::Disconnected {
+            Some(Progress::Connecting)
+        } else if self.restoring.is_some() {
+            Some(Progress::Syncing)
+        } else if self.user.roster().len() <= 1 {
+            Some(Progress::AwaitingWelcome)
+        } else {
+            None
+        };
+        self.window.set_status(Status {
+            address: self.network.address().to_string(),
+            state: self.network.state(),
+            epoch: self.user.epoch(),
+            leaf: self.user.own_index(),
+            members: self.user.roster().len(),
+            pending: self.network.pending(),
+            progress,
+            round_trip: stats.round_trip,
+            upload_rate: stats.upload_rate,
+            download_rate: stats.download_rate,
+        });
+    }
+
+    /// Returns the name to display for the sender of a message. See `Controller::member_name()`.
+    fn display_name(&self, msg: &Received) -> String {
+        self.member_name(msg.sender, &msg.identity)
+    }
+
+    /// Returns the name to display for the member at leaf `leaf` whose credential holds `identity`: the name they last
+    /// chose with `/nick` followed by their identity, e.g. "bobby (bob)", or just the identity if they haven't chosen
+    /// one. The identity is always shown, since anyone can choose any name that isn't taken yet.
+    fn member_name(&self, leaf: u32, identity: &str) -> String {
+        let identity = sanitize(identity, MAX_NAME_CHARS);
+        match self.nicknames.get(&leaf) {
+            Some(name) if *name != identity => format!("{} ({})", name, identity),
+            _ => identity,
+        }
+    }
+
+    /// Returns whether `name` is the identity or nickname of a member other than the one at leaf `leaf`, or of anyone
+    /// other than the user if `leaf` is None, so that taking it with `/nick` would pass them off as someone else.
+    fn name_taken(&self, name: &str, leaf: Option<u32>) -> bool {
+        let own = self.user.own_index();
+        let other = |index: u32| if leaf.is_some() { Some(index) != leaf } else { Some(index) != own };
+        self.user.roster().iter().any(|member| other(member.index) && member.identity == name)
+            || self.nicknames.iter().any(|(&index, nickname)| other(index) && nickname == name)
+            || (leaf.is_some() && *self.user.get_id() == name)
+    }
+
+    /// Alerts the user that `sender` mentioned them, as configured: by ringing the terminal bell and/or sending a
+    /// desktop notification through `notify-send`. Failures are ignored, since a missing notification daemon shouldn't
+    /// interrupt the chat.
+    fn notify_mention(&mut self, sender: &str, text: &str) {
+        if self.notifications.bell {
+            self.window.bell();
+        }
+        if self.notifications.desktop {
+            let _ = process::Command::new("notify-send")
+                .arg(format!("{} mentioned you", sender))
+                .arg(text)
+                .stdout(process::Stdio::null())
+                .stderr(process::Stdio::null())
+                .spawn();
+        }
+    }
+
+    /// Copies the text of the log entry at `index` (as counted by `ChatLog::total()`) to the system clipboard. The
+    /// outcome is reported in the log, since a missing clipboard (e.g. over SSH) shouldn't interrupt the chat.
+    fn copy_entry(&mut self, index: usize) {
+        let Some(entry) = self.log.get(index) else {
+            self.log_system("That message is no longer in the log.");
+            return;
+        };
+        let text = entry.text.clone();
+        let description = match entry.kind {
+            EntryKind::Chat { number, .. } => format!("message #{}", number),
+            EntryKind::System => String::from("the notice"),
+            EntryKind::Warning => String::from("the warning"),
+        };
+
+        if self.clipboard.is_none() {
+            match Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(err) => return self.log_system(&format!("Couldn't open the clipboard: {}", err)),
+            }
+        }
+        let result = self.clipboard.as_mut().map(|clipboard| clipboard.set_text(text));
+
+        match result {
+            Some(Err(err)) => self.log_system(&format!("Couldn't copy to the clipboard: {}", err)),
+            _ => self.log_system(&format!("Copied {} to the clipboard.", description)),
+        }
+    }
+
+    /// Adds a message from the application itself (rather than another member) to the log.
+    fn log_system(&mut self, msg: &str) {
+        self.log.push_system(self.clock.utc().timestamp(), msg.to_string());
+        self.push_last();
+    }
+
+    /// Saves the session to the configured path, if saving it is on. If saving fails, the user is told and it's turned
+    /// off, rather than leaving an outdated session around to be resumed later.
+    fn save_session(&mut self) {
+        self.session_dirty = false;
+        let Some(path) = self.session_path.clone() else { return };
+
+        let in_flight = self.in_flight.iter().cloned().collect();
+        let saved = Session::capture(&mut self.user, self.last_seq, in_flight).and_then(|mut session| {
+            if self.save_log {
+                session.log = self.log.iter().cloned().collect();
+            }
+            session.outbox = self.outbox.clone();
+            session.save(&path)
+        });
+        if let Err(e) = saved {
+            self.session_path = None;
+            let _ = std::fs::remove_file(&path);
+            self.log_system(&format!("Couldn't save the session ({}), so it won't be resumed after a restart.", e));
+        }
+    }
+
+    /// Forgets everything the client holds about the user and the group: the key store, the chat log, and the
+    /// pending link code. Saving the session and writing to the audit log are turned off so nothing gets written back,
+    /// and the main loop ends. The files themselves are deleted by `Controller::finish_wipe()` once the terminal is
+    /// restored, so the results can be printed.
+    fn wipe(&mut self) {
+        self.session_path = None;
+        self.session_dirty = false;
+        self.audit = AuditLog::disabled();
+        (*PROVIDER).key_store().clear();
+        self.log = ChatLog::new(1);
+        self.link = None;
+        self.wiped = true;
+    }
+
+    /// Deletes the files listed in `wipe_targets` and reports on them.
+    fn finish_wipe(&mut self) {
+        let (deleted, failed) = wipe::wipe(&self.wipe_targets);
+        for path in deleted {
+            println!("Deleted {}", path.display());
+        }
+        for (path, e) in &failed {
+            eprintln!("Couldn't delete {}: {}", path.display(), e);
+        }
+        println!("Wiped. Clear your terminal's scrollback too if it shows any of the chat.");
+    }
+
+    /// Appends an event to the audit log. See `Controller::audit_failed()`.
+    fn audit(&mut self, level: AuditLevel, event: &str, details: &str) {
+        if let Err(e) = self.audit.record(level, event, details) {
+            self.audit_failed(e);
+        }
+    }
+
+    /// Appends a merged commit to the audit log. See `Controller::audit_failed()`.
+    fn audit_commit(&mut self, summary: &CommitSummary) {
+        if let Err(e) = self.audit.record_commit(summary) {
+            self.audit_failed(e);
+        }
+    }
+
+    /// Appends an event about the user's group as a whole to the audit log, with its ID, epoch, and members.
+    fn audit_group(&mut self, level: AuditLevel, event: &str) {
+        let members: Vec<String> = self.user.roster().into_iter().map(|member| member.identity).collect();
+        let details = format!(
+            "identity={:?} group={} epoch={} members={:?}",
+            self.user.get_id(),
+            self.user.group_id().map(|id| hex(id.as_slice())).unwrap_or_default(),
+            self.user.epoch().unwrap_or_default(),
+            members,
+        );
+        self.audit(level, event, &details);
+    }
+
+    /// Turns the audit log off after a failed write and warns the user, since a log with gaps in it can't be trusted
+    /// to reconstruct anything.
+    fn audit_failed(&mut self, e: io::Error) {
+        self.audit = AuditLog::disabled();
+        self.log_warning(&format!("Couldn't write to the audit log ({}), so nothing more will be recorded.", e));
+    }
+
+    /// Adds a warning about the security of the group to the log.
+    fn log_warning(&mut self, msg: &str) {
+        self.log.push_warning(self.clock.utc().timestamp(), msg.to_string());
+        self.push_last();
+    }
+
+    /// Passes the entry just added to the log on to the view.
+    fn push_last(&mut self) {
+        if let Some(entry) = self.log.last() {
+            self.window.push_message(entry);
+        }
+    }
+
+    /// Helper function to remove repetition of the message serialize and send operations. `sent` records what the
+    /// message is until the server sequences it.
+    ///
+    /// The session is saved before the message goes out, since creating it used up key material: a session resumed
+    /// from before it would encrypt again with the same keys.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::TlsSerialize` if `tls_serialize_detached()` fails.
+    async fn serialize_and_send<T>(&mut self, msg: T, sent: Sent) -> Result<(), ApplicationError> where T: TlsSerializeTrait  {
+        match msg.tls_serialize_detached() {
+            Ok(data) => {
+                self.send_in_flight(InFlight { sent, data, welcome: None, key_package: None }).await;
+                Ok(())
+            }
+            Err(e) => Err(MlsError::TlsSerialize(e.to_string()).into()),
+        }
+    }
+
+    /// Sends an MLS message that's already serialized, keeping track of it until the server sequences it. The session
+    /// is saved first, as in `Controller::serialize_and_send()`.
+    async fn send_in_flight(&mut self, sent: InFlight) {
+        let msg = sent.data.clone();
+        self.in_flight.push_back(sent);
+        self.sent_at.push_back(self.clock.now());
+        self.sent_ids.record(&msg);
+        self.save_session();
+        self.metrics.message_sent();
+        self.trace.outbound(&msg, self.user.own_index());
+        self.network.send(msg).await;
+    }
+}
+
+/// Rekeys state kept by leaf index from the leaves of a group to those of its successor, as `leaves` maps them,
+/// dropping whatever belonged to members who didn't move. See `Controller::moved()`.
+fn move_leaves<V>(by_leaf: HashMap<u32, V>, leaves: &HashMap<u32, u32>) -> HashMap<u32, V> {
+    by_leaf.into_iter().filter_map(|(leaf, value)| Some((*leaves.get(&leaf)?, value))).collect()
+}
+
+/// Formats bytes as lowercase hex, e.g. for showing a group ID.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Picks a random time until the next cover message, between half and one and a half times `interval` seconds.
+fn cover_delay(interval: u64) -> Duration {
+    let interval = Duration::from_secs(interval.max(1));
+    rand::thread_rng().gen_range(interval / 2..=interval * 3 / 2)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat_log::LogEntry, clock::ManualClock, link::unhex};
+    use chrono::DateTime;
+    use std::sync::{Arc, Mutex};
+    use tokio::{net::{TcpListener, TcpStream}, sync::mpsc::UnboundedReceiver};
+
+    /// A `ChatView` that plays back scripted input and records the text of every entry and notice pushed to it.
+    struct ScriptedView {
+        /// played back from the end
+        input: Arc<Mutex<Vec<UserInput>>>,
+        pushed: Arc<Mutex<Vec<String>>>,
+        notices: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ChatView for ScriptedView {
+        fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+
+        fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+            Ok(self.input.lock().unwrap().pop())
+        }
+
+        fn push_message(&mut self, entry: &LogEntry) {
+            self.pushed.lock().unwrap().push(entry.text.clone());
+        }
+
+        fn show_notice(&mut self, notice: &str) {
+            self.notices.lock().unwrap().push(notice.to_string());
+        }
+
+        fn shutdown(&mut self) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_a_scripted_view() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddr::from(listener.local_addr().unwrap());
+        let pushed = Arc::new(Mutex::new(Vec::new()));
+        let view = ScriptedView {
+            input: Arc::new(Mutex::new(vec![
+                UserInput::Quit,
+                UserInput::Line(String::from("hello")),
+                UserInput::Line(String::from("/topic tests")),
+                UserInput::Line(String::from("/bogus")),
+            ])),
+            pushed: Arc::clone(&pushed),
+            notices: Arc::default(),
+        };
+
+        let server = tokio::spawn(async move { accept(&listener).await });
+        let mut controller = Controller::with_view(address, String::from("alice"), ClientConfig::default(), Box::new(view))
+            .await
+            .unwrap();
+        let _connection = server.await.unwrap();
+        controller.run().await.unwrap();
+
+        let pushed = pushed.lock().unwrap();
+        assert!(pushed[0].starts_with("Connected to 127.0.0.1:"));
+        assert!(pushed[1].starts_with("Waiting to be added to the group."));
+        assert_eq!(pushed[2..], ["Unknown command /bogus", "You set the topic to \"tests\".", "hello", "Disconnected from the server."]);
+    }
+
+    /// A started `Controller` over `Client::in_memory()`, showing a `ScriptedView`, with handles on both.
+    struct Scripted {
+        controller: Controller,
+        /// frames the controller sends
+        outgoing: UnboundedReceiver<Frame>,
+        /// frames for the controller to receive on its next tick
+        incoming: Arc<tokio::sync::Mutex<Vec<Frame>>>,
+        input: Arc<Mutex<Vec<UserInput>>>,
+        pushed: Arc<Mutex<Vec<String>>>,
+        notices: Arc<Mutex<Vec<String>>>,
+    }
+
+    /// Builds a `Controller` for alice with `config` and `clock`, connected to nothing, and starts it.
+    async fn scripted_controller(config: ClientConfig, clock: impl Clock + 'static) -> Scripted {
+        let (client, outgoing, incoming) = Client::in_memory(String::from("nowhere"));
+        let (input, pushed, notices) = (Arc::default(), Arc::default(), Arc::default());
+        let view = ScriptedView { input: Arc::clone(&input), pushed: Arc::clone(&pushed), notices: Arc::clone(&notices) };
+        let mut controller =
+            Controller::with_client(client, String::from("alice"), config, Box::new(view), Box::new(clock)).unwrap();
+        controller.start().await.unwrap();
+        Scripted { controller, outgoing, incoming, input, pushed, notices }
+    }
+
+    /// Stands in for the server for one connection, answering its handshake and nothing else. Its offer of compression
+    /// goes unanswered, which turns it down.
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut connection, _) = listener.accept().await.unwrap();
+        Frame::Connected { id: 1, versions: wire::SUPPORTED_VERSIONS.to_vec() }.write(&mut connection).await.unwrap();
+        loop {
+            match Frame::read(&mut connection).await {
+                Ok(Some(Frame::Version { .. } | Frame::Compression { .. })) => continue,
+                Ok(Some(Frame::Register { id })) => {
+                    Frame::Registered { id }.write(&mut connection).await.unwrap();
+                    return connection;
+                }
+                frame => panic!("no Register: {:?}", frame),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn speaks_the_legacy_version_to_old_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddr::from(listener.local_addr().unwrap());
+        let pushed = Arc::new(Mutex::new(Vec::new()));
+        let view = ScriptedView {
+            input: Arc::new(Mutex::new(vec![UserInput::Quit, UserInput::Line(String::from("/create book-club"))])),
+            pushed: Arc::clone(&pushed),
+            notices: Arc::default(),
+        };
+
+        // offers no versions, and so is sent neither a `Version` nor an offer of compression it wouldn't know
+        let server = tokio::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            Frame::Connected { id: 1, versions: Vec::new() }.write(&mut connection).await.unwrap();
+            let Ok(Some(Frame::Register { id })) = Frame::read(&mut connection).await else { panic!("no Register") };
+            Frame::Registered { id }.write(&mut connection).await.unwrap();
+            while let Ok(Some(frame)) = Frame::read(&mut connection).await {
+                assert!(!matches!(frame, Frame::CreateRoom { .. }), "asked an old server to create a room");
+            }
+        });
+        let mut controller = Controller::with_view(address, String::from("alice"), ClientConfig::default(), Box::new(view))
+            .await
+            .unwrap();
+        assert_eq!(controller.network.version(), wire::LEGACY_VERSION);
+        controller.run().await.unwrap();
+        drop(controller);
+        server.await.unwrap();
+
+        let pushed = pushed.lock().unwrap();
+        assert!(pushed[1].starts_with("The server speaks an older version of the protocol (1,"), "{:?}", pushed);
+        assert!(pushed.iter().any(|line| line.ends_with("without rooms to create.")), "{:?}", pushed);
+    }
+
+    #[tokio::test]
+    async fn spools_messages_while_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddr::from(listener.local_addr().unwrap());
+        let input = Arc::new(Mutex::new(Vec::new()));
+        let view = ScriptedView { input: Arc::clone(&input), pushed: Arc::default(), notices: Arc::default() };
+
+        // drops the first connection straight away, and answers the second once the client has caught up
+        let server = tokio::spawn(async move {
+            drop(accept(&listener).await);
+            let mut connection = accept(&listener).await;
+            while !matches!(Frame::read(&mut connection).await.unwrap(), Some(Frame::Ping)) {}
+            Frame::Pong { time: 0 }.write(&mut connection).await.unwrap();
+            while !matches!(Frame::read(&mut connection).await.unwrap(), Some(Frame::Mls(_))) {}
+        });
+        let mut controller = Controller::with_view(address, String::from("alice"), ClientConfig::default(), Box::new(view))
+            .await
+            .unwrap();
+        controller.start().await.unwrap();
+        controller.network.handle_stream().await.unwrap();
+        while controller.network.state() == ConnectionState::Connected {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        input.lock().unwrap().push(UserInput::Line(String::from("hello")));
+        assert!(controller.tick().await.unwrap());
+        let pending = |controller: &Controller| controller.log.iter().find(|entry| entry.text == "hello").unwrap().pending;
+        assert!(pending(&controller));
+        assert_eq!(controller.outbox.len(), 1);
+
+        for _ in 0..500 {
+            if controller.outbox.is_empty() {
+                break;
+            }
+            assert!(controller.tick().await.unwrap());
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!pending(&controller));
+        time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn timers_follow_the_clock() {
+        let clock = ManualClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let config = ClientConfig { key_rotation_interval: 60, ..ClientConfig::default() };
+        let Scripted { mut controller, mut outgoing, input, .. } = scripted_controller(config, clock.clone()).await;
+        while outgoing.try_recv().is_ok() {}
+
+        let mut sent_mls = || {
+            let mut count = 0;
+            while let Ok(frame) = outgoing.try_recv() {
+                count += matches!(frame, Frame::Mls(_)) as usize;
+            }
+            count
+        };
+
+        // under a minute in, a message goes out on its own, stamped with the clock's time
+        clock.advance(Duration::from_secs(59));
+        input.lock().unwrap().push(UserInput::Line(String::from("hello")));
+        assert!(controller.tick().await.unwrap());
+        assert_eq!(sent_mls(), 1);
+        assert_eq!(controller.log.last().unwrap().time, 1_700_000_059);
+
+        // past the rotation interval, it's followed by an update
+        clock.advance(Duration::from_secs(2));
+        input.lock().unwrap().push(UserInput::Line(String::from("again")));
+        assert!(controller.tick().await.unwrap());
+        assert_eq!(sent_mls(), 2);
+        assert_eq!(controller.log.last().unwrap().time, 1_700_000_061);
+    }
+
+    #[tokio::test]
+    async fn skips_malformed_input_from_peers() {
+        let Scripted { mut controller, incoming, notices, .. } = scripted_controller(ClientConfig::default(), SystemClock).await;
+
+        // a key package whose signature doesn't check out
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let mut tampered = bob.generate_key_package().unwrap().tls_serialize_detached().unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+
+        incoming.lock().await.extend([
+            Frame::Relay { seq: 1, data: vec![0xff; 16] },
+            Frame::KeyPackage { owner: 2, data: vec![1, 2, 3] },
+            Frame::KeyPackage { owner: 2, data: tampered },
+        ]);
+        assert!(controller.tick().await.unwrap());
+        assert_eq!(notices.lock().unwrap().len(), 3);
+        assert_eq!(controller.user.roster().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn key_packages_cover_every_ciphersuite_a_group_could_use() {
+        let Scripted { mut controller, mut outgoing, incoming, notices, .. } =
+            scripted_controller(ClientConfig::default(), SystemClock).await;
+
+        // the last resort is for the user's own ciphersuite, and the rest take turns
+        let mut published = Vec::new();
+        while let Ok(frame) = outgoing.try_recv() {
+            if let Frame::PublishKeyPackage { data, .. } = frame {
+                let kp = KeyPackageIn::tls_deserialize(&mut data.as_slice()).unwrap();
+                published.push(group::key_package_ciphersuite(&kp).unwrap());
+            }
+        }
+        let ciphersuites = group::key_package_ciphersuites(controller.user.ciphersuite());
+        assert_eq!(published.len(), KEY_PACKAGE_POOL_SIZE as usize + 1);
+        assert_eq!(published[0], controller.user.ciphersuite());
+        assert!(published[1..].iter().zip(ciphersuites.iter().cycle()).all(|(published, expected)| published == expected));
+
+        // one for another ciphersuite than the group's is claimed again, as long as another might come up
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let other = u16::from(ciphersuites[1]);
+        let mut claimed = || {
+            let data = bob.successor_key_package(other).unwrap().tls_serialize_detached().unwrap();
+            Frame::KeyPackage { owner: 2, data }
+        };
+        incoming.lock().await.push(claimed());
+        assert!(controller.tick().await.unwrap());
+        assert!(std::iter::from_fn(|| outgoing.try_recv().ok()).any(|frame| frame == Frame::ClaimKeyPackage { owner: 2 }));
+        assert!(notices.lock().unwrap().is_empty());
+
+        incoming.lock().await.push(claimed());
+        assert!(controller.tick().await.unwrap());
+        assert!(!std::iter::from_fn(|| outgoing.try_recv().ok()).any(|frame| frame == Frame::ClaimKeyPackage { owner: 2 }));
+        assert_eq!(notices.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_unencrypted_chat_messages() {
+        // no client here will send one, so take another implementation's
+        let vectors: serde_json::Value = serde_json::from_str(include_str!("../testdata/interop/messages.json")).unwrap();
+        let data = unhex(vectors[0]["public_message_application"].as_str().unwrap()).unwrap();
+
+        for (policy, warned) in [(PlaintextPolicy::Strict, true), (PlaintextPolicy::Lenient, false)] {
+            let mut config = ClientConfig::default();
+            config.security.plaintext = policy;
+            let Scripted { mut controller, incoming, pushed, .. } = scripted_controller(config, SystemClock).await;
+
+            incoming.lock().await.push(Frame::Relay { seq: 1, data: data.clone() });
+            assert!(controller.tick().await.unwrap());
+            let pushed = pushed.lock().unwrap();
+            assert_eq!(pushed.iter().any(|text| text.contains("sent unencrypted")), warned, "{:?}", pushed);
+            assert_eq!(controller.early_messages.len(), usize::from(!warned));
+        }
+    }
+
+    #[tokio::test]
+    async fn room_owner_moderates_invite_only_rooms() {
+        let Scripted { mut controller, incoming, .. } = scripted_controller(ClientConfig::default(), SystemClock).await;
+        assert!(controller.user.is_moderator());
+
+        incoming.lock().await.push(Frame::RoomOwner { owner: String::from("carol"), invite_only: true });
+        assert!(controller.tick().await.unwrap());
+        assert_eq!(controller.moderation.policy, ModerationPolicy::Enforce);
+        assert!(controller.moderation.allows("carol"));
+        assert_eq!(controller.locked_by.as_deref(), Some("carol"));
+        assert!(!controller.user.is_moderator());
+        assert_eq!(controller.claim_delay(), None);
+    }
+
+    #[tokio::test]
+    async fn stats_measure_send_latency() {
+        let clock = ManualClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let config = ClientConfig { key_rotation_interval: 3600, ..ClientConfig::default() };
+        let Scripted { mut controller, incoming, input, .. } = scripted_controller(config, clock.clone()).await;
+        let sent = controller.metrics.stats().messages_sent;
+
+        input.lock().unwrap().push(UserInput::Line(String::from("hello")));
+        assert!(controller.tick().await.unwrap());
+        clock.advance(Duration::from_millis(50));
+        incoming.lock().await.push(Frame::Sequenced { seq: 1 });
+        assert!(controller.tick().await.unwrap());
+
+        let stats = controller.metrics.stats();
+        assert_eq!(stats.messages_sent, sent + 1);
+        assert_eq!(stats.average_latency, Some(Duration::from_millis(50)));
+    }
+
+    #[tokio::test]
+    async fn muted_messages_are_no_longer_in_flight() {
+        let config = ClientConfig { key_rotation_interval: 3600, ..ClientConfig::default() };
+        let Scripted { mut controller, incoming, input, notices, .. } = scripted_controller(config, SystemClock).await;
+
+        input.lock().unwrap().push(UserInput::Line(String::from("hello")));
+        assert!(controller.tick().await.unwrap());
+        let data = controller.in_flight.back().unwrap().data.clone();
+        incoming.lock().await.push(Frame::Muted { secs: 60, data });
+        assert!(controller.tick().await.unwrap());
+
+        assert!(controller.in_flight.is_empty());
+        assert!(controller.sent_at.is_empty());
+        assert!(notices.lock().unwrap().iter().any(|notice| notice.contains("muted you for flooding")));
+    }
+
+    #[tokio::test]
+    async fn nicknames_are_qualified_and_unique() {
+        let Scripted { mut controller, pushed, .. } = scripted_controller(ClientConfig::default(), SystemClock).await;
+        let nick = |name: &str| Received {
+            sender: 1,
+            identity: String::from("mallory"),
+            content: Payload::Nick { name: String::from(name) }.encode().unwrap(),
+        };
+
+        controller.handle_received(nick("alice")).await.unwrap();
+        assert!(controller.nicknames.is_empty());
+        assert!(pushed.lock().unwrap().iter().any(|line| line.contains("tried to take the name alice")));
+
+        controller.handle_received(nick("mal")).await.unwrap();
+        assert_eq!(controller.display_name(&nick("mal")), "mal (mallory)");
+        assert!(controller.name_taken("mal", None));
+        assert!(!controller.name_taken("mallory", Some(1)));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+use crate::network::{address::ServerAddr, frame::Frame};
+
+use chrono::Utc;
+use tokio::{io::BufReader, net::TcpStream, time::{self, Instant}};
+
+/// Id the protocol handshake registers with the server.
+const DOCTOR_ID: &str = "doctor";
+
+/// How far apart the clocks can be before the report warns that message times will be off.
+const SKEW_WARNING: Duration = Duration::from_secs(60);
+
+/// How far apart the clocks can be before the check fails, since key packages made on one side start to look not yet
+/// valid on the other.
+const SKEW_LIMIT: Duration = Duration::from_secs(3600);
+
+/// Every check `diagnose()` makes, in order.
+const CHECKS: [&str; 5] = ["DNS resolution", "TCP connection", "TLS handshake", "Protocol handshake", "Clock skew"];
+
+/// How a `Check` went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Connecting works, but something's off that may cause trouble later.
+    Warn,
+    Fail,
+    /// Not checked, because it doesn't apply or because an earlier check failed.
+    Skip,
+}
+
+/// One line of the report `mls_chat doctor` prints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.outcome {
+            Outcome::Pass => "[ ok ]",
+            Outcome::Warn => "[warn]",
+            Outcome::Fail => "[FAIL]",
+            Outcome::Skip => "[skip]",
+        };
+        write!(f, "{} {:<18} {}", label, self.name, self.detail)
+    }
+}
+
+/// Checks step by step whether a client could join the server at `address`: that its host resolves, that one of its
+/// addresses accepts a connection, that TLS isn't asked for (`tls`, which this client can't do yet), that the server
+/// answers the protocol's handshake (entering `room` first, on a rendezvous server), and that the server's clock
+/// agrees with this machine's. Each step gets `timeout`, and once one fails, the rest are skipped.
+///
+/// The handshake registers the connection as `DOCTOR_ID`, so the rest of the room sees it come and go.
+pub async fn diagnose(address: &ServerAddr, room: Option<&str>, tls: bool, timeout: Duration) -> Vec<Check> {
+    let mut report = Report::default();
+    let addresses = match address.resolve().await {
+        Ok(addresses) => {
+            let list: Vec<String> = addresses.iter().map(SocketAddr::to_string).collect();
+            report.push(Outcome::Pass, format!("{} resolved to {}", address.host, list.join(", ")));
+            addresses
+        }
+        Err(_) => {
+            report.push(Outcome::Fail, format!("couldn't resolve {}", address.host));
+            return report.finish();
+        }
+    };
+
+    let mut failures = Vec::new();
+    let mut connected = None;
+    for socket in addresses {
+        let started = Instant::now();
+        match time::timeout(timeout, TcpStream::connect(socket)).await {
+            Ok(Ok(stream)) => {
+                connected = Some((socket, stream, started.elapsed()));
+                break;
+            }
+            Ok(Err(e)) => failures.push(format!("{}: {}", socket, e)),
+            Err(_) => failures.push(format!("{}: timed out after {} s", socket, timeout.as_secs())),
+        }
+    }
+    let Some((socket, stream, elapsed)) = connected else {
+        report.push(Outcome::Fail, failures.join("; "));
+        return report.finish();
+    };
+    report.push(Outcome::Pass, format!("connected to {} in {} ms", socket, elapsed.as_millis()));
+
+    if tls {
+        report.push(Outcome::Fail, String::from("the server asks for TLS, which this client doesn't support yet"));
+        return report.finish();
+    }
+    report.push(Outcome::Skip, String::from("not enabled for this server"));
+
+    let mut stream = BufReader::new(stream);
+    match time::timeout(timeout, handshake(&mut stream, room)).await {
+        Ok(Ok(id)) => report.push(Outcome::Pass, format!("the server accepted the connection and registered it as {}", id)),
+        Ok(Err(msg)) => {
+            report.push(Outcome::Fail, msg);
+            return report.finish();
+        }
+        Err(_) => {
+            report.push(Outcome::Fail, format!("the server didn't answer the handshake{}", rendezvous_hint(room)));
+            return report.finish();
+        }
+    }
+
+    match time::timeout(timeout, skew(&mut stream)).await {
+        Ok(Ok((skew, round_trip))) => {
+            let direction = if skew < 0 { "behind" } else { "ahead of" };
+            let magnitude = Duration::from_millis(skew.unsigned_abs());
+            let detail = format!(
+                "the server's clock is {:.1} s {} this machine's (round trip {} ms)",
+                magnitude.as_secs_f64(),
+                direction,
+                round_trip.as_millis(),
+            );
+            let outcome = match magnitude {
+                magnitude if magnitude >= SKEW_LIMIT => Outcome::Fail,
+                magnitude if magnitude >= SKEW_WARNING => Outcome::Warn,
+                _ => Outcome::Pass,
+            };
+            report.push(outcome, detail);
+        }
+        Ok(Err(msg)) => report.push(Outcome::Fail, msg),
+        Err(_) => report.push(Outcome::Warn, String::from("the server didn't report its time; it may be an older version")),
+    }
+    report.finish()
+}
+
+/// The checks made so far, named in the order of `CHECKS`.
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Records how the next check went.
+    fn push(&mut self, outcome: Outcome, detail: String) {
+        let name = CHECKS[self.checks.len()];
+        self.checks.push(Check { name, outcome, detail });
+    }
+
+    /// Marks every check not yet made as skipped, and returns them all.
+    fn finish(mut self) -> Vec<Check> {
+        while self.checks.len() < CHECKS.len() {
+            self.push(Outcome::Skip, String::from("not checked, since an earlier check failed"));
+        }
+        self.checks
+    }
+}
+
+/// Helper function for `diagnose()`. Enters `room` if there is one and registers `DOCTOR_ID`, returning the id the
+/// server registered the connection under, or what went wrong.
+async fn handshake(stream: &mut BufReader<TcpStream>, room: Option<&str>) -> Result<String, String> {
+    let write_failed = |_| String::from("the connection failed while sending the handshake");
+    if let Some(room) = room {
+        Frame::Enter { room: room.to_string() }.write(stream).await.map_err(write_failed)?;
+    }
+    Frame::Register { id: DOCTOR_ID.to_string() }.write(stream).await.map_err(write_failed)?;
+
+    loop {
+        match Frame::read(stream).await {
+            Ok(Some(Frame::Registered { id })) => return Ok(id),
+            Ok(Some(Frame::ServerFull)) => return Err(String::from("the server is full")),
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("the server closed the connection without answering the handshake{}", rendezvous_hint(room))),
+            Err(_) => return Err(String::from("the server sent something that isn't part of the protocol")),
+        }
+    }
+}
+
+/// Points out that a rendezvous server won't answer until it's told the room, if it hasn't been.
+fn rendezvous_hint(room: Option<&str>) -> &'static str {
+    if room.is_none() { "; if it's a rendezvous server, pass the room code with --room" } else { "" }
+}
+
+/// Helper function for `diagnose()`. Sends a `Frame::Ping` and waits for the `Frame::Pong` answering it, returning
+/// how far the server's clock is ahead of this machine's in milliseconds (negative if it's behind), taking the server
+/// to have read its clock halfway through the round trip, along with the round trip's length.
+async fn skew(stream: &mut BufReader<TcpStream>) -> Result<(i64, Duration), String> {
+    let sent = Instant::now();
+    Frame::Ping.write(stream).await.map_err(|_| String::from("the connection failed while sending a ping"))?;
+
+    loop {
+        match Frame::read(stream).await {
+            Ok(Some(Frame::Pong { time })) => {
+                let round_trip = sent.elapsed();
+                let local = Utc::now().timestamp_millis() - (round_trip.as_millis() / 2) as i64;
+                return Ok((time as i64 - local, round_trip));
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(String::from("the server closed the connection")),
+            Err(_) => return Err(String::from("the server sent something that isn't part of the protocol")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn diagnose_a_working_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = ServerAddr::from(listener.local_addr().unwrap());
+        let mut server = Server::new(address.port, 2);
+        server.set_quiet(true);
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+
+        let report = diagnose(&address, None, false, Duration::from_secs(5)).await;
+        let outcomes: Vec<Outcome> = report.iter().map(|check| check.outcome).collect();
+        assert_eq!(outcomes, [Outcome::Pass, Outcome::Pass, Outcome::Skip, Outcome::Pass, Outcome::Pass]);
+        serving.abort();
+
+        // with nothing listening, the connection is refused and the rest are skipped
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let report = diagnose(&ServerAddr::from(closed), None, false, Duration::from_secs(5)).await;
+        assert_eq!(report[1].outcome, Outcome::Fail);
+        assert!(report[2..].iter().all(|check| check.outcome == Outcome::Skip));
+    }
+}
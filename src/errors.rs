@@ -1,30 +1,192 @@
-use openmls::{group::AddMembersError, prelude::KeyPackageVerifyError};
-use openmls_rust_crypto::MemoryKeyStore;
-
-/// A type to encapsulate error types necessary to the program, for the convenience
-/// of being able to pass ApplicationErrors between calling functions with '?' when
-/// appropriate.
-/// 
-/// # TODO
-/// 
-/// Consider using these types as containers to hold lower-level error messages.
-/// 
-/// Also, reconsider which error types are appropriate, which need consolidation, and which need
-/// to be more specific.
-#[derive(Debug)]
-pub enum ApplicationError {
-    AddMemberError(AddMembersError<<MemoryKeyStore as openmls::prelude::OpenMlsKeyStore>::Error>),
-    ConnectionFailed,
-    CryptoError,
-    GroupDNE, // if an operation is attempted on a nonexistent MlsGroup
-    InvalidMessage,
-    IOError,
-    JoinError,
-    KeyPackageDNE, // if the User has no key package
-    KeyPackageVerify(KeyPackageVerifyError),
-    KeyUpdateError,
-    MlsKeyStoreError,
-    ProcessMessageError(openmls::group::ProcessMessageError),
-    TerminalError,
-    TlsSerializeError,
-}
\ No newline at end of file
+use std::fmt;
+use openmls::{
+    group::{
+        AddMembersError, CreateMessageError, ExportSecretError, MergeCommitError, ProcessMessageError, RemoveMembersError,
+        SelfUpdateError, WelcomeError,
+    },
+    key_packages::errors::KeyPackageNewError,
+    prelude::{Ciphersuite, KeyPackageVerifyError},
+};
+use openmls_rust_crypto::MemoryKeyStoreError;
+use openmls_traits::types::CryptoError;
+
+/// The error type returned throughout the crate, for the convenience of being able to pass errors between calling
+/// functions with '?'. Each variant holds a more specific error for one part of the application, so callers can match
+/// on the category they care about (e.g. `ApplicationError::Network(_)`) and leave the rest.
+#[derive(Debug)]
+pub enum ApplicationError {
+    Network(NetworkError),
+    Mls(MlsError),
+    Ui(UiError),
+    Storage(StorageError),
+}
+
+/// Errors connecting to and talking with servers, including federated servers and the chat networks bridged to.
+#[derive(Debug)]
+pub enum NetworkError {
+    Admin(String), // if the admin socket can't be opened, or a request sent over it gets no answer
+    Bridge(String), // if the bridge can't reach the other chat network, or is refused by it
+    ConnectionFailed,
+    ConnectionRefused, // if nothing is listening at the server's address
+    ConnectTimedOut, // if the server doesn't accept the connection in time
+    CorruptFrame, // if a frame read from the network doesn't match its checksum
+    FederationLoop, // if a connection is forwarded between federated servers in a loop
+    HostNotFound, // if the server's address can't be resolved
+    InvalidFrame, // if bytes read from the network don't form a valid `Frame`
+    Io, // if reading from or writing to a socket fails
+    ListenerFailed, // if the server's task accepting connections panics
+    ServerFull, // if the server turns the connection away for already having as many as it allows
+}
+
+/// Errors from the MLS group and the key material behind it. Where openmls reports what went wrong, it's kept, so that
+/// it can be shown to the user and written to the audit log.
+#[derive(Debug)]
+pub enum MlsError {
+    AddMember(AddMembersError<MemoryKeyStoreError>),
+    CommitPending, // if a commit is created while one of the user's own is still waiting to be merged
+    CreateMessage(CreateMessageError),
+    Crypto(CryptoError),
+    ExportSecret(ExportSecretError),
+    GroupDNE, // if an operation is attempted on a nonexistent MlsGroup
+    InvalidMessage,
+    KeyPackageBuild(KeyPackageNewError<MemoryKeyStoreError>),
+    KeyPackageCiphersuite(Ciphersuite), // if a key package is for a ciphersuite other than the group's
+    KeyPackageDNE, // if the User has no key package, or a Welcome is for none of theirs
+    KeyPackageExpired, // if a key package's lifetime has run out (or not yet begun)
+    KeyPackageVerify(KeyPackageVerifyError),
+    KeyPackageVersion, // if a key package is for an MLS protocol version the client doesn't speak
+    KeyStore(String), // if the key store can't be read or written, with what went wrong
+    KeyUpdate(SelfUpdateError<MemoryKeyStoreError>),
+    MergeCommit(MergeCommitError<MemoryKeyStoreError>), // if a peer's commit was staged but can't be merged
+    ProcessMessage(ProcessMessageError),
+    RemoveMember(RemoveMembersError<MemoryKeyStoreError>),
+    TlsSerialize(String), // if a message can't be serialized, with what went wrong
+    UnknownWireFormat(u16), // if a message's header names a wire format MLS doesn't define
+    UnsupportedCiphersuite(u16), // if the group can't move to a ciphersuite, see `group::successor_ciphersuite()`
+    UnsupportedVersion(u16), // if a message's header names an MLS protocol version other than the client's
+    Welcome(WelcomeError<MemoryKeyStoreError>), // if a Welcome for one of the user's key packages can't be joined with
+}
+
+/// Errors driving the user interface.
+#[derive(Debug)]
+pub enum UiError {
+    Terminal, // if the terminal can't be set up, read from, or restored
+}
+
+/// Errors reading and writing the files the client keeps.
+#[derive(Debug)]
+pub enum StorageError {
+    Config(String), // if the config file (or the audit log it names) can't be read, parsed, or written
+    Export(String), // if a transcript of the chat log, or a dump of the group's state, can't be written
+    ServerState(String), // if the room state a server keeps across restarts can't be read or written
+    Session(String), // if the saved session can't be read or written
+}
+
+impl From<NetworkError> for ApplicationError {
+    fn from(err: NetworkError) -> Self {
+        ApplicationError::Network(err)
+    }
+}
+
+impl From<MlsError> for ApplicationError {
+    fn from(err: MlsError) -> Self {
+        ApplicationError::Mls(err)
+    }
+}
+
+impl From<UiError> for ApplicationError {
+    fn from(err: UiError) -> Self {
+        ApplicationError::Ui(err)
+    }
+}
+
+impl From<StorageError> for ApplicationError {
+    fn from(err: StorageError) -> Self {
+        ApplicationError::Storage(err)
+    }
+}
+
+impl fmt::Display for ApplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplicationError::Network(err) => err.fmt(f),
+            ApplicationError::Mls(err) => err.fmt(f),
+            ApplicationError::Ui(err) => err.fmt(f),
+            ApplicationError::Storage(err) => err.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Admin(msg) => write!(f, "admin socket error: {}", msg),
+            NetworkError::Bridge(msg) => write!(f, "bridge error: {}", msg),
+            NetworkError::ConnectionFailed => write!(f, "couldn't connect to the server"),
+            NetworkError::ConnectionRefused => write!(f, "the server refused the connection"),
+            NetworkError::ConnectTimedOut => write!(f, "the server didn't answer in time"),
+            NetworkError::CorruptFrame => write!(f, "received a frame that was corrupted on the way"),
+            NetworkError::FederationLoop => write!(f, "the connection was forwarded between servers in a loop"),
+            NetworkError::HostNotFound => write!(f, "the server's address couldn't be found"),
+            NetworkError::InvalidFrame => write!(f, "received a frame that couldn't be read"),
+            NetworkError::Io => write!(f, "reading from or writing to the network failed"),
+            NetworkError::ListenerFailed => write!(f, "the server stopped accepting connections"),
+            NetworkError::ServerFull => write!(f, "the server is full"),
+        }
+    }
+}
+
+impl fmt::Display for MlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MlsError::AddMember(err) => write!(f, "couldn't add the member: {}", err),
+            MlsError::CommitPending => write!(f, "another of your commits is still pending"),
+            MlsError::CreateMessage(err) => write!(f, "couldn't encrypt the message: {}", err),
+            MlsError::Crypto(err) => write!(f, "cryptographic error: {}", err),
+            MlsError::ExportSecret(err) => write!(f, "couldn't export a secret from the group: {}", err),
+            MlsError::GroupDNE => write!(f, "you aren't in a group"),
+            MlsError::InvalidMessage => write!(f, "the message couldn't be read"),
+            MlsError::KeyPackageBuild(err) => write!(f, "couldn't build a key package: {}", err),
+            MlsError::KeyPackageCiphersuite(ciphersuite) => {
+                write!(f, "the key package is for another ciphersuite ({:?})", ciphersuite)
+            }
+            MlsError::KeyPackageDNE => write!(f, "no matching key package"),
+            MlsError::KeyPackageExpired => write!(f, "the key package has expired"),
+            MlsError::KeyPackageVerify(err) => write!(f, "the key package doesn't verify: {}", err),
+            MlsError::KeyPackageVersion => write!(f, "the key package is for an unsupported MLS version"),
+            MlsError::KeyStore(msg) => write!(f, "key store error: {}", msg),
+            MlsError::KeyUpdate(err) => write!(f, "couldn't update your keys: {}", err),
+            MlsError::MergeCommit(err) => write!(f, "couldn't merge the commit: {}", err),
+            MlsError::ProcessMessage(err) => write!(f, "couldn't process the message: {}", err),
+            MlsError::RemoveMember(err) => write!(f, "couldn't remove the member: {}", err),
+            MlsError::TlsSerialize(msg) => write!(f, "couldn't serialize the message: {}", msg),
+            MlsError::UnknownWireFormat(format) => write!(f, "the message is of an unknown wire format ({})", format),
+            MlsError::UnsupportedCiphersuite(ciphersuite) => {
+                write!(f, "the group can't move to ciphersuite {}", ciphersuite)
+            }
+            MlsError::UnsupportedVersion(version) => {
+                write!(f, "the message is for an unsupported MLS version ({})", version)
+            }
+            MlsError::Welcome(err) => write!(f, "couldn't join with the Welcome: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for UiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UiError::Terminal => write!(f, "the terminal couldn't be used"),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Config(msg)
+            | StorageError::Export(msg)
+            | StorageError::ServerState(msg)
+            | StorageError::Session(msg) => write!(f, "{}", msg),
+        }
+    }
+}
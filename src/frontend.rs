@@ -0,0 +1,123 @@
+use std::{sync::{mpsc::{self, Receiver, SendError, Sender, TryRecvError}, Arc, Mutex}, time::Duration};
+use crate::{ApplicationError, chat_log::{ChatLog, LogEntry}, view::{MemberLine, Status}};
+
+use tokio::sync::Notify;
+
+/// Something the user did, as reported by `ChatView::poll_input()`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserInput {
+    /// A line to be parsed as a `Command`.
+    Line(String),
+    /// The user asked to copy the log entry at the given index (as counted by `ChatLog::total()`) to the clipboard.
+    Copy(usize),
+    /// The user asked to retry sending their messages that failed.
+    Retry,
+    /// The user asked to quit.
+    Quit,
+}
+
+/// A user interface the `Controller` can drive: the full-screen `ChatWindow`, the line-based `PlainView`, or a
+/// test double. Everything past the four required methods has a default that does nothing, for views with no way
+/// to show that part of the session.
+pub trait ChatView {
+    /// Shows the current state of the chat log. Called on every pass of the `Controller`'s loop.
+    fn draw(&mut self, log: &ChatLog) -> Result<(), ApplicationError>;
+
+    /// Returns input from the user that's ready, if there is any, without waiting for more. The `Controller` calls it
+    /// until it returns None each time `ChatView::input_ready()` wakes it.
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError>;
+
+    /// Called with each entry as it's added to the log, for views that show messages as they arrive rather than
+    /// redrawing the whole log.
+    fn push_message(&mut self, entry: &LogEntry);
+
+    /// Restores the terminal (or whatever else the view took over) before the application exits.
+    fn shutdown(&mut self) -> Result<(), ApplicationError>;
+
+    /// Alerts the user, e.g. when they're mentioned.
+    fn bell(&mut self) {}
+
+    fn set_topic(&mut self, _topic: Option<String>) {}
+
+    fn set_members(&mut self, _members: Vec<MemberLine>) {}
+
+    fn set_status(&mut self, _status: Status) {}
+
+    /// Lists the active key bindings.
+    fn show_keys(&mut self) {}
+
+    /// Briefly shows a problem the client recovered from, e.g. a message that couldn't be decrypted, outside the chat
+    /// log so that it doesn't clutter the history.
+    fn show_notice(&mut self, _notice: &str) {}
+
+    /// Returns the `Notify` the `Controller` waits on for input between passes of its loop, which the view notifies
+    /// whenever there's input for `ChatView::poll_input()`. The default is never notified, for views whose input is
+    /// only ever ready when the `Controller` wakes for something else, like a frame arriving.
+    fn input_ready(&self) -> Arc<Notify> {
+        Arc::new(Notify::new())
+    }
+
+    /// Returns how often the view needs drawing even when nothing's changed, e.g. to animate a spinner, or None if it
+    /// only needs drawing when something has.
+    fn redraw_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Builds a queue of lines of input handed to a view by threads of its own, e.g. reading stdin or serving a socket.
+/// Each line sent wakes the `Controller` through the queue's `Notify`.
+pub fn input_queue() -> (InputSender, InputQueue) {
+    let (tx, rx) = mpsc::channel();
+    let ready = Arc::new(Notify::new());
+    (InputSender { tx, ready: Arc::clone(&ready) }, InputQueue { rx: Arc::new(Mutex::new(rx)), ready })
+}
+
+/// The sending half of an `input_queue()`, for the threads feeding a view.
+#[derive(Clone)]
+pub struct InputSender {
+    tx: Sender<String>,
+    ready: Arc<Notify>,
+}
+
+impl InputSender {
+    /// Queues a line of input and wakes the `Controller` to take it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `SendError` if the `InputQueue` has been dropped, so there's nobody left to take the line.
+    pub fn send(&self, line: String) -> Result<(), SendError<String>> {
+        self.tx.send(line)?;
+        self.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for InputSender {
+    /// Wakes the `Controller` so it notices if this was the last sender, e.g. when stdin ends.
+    fn drop(&mut self) {
+        self.ready.notify_one();
+    }
+}
+
+/// The receiving half of an `input_queue()`, held by the view. Clones take from the same queue.
+#[derive(Clone)]
+pub struct InputQueue {
+    rx: Arc<Mutex<Receiver<String>>>,
+    ready: Arc<Notify>,
+}
+
+impl InputQueue {
+    /// Returns the next line queued, if there is one, or `UserInput::Quit` once every `InputSender` is gone.
+    pub fn poll(&self) -> Option<UserInput> {
+        match self.rx.lock().unwrap().try_recv() {
+            Ok(line) => Some(UserInput::Line(line)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(UserInput::Quit),
+        }
+    }
+
+    /// Returns the `Notify` woken as each line is queued, for `ChatView::input_ready()`.
+    pub fn ready(&self) -> Arc<Notify> {
+        Arc::clone(&self.ready)
+    }
+}
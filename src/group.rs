@@ -1,173 +1,746 @@
-use errors::ApplicationError;
-use openmls_rust_crypto::RustCrypto;
-use super::*;
-
-use openmls::{
-    credentials::CredentialWithKey,
-    group::{MlsGroup, MlsGroupConfig},
-};
-use openmls_traits::signatures::Signer;
-
-pub struct Group {
-    group: MlsGroup,
-}
-
-impl Group {
-    /// Generates a new `MlsGroup` with the initiator's credentials.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if `MlsGroup::new()` fails.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    pub fn build_new(
-        signer: &impl Signer,
-        credential: CredentialWithKey
-    )-> Group {
-        let mls_group_config = MlsGroupConfig::builder()
-            .use_ratchet_tree_extension(true)
-            .build();
-
-        Group {
-            group: MlsGroup::new(
-                &(*PROVIDER),
-                signer,
-                &mls_group_config,
-                credential,
-            ).unwrap(),
-        }
-    }
-
-    /// Generates a new `MlsGroup` based on a `Welcome` message.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::KeyPackageDNE` if no `KeyPackage` can be found.
-    pub fn build_join(welcome: Welcome) -> Result<Group, ApplicationError> {
-        let config = MlsGroupConfig::builder()
-            .use_ratchet_tree_extension(true)
-            .build();
-
-        if let Ok(group) = MlsGroup::new_from_welcome(
-            &(*PROVIDER),
-            &config,
-            welcome,
-            None) {
-                Ok(Group { group })
-        } else {
-            Err(ApplicationError::KeyPackageDNE)
-        }
-    }
-
-    /// Creates the necessary messages for adding a new member to the group. Returns a tuple
-    /// `(MlsMessageOut, MlsMessageOut)` where the first is a Commit to be merged by the other members
-    /// of the group, and the Welcome contains the information needed by the new member to calculate
-    /// the necessary tree information on their machine.
-    /// 
-    /// Takes in the calling `User`'s `SignatureKeyPair` and the new member's `KeyPackageIn`.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `AddMembersError` if `MlsGroup::add_members()` fails, or a `KeyPackageVerify` error 
-    /// if `KeyPackageIn::validate()` returns that the key package can't be validated.
-    pub fn add_member(
-        &mut self,
-        signer: &impl Signer,
-        key_package: KeyPackageIn
-    ) -> Result<(MlsMessageOut, MlsMessageOut), ApplicationError> {
-        let key_package = match key_package.validate(&RustCrypto::default(), ProtocolVersion::default()) {
-            Ok(kp) => kp,
-            Err(e) => return Err(ApplicationError::KeyPackageVerify(e)),
-        };
-
-        match self.group.add_members(&(*PROVIDER), signer, &[key_package]) {
-            Ok((commit, welcome, _)) => Ok((commit, welcome)),
-            Err(e) => Err(ApplicationError::AddMemberError(e))
-        }
-    }
-
-    /// Uses a `User`'s provided signature keys to encrypt a message. Returns an `MlsMessageOut`.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an Mls `CreateMessageError` if `MlsGroup::create_message()` fails.
-    pub fn create_message(&mut self, signer: &impl Signer, msg: &str) -> Result<MlsMessageOut, CreateMessageError> {
-        Ok(
-            self.group
-                .create_message(&(*PROVIDER), signer, msg.as_bytes())?
-        )
-    }
-
-    /// Merges an incoming commit (such as a member being added to or removed from the group).
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if `MlsGroup::merge_staged_commit()` fails.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    pub fn merge_commit(&mut self, commit: StagedCommit) {
-        self.group
-            .merge_staged_commit(&(*PROVIDER), commit)
-            .unwrap();
-    }
-
-    /// Converts any MLS message with the `Into<ProtocolMessage>` into a `ProcessedMessage`.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::ProcessMessageError()` containing any errors returned by
-    /// `MlsGroup::proces_message()`.
-    pub fn process_message(&mut self, msg: impl Into<ProtocolMessage>) -> Result<ProcessedMessage, ApplicationError> {
-        match self.group.process_message(&(*PROVIDER), msg.into()) {
-            Ok(processed_message) => Ok(processed_message),
-            Err(err) => Err(ApplicationError::ProcessMessageError(err))
-        }
-    }
-
-    /// Returns a commit `MlsMessageOut` to remove a specified member from the group.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if `MlsGroup::remove_members()` fails.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    pub fn remove_member(&mut self, signer: &impl Signer, member_index: u32) -> MlsMessageOut {
-        let member_index = LeafNodeIndex::new(member_index);
-        
-        let (commit, _, _) = self.group
-            .remove_members(&(*PROVIDER), signer, &[member_index])
-            .unwrap();
-
-        commit
-    }
-
-    /// Returns a commit `MlsMessageOut` to update the sender's key package.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if `MlsGroup::self_update()` fails.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::MlsKeyStoreError` if `MlsGroup::merge_pending_commit()` fails. Returns
-    /// an `ApplicationError::KeyUpdateError` if `MlsGroup::self_update()` fails.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    pub fn update_keys(&mut self, signer: &impl Signer) -> Result<MlsMessageOut, ApplicationError> {
-        let Ok(_) = self.group.merge_pending_commit(&(*PROVIDER)) else { return Err(ApplicationError::MlsKeyStoreError) };
-
-        if let Ok((msg, _, _)) = self.group.self_update(&(*PROVIDER), signer) {
-            Ok(msg)
-        } else {
-            Err(ApplicationError::KeyUpdateError)
-        }
-    }
-}
\ No newline at end of file
+use errors::{ApplicationError, MlsError};
+use super::*;
+use crate::config::ModerationConfig;
+
+use openmls::{
+    credentials::CredentialWithKey,
+    group::{MlsGroup, MlsGroupConfig},
+};
+use openmls_traits::signatures::Signer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Number of past epochs whose application messages can still be decrypted. See `Group::config()`.
+const MAX_PAST_EPOCHS: usize = 2;
+
+pub struct Group {
+    group: MlsGroup,
+}
+
+/// A member of the group, as listed in its ratchet tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RosterEntry {
+    /// Leaf index of the member in the group's ratchet tree.
+    pub index: u32,
+    /// Identity from the member's credential.
+    pub identity: String,
+    /// Whether this is the local user's own leaf.
+    pub own: bool,
+    /// Public key the member signs their messages with.
+    pub signature_key: Vec<u8>,
+    /// The profile from the member's leaf, if they published one.
+    pub profile: Option<Profile>,
+}
+
+/// A node of the group's ratchet tree, in the order the tree is stored: leaves at even positions, with the parent
+/// nodes between them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "node", rename_all = "snake_case")]
+pub enum TreeNode {
+    /// A node with no keys in it, either an empty leaf or a parent whose keys were cleared by a change below it.
+    Blank,
+    Leaf {
+        /// Leaf index of the member.
+        index: u32,
+        /// Identity from the member's credential.
+        identity: String,
+        /// Whether this is the local user's own leaf.
+        own: bool,
+    },
+    Parent {
+        /// Leaf indices of the members added below this node since its keys were last set, who don't know them yet.
+        unmerged: Vec<u32>,
+    },
+}
+
+/// Application-level details a member publishes in the application_id extension of their leaf node, serialized as
+/// JSON. Unlike the credential and signature key, which are regenerated every time the member updates their keys,
+/// `user_id` stays the same for as long as they're in the group, so it can tell them apart from anyone who later
+/// takes the same name.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub user_id: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Set by a device joining with a `LinkCode`, to prove to the device that showed the code that it's another
+    /// device of the same user. See `LinkCode::proof()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl Profile {
+    /// Serializes the profile into the bytes carried by the extension.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::InvalidMessage` if serialization fails.
+    pub fn encode(&self) -> Result<Vec<u8>, ApplicationError> {
+        serde_json::to_vec(self).map_err(|_| MlsError::InvalidMessage.into())
+    }
+
+    /// Deserializes a profile from an extension's bytes, returning None if they aren't a valid profile.
+    pub fn decode(bytes: &[u8]) -> Option<Profile> {
+        serde_json::from_slice(bytes).ok()
+    }
+
+    /// Returns the application_id extension carrying the profile, to put in a leaf node.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Profile::encode()`.
+    pub fn extension(&self) -> Result<Extension, ApplicationError> {
+        Ok(Extension::ApplicationId(ApplicationIdExtension::new(&self.encode()?)))
+    }
+}
+
+/// A member whose signature key changed in a commit that gave no reason for it to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyChange {
+    /// Leaf index of the member.
+    pub index: u32,
+    /// Identity from the member's credential before the commit.
+    pub before: String,
+    /// Identity from the member's credential after the commit.
+    pub after: String,
+}
+
+/// What a merged commit changed, as recorded in the audit log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitSummary {
+    /// Identity of the member who sent the commit.
+    pub committer: String,
+    /// Epoch the commit moved the group into.
+    pub epoch: u64,
+    /// Identities of the members it added.
+    pub added: Vec<String>,
+    /// Identities of the members it removed.
+    pub removed: Vec<String>,
+    /// Identities of the members whose keys it updated. A commit with no proposals at all only updates its sender's.
+    pub updated: Vec<String>,
+}
+
+impl Group {
+    /// Generates a new `MlsGroup` with the initiator's credentials. `ratchet_tree` is whether the Welcomes sent into the
+    /// group include its ratchet tree, which openmls 0.5 fixes for the group's lifetime; see
+    /// `Group::includes_ratchet_tree()`.
+    /// 
+    /// # Panics
+    /// 
+    /// Panics if `MlsGroup::new()` fails.
+    /// 
+    /// # TODO
+    /// 
+    /// Replace `unwrap()` with more robust error handling.
+    pub fn build_new(
+        signer: &impl Signer,
+        credential: CredentialWithKey,
+        ratchet_tree: bool,
+        ciphersuite: Ciphersuite,
+    )-> Group {
+        let mls_group_config = Self::config(ciphersuite, 0, ratchet_tree);
+
+        Group {
+            group: MlsGroup::new(
+                &(*PROVIDER),
+                signer,
+                &mls_group_config,
+                credential,
+            ).unwrap(),
+        }
+    }
+
+    /// Generates a new `MlsGroup` based on a `Welcome` message. `ratchet_tree` is the group's ratchet tree, if it was
+    /// fetched separately because the Welcome was sent without it (see `Group::set_ratchet_tree_extension()`).
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::KeyPackageDNE` if no `KeyPackage` can be found, or an `MlsError::Welcome` with
+    /// whatever else `MlsGroup::new_from_welcome()` fails with, including when the Welcome has no ratchet tree and
+    /// none was given.
+    pub fn build_join(welcome: Welcome, ratchet_tree: Option<RatchetTreeIn>) -> Result<Group, ApplicationError> {
+        // openmls keeps including the tree in Welcomes only if it was in this one, which it must have been without
+        // a tree given separately; the configuration is set to match, so `Group::includes_ratchet_tree()` knows. The
+        // ciphersuite is taken from the Welcome instead of the configuration.
+        let config = Self::config(CIPHERSUITE, 0, ratchet_tree.is_none());
+
+        match MlsGroup::new_from_welcome(&(*PROVIDER), &config, welcome, ratchet_tree) {
+            Ok(group) => Ok(Group { group }),
+            Err(WelcomeError::NoMatchingKeyPackage) => Err(MlsError::KeyPackageDNE.into()),
+            Err(e) => Err(MlsError::Welcome(e).into()),
+        }
+    }
+
+    /// Stores the group's state in the provider's key store under its group ID, so that it's saved along with the rest
+    /// of the store. See `Group::load()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::KeyStore` if `MlsGroup::save()` fails.
+    pub fn save(&mut self) -> Result<(), ApplicationError> {
+        self.group.save(&(*PROVIDER)).map_err(|e| MlsError::KeyStore(e.to_string()).into())
+    }
+
+    /// Loads a group stored with `Group::save()` from the provider's key store, or returns None if there's no group
+    /// under `group_id`.
+    pub fn load(group_id: &GroupId) -> Option<Group> {
+        MlsGroup::load(group_id, &(*PROVIDER)).map(|group| Group { group })
+    }
+
+    /// Returns the `MlsGroupConfig` the application's groups use, with the given ciphersuite, padding application
+    /// messages as in `Group::set_padding()`, and including the ratchet tree in Welcomes if `ratchet_tree` is set.
+    ///
+    /// Application messages from the last `MAX_PAST_EPOCHS` epochs can still be decrypted, since a member may encrypt
+    /// one before seeing a commit the server ordered ahead of it (e.g. announcing their presence as a newcomer
+    /// updates their keys).
+    fn config(ciphersuite: Ciphersuite, padding: usize, ratchet_tree: bool) -> MlsGroupConfig {
+        MlsGroupConfig::builder()
+            .use_ratchet_tree_extension(ratchet_tree)
+            .max_past_epochs(MAX_PAST_EPOCHS)
+            .crypto_config(CryptoConfig::with_default_version(ciphersuite))
+            .padding_size(padding)
+            .build()
+    }
+
+    /// Pads the plaintext of every application message sent from now on to a multiple of `padding` bytes, so that
+    /// the length of the ciphertext says less about what's in it. 0 turns padding off.
+    pub fn set_padding(&mut self, padding: usize) {
+        let ratchet_tree = self.includes_ratchet_tree();
+        self.group.set_configuration(&Self::config(self.ciphersuite(), padding, ratchet_tree));
+    }
+
+    /// Returns the ciphersuite the group was created with.
+    pub fn ciphersuite(&self) -> Ciphersuite {
+        self.group.ciphersuite()
+    }
+
+    /// Creates the successor of the group, for moving its members to `ciphersuite`: a new group with its own ID, the
+    /// same settings, and only the user in it, who adds the others with `Group::add_members()`.
+    ///
+    /// MLS would have the move start with a ReInit proposal, committed in this group so that every member agrees to
+    /// it. openmls 0.5 can't create ReInit proposals, and keeps what an incoming one proposes to itself, so the
+    /// members are asked over application messages instead (see `Payload::ReInit`). Only the ciphersuite can change:
+    /// openmls 0.5 speaks no MLS version but `PROTOCOL_VERSION`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `MlsGroup::new()` fails, as in `Group::build_new()`.
+    pub fn reinit(&self, signer: &impl Signer, credential: CredentialWithKey, ciphersuite: Ciphersuite) -> Group {
+        Self::build_new(signer, credential, self.includes_ratchet_tree(), ciphersuite)
+    }
+
+    /// Returns whether the Welcomes the user sends include the group's ratchet tree. Without it, Welcomes stay small
+    /// however large the group grows, but the new member has to fetch the tree some other way; see
+    /// `Group::export_ratchet_tree()`. openmls 0.5 decides it when the group is created, and a member welcomed into
+    /// it keeps to whatever their Welcome did, so it can't be changed afterwards.
+    pub fn includes_ratchet_tree(&self) -> bool {
+        self.group.configuration().use_ratchet_tree_extension()
+    }
+
+    /// Returns the group's ratchet tree, TLS-serialized, for a member joining from a Welcome sent without one. Export
+    /// it after merging the commit that adds them, so it's the tree of the epoch they join in.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::TlsSerialize` if serializing fails.
+    pub fn export_ratchet_tree(&self) -> Result<Vec<u8>, ApplicationError> {
+        self.group.export_ratchet_tree().tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()).into())
+    }
+
+    /// Creates the necessary messages for adding a new member to the group. Returns a tuple
+    /// `(MlsMessageOut, MlsMessageOut)` where the first is a Commit to be merged by the other members
+    /// of the group, and the Welcome contains the information needed by the new member to calculate
+    /// the necessary tree information on their machine.
+    /// 
+    /// Takes in the calling `User`'s `SignatureKeyPair` and the new member's `KeyPackageIn`. The commit is left
+    /// pending; see `Group::merge_pending_commit()`.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns a `CommitPending` error if another of the user's commits hasn't been merged or discarded yet, an
+    /// `AddMembersError` if `MlsGroup::add_members()` fails, a `KeyPackageExpired` error if the key
+    /// package is outside its lifetime, a `KeyPackageVersion` error if it's for an MLS protocol version other than
+    /// `PROTOCOL_VERSION`, a `KeyPackageCiphersuite` error if it's for a ciphersuite other than the group's, or a
+    /// `KeyPackageVerify` error if `KeyPackageIn::validate()` returns that the key package can't be validated for any
+    /// other reason.
+    pub fn add_member(
+        &mut self,
+        signer: &impl Signer,
+        key_package: KeyPackageIn
+    ) -> Result<(MlsMessageOut, MlsMessageOut), ApplicationError> {
+        self.add_members(signer, vec![key_package])
+    }
+
+    /// Like `Group::add_member()`, but adds everyone in `key_packages` with one commit and one Welcome.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` `Group::add_member()` does, for the first key package that fails.
+    pub fn add_members(
+        &mut self,
+        signer: &impl Signer,
+        key_packages: Vec<KeyPackageIn>,
+    ) -> Result<(MlsMessageOut, MlsMessageOut), ApplicationError> {
+        if self.has_pending_commit() {
+            return Err(MlsError::CommitPending.into());
+        }
+
+        let mut validated = Vec::with_capacity(key_packages.len());
+        for key_package in key_packages {
+            let key_package = match key_package.validate((*PROVIDER).crypto(), PROTOCOL_VERSION) {
+                Ok(kp) => kp,
+                Err(KeyPackageVerifyError::InvalidLifetime) => return Err(MlsError::KeyPackageExpired.into()),
+                Err(KeyPackageVerifyError::InvalidProtocolVersion) => return Err(MlsError::KeyPackageVersion.into()),
+                Err(e) => return Err(MlsError::KeyPackageVerify(e).into()),
+            };
+            if key_package.ciphersuite() != self.group.ciphersuite() {
+                return Err(MlsError::KeyPackageCiphersuite(key_package.ciphersuite()).into());
+            }
+            validated.push(key_package);
+        }
+
+        match self.group.add_members(&(*PROVIDER), signer, &validated) {
+            Ok((commit, welcome, _)) => Ok((commit, welcome)),
+            Err(e) => Err(MlsError::AddMember(e).into())
+        }
+    }
+
+    /// Uses a `User`'s provided signature keys to encrypt a message. Returns an `MlsMessageOut`.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an Mls `CreateMessageError` if `MlsGroup::create_message()` fails.
+    pub fn create_message(&mut self, signer: &impl Signer, msg: &[u8]) -> Result<MlsMessageOut, CreateMessageError> {
+        self.group
+            .create_message(&(*PROVIDER), signer, msg)
+    }
+
+    /// Returns true if one of the user's own commits is waiting to be merged.
+    ///
+    /// A commit the user creates isn't merged straight away, since another member may have committed in the same
+    /// epoch, and only one of the two can win. The server decides by relaying them in order: the user's commit is
+    /// merged with `Group::merge_pending_commit()` once the server has sequenced it, or dropped with
+    /// `Group::discard_pending_commit()` if another member's commit for the same epoch was relayed first. Until then,
+    /// no other commit can be created.
+    pub fn has_pending_commit(&self) -> bool {
+        self.group.pending_commit().is_some()
+    }
+
+    /// Merges the user's own pending commit, if there is one, and returns what it changed. See
+    /// `Group::has_pending_commit()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::KeyStore` if `MlsGroup::merge_pending_commit()` fails.
+    pub fn merge_pending_commit(&mut self) -> Result<Option<CommitSummary>, ApplicationError> {
+        let own_identity = self.roster().into_iter()
+            .find(|member| member.own)
+            .map(|member| member.identity)
+            .unwrap_or_default();
+        let summary = self.group.pending_commit().map(|commit| self.summarize(commit, &own_identity));
+        self.group
+            .merge_pending_commit(&(*PROVIDER))
+            .map_err(|e| MlsError::KeyStore(e.to_string()))?;
+        Ok(summary)
+    }
+
+    /// Describes what a staged commit from the member with the identity `committer` changes, using the roster as it
+    /// stands before the commit is merged.
+    pub fn summarize(&self, commit: &StagedCommit, committer: &str) -> CommitSummary {
+        let roster = self.roster();
+        let identity = |index: u32| roster.iter()
+            .find(|member| member.index == index)
+            .map_or_else(|| format!("leaf {}", index), |member| member.identity.clone());
+
+        let added: Vec<String> = commit.add_proposals()
+            .map(|proposal| {
+                let credential = proposal.add_proposal().key_package().leaf_node().credential();
+                String::from_utf8_lossy(credential.identity()).into_owned()
+            })
+            .collect();
+        let removed: Vec<String> = commit.remove_proposals()
+            .map(|proposal| identity(proposal.remove_proposal().removed().u32()))
+            .collect();
+        let mut updated: Vec<String> = commit.update_proposals()
+            .filter_map(|proposal| match proposal.sender() {
+                Sender::Member(index) => Some(identity(index.u32())),
+                _ => None,
+            })
+            .collect();
+        if added.is_empty() && removed.is_empty() && updated.is_empty() {
+            updated.push(committer.to_string());
+        }
+
+        CommitSummary {
+            committer: committer.to_string(),
+            epoch: commit.group_context().epoch().as_u64(),
+            added,
+            removed,
+            updated,
+        }
+    }
+
+    /// Returns the identities of the members a staged commit from the member with the identity `committer` removes and
+    /// adds back under a different signature key, other than the committer's own. Nobody but the member themselves
+    /// should be handing their identity a new key, so this is how someone would slip an impostor into their place.
+    pub fn swapped_credentials(&self, commit: &StagedCommit, committer: &str) -> Vec<String> {
+        let roster = self.roster();
+        commit.remove_proposals()
+            .filter_map(|proposal| roster.iter().find(|member| member.index == proposal.remove_proposal().removed().u32()))
+            .filter(|removed| removed.identity != committer)
+            .filter(|removed| commit.add_proposals().any(|proposal| {
+                let leaf = proposal.add_proposal().key_package().leaf_node();
+                leaf.credential().identity() == removed.identity.as_bytes()
+                    && leaf.signature_key().as_slice() != removed.signature_key.as_slice()
+            }))
+            .map(|removed| removed.identity.clone())
+            .collect()
+    }
+
+    /// Drops the user's own pending commit, if there is one. See `Group::has_pending_commit()`.
+    pub fn discard_pending_commit(&mut self) {
+        self.group.clear_pending_commit();
+    }
+
+    /// Merges an incoming commit (such as a member being added to or removed from the group).
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::MergeCommit()` containing any errors returned by `MlsGroup::merge_staged_commit()`.
+    pub fn merge_commit(&mut self, commit: StagedCommit) -> Result<(), ApplicationError> {
+        self.group
+            .merge_staged_commit(&(*PROVIDER), commit)
+            .map_err(|e| MlsError::MergeCommit(e).into())
+    }
+
+    /// Converts any MLS message with the `Into<ProtocolMessage>` into a `ProcessedMessage`.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::ProcessMessage()` containing any errors returned by
+    /// `MlsGroup::proces_message()`.
+    pub fn process_message(&mut self, msg: impl Into<ProtocolMessage>) -> Result<ProcessedMessage, ApplicationError> {
+        match self.group.process_message(&(*PROVIDER), msg.into()) {
+            Ok(processed_message) => Ok(processed_message),
+            Err(err) => Err(MlsError::ProcessMessage(err).into())
+        }
+    }
+
+    /// Derives `length` bytes of secret key material from the current epoch's exporter secret. Every member of the
+    /// group derives the same bytes for the same `label` and `context`, and nobody outside the group can, so they can
+    /// key anything the application needs to keep between members. The bytes change with every epoch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::ExportSecret` if `MlsGroup::export_secret()` fails, e.g. because `length`
+    /// is too long for the ciphersuite's KDF or the group is no longer active.
+    pub fn export_secret(&self, label: &str, context: &[u8], length: usize) -> Result<Vec<u8>, ApplicationError> {
+        self.group
+            .export_secret(&(*PROVIDER), label, context, length)
+            .map_err(|e| MlsError::ExportSecret(e).into())
+    }
+
+    /// Returns a short code derived from the current epoch's authenticator, as four groups of five digits. Members
+    /// who read out matching codes (e.g. over a phone call) share the same view of the group and its history, so
+    /// nobody has been slipped in or out by a tampering server. The code changes with every epoch.
+    pub fn verification_code(&self) -> String {
+        verification_code(self.group.epoch_authenticator().as_slice())
+    }
+
+    /// Returns the ID shared by every member's copy of the group.
+    pub fn group_id(&self) -> &GroupId {
+        self.group.group_id()
+    }
+
+    /// Returns the user's own leaf index in the group's ratchet tree.
+    pub fn own_index(&self) -> u32 {
+        self.group.own_leaf_index().u32()
+    }
+
+    /// Returns the group's current epoch, which advances with every commit merged.
+    pub fn epoch(&self) -> u64 {
+        self.group.epoch().as_u64()
+    }
+
+    /// Returns a SHA-256 hash of the group's ratchet tree as exported, which members in the same epoch agree on unless
+    /// their views of the group have diverged. It isn't the tree hash of RFC 9420, which openmls 0.5 keeps to itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::TlsSerialize` if serializing the tree fails, or an `MlsError::Crypto` if hashing it does.
+    pub fn tree_hash(&self) -> Result<Vec<u8>, ApplicationError> {
+        let tree = self.export_ratchet_tree()?;
+        (*PROVIDER).crypto().hash(HashType::Sha2_256, &tree).map_err(|e| MlsError::Crypto(e).into())
+    }
+
+    /// Returns the type of each proposal received but not yet committed, e.g. "Add", along with the leaf index of its
+    /// sender, or None if it came from outside the group.
+    pub fn pending_proposals(&self) -> Vec<(String, Option<u32>)> {
+        self.group.pending_proposals()
+            .map(|queued| {
+                let sender = match queued.sender() {
+                    Sender::Member(index) => Some(index.u32()),
+                    _ => None,
+                };
+                (format!("{:?}", queued.proposal().proposal_type()), sender)
+            })
+            .collect()
+    }
+
+    /// Returns the current members of the group, ordered by leaf index. Reflects every commit merged so far.
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        let own_index = self.own_index();
+        let mut profiles = self.profiles();
+
+        self.group
+            .members()
+            .map(|member| RosterEntry {
+                index: member.index.u32(),
+                identity: String::from_utf8_lossy(member.credential.identity()).into_owned(),
+                own: member.index.u32() == own_index,
+                signature_key: member.signature_key,
+                profile: profiles.remove(&member.index.u32()),
+            })
+            .collect()
+    }
+
+    /// Returns the profiles published in the leaves of the group's ratchet tree, by leaf index.
+    ///
+    /// openmls 0.5 doesn't expose other members' leaf nodes (`Member` leaves out their extensions), so they're read
+    /// from the exported ratchet tree instead. It only serializes as its list of nodes, which is round-tripped through
+    /// serde to get at them; leaves sit at the even positions.
+    fn profiles(&self) -> HashMap<u32, Profile> {
+        self.nodes()
+            .iter()
+            .step_by(2)
+            .enumerate()
+            .filter_map(|(index, node)| match node {
+                Some(Node::LeafNode(leaf)) => {
+                    let profile = Profile::decode(leaf.extensions().application_id()?.as_slice())?;
+                    Some((index as u32, profile))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns every node of the group's ratchet tree, blank ones included. Read from the exported ratchet tree, as in
+    /// `Group::profiles()`.
+    pub fn tree(&self) -> Vec<TreeNode> {
+        let own_index = self.own_index();
+
+        self.nodes()
+            .into_iter()
+            .enumerate()
+            .map(|(position, node)| match node {
+                None => TreeNode::Blank,
+                Some(Node::LeafNode(leaf)) => {
+                    let index = (position / 2) as u32;
+                    TreeNode::Leaf {
+                        index,
+                        identity: String::from_utf8_lossy(leaf.credential().identity()).into_owned(),
+                        own: index == own_index,
+                    }
+                }
+                // a parent node's fields are private too, so its unmerged leaves are picked out of its serialization
+                Some(Node::ParentNode(parent)) => TreeNode::Parent {
+                    unmerged: serde_json::to_value(parent)
+                        .ok()
+                        .and_then(|parent| serde_json::from_value(parent["unmerged_leaves"]["list"].clone()).ok())
+                        .unwrap_or_default(),
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the nodes of the group's ratchet tree by round-tripping the exported tree through serde, as explained in
+    /// `Group::profiles()`.
+    fn nodes(&self) -> Vec<Option<Node>> {
+        serde_json::to_value(self.group.export_ratchet_tree())
+            .and_then(serde_json::from_value)
+            .unwrap_or_default()
+    }
+
+    /// Returns a commit `MlsMessageOut` to remove a specified member from the group.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::RemoveMember()` containing any errors returned by `MlsGroup::remove_members()`, such as
+    /// when there's no member at `member_index`.
+    pub fn remove_member(&mut self, signer: &impl Signer, member_index: u32) -> Result<MlsMessageOut, ApplicationError> {
+        let member_index = LeafNodeIndex::new(member_index);
+        
+        let (commit, _, _) = self.group
+            .remove_members(&(*PROVIDER), signer, &[member_index])
+            .map_err(MlsError::RemoveMember)?;
+
+        Ok(commit)
+    }
+
+    /// Returns a commit `MlsMessageOut` that replaces the encryption keys in the sender's leaf and along its path. The
+    /// commit is left pending; see `Group::merge_pending_commit()`. `signer` must be the key pair the sender has
+    /// signed with all along, since openmls 0.5 can't change a leaf's signature key in an update.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::CommitPending` if another of the user's commits hasn't been merged or discarded
+    /// yet, or an `MlsError::KeyUpdate` if `MlsGroup::self_update()` fails.
+    pub fn update_keys(&mut self, signer: &impl Signer) -> Result<MlsMessageOut, ApplicationError> {
+        if self.has_pending_commit() {
+            return Err(MlsError::CommitPending.into());
+        }
+
+        match self.group.self_update(&(*PROVIDER), signer) {
+            Ok((msg, _, _)) => Ok(msg),
+            Err(e) => Err(MlsError::KeyUpdate(e).into()),
+        }
+    }
+}
+
+/// Derives the code shown by `/verify-group` from an epoch authenticator, as four groups of five digits taken from its
+/// first 20 bytes.
+pub fn verification_code(epoch_authenticator: &[u8]) -> String {
+    epoch_authenticator
+        .chunks_exact(5)
+        .take(4)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |n, &byte| n << 8 | u64::from(byte));
+            format!("{:05}", n % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the `Profile` from a key package's leaf node along with its signature key, or None if the key package
+/// doesn't validate or carries no profile.
+pub fn key_package_profile(key_package: &KeyPackageIn) -> Option<(Profile, Vec<u8>)> {
+    let key_package = key_package.clone().validate((*PROVIDER).crypto(), PROTOCOL_VERSION).ok()?;
+    let leaf = key_package.leaf_node();
+    let profile = Profile::decode(leaf.extensions().application_id()?.as_slice())?;
+    Some((profile, leaf.signature_key().as_slice().to_vec()))
+}
+
+/// Returns the ciphersuite with the code point `code`, if a group using `current` can move to it with
+/// `Group::reinit()`. openmls has to support it, and it has to sign with the same scheme as `current`, since every
+/// member's signature key carries over into the successor, and with it how the others recognize them.
+///
+/// # Errors
+///
+/// Returns an `MlsError::UnsupportedCiphersuite` otherwise, including for `current` itself.
+pub fn successor_ciphersuite(current: Ciphersuite, code: u16) -> Result<Ciphersuite, ApplicationError> {
+    match Ciphersuite::try_from(code) {
+        Ok(ciphersuite)
+            if ciphersuite != current
+                && ciphersuite.signature_algorithm() == current.signature_algorithm()
+                && (*PROVIDER).crypto().supports(ciphersuite).is_ok() => Ok(ciphersuite),
+        _ => Err(MlsError::UnsupportedCiphersuite(code).into()),
+    }
+}
+
+/// Returns the ciphersuites a user in a group with the ciphersuite `current` publishes key packages for: `current`,
+/// followed by every one a group could move to from it (see `successor_ciphersuite()`), so whichever group claims
+/// them can add them even if it's moved off the ciphersuite it was created with.
+pub fn key_package_ciphersuites(current: Ciphersuite) -> Vec<Ciphersuite> {
+    std::iter::once(current)
+        .chain((1..=7).filter_map(|code| successor_ciphersuite(current, code).ok()))
+        .collect()
+}
+
+/// Returns a digest of `moderation`, for members to compare their settings by without sending each other the whole
+/// roster. Members with the same policy and moderators get the same digest whatever order they're listed in.
+///
+/// openmls 0.5 can't put the roster in a group context extension, where every member would have to agree on it, so
+/// each client keeps its own in its config, and comparing digests is how members notice theirs differ.
+pub fn moderation_digest(moderation: &ModerationConfig) -> Vec<u8> {
+    let mut moderators: Vec<&str> = moderation.moderators.iter().map(String::as_str).collect();
+    moderators.sort_unstable();
+    moderators.dedup();
+    let canonical = format!("{:?}\n{}", moderation.policy, moderators.join("\n"));
+    (*PROVIDER).crypto().hash(HashType::Sha2_256, canonical.as_bytes()).unwrap_or_default()
+}
+
+/// Returns the ciphersuite of a key package, or None if the key package doesn't validate.
+pub fn key_package_ciphersuite(key_package: &KeyPackageIn) -> Option<Ciphersuite> {
+    let key_package = key_package.clone().validate((*PROVIDER).crypto(), PROTOCOL_VERSION).ok()?;
+    Some(key_package.ciphersuite())
+}
+
+/// Returns the signature key of a key package's leaf node, or None if the key package doesn't validate.
+pub fn key_package_signature_key(key_package: &KeyPackageIn) -> Option<Vec<u8>> {
+    let key_package = key_package.clone().validate((*PROVIDER).crypto(), PROTOCOL_VERSION).ok()?;
+    Some(key_package.leaf_node().signature_key().as_slice().to_vec())
+}
+
+/// Compares the rosters from before and after a commit from the member at leaf `committer`, returning the leaves it
+/// added whose profile claims the user ID of someone already in the group, unless that's the committer's own. Only a
+/// user's own device can link another to them (see `LinkCode`), so any other commit doing it is passing a newcomer off
+/// as one of the group's members.
+pub fn impostor_devices(before: &[RosterEntry], after: &[RosterEntry], committer: Option<u32>) -> Vec<u32> {
+    let user_id = |member: &RosterEntry| member.profile.as_ref().map(|profile| profile.user_id.clone());
+    let committer_id = before.iter().find(|member| Some(member.index) == committer).and_then(user_id);
+
+    after.iter()
+        .filter(|member| !before.iter().any(|previous| previous.signature_key == member.signature_key))
+        .filter(|member| {
+            let Some(claimed) = user_id(member) else { return false };
+            committer_id.as_ref() != Some(&claimed) && before.iter().any(|previous| user_id(previous) == Some(claimed.clone()))
+        })
+        .map(|member| member.index)
+        .collect()
+}
+
+/// Compares the rosters from before and after a commit, returning the members whose signature key changed even though
+/// their leaf isn't in `expected`. A member's key is expected to change when they commit or propose an update
+/// themselves, or when their leaf is emptied and handed to someone new; any other change means someone else replaced
+/// their key material, which could be an attempt to impersonate them.
+pub fn unexpected_key_changes(before: &[RosterEntry], after: &[RosterEntry], expected: &[u32]) -> Vec<KeyChange> {
+    after.iter()
+        .filter(|member| !expected.contains(&member.index))
+        .filter_map(|member| {
+            let previous = before.iter().find(|previous| previous.index == member.index)?;
+            (previous.signature_key != member.signature_key).then(|| KeyChange {
+                index: member.index,
+                before: previous.identity.clone(),
+                after: member.identity.clone(),
+            })
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(index: u32, identity: &str, key: u8) -> RosterEntry {
+        RosterEntry { index, identity: String::from(identity), own: false, signature_key: vec![key], profile: None }
+    }
+
+    fn device(index: u32, user_id: &str, key: u8) -> RosterEntry {
+        let profile = Profile { user_id: String::from(user_id), display_name: None, link: None };
+        RosterEntry { profile: Some(profile), ..member(index, "alice", key) }
+    }
+
+    #[test]
+    fn detect_impostor_devices() {
+        let before = vec![device(0, "a11ce", 1), device(1, "b0b", 2)];
+        let after = [before.clone(), vec![device(2, "a11ce", 3)]].concat();
+
+        assert!(impostor_devices(&before, &after, Some(0)).is_empty());
+        assert_eq!(impostor_devices(&before, &after, Some(1)), vec![2]);
+        assert!(impostor_devices(&before, &[before.clone(), vec![device(2, "ca7", 3)]].concat(), Some(1)).is_empty());
+    }
+
+    #[test]
+    fn detect_unexpected_key_changes() {
+        let before = [member(0, "alice", 1), member(1, "bob", 2), member(2, "carol", 3)];
+        let after = [member(0, "alice", 4), member(1, "mallory", 5), member(2, "dave", 6), member(3, "erin", 7)];
+
+        // alice committed, carol was removed and her leaf reused for dave, and erin was added
+        assert_eq!(unexpected_key_changes(&before, &after, &[0, 2]), vec![KeyChange {
+            index: 1,
+            before: String::from("bob"),
+            after: String::from("mallory"),
+        }]);
+        assert!(unexpected_key_changes(&before, &before, &[]).is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    group::{self, Group},
+    link::unhex,
+    network::frame::Frame,
+    CIPHERSUITE, PROTOCOL_VERSION, PROVIDER,
+};
+
+use openmls::prelude::*;
+use openmls_traits::{types::HpkePrivateKey, OpenMlsCryptoProvider};
+use serde::Deserialize;
+
+// The vectors in testdata/interop are from the MLS working group's mls-implementations repository, as shipped with
+// openmls 0.5, and were produced by other implementations. They pin the parts of the wire format this crate handles
+// itself: what it unwraps from the server's frames, what it reads out of key packages, and how it joins with a
+// Welcome, so a change to any of those that only works against our own output shows up here.
+
+/// One of each kind of message, from `messages.json`.
+#[derive(Deserialize)]
+struct Messages {
+    mls_welcome: String,
+    mls_group_info: String,
+    mls_key_package: String,
+    ratchet_tree: String,
+    public_message_application: String,
+    public_message_proposal: String,
+    public_message_commit: String,
+    private_message: String,
+}
+
+/// A Welcome and the key package it's for, from `welcome.json`.
+#[derive(Deserialize)]
+struct WelcomeVector {
+    cipher_suite: u16,
+    key_package: String,
+    welcome: String,
+}
+
+/// A Welcome along with the private keys of the key package it's for, and the epoch authenticator a client joining
+/// with it should end up with, from `passive-client-welcome.json`.
+#[derive(Deserialize)]
+struct PassiveClientWelcome {
+    cipher_suite: u16,
+    key_package: String,
+    encryption_priv: String,
+    init_priv: String,
+    welcome: String,
+    ratchet_tree: Option<String>,
+    initial_epoch_authenticator: String,
+}
+
+fn load<T: for<'a> Deserialize<'a>>(json: &str) -> Vec<T> {
+    serde_json::from_str(json).unwrap()
+}
+
+/// Deserializes an `MLSMessage` as the controller does with what the server relays, checking nothing is left over.
+fn read_message(bytes: &[u8]) -> MlsMessageInBody {
+    let mut reader = bytes;
+    let message = MlsMessageIn::tls_deserialize(&mut reader).unwrap();
+    assert!(reader.is_empty(), "{} bytes left over", reader.len());
+    assert_eq!(message.tls_serialized_len(), bytes.len());
+    message.extract()
+}
+
+/// Deserializes the key package wrapped in an `MLSMessage`, returning it along with the bare key package, which is
+/// what clients publish to the server and claim from it.
+fn read_key_package(bytes: &[u8]) -> (KeyPackageIn, Vec<u8>) {
+    let MlsMessageInBody::KeyPackage(key_package) = read_message(bytes) else { panic!("not a key package") };
+    // the header wrapping it is the protocol version and wire format, two bytes each
+    let bare = bytes[4..].to_vec();
+    let mut reader = bare.as_slice();
+    assert_eq!(KeyPackageIn::tls_deserialize(&mut reader).unwrap(), key_package);
+    assert!(reader.is_empty());
+    (key_package, bare)
+}
+
+/// Puts the private keys for `key_package` in the key store where openmls looks for them when joining a group, as if
+/// this client had built it.
+fn store_private_keys(key_package: &KeyPackage, init_priv: Vec<u8>, encryption_priv: Vec<u8>) {
+    let crypto = (*PROVIDER).crypto();
+    let encryption_key = key_package.leaf_node().encryption_key();
+    // the key store indexes the leaf's key pair by the bare key, which serializes after a one-byte length
+    let mut encryption_index = b"leaf_encryption_key".to_vec();
+    encryption_index.extend_from_slice(&encryption_key.tls_serialize_detached().unwrap()[1..]);
+    let encryption_pair = serde_json::json!({
+        "public_key": encryption_key,
+        "private_key": { "key": HpkePrivateKey::from(encryption_priv) },
+    });
+
+    (*PROVIDER).key_store().restore(vec![
+        (key_package.hash_ref(crypto).unwrap().as_slice().to_vec(), serde_json::to_vec(key_package).unwrap()),
+        (key_package.hpke_init_key().as_slice().to_vec(), serde_json::to_vec(&HpkePrivateKey::from(init_priv)).unwrap()),
+        (encryption_index, serde_json::to_vec(&encryption_pair).unwrap()),
+    ]);
+}
+
+#[tokio::test]
+async fn reads_foreign_messages() {
+    for vector in load::<Messages>(include_str!("../testdata/interop/messages.json")) {
+        let (key_package, bare) = read_key_package(&unhex(&vector.mls_key_package).unwrap());
+        assert_eq!(key_package.tls_serialize_detached().unwrap(), bare);
+
+        let MlsMessageInBody::Welcome(welcome) = read_message(&unhex(&vector.mls_welcome).unwrap()) else { panic!("not a Welcome") };
+        assert!(!welcome.secrets().is_empty());
+        assert!(matches!(read_message(&unhex(&vector.mls_group_info).unwrap()), MlsMessageInBody::GroupInfo(_)));
+
+        for message in [&vector.public_message_application, &vector.public_message_proposal, &vector.public_message_commit] {
+            let MlsMessageInBody::PublicMessage(message) = read_message(&unhex(message).unwrap()) else {
+                panic!("not a PublicMessage")
+            };
+            ProtocolMessage::from(message).epoch();
+        }
+        let MlsMessageInBody::PrivateMessage(message) = read_message(&unhex(&vector.private_message).unwrap()) else {
+            panic!("not a PrivateMessage")
+        };
+        ProtocolMessage::from(message).epoch();
+
+        // as published for joiners to fetch when Welcomes leave it out
+        let tree = unhex(&vector.ratchet_tree).unwrap();
+        let mut reader = tree.as_slice();
+        RatchetTreeIn::tls_deserialize(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        // and each passes through the server unchanged
+        for data in [unhex(&vector.mls_welcome).unwrap(), unhex(&vector.private_message).unwrap(), bare] {
+            let relay = Frame::Relay { seq: 1, data };
+            let bytes = relay.encode_with(true);
+            let body = Frame::read_body(&mut bytes.as_slice()).await.unwrap().unwrap();
+            assert_eq!(Frame::decode(&body).unwrap(), relay);
+        }
+    }
+}
+
+#[test]
+fn matches_foreign_welcomes_to_key_packages() {
+    let vectors = load::<WelcomeVector>(include_str!("../testdata/interop/welcome.json"));
+    let vectors: Vec<_> = vectors.into_iter().filter(|vector| vector.cipher_suite == u16::from(CIPHERSUITE)).collect();
+    assert!(!vectors.is_empty());
+
+    for vector in vectors {
+        let (key_package, _) = read_key_package(&unhex(&vector.key_package).unwrap());
+        assert!(group::key_package_signature_key(&key_package).is_some());
+        assert!(group::key_package_profile(&key_package).is_none());
+
+        // the Welcome names the key package it's for by the same reference `User::welcome_for()` looks for
+        let crypto = (*PROVIDER).crypto();
+        let hash_ref = key_package.validate(crypto, PROTOCOL_VERSION).unwrap().hash_ref(crypto).unwrap();
+        let MlsMessageInBody::Welcome(welcome) = read_message(&unhex(&vector.welcome).unwrap()) else { panic!("not a Welcome") };
+        assert!(welcome.secrets().iter().any(|secrets| secrets.new_member() == hash_ref));
+    }
+}
+
+#[test]
+fn joins_foreign_groups() {
+    let vectors = load::<PassiveClientWelcome>(include_str!("../testdata/interop/passive-client-welcome.json"));
+    assert!(vectors.iter().any(|vector| vector.ratchet_tree.is_some()));
+    assert!(vectors.iter().any(|vector| vector.ratchet_tree.is_none()));
+
+    for vector in vectors {
+        assert_eq!(vector.cipher_suite, u16::from(CIPHERSUITE));
+        let (key_package, _) = read_key_package(&unhex(&vector.key_package).unwrap());
+        // their lifetimes have run out, which `KeyPackageIn::validate()` rejects, but the Welcome is what's under test
+        let key_package: KeyPackage = serde_json::from_value(serde_json::to_value(key_package).unwrap()).unwrap();
+        store_private_keys(&key_package, unhex(&vector.init_priv).unwrap(), unhex(&vector.encryption_priv).unwrap());
+
+        let MlsMessageInBody::Welcome(welcome) = read_message(&unhex(&vector.welcome).unwrap()) else { panic!("not a Welcome") };
+        let ratchet_tree = vector.ratchet_tree
+            .map(|tree| RatchetTreeIn::tls_deserialize(&mut unhex(&tree).unwrap().as_slice()).unwrap());
+        let group = Group::build_join(welcome, ratchet_tree).unwrap();
+
+        // the code members compare with `/verify-group` agrees with the other implementation's view of the group
+        assert_eq!(group.verification_code(), group::verification_code(&unhex(&vector.initial_epoch_authenticator).unwrap()));
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fmt, str::FromStr};
+use crate::network::address::ServerAddr;
+
+use rand::Rng;
+
+/// The URI scheme used for invites.
+pub const SCHEME: &str = "mls-chat";
+
+/// Longest room code a rendezvous server accepts, in bytes.
+pub const MAX_ROOM_CODE_LEN: usize = 64;
+
+/// Characters `new_room_code()` picks from: lowercase letters and digits, leaving out the ones easily mistaken for
+/// each other when read aloud or copied by hand.
+const ROOM_CODE_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+/// Everything needed to join a server, in a single copy-pasteable string of the form
+/// `mls-chat://host:port/room?tls=1&token=...`. The room, `tls`, and `token` parts are optional.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// Hostname or IP address of the server. IPv6 addresses are stored without brackets.
+    pub host: String,
+    pub port: u16,
+    pub room: Option<String>,
+    pub tls: bool,
+    pub token: Option<String>,
+}
+
+impl Invite {
+    pub fn new(host: String, port: u16) -> Invite {
+        Invite { host, port, room: None, tls: false, token: None }
+    }
+
+    /// Returns the address of the server the invite is to.
+    pub fn address(&self) -> ServerAddr {
+        ServerAddr::new(self.host.clone(), self.port)
+    }
+}
+
+impl FromStr for Invite {
+    type Err = String;
+
+    /// Parses an invite URI. Room names and tokens may be percent-encoded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix(SCHEME).and_then(|rest| rest.strip_prefix("://")) else {
+            return Err(format!("invite must start with \"{}://\"", SCHEME));
+        };
+
+        let (authority, rest) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rest, None),
+        };
+
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let Some((host, port)) = bracketed.split_once("]:") else {
+                return Err(String::from("invite is missing a port after the IPv6 address"));
+            };
+            (host, port)
+        } else {
+            let Some((host, port)) = authority.rsplit_once(':') else {
+                return Err(String::from("invite is missing a port"));
+            };
+            (host, port)
+        };
+        if host.is_empty() {
+            return Err(String::from("invite is missing a host"));
+        }
+        let Ok(port) = port.parse() else { return Err(format!("invalid port \"{}\" in invite", port)) };
+
+        let mut invite = Invite::new(host.to_string(), port);
+
+        let room = path.trim_matches('/');
+        if !room.is_empty() {
+            invite.room = Some(percent_decode(room)?);
+        }
+
+        for param in query.into_iter().flat_map(|query| query.split('&')).filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "tls" => invite.tls = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err(format!("invalid tls value \"{}\" in invite", value)),
+                },
+                "token" => invite.token = Some(percent_decode(value)?),
+                _ => return Err(format!("unknown parameter \"{}\" in invite", key)),
+            }
+        }
+
+        Ok(invite)
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}/", SCHEME, self.host, self.port)?;
+        } else {
+            write!(f, "{}://{}:{}/", SCHEME, self.host, self.port)?;
+        }
+
+        if let Some(room) = &self.room {
+            write!(f, "{}", percent_encode(room))?;
+        }
+
+        let mut params = Vec::new();
+        if self.tls {
+            params.push(String::from("tls=1"));
+        }
+        if let Some(token) = &self.token {
+            params.push(format!("token={}", percent_encode(token)));
+        }
+        if !params.is_empty() {
+            write!(f, "?{}", params.join("&"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns a fresh random room code, for pairing clients on a rendezvous server, e.g. `q7rk-2mfx-h3wa-tz8e`. Anyone
+/// with the code can enter the room, so it's long enough not to be guessed.
+pub fn new_room_code() -> String {
+    let mut rng = rand::thread_rng();
+    let groups: Vec<String> = (0..4)
+        .map(|_| (0..4).map(|_| ROOM_CODE_ALPHABET[rng.gen_range(0..ROOM_CODE_ALPHABET.len())] as char).collect())
+        .collect();
+    groups.join("-")
+}
+
+/// Checks that `code` can be used as a room code on a rendezvous server: not empty, no longer than
+/// `MAX_ROOM_CODE_LEN`, and without control characters.
+///
+/// # Errors
+///
+/// Returns a message saying what's wrong with the code.
+pub fn check_room_code(code: &str) -> Result<(), String> {
+    if code.is_empty() {
+        Err(String::from("room code can't be empty"))
+    } else if code.len() > MAX_ROOM_CODE_LEN {
+        Err(format!("room code can't be longer than {} bytes", MAX_ROOM_CODE_LEN))
+    } else if code.chars().any(char::is_control) {
+        Err(String::from("room code can't contain control characters"))
+    } else {
+        Ok(())
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next(), iter.next()];
+            let decoded = match hex {
+                [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None,
+            };
+            let Some(decoded) = decoded else { return Err(format!("invalid percent-encoding in \"{}\"", s)) };
+            bytes.push(decoded);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| format!("invalid UTF-8 in \"{}\"", s))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_invite() {
+        let invite: Invite = "mls-chat://example.org:4000/book%20club?tls=1&token=a%2Bb".parse().unwrap();
+
+        assert_eq!(invite, Invite {
+            host: String::from("example.org"),
+            port: 4000,
+            room: Some(String::from("book club")),
+            tls: true,
+            token: Some(String::from("a+b")),
+        });
+        assert_eq!(invite.to_string(), "mls-chat://example.org:4000/book%20club?tls=1&token=a%2Bb");
+    }
+
+    #[test]
+    fn parse_ipv6_invite() {
+        let invite: Invite = "mls-chat://[::1]:4000".parse().unwrap();
+
+        assert_eq!(invite, Invite::new(String::from("::1"), 4000));
+        assert_eq!(invite.to_string(), "mls-chat://[::1]:4000/");
+    }
+
+    #[test]
+    fn reject_malformed_invites() {
+        for uri in ["http://host:1/", "mls-chat://host/", "mls-chat://:1/", "mls-chat://host:1/?x=1", "mls-chat://host:1/%zz"] {
+            assert!(uri.parse::<Invite>().is_err(), "{} parsed successfully", uri);
+        }
+    }
+
+    #[test]
+    fn new_room_codes_are_valid() {
+        let code = new_room_code();
+        assert_eq!(code.len(), 19);
+        assert!(check_room_code(&code).is_ok());
+        assert_ne!(code, new_room_code());
+
+        assert!(check_room_code("").is_err());
+        assert!(check_room_code("line\nbreak").is_err());
+        assert!(check_room_code(&"x".repeat(MAX_ROOM_CODE_LEN + 1)).is_err());
+    }
+}
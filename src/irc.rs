@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+use crate::{
+    ApplicationError,
+    NetworkError,
+    chat_log::{ChatLog, EntryKind, LogEntry},
+    frontend::{self, ChatView, InputQueue, InputSender, UserInput},
+};
+
+use tokio::sync::Notify;
+
+/// Name the gateway gives itself as an IRC server.
+const SERVER_NAME: &str = "mls_chat";
+/// Prefix marking messages relayed from IRC into the group.
+const FROM_IRC: &str = "[irc]";
+/// Told to every IRC client on connecting, and to the group whenever one joins the channel.
+const PLAINTEXT_WARNING: &str = "This IRC gateway is plaintext: what's said through it isn't end-to-end encrypted, \
+    and anyone on the network between it and its IRC users can read and change it.";
+
+/// IRC clients registered with the gateway, by nick, and whether each has joined the channel.
+#[derive(Default)]
+struct Clients {
+    streams: HashMap<String, (TcpStream, bool)>,
+}
+
+impl Clients {
+    /// Sends a line to every client in the channel other than `except`.
+    fn broadcast(&mut self, line: &str, except: Option<&str>) {
+        for (nick, (stream, joined)) in &mut self.streams {
+            if *joined && Some(nick.as_str()) != except {
+                // a client that's gone is removed by its own thread
+                let _ = write!(stream, "{}\r\n", line);
+            }
+        }
+    }
+}
+
+/// A view for a gateway member of the group that lets plain IRC clients take part: it listens for IRC connections,
+/// relays what's said in one IRC channel to the group, marked `[irc]`, and relays the group's messages back to the
+/// channel under each sender's name. MLS ends at the gateway, so its IRC users don't get end-to-end encryption, and
+/// the group and the IRC users are both told so. It's meant for a trusted LAN only.
+///
+/// Clones share the same listener and clients, so a clone can be handed to each attempt at building a `Controller`.
+#[derive(Clone)]
+pub struct IrcView {
+    address: SocketAddr,
+    channel: String,
+    clients: Arc<Mutex<Clients>>,
+    input: InputQueue,
+}
+
+impl IrcView {
+    /// Starts listening for IRC clients on `address`, relaying the IRC channel `channel` (e.g. `#mls_chat`), spawning
+    /// threads to serve the clients without blocking the `Controller`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if the address can't be listened on.
+    pub fn bind(address: SocketAddr, channel: String) -> Result<IrcView, ApplicationError> {
+        let listener = TcpListener::bind(address).map_err(|_| NetworkError::Io)?;
+        let address = listener.local_addr().map_err(|_| NetworkError::Io)?;
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        let (tx, input) = frontend::input_queue();
+
+        let shared = Arc::clone(&clients);
+        let served = channel.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let (clients, channel, tx) = (Arc::clone(&shared), served.clone(), tx.clone());
+                thread::spawn(move || {
+                    let _ = serve(stream, &clients, &channel, &tx);
+                });
+            }
+        });
+
+        Ok(IrcView { address, channel, clients, input })
+    }
+
+    /// Returns the address the gateway is listening on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+/// Serves one IRC client until it quits or disconnects, passing what it says in the channel to `tx`.
+fn serve(stream: TcpStream, clients: &Mutex<Clients>, channel: &str, tx: &InputSender) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut nick: Option<String> = None;
+    let mut registered = false;
+    writeln_irc(&mut writer, &format!(":{} NOTICE * :{}", SERVER_NAME, PLAINTEXT_WARNING))?;
+
+    let result = (|| {
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let (command, params) = parse(&line);
+            match (command.to_ascii_uppercase().as_str(), registered) {
+                ("CAP", _) => writeln_irc(&mut writer, &format!(":{} CAP * LS :", SERVER_NAME))?,
+                ("PING", _) => writeln_irc(&mut writer, &format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, params.join(" ")))?,
+                ("QUIT", _) => break,
+                ("NICK", false) => {
+                    let Some(wanted) = params.first().filter(|wanted| valid_nick(wanted)) else {
+                        writeln_irc(&mut writer, &format!(":{} 432 * :Erroneous nickname", SERVER_NAME))?;
+                        continue;
+                    };
+                    let mut clients = clients.lock().unwrap();
+                    if clients.streams.contains_key(*wanted) {
+                        writeln_irc(&mut writer, &format!(":{} 433 * {} :Nickname is already in use", SERVER_NAME, wanted))?;
+                        continue;
+                    }
+                    if let Some(previous) = nick.replace(wanted.to_string()) {
+                        clients.streams.remove(&previous);
+                    }
+                    clients.streams.insert(wanted.to_string(), (writer.try_clone()?, false));
+                }
+                ("USER", false) => {
+                    let Some(nick) = &nick else { continue };
+                    registered = true;
+                    writeln_irc(&mut writer, &format!(":{} 001 {} :Welcome to the mls_chat IRC gateway", SERVER_NAME, nick))?;
+                    writeln_irc(&mut writer, &format!(":{} 422 {} :Join {} to talk to the group", SERVER_NAME, nick, channel))?;
+                }
+                (_, false) => writeln_irc(&mut writer, &format!(":{} 451 * :You have not registered", SERVER_NAME))?,
+                ("JOIN", true) => {
+                    let nick = nick.as_deref().unwrap_or_default();
+                    if !params.first().is_some_and(|joined| joined.eq_ignore_ascii_case(channel)) {
+                        writeln_irc(&mut writer, &format!(":{} 403 {} {} :Only {} is bridged", SERVER_NAME, nick, params.first().unwrap_or(&""), channel))?;
+                        continue;
+                    }
+                    let mut clients = clients.lock().unwrap();
+                    if let Some((_, joined)) = clients.streams.get_mut(nick) {
+                        *joined = true;
+                    }
+                    clients.broadcast(&format!(":{}!{}@{} JOIN {}", nick, nick, SERVER_NAME, channel), None);
+                    let names: Vec<&str> = clients.streams.iter().filter(|(_, (_, joined))| *joined).map(|(nick, _)| nick.as_str()).collect();
+                    writeln_irc(&mut writer, &format!(":{} 353 {} = {} :{}", SERVER_NAME, nick, channel, names.join(" ")))?;
+                    writeln_irc(&mut writer, &format!(":{} 366 {} {} :End of /NAMES list", SERVER_NAME, nick, channel))?;
+                    drop(clients);
+                    let _ = tx.send(format!("{} {} joined through the IRC gateway. {}", FROM_IRC, nick, PLAINTEXT_WARNING));
+                }
+                ("PRIVMSG" | "NOTICE", true) => {
+                    let nick = nick.as_deref().unwrap_or_default();
+                    let (Some(target), Some(text)) = (params.first(), params.get(1)) else { continue };
+                    if !target.eq_ignore_ascii_case(channel) || text.is_empty() {
+                        continue;
+                    }
+                    clients.lock().unwrap().broadcast(&format!(":{}!{}@{} PRIVMSG {} :{}", nick, nick, SERVER_NAME, channel, text), Some(nick));
+                    let _ = tx.send(format!("{} {}: {}", FROM_IRC, nick, text));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    })();
+
+    if let Some(nick) = nick {
+        let mut clients = clients.lock().unwrap();
+        if clients.streams.remove(&nick).is_some_and(|(_, joined)| joined) {
+            clients.broadcast(&format!(":{}!{}@{} QUIT :Quit", nick, nick, SERVER_NAME), None);
+        }
+    }
+    result
+}
+
+fn writeln_irc(stream: &mut TcpStream, line: &str) -> io::Result<()> {
+    write!(stream, "{}\r\n", line)
+}
+
+/// Splits an IRC line into its command and parameters, dropping any source prefix. A parameter starting with `:` takes
+/// the rest of the line.
+fn parse(line: &str) -> (&str, Vec<&str>) {
+    let mut rest = line.trim_end();
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+    }
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+    let mut params = vec![];
+    while !rest.is_empty() {
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing);
+            break;
+        }
+        let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+        if !param.is_empty() {
+            params.push(param);
+        }
+        rest = next;
+    }
+    (command, params)
+}
+
+fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty() && nick.len() <= 30 && nick.chars().all(|c| c.is_ascii_alphanumeric() || "-_[]{}\\|^`".contains(c))
+}
+
+/// Turns a group member's name into one IRC clients will accept as a nick.
+fn irc_nick(name: &str) -> String {
+    let nick: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || "-_[]{}\\|^`".contains(c) { c } else { '_' }).collect();
+    if nick.is_empty() { String::from("_") } else { nick }
+}
+
+impl ChatView for IrcView {
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.input.poll())
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        // the gateway's own messages are what it relayed from IRC, which its IRC users have already seen
+        let EntryKind::Chat { sender, author: Some(_), .. } = &entry.kind else { return };
+        if entry.deleted {
+            return;
+        }
+        let nick = irc_nick(sender);
+        let mut clients = self.clients.lock().unwrap();
+        for line in entry.text.lines() {
+            clients.broadcast(&format!(":{}!{}@{} PRIVMSG {} :{}", nick, nick, SERVER_NAME, self.channel, line), None);
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn set_topic(&mut self, topic: Option<String>) {
+        let line = format!(":{} TOPIC {} :{}", SERVER_NAME, self.channel, topic.unwrap_or_default());
+        self.clients.lock().unwrap().broadcast(&line, None);
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        self.input.ready()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Waits for the next input from the gateway, which its threads pass on in their own time.
+    fn next_input(view: &mut IrcView) -> Option<UserInput> {
+        for _ in 0..500 {
+            if let Some(input) = view.poll_input().unwrap() {
+                return Some(input);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn parse_irc_lines() {
+        assert_eq!(parse("PRIVMSG #mls_chat :hello there"), ("PRIVMSG", vec!["#mls_chat", "hello there"]));
+        assert_eq!(parse(":alice!a@host JOIN #mls_chat"), ("JOIN", vec!["#mls_chat"]));
+        assert_eq!(parse("USER alice 0 * :Alice A"), ("USER", vec!["alice", "0", "*", "Alice A"]));
+        assert_eq!(parse("QUIT"), ("QUIT", vec![]));
+        assert_eq!(irc_nick("bob smith"), "bob_smith");
+        assert!(!valid_nick("bad nick"));
+    }
+
+    #[test]
+    fn relays_between_irc_and_the_group() {
+        let mut view = IrcView::bind(SocketAddr::from(([127, 0, 0, 1], 0)), String::from("#mls_chat")).unwrap();
+        let mut stream = TcpStream::connect(view.address()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        write!(stream, "NICK carol\r\nUSER carol 0 * :Carol\r\nJOIN #mls_chat\r\nPRIVMSG #mls_chat :hi all\r\n").unwrap();
+
+        assert!(next_input(&mut view).is_some_and(|input| matches!(input, UserInput::Line(line) if line.contains("joined"))));
+        assert_eq!(next_input(&mut view), Some(UserInput::Line(String::from("[irc] carol: hi all"))));
+
+        let mut log = ChatLog::new(10);
+        log.push_chat(1, 0, String::from("alice"), Some(0), "gateway", String::from("welcome"));
+        view.push_message(log.last().unwrap());
+        let relayed = lines.find(|line| line.as_ref().is_ok_and(|line| line.contains("PRIVMSG"))).unwrap().unwrap();
+        assert_eq!(relayed, ":alice!alice@mls_chat PRIVMSG #mls_chat :welcome");
+    }
+}
@@ -1,86 +1,477 @@
-use openmls::prelude::*;
-use openmls_basic_credential::SignatureKeyPair;
-use openmls_rust_crypto::OpenMlsRustCrypto;
-
-// prelude for easy use in main:
-pub use crate::controller::Controller;
-pub use crate::network::server::Server;
-pub use crate::errors::ApplicationError;
-pub use crate::user::User;
-
-#[macro_use]
-extern crate lazy_static;
-
-// constants for use in the group and user mods:
-static CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519;
-lazy_static!( static ref PROVIDER: OpenMlsRustCrypto = OpenMlsRustCrypto::default(); );
-
-
-pub mod controller;
-pub mod errors;
-pub mod group;
-pub mod network;
-pub mod user;
-pub mod view;
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn serialize_key_package() {
-        let mut bob = User::build(String::from("bob")).unwrap();
-        let key_package = bob.generate_key_package();
-        let key_package = key_package.tls_serialize_detached();
-
-        assert!(key_package.is_ok(), "Key package returns error: {:?}", key_package);
-    }
-
-    #[test]
-    fn deserialize_key_package() {
-        let mut bob = User::build(String::from("bob")).unwrap();
-        let key_package = bob.generate_key_package();
-        let key_package = key_package.tls_serialize_detached().unwrap();
-        let key_package = KeyPackageIn::tls_deserialize(&mut key_package.as_slice());
-
-        assert!(key_package.is_ok(), "Key package returns error: {:?}", key_package);
-    }
-
-    #[test]
-    fn update_keys() {
-        let mut bob = User::build(String::from("bob")).unwrap();
-        let _key_package = bob.generate_key_package();
-        let update = bob.update_keys();
-
-        assert!(update.is_ok(), "Key update returns error: {:?}", update);
-    }
-
-    #[test]
-    fn join_from_welcome() {
-        let mut bob = User::build(String::from("bob")).unwrap();
-        let key_package = KeyPackageIn::tls_deserialize(&mut
-            (bob.generate_key_package()
-                .tls_serialize_detached()
-                .unwrap())
-                .as_slice())
-                .unwrap();
-        let mut alice = User::build(String::from("alice")).unwrap();
-        let res = alice.add_member(key_package);
-
-        assert!(res.is_ok(), "add_member returns error: {:?}", res);
-
-        let (_commit, welcome) = res.unwrap();
-        let welcome = Welcome::tls_deserialize(&mut
-            (welcome.tls_serialize_detached()
-            .unwrap())
-            .as_slice());
-        
-        assert!(welcome.is_ok(), "Welcome::tls_deserialize returns error: {:?}", welcome);
-
-        let welcome = welcome.unwrap();
-        let res = bob.join_group(welcome);
-
-        assert!(res.is_ok(), "join_group returns error: {:?}", res);
-    }
+use openmls::prelude::*;
+use openmls_basic_credential::SignatureKeyPair;
+use crate::store::Provider;
+
+// prelude for easy use in main:
+pub use crate::backup::Backup;
+pub use crate::config::ClientConfig;
+pub use crate::controller::Controller;
+pub use crate::network::server::Server;
+pub use crate::errors::{ApplicationError, MlsError, NetworkError, StorageError, UiError};
+pub use crate::invite::Invite;
+pub use crate::link::LinkCode;
+pub use crate::session::Session;
+pub use crate::user::User;
+
+#[macro_use]
+extern crate lazy_static;
+
+// constants for use in the group and user mods:
+static CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519;
+/// MLS protocol version key packages must be for to be added to the group.
+static PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::Mls10;
+/// Seconds a key package stays valid for after it's generated.
+static KEY_PACKAGE_LIFETIME: u64 = 60 * 60 * 24 * 7;
+lazy_static!( static ref PROVIDER: Provider = Provider::default(); );
+
+
+pub mod audit;
+pub mod backup;
+pub mod bench;
+pub mod bot;
+pub mod chat_log;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod controller;
+pub mod doctor;
+pub mod errors;
+pub mod frontend;
+pub mod group;
+#[cfg(test)]
+mod interop;
+pub mod invite;
+pub mod irc;
+pub mod link;
+pub mod markup;
+#[cfg(feature = "matrix-bridge")]
+pub mod matrix;
+pub mod message;
+pub mod metrics;
+pub mod network;
+pub mod plain;
+pub mod policy;
+pub mod session;
+#[cfg(test)]
+mod sim;
+pub mod snapshot;
+pub mod store;
+pub mod theme;
+pub mod trace;
+pub mod transcript;
+pub mod user;
+pub mod view;
+pub mod webhook;
+pub mod wipe;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{group::TreeNode, session::Session, user::Processed};
+
+    /// Passes a message through its wire format, as the server relays it.
+    fn relay(msg: MlsMessageOut) -> MlsMessageInBody {
+        MlsMessageIn::tls_deserialize(&mut msg.tls_serialize_detached().unwrap().as_slice()).unwrap().extract()
+    }
+
+    fn protocol_message(msg: MlsMessageOut) -> ProtocolMessage {
+        match relay(msg) {
+            MlsMessageInBody::PrivateMessage(msg) => msg.into(),
+            MlsMessageInBody::PublicMessage(msg) => msg.into(),
+            _ => panic!("Relayed message isn't a protocol message"),
+        }
+    }
+
+    /// Returns alice and bob in a group together, after alice's commit adding bob was sequenced and merged.
+    fn pair() -> (User, User) {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = relay_key_package(bob.generate_key_package().unwrap());
+        let mut alice = User::build(String::from("alice")).unwrap();
+
+        let (_commit, welcome) = alice.add_member(key_package).unwrap();
+        alice.merge_pending_commit().unwrap();
+        let MlsMessageInBody::Welcome(welcome) = relay(welcome) else { panic!("Relayed message isn't a Welcome") };
+        bob.join_group(welcome, None).unwrap();
+
+        (alice, bob)
+    }
+
+    fn relay_key_package(key_package: KeyPackage) -> KeyPackageIn {
+        KeyPackageIn::tls_deserialize(&mut key_package.tls_serialize_detached().unwrap().as_slice()).unwrap()
+    }
+
+    #[test]
+    fn serialize_key_package() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = bob.generate_key_package().unwrap();
+        let key_package = key_package.tls_serialize_detached();
+
+        assert!(key_package.is_ok(), "Key package returns error: {:?}", key_package);
+    }
+
+    #[test]
+    fn deserialize_key_package() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = bob.generate_key_package().unwrap();
+        let key_package = key_package.tls_serialize_detached().unwrap();
+        let key_package = KeyPackageIn::tls_deserialize(&mut key_package.as_slice());
+
+        assert!(key_package.is_ok(), "Key package returns error: {:?}", key_package);
+    }
+
+    #[test]
+    fn update_keys() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let _key_package = bob.generate_key_package().unwrap();
+        let update = bob.update_keys();
+
+        assert!(update.is_ok(), "Key update returns error: {:?}", update);
+    }
+
+    #[test]
+    fn join_from_welcome() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = KeyPackageIn::tls_deserialize(&mut
+            (bob.generate_key_package().unwrap()
+                .tls_serialize_detached()
+                .unwrap())
+                .as_slice())
+                .unwrap();
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let res = alice.add_member(key_package);
+
+        assert!(res.is_ok(), "add_member returns error: {:?}", res);
+
+        let (_commit, welcome) = res.unwrap();
+        let welcome = MlsMessageIn::tls_deserialize(&mut
+            (welcome.tls_serialize_detached()
+            .unwrap())
+            .as_slice());
+        
+        assert!(welcome.is_ok(), "MlsMessageIn::tls_deserialize returns error: {:?}", welcome);
+
+        let MlsMessageInBody::Welcome(welcome) = welcome.unwrap().extract() else {
+            panic!("Deserialized message isn't a Welcome");
+        };
+        let res = bob.join_group(welcome, None);
+
+        assert!(res.is_ok(), "join_group returns error: {:?}", res);
+    }
+
+    #[test]
+    fn commits_wait_to_be_merged() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = relay_key_package(bob.generate_key_package().unwrap());
+        let mut alice = User::build(String::from("alice")).unwrap();
+
+        alice.add_member(key_package).unwrap();
+        assert!(alice.has_pending_commit());
+        assert_eq!(alice.epoch(), Some(0));
+        assert!(matches!(alice.update_keys(), Err(ApplicationError::Mls(MlsError::CommitPending))));
+
+        alice.merge_pending_commit().unwrap();
+        assert!(!alice.has_pending_commit());
+        assert_eq!(alice.epoch(), Some(1));
+    }
+
+    #[test]
+    fn epochs_converge_after_updates() {
+        let (mut alice, mut bob) = pair();
+        assert_eq!(alice.epoch(), bob.epoch());
+
+        for _ in 0..3 {
+            let update = bob.update_keys().unwrap();
+            alice.process_message(protocol_message(update)).unwrap();
+            bob.merge_pending_commit().unwrap();
+
+            let update = alice.update_keys().unwrap();
+            bob.process_message(protocol_message(update)).unwrap();
+            alice.merge_pending_commit().unwrap();
+        }
+
+        assert_eq!(alice.epoch(), Some(7));
+        assert_eq!(alice.epoch(), bob.epoch());
+        assert_eq!(alice.verification_code(), bob.verification_code());
+    }
+
+    #[test]
+    fn summarize_commits() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = relay_key_package(bob.generate_key_package().unwrap());
+        let mut alice = User::build(String::from("alice")).unwrap();
+
+        alice.add_member(key_package).unwrap();
+        let summary = alice.merge_pending_commit().unwrap().unwrap();
+        assert_eq!((summary.committer.as_str(), summary.epoch), ("alice", 1));
+        assert_eq!(summary.added, vec!["bob"]);
+        assert!(summary.removed.is_empty() && summary.updated.is_empty());
+        assert_eq!(alice.merge_pending_commit().unwrap(), None);
+
+        let (mut alice, mut bob) = pair();
+        let update = bob.update_keys().unwrap();
+        let Ok(Processed::Commit { summary, .. }) = alice.process_message(protocol_message(update)) else {
+            panic!("Update wasn't merged as a commit");
+        };
+        assert_eq!((summary.committer.as_str(), summary.epoch), ("bob", 2));
+        assert_eq!(summary.updated, vec!["bob"]);
+    }
+
+    #[test]
+    fn concurrent_commits_resolve_in_relay_order() {
+        let (mut alice, mut bob) = pair();
+        let from_alice = alice.update_keys().unwrap();
+        let from_bob = bob.update_keys().unwrap();
+
+        // the server relays alice's commit first, so bob's is dropped by everyone, including bob
+        let processed = bob.process_message(protocol_message(from_alice)).unwrap();
+        assert!(matches!(processed, Processed::Commit { superseded: true, .. }));
+        assert!(!bob.has_pending_commit());
+        alice.merge_pending_commit().unwrap();
+        assert!(matches!(alice.process_message(protocol_message(from_bob)), Ok(Processed::Other)));
+
+        assert_eq!(alice.epoch(), bob.epoch());
+        assert_eq!(alice.verification_code(), bob.verification_code());
+        let msg = bob.encrypt_message(b"still in sync").unwrap();
+        assert!(matches!(alice.process_message(protocol_message(msg)), Ok(Processed::Application(_))));
+    }
+
+    #[test]
+    fn removed_member_cannot_encrypt() {
+        let (mut alice, mut bob) = pair();
+        let bob_leaf = alice.roster().into_iter().find(|member| !member.own).unwrap().index;
+        let removal = alice.remove_member(bob_leaf).unwrap();
+        alice.merge_pending_commit().unwrap();
+        bob.process_message(protocol_message(removal)).unwrap();
+
+        assert!(matches!(bob.encrypt_message(b"anyone?"), Err(ApplicationError::Mls(MlsError::CreateMessage(_)))));
+    }
+
+    #[test]
+    fn reject_expired_key_package() {
+        let credential = Credential::new(b"bob".to_vec(), CredentialType::Basic).unwrap();
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+        let credential_with_key = CredentialWithKey { credential, signature_key: signer.public().into() };
+        let key_package = KeyPackage::builder()
+            .key_package_lifetime(Lifetime::new(0))
+            .build(CryptoConfig::with_default_version(CIPHERSUITE), &(*PROVIDER), &signer, credential_with_key)
+            .unwrap();
+        let key_package = KeyPackageIn::tls_deserialize(&mut key_package.tls_serialize_detached().unwrap().as_slice()).unwrap();
+
+        let mut alice = User::build(String::from("alice")).unwrap();
+        assert!(matches!(alice.add_member(key_package), Err(ApplicationError::Mls(MlsError::KeyPackageExpired))));
+    }
+
+    #[test]
+    fn reject_incompatible_key_packages() {
+        let credential = Credential::new(b"bob".to_vec(), CredentialType::Basic).unwrap();
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+        let credential_with_key = CredentialWithKey { credential, signature_key: signer.public().into() };
+        let build = |crypto_config| {
+            let key_package = KeyPackage::builder()
+                .build(crypto_config, &(*PROVIDER), &signer, credential_with_key.clone())
+                .unwrap();
+            KeyPackageIn::tls_deserialize(&mut key_package.tls_serialize_detached().unwrap().as_slice()).unwrap()
+        };
+        let mut alice = User::build(String::from("alice")).unwrap();
+
+        let other_suite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+        let key_package = build(CryptoConfig::with_default_version(other_suite));
+        assert!(matches!(
+            alice.add_member(key_package),
+            Err(ApplicationError::Mls(MlsError::KeyPackageCiphersuite(suite))) if suite == other_suite
+        ));
+
+        let key_package = build(CryptoConfig { ciphersuite: CIPHERSUITE, version: ProtocolVersion::Mls10Draft11 });
+        assert!(matches!(alice.add_member(key_package), Err(ApplicationError::Mls(MlsError::KeyPackageVersion))));
+        assert_eq!(alice.roster().len(), 1);
+    }
+
+    #[test]
+    fn reject_tampered_key_package() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let mut data = bob.generate_key_package().unwrap().tls_serialize_detached().unwrap();
+        assert!(KeyPackageIn::tls_deserialize(&mut &data[..data.len() / 2]).is_err());
+
+        // flipping a bit of the signature still deserializes, but no longer verifies
+        *data.last_mut().unwrap() ^= 1;
+        let key_package = KeyPackageIn::tls_deserialize(&mut data.as_slice()).unwrap();
+        let mut alice = User::build(String::from("alice")).unwrap();
+        assert!(matches!(alice.add_member(key_package), Err(ApplicationError::Mls(MlsError::KeyPackageVerify(_)))));
+        assert_eq!(alice.roster().len(), 1);
+    }
+
+    #[test]
+    fn reject_tampered_commit() {
+        let (mut alice, mut bob) = pair();
+        let mut tampered = bob.update_keys().unwrap().tls_serialize_detached().unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let msg = MlsMessageIn::tls_deserialize(&mut tampered.as_slice()).unwrap();
+        let msg: ProtocolMessage = match msg.extract() {
+            MlsMessageInBody::PrivateMessage(msg) => msg.into(),
+            MlsMessageInBody::PublicMessage(msg) => msg.into(),
+            _ => panic!("Relayed message isn't a protocol message"),
+        };
+        assert!(alice.process_message(msg).is_err());
+        assert_eq!(alice.epoch(), Some(1));
+
+        // openmls spends the key the tampered copy was sent with, so bob's commit is lost, but the group carries on
+        // once the next commit supersedes it
+        let update = alice.update_keys().unwrap();
+        let processed = bob.process_message(protocol_message(update)).unwrap();
+        assert!(matches!(processed, Processed::Commit { superseded: true, .. }));
+        alice.merge_pending_commit().unwrap();
+        assert_eq!(alice.epoch(), bob.epoch());
+        let msg = bob.encrypt_message(b"still in sync").unwrap();
+        assert!(matches!(alice.process_message(protocol_message(msg)), Ok(Processed::Application(_))));
+    }
+
+    #[test]
+    fn roster_lists_members() {
+        let mut bob = User::build(String::from("bob")).unwrap();
+        let key_package = KeyPackageIn::tls_deserialize(&mut
+            (bob.generate_key_package().unwrap()
+                .tls_serialize_detached()
+                .unwrap())
+                .as_slice())
+                .unwrap();
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let (_commit, welcome) = alice.add_member(key_package).unwrap();
+        let welcome = MlsMessageIn::tls_deserialize(&mut welcome.tls_serialize_detached().unwrap().as_slice()).unwrap();
+        let MlsMessageInBody::Welcome(welcome) = welcome.extract() else { panic!("Deserialized message isn't a Welcome") };
+        bob.join_group(welcome, None).unwrap();
+
+        let roster = bob.roster();
+        let names: Vec<&str> = roster.iter().map(|member| member.identity.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert!(roster[1].own && !roster[0].own);
+        // the creator's leaf can't carry a profile, but everyone added from a key package does
+        assert_eq!(roster[0].profile, None);
+        assert_eq!(roster[1].profile.as_ref(), Some(bob.profile()));
+    }
+
+    #[test]
+    fn tree_shows_every_node() {
+        let (alice, bob) = pair();
+        let tree = bob.tree();
+
+        assert_eq!(tree.len(), 3);
+        assert!(matches!(&tree[0], TreeNode::Leaf { index: 0, identity, own: false } if identity == "alice"));
+        assert!(matches!(&tree[2], TreeNode::Leaf { index: 1, identity, own: true } if identity == "bob"));
+        assert_eq!(alice.tree()[1], tree[1]);
+    }
+
+    #[test]
+    fn resume_session() {
+        let (mut alice, mut bob) = pair();
+        let path = std::env::temp_dir().join(format!("mls_chat_session_{}.json", std::process::id()));
+        Session::capture(&mut bob, Some(7), vec![]).unwrap().save(&path).unwrap();
+        let (epoch, leaf) = (bob.epoch(), bob.own_index());
+        drop(bob);
+
+        let mut session = Session::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(session.user.id(), "bob");
+        assert_eq!(session.last_seq, Some(7));
+        let mut bob = session.resume_user().unwrap();
+        assert_eq!((bob.epoch(), bob.own_index()), (epoch, leaf));
+
+        let msg = alice.encrypt_message(b"welcome back").unwrap();
+        assert!(matches!(bob.process_message(protocol_message(msg)), Ok(Processed::Application(_))));
+        let msg = bob.encrypt_message(b"thanks").unwrap();
+        assert!(matches!(alice.process_message(protocol_message(msg)), Ok(Processed::Application(_))));
+    }
+
+    #[test]
+    fn verification_code() {
+        let alice = User::build(String::from("alice")).unwrap();
+        let code = alice.verification_code().unwrap();
+
+        assert_eq!(code.len(), 23, "{}", code);
+        assert!(code.split(' ').all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+        assert_eq!(alice.verification_code().unwrap(), code);
+    }
+
+    #[test]
+    fn pad_messages() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let mut length = |text: &str| alice.encrypt_message(text.as_bytes()).unwrap().tls_serialize_detached().unwrap().len();
+        assert_ne!(length("hi"), length("a somewhat longer message"));
+
+        alice.set_padding(256);
+        let mut length = |text: &str| alice.encrypt_message(text.as_bytes()).unwrap().tls_serialize_detached().unwrap().len();
+        assert_eq!(length("hi"), length("a somewhat longer message"));
+        assert_ne!(length("hi"), length(&"a".repeat(300)));
+    }
+
+    #[test]
+    fn export_secret() {
+        let alice = User::build(String::from("alice")).unwrap();
+        let secret = alice.export_secret("mls_chat test", b"context", 32).unwrap();
+
+        assert_eq!(secret.len(), 32);
+        assert_eq!(alice.export_secret("mls_chat test", b"context", 32).unwrap(), secret);
+        assert_ne!(alice.export_secret("mls_chat other", b"context", 32).unwrap(), secret);
+        assert_ne!(alice.export_secret("mls_chat test", b"other", 32).unwrap(), secret);
+    }
+
+    #[test]
+    fn refuse_commits_under_other_moderation_settings() {
+        let (mut alice, mut bob) = pair();
+        let moderation = config::ModerationConfig {
+            policy: config::ModerationPolicy::Enforce,
+            moderators: vec![String::from("alice")],
+        };
+        alice.set_moderation(moderation.clone());
+        alice.set_id(String::from("ally"));
+        assert!(alice.is_moderator());
+
+        bob.set_moderation(config::ModerationConfig { moderators: vec![String::from("alice"), String::from("bob")], ..moderation });
+        assert!(bob.note_moderation(0, alice.moderation_digest()));
+        assert!(!bob.note_moderation(0, alice.moderation_digest()));
+
+        let mut carol = User::build(String::from("carol")).unwrap();
+        let (commit, _welcome) = alice.add_member(relay_key_package(carol.generate_key_package().unwrap())).unwrap();
+        let processed = bob.process_message(protocol_message(commit)).unwrap();
+        assert!(matches!(processed, Processed::Refused { reason, .. } if reason.contains("moderation settings")));
+    }
+
+    #[test]
+    fn move_to_successor() {
+        let (mut alice, mut bob) = pair();
+        assert!(matches!(alice.reinit(3), Err(ApplicationError::Mls(MlsError::UnsupportedCiphersuite(3)))));
+        assert!(matches!(alice.reinit(2), Err(ApplicationError::Mls(MlsError::UnsupportedCiphersuite(2)))));
+
+        let group_id = alice.reinit(1).unwrap();
+        let key_package = relay_key_package(bob.successor_key_package(1).unwrap());
+        let (welcome, ratchet_tree) = alice.welcome_to_successor(vec![key_package]).unwrap();
+        alice.switch_to_successor().unwrap();
+        // sent before bob follows, so only the group alice left can read them
+        let late = protocol_message(bob.encrypt_message(b"still here").unwrap());
+        let later = protocol_message(bob.encrypt_message(b"too late").unwrap());
+        let MlsMessageInBody::Welcome(welcome) = relay(welcome) else { panic!("Relayed message isn't a Welcome") };
+        bob.join_successor(welcome, ratchet_tree.as_deref()).unwrap();
+
+        assert_eq!(alice.group_id().unwrap().as_slice(), group_id);
+        assert_eq!(bob.group_id(), alice.group_id());
+        assert_eq!(bob.ciphersuite(), Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+        assert_eq!(alice.successor_id(), None);
+        let msg = protocol_message(alice.encrypt_message(b"hi").unwrap());
+        let Processed::Application(received) = bob.process_message(msg).unwrap() else {
+            panic!("Message from alice isn't an application message");
+        };
+        assert_eq!(received.content, b"hi");
+
+        let bob_leaf = alice.roster().into_iter().find(|member| !member.own).unwrap().index;
+        let Processed::Application(received) = alice.process_message(late).unwrap() else {
+            panic!("Message from bob in the old group isn't an application message");
+        };
+        assert_eq!((received.sender, received.content.as_slice()), (bob_leaf, b"still here".as_slice()));
+        alice.drop_predecessor();
+        assert!(matches!(alice.process_message(later).unwrap(), Processed::Other));
+        assert_eq!(
+            group::key_package_ciphersuites(CIPHERSUITE),
+            vec![CIPHERSUITE, Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519],
+        );
+    }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+use std::{fmt, str::FromStr};
+use crate::{PROVIDER, group::Profile};
+
+use openmls_traits::{crypto::OpenMlsCrypto, types::HashType, OpenMlsCryptoProvider};
+use rand::Rng;
+
+/// Number of random bytes in a link code's secret.
+const SECRET_LEN: usize = 16;
+
+/// A one-time code shown by `/link` on a device already in the group, and passed to `join --link` on a new one.
+///
+/// The new device publishes key packages whose `Profile` carries the same `user_id` as the device that showed the code,
+/// along with a proof that it knows the code's secret: an HMAC of its signature key, keyed with the secret. Only the
+/// device holding the code can check the proof, so only it adds the new device, and the other members accept the
+/// shared `user_id` because the commit adding it came from a leaf that already had it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LinkCode {
+    pub user_id: String,
+    secret: Vec<u8>,
+}
+
+impl LinkCode {
+    /// Generates a fresh code for linking another device to the user with the given `user_id`.
+    pub fn generate(user_id: &str) -> LinkCode {
+        LinkCode { user_id: user_id.to_string(), secret: rand::thread_rng().gen::<[u8; SECRET_LEN]>().to_vec() }
+    }
+
+    /// Returns the proof a new device puts in its `Profile` for the signature key `signature_key`.
+    pub fn proof(&self, signature_key: &[u8]) -> String {
+        (*PROVIDER).crypto()
+            .hkdf_extract(HashType::Sha2_256, &self.secret, signature_key)
+            .map(|mac| to_hex(mac.as_slice()))
+            .unwrap_or_default()
+    }
+
+    /// Returns whether a key package with the given profile and signature key came from the device this code was
+    /// handed to.
+    pub fn verify(&self, profile: &Profile, signature_key: &[u8]) -> bool {
+        let Some(link) = &profile.link else { return false };
+        let expected = self.proof(signature_key);
+        // compared in full, so that the time taken doesn't give away how much of a guess was right
+        profile.user_id == self.user_id
+            && !expected.is_empty()
+            && link.len() == expected.len()
+            && link.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Display for LinkCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.user_id, to_hex(&self.secret))
+    }
+}
+
+// the secret is left out, so the code doesn't end up in logs
+impl fmt::Debug for LinkCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LinkCode").field("user_id", &self.user_id).finish_non_exhaustive()
+    }
+}
+
+impl FromStr for LinkCode {
+    type Err = String;
+
+    /// Parses a code in the `<user id>-<secret>` form `/link` shows.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || String::from("invalid link code: copy it exactly as /link showed it");
+
+        let (user_id, secret) = s.trim().rsplit_once('-').ok_or_else(invalid)?;
+        let secret = unhex(secret).filter(|secret| secret.len() == SECRET_LEN).ok_or_else(invalid)?;
+        if user_id.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(LinkCode { user_id: user_id.to_string(), secret })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes a string of hex digits, two to a byte, or returns None if it isn't one.
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_verify() {
+        let code = LinkCode::generate("00c0ffee00c0ffee");
+        let parsed: LinkCode = code.to_string().parse().unwrap();
+        assert_eq!(parsed, code);
+        assert!("00c0ffee00c0ffee-abcd".parse::<LinkCode>().is_err());
+
+        let mut profile = Profile {
+            user_id: code.user_id.clone(),
+            display_name: None,
+            link: Some(code.proof(b"new device")),
+        };
+        assert!(parsed.verify(&profile, b"new device"));
+        assert!(!parsed.verify(&profile, b"another device"));
+        assert!(!LinkCode::generate("00c0ffee00c0ffee").verify(&profile, b"new device"));
+
+        profile.user_id = String::from("someone else");
+        assert!(!parsed.verify(&profile, b"new device"));
+    }
+}
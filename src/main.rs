@@ -1,6 +1,12 @@
-use std::{ io::Result, net::IpAddr, process };
+use std::{ io::{self, IsTerminal, Result, Write}, net::{IpAddr, SocketAddr}, path::PathBuf, process, thread, time::Duration };
 use clap::{Parser, Subcommand};
-use mls_chat::*;
+use crossterm::{event::{self, Event, KeyCode, KeyEventKind, KeyModifiers}, terminal};
+use mls_chat::{*, config::ServerConfig, network::{access_log::AccessLog, address::{ServerAddr, Target}}};
+use tokio::runtime::{self, Runtime};
+use tokio_util::sync::CancellationToken;
+
+/// Most peers the server hosted by `join --listen` takes at once, which is also how many messages it queues for each.
+const HUB_SIZE: usize = 64;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -13,69 +19,919 @@ struct Args {
 enum Commands {
     /// host a chat server on this terminal
     Host {
-        /// network port to host on
+        /// network port to host on, unless systemd passes in a listening socket (socket activation)
         #[arg(short, long)]
         port: u16,
 
         /// number of concurrent connections allowed on server
         #[arg(short, long)]
         size: usize,
+
+        /// number of worker threads relaying messages (defaults to the number of CPU cores)
+        #[arg(long)]
+        threads: Option<usize>,
+
+        /// disconnect clients that send nothing for this many seconds (defaults to 90; 0 keeps idle clients connected)
+        #[arg(long, value_name="SECS")]
+        idle_timeout: Option<u64>,
+
+        /// address to print in the invite URI, if clients reach this server at something other than 127.0.0.1
+        #[arg(long, value_name="ADDRESS")]
+        advertise: Option<String>,
+
+        /// also let plain IRC clients on a trusted LAN take part through a gateway listening on this address (e.g.
+        /// 0.0.0.0:6667). Their messages aren't end-to-end encrypted
+        #[arg(long, value_name="ADDRESS", conflicts_with = "rendezvous")]
+        irc: Option<SocketAddr>,
+
+        /// IRC channel the gateway relays to the group
+        #[arg(long, value_name="CHANNEL", default_value = "#mls_chat", requires = "irc")]
+        irc_channel: String,
+
+        /// run as a public relay on every interface, pairing clients that can't host a server themselves (e.g. behind
+        /// NATs) by the room code they join with
+        #[arg(long)]
+        rendezvous: bool,
+
+        /// forward everyone joining ROOM (or the server, outside rendezvous mode) to the room's home on the server in
+        /// INVITE, so that members on both servers share one group (can be given more than once)
+        #[arg(long, value_name="[ROOM=]INVITE")]
+        federate: Vec<network::federation::Federation>,
+
+        /// keep created rooms and the messages waiting to be replayed to their members in this file, so they survive
+        /// restarting the server
+        #[arg(long, value_name="PATH")]
+        state: Option<PathBuf>,
+
+        /// take admin requests from `mls_chat admin` on a Unix socket at this path, to manage the server without its
+        /// terminal (e.g. when it runs as a daemon)
+        #[arg(long, value_name="PATH")]
+        admin_socket: Option<PathBuf>,
+
+        /// server config file, e.g. to turn on the access log (defaults to server.toml next to the client's config)
+        #[arg(long, value_name="PATH")]
+        config: Option<PathBuf>,
     },
 
     /// connect to an existing server
-    Join {
-        /// IP address to connect to
-        #[arg(short, long, value_name="ADDRESS")]
-        target: IpAddr,
+    Join(Box<JoinArgs>),
+
+    /// check step by step whether the server can be joined, for when joining doesn't work
+    Doctor {
+        /// invite URI (mls-chat://host:port/...) to take the target, port, and room from
+        invite: Option<Invite>,
 
-        /// network port to join on
+        /// hostname or IP address of the server, optionally with the port (defaults to the config file's server
+        /// address)
+        #[arg(short, long, value_name="HOST[:PORT]")]
+        target: Option<Target>,
+
+        /// network port of the server (defaults to the config file's server port)
         #[arg(short, long)]
-        port: u16,
+        port: Option<u16>,
+
+        /// room code to enter on a rendezvous server (defaults to the invite's room)
+        #[arg(long, value_name="CODE", value_parser = parse_room_code)]
+        room: Option<String>,
+
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
+    },
+
+    /// load-test a server with headless clients sending each other messages, reporting delivery latency and loss
+    Bench {
+        /// invite URI (mls-chat://host:port/...) to take the target, port, and room from
+        invite: Option<Invite>,
+
+        /// hostname or IP address of the server, optionally with the port (defaults to the config file's server
+        /// address)
+        #[arg(short, long, value_name="HOST[:PORT]")]
+        target: Option<Target>,
+
+        /// network port of the server (defaults to the config file's server port)
+        #[arg(short, long)]
+        port: Option<u16>,
+
+        /// room code to enter on a rendezvous server (defaults to the invite's room)
+        #[arg(long, value_name="CODE", value_parser = parse_room_code)]
+        room: Option<String>,
+
+        /// number of clients to connect
+        #[arg(short = 'n', long, default_value_t = 10)]
+        clients: usize,
+
+        /// messages per second sent across every client
+        #[arg(short, long, default_value_t = 100.0)]
+        rate: f64,
+
+        /// seconds to keep sending for
+        #[arg(short, long, value_name="SECS", default_value_t = 10)]
+        duration: u64,
+
+        /// size in bytes of each message
+        #[arg(long, value_name="BYTES", default_value_t = 256)]
+        size: usize,
+
+        /// instead of load-testing a server, measure locally what MLS costs in groups of these sizes, with and without
+        /// the ratchet tree in Welcomes (e.g. 50,100,500)
+        #[arg(long, value_name="SIZES", value_delimiter = ',')]
+        groups: Vec<usize>,
+
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
+    },
+
+    /// write the saved session to an encrypted backup, to move your identity and place in the group to another machine
+    ExportIdentity {
+        /// file to write the backup to
+        out: PathBuf,
+
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
+
+        /// overwrite an existing file
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// restore a backup made with export-identity as this machine's saved session
+    ImportIdentity {
+        /// backup file to import
+        backup: PathBuf,
+
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
+
+        /// replace a session already saved on this machine
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// write the chat log saved with the session to a transcript, as JSON, Markdown, or plain text
+    ExportLog {
+        /// file to write the transcript to
+        out: PathBuf,
+
+        /// json, markdown, or text (defaults to the one matching the file's extension, or text)
+        #[arg(long)]
+        format: Option<transcript::Format>,
+
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
+
+        /// overwrite an existing file
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// overwrite and delete the saved session, audit log, and config file
+    Wipe {
+        /// config file to use instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        config: Option<PathBuf>,
 
-        /// user id to identify with
+        /// don't ask for confirmation first
         #[arg(short, long)]
-        id: String,
+        yes: bool,
     },
+
+    /// manage the client config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// manage a server hosted with --admin-socket
+    Admin {
+        /// the server's admin socket
+        #[arg(short, long, value_name="PATH")]
+        socket: PathBuf,
+
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+// boxed in `Commands`, since they take up far more room than any other subcommand's
+#[derive(clap::Args)]
+struct JoinArgs {
+    /// invite URI (mls-chat://host:port/...) to take the target and port from
+    invite: Option<Invite>,
+
+    /// hostname or IP address to connect to, optionally with the port (e.g. chat.example.org:4000). Defaults to the
+    /// config file's server address
+    #[arg(short, long, value_name="HOST[:PORT]")]
+    target: Option<Target>,
+
+    /// network port to join on (defaults to the config file's server port)
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// user id to identify with (defaults to the config file's username)
+    #[arg(short, long)]
+    id: Option<String>,
+
+    /// config file to use instead of ~/.config/mls_chat/config.toml
+    #[arg(short, long, value_name="PATH")]
+    config: Option<PathBuf>,
+
+    /// use a line-based interface on stdin/stdout instead of the full-screen one
+    #[arg(long)]
+    plain: bool,
+
+    /// host the chat for peers to join directly, listening on this address (e.g. 0.0.0.0:4000), instead of joining a
+    /// server. The chat ends for everyone once you leave
+    #[arg(long, value_name="ADDRESS", conflicts_with_all = ["invite", "target", "port", "room"])]
+    listen: Option<SocketAddr>,
+
+    /// room code to enter on a rendezvous server (defaults to the invite's room)
+    #[arg(long, value_name="CODE", value_parser = parse_room_code)]
+    room: Option<String>,
+
+    /// join as another device of the user who showed this code with /link
+    #[arg(long, value_name="CODE")]
+    link: Option<LinkCode>,
+
+    /// run as a bot that sends each line POSTed to this address (e.g. 127.0.0.1:8080) as a message
+    #[arg(long, value_name="ADDRESS")]
+    webhook: Option<SocketAddr>,
+
+    /// run as a bot that answers messages containing TRIGGER with REPLY (can be given more than once)
+    #[arg(long, value_name="TRIGGER=REPLY", conflicts_with = "webhook")]
+    respond: Vec<bot::AutoResponder>,
+
+    /// append the type, epoch, sender, and size of every MLS message sent and received, and what was done with each
+    /// one received, to this file
+    #[arg(long, value_name="PATH")]
+    debug_mls: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// write a commented config template
+    Init {
+        /// where to write the template instead of ~/.config/mls_chat/config.toml
+        #[arg(short, long, value_name="PATH")]
+        path: Option<PathBuf>,
+
+        /// overwrite an existing config file
+        #[arg(short, long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommands {
+    /// list every open connection
+    List,
+    /// disconnect a connection, telling its client not to reconnect
+    Kick {
+        /// the connection's id, as listed
+        id: u64,
+    },
+    /// disconnect everyone connected from an address, and turn it away from now on
+    Ban {
+        address: IpAddr,
+    },
+    /// let a banned address connect again
+    Unban {
+        address: IpAddr,
+    },
+    /// show a message to everyone connected
+    Announce {
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// show connection, room, and traffic counts for the server
+    Stats,
+    /// shut the server down
+    Shutdown,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn main() {
     let args = Args::parse();
 
     match args.command {
-        Commands::Host{ port , size } =>
-            match host(port, size).await {
+        Commands::Host{
+            port,
+            size,
+            threads,
+            idle_timeout,
+            advertise,
+            irc,
+            irc_channel,
+            rendezvous,
+            federate,
+            state,
+            admin_socket,
+            config,
+        } => {
+            for federation in &federate {
+                if rendezvous == federation.room.is_empty() {
+                    eprintln!("Error: --federate takes a room code in rendezvous mode, and only an invite otherwise.");
+                    process::exit(1)
+                }
+            }
+
+            // an IPv6 address may come bracketed, as it would be along with a port
+            let advertise = advertise.map(|host| host.trim_start_matches('[').trim_end_matches(']').to_string());
+            let mut invite = Invite::new(advertise.unwrap_or_else(|| String::from("127.0.0.1")), port);
+            if rendezvous {
+                // any code works; this is one for whoever's hosting, and others can be made up as they're needed
+                invite.room = Some(invite::new_room_code());
+                println!("Invite to a fresh room: {}", invite);
+            } else {
+                println!("Invite: {}", invite);
+            }
+            let server_config = match config.or_else(ServerConfig::default_path) {
+                Some(path) => ServerConfig::load(&path).unwrap_or_else(|err| fail(err)),
+                None => ServerConfig::default(),
+            };
+            let access_log = AccessLog::open(&server_config.access_log).unwrap_or_else(|err| fail(err));
+
+            if let Some(address) = irc {
+                start_irc_gateway(port, address, irc_channel);
+            }
+
+            let mut server = Server::new(port, size);
+            server.set_state_path(state);
+            server.set_admin_socket(admin_socket);
+            server.set_access_log(access_log);
+            server.set_rate_limit(server_config.rate_limit);
+            server.set_tuning(server_config.tuning);
+            let runtime = build_runtime(Some(threads.unwrap_or_else(default_threads)));
+            match runtime.block_on(host(server, idle_timeout, rendezvous, federate)) {
                 Ok(()) => (),
                 Err(err) => {
                     eprintln!("Error: {}", err);
                     process::exit(1)
                 }
             }
-        Commands::Join{ target, port, id } =>
-            join(target, port, id).await,
+        }
+        Commands::Join(args) => {
+            let JoinArgs { invite, target, port, id, config, plain, listen, room, link, webhook, respond, debug_mls } = *args;
+            let mut config = load_config(config);
+            config.plain |= plain;
+            config.link = link;
+            config.webhook = webhook;
+            config.debug_mls = debug_mls;
+            let (invite_target, invite_port, invite_room) = match invite {
+                Some(invite) => invite_address(invite),
+                None => (None, None, None),
+            };
+            config.room = room.or(invite_room);
+            let hub = listen.map(PeerHub::start);
+            let (Some(target), Some(port), Some(id)) = (
+                hub.as_ref().map(PeerHub::target).or(target.as_ref().map(|target| target.host.clone()))
+                    .or(invite_target)
+                    .or(config.server.address.clone()),
+                listen.map(|address| address.port())
+                    .or(port)
+                    .or(target.and_then(|target| target.port))
+                    .or(invite_port)
+                    .or(config.server.port),
+                id.or_else(|| config.username.clone()),
+            ) else {
+                eprintln!("Error: a target, port, and id are required, either as flags or in the config file.");
+                process::exit(1)
+            };
+
+            let handlers: Vec<Box<dyn bot::MessageHandler>> = respond.into_iter()
+                .map(|responder| Box::new(responder) as Box<dyn bot::MessageHandler>)
+                .collect();
+            build_runtime(None).block_on(join(ServerAddr::new(target, port), id, config, handlers));
+            if let Some(hub) = hub {
+                hub.stop();
+            }
+        }
+        Commands::Doctor{ invite, target, port, room, config } => {
+            let config = load_config(config);
+            let (Some(host), Some(port)) = (
+                target.as_ref().map(|target| target.host.clone())
+                    .or(invite.as_ref().map(|invite| invite.host.clone()))
+                    .or(config.server.address.clone()),
+                port.or(target.and_then(|target| target.port))
+                    .or(invite.as_ref().map(|invite| invite.port))
+                    .or(config.server.port),
+            ) else {
+                eprintln!("Error: a target and port are required, either as flags, an invite, or in the config file.");
+                process::exit(1)
+            };
+            let room = room.or(invite.as_ref().and_then(|invite| invite.room.clone()));
+            let tls = invite.is_some_and(|invite| invite.tls);
+
+            let address = ServerAddr::new(host, port);
+            println!("Checking {}...", address);
+            let timeout = Duration::from_secs(config.server.connect_timeout);
+            let report = build_runtime(Some(1)).block_on(doctor::diagnose(&address, room.as_deref(), tls, timeout));
+            for check in &report {
+                println!("{}", check);
+            }
+            if report.iter().any(|check| check.outcome == doctor::Outcome::Fail) {
+                process::exit(1)
+            }
+        }
+        Commands::Bench{ groups, .. } if !groups.is_empty() => {
+            for size in groups {
+                for ratchet_tree_in_welcome in [true, false] {
+                    match bench::group_costs(size, ratchet_tree_in_welcome) {
+                        Ok(costs) => println!("{}\n", costs),
+                        Err(err) => fail(err),
+                    }
+                }
+            }
+        }
+        Commands::Bench{ invite, target, port, room, clients, rate, duration, size, config, .. } => {
+            let config = load_config(config);
+            let (Some(host), Some(port)) = (
+                target.as_ref().map(|target| target.host.clone())
+                    .or(invite.as_ref().map(|invite| invite.host.clone()))
+                    .or(config.server.address.clone()),
+                port.or(target.and_then(|target| target.port))
+                    .or(invite.as_ref().map(|invite| invite.port))
+                    .or(config.server.port),
+            ) else {
+                eprintln!("Error: a target and port are required, either as flags, an invite, or in the config file.");
+                process::exit(1)
+            };
+            if clients < 2 || rate <= 0.0 {
+                eprintln!("Error: a bench needs at least 2 clients and a rate above 0.");
+                process::exit(1)
+            }
+            let room = room.or(invite.and_then(|invite| invite.room));
+
+            let address = ServerAddr::new(host, port);
+            println!("Running {} clients against {} at {} messages a second for {} seconds...", clients, address, rate, duration);
+            let options = bench::BenchOptions { clients, rate, duration: Duration::from_secs(duration), size };
+            let runtime = build_runtime(Some(default_threads()));
+            match runtime.block_on(bench::run(&address, room.as_deref(), options)) {
+                Ok(report) => println!("{}", report),
+                Err(err) => fail(err),
+            }
+        }
+        Commands::ExportIdentity{ out, config, force } => {
+            let config = load_config(config);
+            let path = session_path(&config);
+            let session = match Session::load(&path) {
+                Ok(Some(session)) => session,
+                Ok(None) => fail(StorageError::Session(format!(
+                    "there's no saved session at {}. Join a group with the session path set first.", path.display(),
+                )).into()),
+                Err(err) => fail(err),
+            };
+
+            let passphrase = read_passphrase("Passphrase to encrypt the backup with: ");
+            if passphrase.is_empty() {
+                fail(StorageError::Session(String::from("the passphrase can't be empty.")).into());
+            }
+            if read_passphrase("Repeat the passphrase: ") != passphrase {
+                fail(StorageError::Session(String::from("the passphrases don't match.")).into());
+            }
+
+            if let Err(err) = Backup::seal(&session, &passphrase).and_then(|backup| backup.write(&out, force)) {
+                fail(err)
+            }
+            println!("Exported {}'s identity to {}.", session.user.id(), out.display());
+            println!("Once it's imported elsewhere, stop using it here: two clients can't share one place in a group.");
+        }
+        Commands::ImportIdentity{ backup, config, force } => {
+            let config = load_config(config);
+            let path = session_path(&config);
+            if path.exists() && !force {
+                fail(StorageError::Session(format!(
+                    "there's already a saved session at {}. Pass --force to replace it.", path.display(),
+                )).into())
+            }
+
+            let backup = Backup::read(&backup).unwrap_or_else(|err| fail(err));
+            let session = backup.open(&read_passphrase("Passphrase: ")).unwrap_or_else(|err| fail(err));
+            if let Err(err) = session.save(&path) {
+                fail(err)
+            }
+            println!("Imported {}'s identity. Join with that id to carry on where it left off.", session.user.id());
+        }
+        Commands::ExportLog{ out, format, config, force } => {
+            let config = load_config(config);
+            let path = session_path(&config);
+            let session = match Session::load(&path) {
+                Ok(Some(session)) => session,
+                Ok(None) => fail(StorageError::Session(format!("there's no saved session at {}.", path.display())).into()),
+                Err(err) => fail(err),
+            };
+            if session.log.is_empty() {
+                fail(StorageError::Export(String::from(
+                    "the saved session has no chat log. Set [session] save_log = true to keep one.",
+                )).into())
+            }
+
+            let format = format.unwrap_or_else(|| transcript::Format::from_path(&out));
+            if let Err(err) = transcript::export(&session.log, format, &out, force) {
+                fail(err)
+            }
+            println!(
+                "Exported {} lines of the chat to {}. It's unencrypted, so keep it somewhere safe.",
+                session.log.len(),
+                out.display(),
+            );
+        }
+        Commands::Wipe{ config, yes } => {
+            // a config that doesn't load is still wiped, along with whatever else it can be read for
+            let path = config.or_else(ClientConfig::default_path);
+            let config = path.map_or_else(ClientConfig::default, |path| {
+                ClientConfig::load(&path).unwrap_or_else(|_| ClientConfig { source: Some(path), ..ClientConfig::default() })
+            });
+            let targets: Vec<PathBuf> = wipe::targets(&config).into_iter().filter(|path| path.exists()).collect();
+            if targets.is_empty() {
+                println!("There's nothing to wipe.");
+                return;
+            }
+
+            println!("This overwrites and deletes:");
+            for path in &targets {
+                println!("  {}", path.display());
+            }
+            if !yes {
+                eprint!("Type \"wipe\" to go ahead: ");
+                let mut answer = String::new();
+                if io::stdin().read_line(&mut answer).is_err() || answer.trim() != "wipe" {
+                    println!("Nothing was deleted.");
+                    process::exit(1)
+                }
+            }
+
+            let (deleted, failed) = wipe::wipe(&targets);
+            for path in deleted {
+                println!("Deleted {}", path.display());
+            }
+            for (path, err) in &failed {
+                eprintln!("Error: couldn't delete {}: {}", path.display(), err);
+            }
+            if !failed.is_empty() {
+                process::exit(1)
+            }
+        }
+        Commands::Config{ command: ConfigCommands::Init{ path, force } } => {
+            let path = path.unwrap_or_else(config_path);
+            match ClientConfig::write_template(&path, force) {
+                Ok(()) => println!("Wrote a config template to {}.", path.display()),
+                Err(ApplicationError::Storage(StorageError::Config(msg))) => {
+                    eprintln!("Error: {}", msg);
+                    process::exit(1)
+                }
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    process::exit(1)
+                }
+            }
+        }
+        Commands::Admin{ socket, command } => admin(&socket, command),
     }
 }
 
-async fn host(port: u16, size: usize) -> Result<()> {
-    let mut server = Server::new(port, size);
+/// Sends an admin request to the server whose admin socket is at `socket`, and prints its answer.
+#[cfg(unix)]
+fn admin(socket: &std::path::Path, command: AdminCommands) {
+    use network::admin::{self, AdminRequest, AdminResponse};
+
+    let request = match command {
+        AdminCommands::List => AdminRequest::List,
+        AdminCommands::Kick{ id } => AdminRequest::Kick { id },
+        AdminCommands::Ban{ address } => AdminRequest::Ban { address },
+        AdminCommands::Unban{ address } => AdminRequest::Unban { address },
+        AdminCommands::Announce{ text } => AdminRequest::Announce { text: text.join(" ") },
+        AdminCommands::Stats => AdminRequest::Stats,
+        AdminCommands::Shutdown => AdminRequest::Shutdown,
+    };
+    let response = build_runtime(Some(1)).block_on(admin::request(socket, &request)).unwrap_or_else(|err| fail(err));
+
+    match response {
+        AdminResponse::Done => println!("Done."),
+        AdminResponse::Connections{ connections } if connections.is_empty() => println!("No open connections."),
+        AdminResponse::Connections{ connections } => {
+            for connection in connections {
+                println!(
+                    "{:>5}  {:<24} {:<20} since {}  {} bytes in, {} bytes out",
+                    connection.id,
+                    connection.address,
+                    connection.identity.as_deref().unwrap_or("-"),
+                    connection.connected_at,
+                    connection.stats.bytes_in,
+                    connection.stats.bytes_out,
+                );
+            }
+        }
+        AdminResponse::Stats{ stats } => {
+            println!("uptime: {} s", stats.uptime_secs);
+            println!("connections: {}", stats.connections);
+            println!("rooms: {} open, {} created", stats.rooms, stats.created_rooms);
+            println!("traffic: {} frames ({} bytes) in, {} frames ({} bytes) out",
+                stats.traffic.frames_in, stats.traffic.bytes_in, stats.traffic.frames_out, stats.traffic.bytes_out);
+            let banned: Vec<String> = stats.banned.iter().map(IpAddr::to_string).collect();
+            println!("banned: {}", if banned.is_empty() { String::from("none") } else { banned.join(", ") });
+        }
+        AdminResponse::Error{ message } => {
+            eprintln!("Error: {}.", message);
+            process::exit(1)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn admin(_: &std::path::Path, _: AdminCommands) {
+    eprintln!("Error: the admin socket is only supported on Unix.");
+    process::exit(1)
+}
+
+/// Returns the target, port, and room code to join from an invite, exiting if the invite asks for
+/// something this client can't do.
+fn invite_address(invite: Invite) -> (Option<String>, Option<u16>, Option<String>) {
+    if invite.tls {
+        eprintln!("Error: the invite requires TLS, which this client doesn't support.");
+        process::exit(1)
+    }
+    if invite.token.is_some() {
+        println!("Note: invite tokens aren't supported yet, so it's being ignored.");
+    }
+    if let Some(Err(msg)) = invite.room.as_deref().map(invite::check_room_code) {
+        eprintln!("Error: the invite's {}.", msg);
+        process::exit(1)
+    }
+
+    (Some(invite.host), Some(invite.port), invite.room)
+}
+
+/// Prints `err` and exits.
+fn fail(err: ApplicationError) -> ! {
+    match err {
+        ApplicationError::Storage(StorageError::Config(msg)) | ApplicationError::Storage(StorageError::Export(msg)) | ApplicationError::Storage(StorageError::Session(msg)) => {
+            eprintln!("Error: {}", msg)
+        }
+        ApplicationError::Network(NetworkError::ServerFull) => eprintln!("Error: the server is full. Try again once someone has left."),
+        ApplicationError::Network(NetworkError::ConnectTimedOut) => {
+            eprintln!("Error: the server didn't answer. Check its address, and that no firewall is in the way.")
+        }
+        ApplicationError::Network(NetworkError::ConnectionRefused) => {
+            eprintln!("Error: the server refused the connection. Check its address and port, and that it's running.")
+        }
+        ApplicationError::Network(NetworkError::HostNotFound) => eprintln!("Error: the server's address couldn't be found. Check it for typos."),
+        ApplicationError::Network(NetworkError::ConnectionFailed) => eprintln!("Error: couldn't connect to the server."),
+        err => eprintln!("Error: {}", err),
+    }
+    process::exit(1)
+}
+
+/// Returns the session path set in `config`, exiting if there isn't one.
+fn session_path(config: &ClientConfig) -> PathBuf {
+    config.session.path.clone().unwrap_or_else(|| {
+        fail(StorageError::Session(String::from(
+            "no session path is set. Set [session] path in the config file first.",
+        )).into())
+    })
+}
+
+/// Prompts for a passphrase on stderr and reads it from stdin, without echoing it if stdin is a terminal.
+fn read_passphrase(prompt: &str) -> String {
+    eprint!("{}", prompt);
+    let _ = io::stderr().flush();
+
+    let mut passphrase = String::new();
+    if !io::stdin().is_terminal() {
+        if io::stdin().read_line(&mut passphrase).is_err() {
+            fail(UiError::Terminal.into())
+        }
+        return passphrase.trim_end_matches(['\r', '\n']).to_string();
+    }
+
+    if terminal::enable_raw_mode().is_err() {
+        fail(UiError::Terminal.into())
+    }
+    let interrupted = loop {
+        let Ok(Event::Key(key)) = event::read() else { continue };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Enter => break false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break true,
+            KeyCode::Char(c) => passphrase.push(c),
+            KeyCode::Backspace => { passphrase.pop(); }
+            _ => (),
+        }
+    };
+    let _ = terminal::disable_raw_mode();
+    eprintln!();
+    if interrupted {
+        process::exit(130)
+    }
+
+    passphrase
+}
+
+/// Returns the default config file path, exiting if there's no home directory to put it in.
+fn config_path() -> PathBuf {
+    ClientConfig::default_path().unwrap_or_else(|| {
+        eprintln!("Error: unable to find a config directory. Pass a config path explicitly.");
+        process::exit(1)
+    })
+}
+
+/// Loads the config file from `path`, or from the default location if `path` is None. With no
+/// default location to look in, the built-in defaults are used.
+fn load_config(path: Option<PathBuf>) -> ClientConfig {
+    let Some(path) = path.or_else(ClientConfig::default_path) else { return ClientConfig::default() };
+
+    match ClientConfig::load(&path) {
+        Ok(config) => config,
+        Err(ApplicationError::Storage(StorageError::Config(msg))) => {
+            eprintln!("Error: unable to load config: {}", msg);
+            process::exit(1)
+        }
+        Err(err) => {
+            eprintln!("Error: unable to load config: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+/// Builds the tokio runtime for a subcommand: multi-threaded with the given number of workers, or
+/// single-threaded if `threads` is None. The client only ever drives one connection and one
+/// terminal, so it has no use for more than one thread; the server relays between every
+/// connection at once.
+fn build_runtime(threads: Option<usize>) -> Runtime {
+    let mut builder = match threads {
+        Some(threads) => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads.max(1));
+            builder
+        }
+        None => runtime::Builder::new_current_thread(),
+    };
+
+    match builder.enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("Error: unable to start the async runtime: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Checks a `--room` code, for clap.
+fn parse_room_code(code: &str) -> std::result::Result<String, String> {
+    invite::check_room_code(code).map(|_| code.to_string())
+}
+
+async fn host(
+    mut server: Server,
+    idle_timeout: Option<u64>,
+    rendezvous: bool,
+    federate: Vec<network::federation::Federation>,
+) -> Result<()> {
+    server.set_rendezvous(rendezvous);
+    if let Some(secs) = idle_timeout {
+        server.set_idle_timeout(Some(Duration::from_secs(secs)).filter(|timeout| !timeout.is_zero()));
+    }
+    for federation in federate {
+        let what = if rendezvous { "a room" } else { "the server" };
+        println!("Federating {} with the server at {}.", what, federation.home.address());
+        server.federate(federation);
+    }
     match server.run().await {
         Ok(_) => println!("Server closed successfully."),
-        Err(_) => todo!(),
+        Err(err) => fail(err),
     }
 
     Ok(())
 }
 
-async fn join(target: IpAddr, port: u16, id: String) {
-    let mut address = String::new();
-    address.push_str(&target.to_string());
-    address.push_str(":");
-    address.push_str(&port.to_string());
+/// A server hosted by `join --listen` in a thread of its own, for peers to connect to directly instead of to a
+/// dedicated server. The user hosting it joins it like any other peer.
+struct PeerHub {
+    address: SocketAddr,
+    shutdown: CancellationToken,
+    thread: thread::JoinHandle<()>,
+}
+
+impl PeerHub {
+    /// Starts the hub listening on `address`, returning once it is. It's quiet, so it doesn't draw over the user's own
+    /// view of the chat. Exits if the address can't be listened on.
+    fn start(address: SocketAddr) -> PeerHub {
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let (bound_tx, bound) = std::sync::mpsc::channel();
+        let thread = thread::spawn(move || build_runtime(Some(1)).block_on(async move {
+            let listener = match tokio::net::TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    let _ = bound_tx.send(Err(err));
+                    return;
+                }
+            };
+            let _ = bound_tx.send(Ok(()));
+
+            let mut server = Server::new(address.port(), HUB_SIZE);
+            server.set_quiet(true);
+            let _ = server.serve(listener, async move {
+                token.cancelled().await;
+                Ok(())
+            }).await;
+        }));
+        if let Ok(Err(err)) = bound.recv() {
+            eprintln!("Error: unable to listen for peers on {}: {}", address, err);
+            process::exit(1)
+        }
 
-    if let Ok(mut controller) = Controller::build(address, id).await {
-        controller.run().await.unwrap();
+        match address.ip().is_unspecified() {
+            true => println!("Listening for peers on port {}. Invite them with your address in place of the target.", address.port()),
+            false => println!("Listening for peers. Invite: {}", Invite::new(address.ip().to_string(), address.port())),
+        }
+        PeerHub { address, shutdown, thread }
+    }
+
+    /// Returns the address to join the hub at from this machine.
+    fn target(&self) -> String {
+        match self.address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::from([127, 0, 0, 1]),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::from(std::net::Ipv6Addr::LOCALHOST),
+            ip => ip,
+        }.to_string()
+    }
+
+    /// Shuts the hub down, telling every peer still connected, and waits for it to finish.
+    fn stop(self) {
+        self.shutdown.cancel();
+        let _ = self.thread.join();
+    }
+}
+
+/// Starts the IRC gateway on `address` in its own thread, as a member of the group on the server hosted at `port`.
+/// Exits if the address can't be listened on.
+fn start_irc_gateway(port: u16, address: SocketAddr, channel: String) {
+    let view = irc::IrcView::bind(address, channel.clone()).unwrap_or_else(|err| {
+        eprintln!("Error: unable to listen for IRC clients on {}: {}", address, err);
+        process::exit(1)
+    });
+    println!("IRC gateway listening on {} for {}. It's plaintext, so IRC users get no end-to-end encryption.", address, channel);
+
+    // a Controller's view isn't Send, so the gateway's Controller gets a thread and runtime of its own
+    thread::spawn(move || build_runtime(Some(1)).block_on(async move {
+        let address = ServerAddr::new(String::from("127.0.0.1"), port);
+        // the server may not be listening yet
+        for _ in 0..20 {
+            let id = String::from("irc-gateway");
+            match Controller::with_view(address.clone(), id, ClientConfig::default(), Box::new(view.clone())).await {
+                Ok(mut controller) => {
+                    if let Err(err) = controller.run().await {
+                        eprintln!("Error: the IRC gateway stopped: {}", err);
+                    }
+                    return;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(250)).await,
+            }
+        }
+        eprintln!("Error: the IRC gateway couldn't connect to the server.");
+    }));
+}
+
+/// Joins the server at `address` as `id`, running as a bot with the given handlers if there are any.
+async fn join(address: ServerAddr, id: String, config: ClientConfig, handlers: Vec<Box<dyn bot::MessageHandler>>) {
+
+    let controller = if handlers.is_empty() {
+        Controller::build(address, id, config).await
     } else {
-        eprintln!("Unable to initialize controller.");
-        process::exit(1);
+        Controller::with_view(address, id, config, Box::new(bot::BotView::new(handlers))).await
+    };
+    match controller {
+        Ok(mut controller) => {
+            // Ctrl-C outside the chat window stops the client the same way quitting does, saving the session
+            let shutdown = controller.shutdown_token();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    shutdown.cancel();
+                }
+            });
+            controller.run().await.unwrap()
+        }
+        Err(
+            err @ (ApplicationError::Storage(StorageError::Session(_))
+            | ApplicationError::Network(NetworkError::ServerFull)
+            | ApplicationError::Network(NetworkError::ConnectTimedOut)
+            | ApplicationError::Network(NetworkError::ConnectionRefused)
+            | ApplicationError::Network(NetworkError::ConnectionFailed)
+            | ApplicationError::Network(NetworkError::HostNotFound)),
+        ) => fail(err),
+        Err(_) => {
+            eprintln!("Unable to initialize controller.");
+            process::exit(1);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,109 @@
+/// The lightweight formatting recognized in chat messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkupKind {
+    /// `*bold*`
+    Bold,
+    /// `_italic_`
+    Italic,
+    /// `` `code` ``
+    Code,
+}
+
+/// A formatted span of a message: the byte range it covers, including its one-byte delimiters at either end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Markup {
+    pub kind: MarkupKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Markup {
+    /// The byte range of the formatted text, without its delimiters.
+    pub fn content(&self) -> (usize, usize) {
+        (self.start + 1, self.end - 1)
+    }
+}
+
+/// Finds the formatted spans in `text`. Code spans run between a pair of backticks, and nothing inside them is
+/// formatted. Bold and italic spans need their opening delimiter at the start of a word and their closing one at the
+/// end of a word, so that e.g. `snake_case_names` and `2*3*4` are left alone. Bold and italic spans can nest.
+pub fn parse(text: &str) -> Vec<Markup> {
+    let mut spans = Vec::new();
+
+    let mut ticks = text.match_indices('`').map(|(i, _)| i);
+    while let (Some(start), Some(end)) = (ticks.next(), ticks.next()) {
+        if end > start + 1 {
+            spans.push(Markup { kind: MarkupKind::Code, start, end: end + 1 });
+        }
+    }
+    let in_code = |i: usize| spans.iter().any(|span: &Markup| span.kind == MarkupKind::Code && span.start <= i && i < span.end);
+
+    let mut emphasis = Vec::new();
+    // delimiters already used to close a span can't open another
+    let mut used = Vec::new();
+    for (start, delimiter) in text.char_indices() {
+        let kind = match delimiter {
+            '*' => MarkupKind::Bold,
+            '_' => MarkupKind::Italic,
+            _ => continue,
+        };
+        if used.contains(&start) || in_code(start) || !opens(text, start) {
+            continue;
+        }
+
+        let close = text[start + 1..]
+            .char_indices()
+            .map(|(i, _)| start + 1 + i)
+            .take_while(|&i| !in_code(i))
+            .find(|&i| i > start + 1 && text[i..].starts_with(delimiter) && closes(text, i));
+        if let Some(end) = close {
+            used.push(end);
+            emphasis.push(Markup { kind, start, end: end + 1 });
+        }
+    }
+
+    spans.extend(emphasis);
+    spans.sort_by_key(|span| span.start);
+    spans
+}
+
+/// Whether the delimiter at byte `i` can open a span: it starts a word and is followed by something other than
+/// whitespace.
+fn opens(text: &str, i: usize) -> bool {
+    let before = text[..i].chars().next_back();
+    let after = text[i + 1..].chars().next();
+    before.is_none_or(|c| !c.is_alphanumeric()) && after.is_some_and(|c| !c.is_whitespace())
+}
+
+/// Whether the delimiter at byte `i` can close a span: it ends a word and follows something other than whitespace.
+fn closes(text: &str, i: usize) -> bool {
+    let before = text[..i].chars().next_back();
+    let after = text[i + 1..].chars().next();
+    before.is_some_and(|c| !c.is_whitespace()) && after.is_none_or(|c| !c.is_alphanumeric())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<(MarkupKind, &str)> {
+        parse(text).iter().map(|span| {
+            let (start, end) = span.content();
+            (span.kind, &text[start..end])
+        }).collect()
+    }
+
+    #[test]
+    fn parse_markup() {
+        assert_eq!(kinds("*hi* there"), vec![(MarkupKind::Bold, "hi")]);
+        assert_eq!(kinds("an _italic *bold* phrase_."), vec![
+            (MarkupKind::Italic, "italic *bold* phrase"),
+            (MarkupKind::Bold, "bold"),
+        ]);
+        assert_eq!(kinds("run `rm *.tmp*` now"), vec![(MarkupKind::Code, "rm *.tmp*")]);
+        assert!(kinds("snake_case_name and 2*3*4").is_empty());
+        assert!(kinds("a * b * c").is_empty());
+        assert!(kinds("``").is_empty());
+    }
+}
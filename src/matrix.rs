@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{mpsc::{self, Sender}, Arc},
+    thread,
+    time::Duration,
+};
+use crate::{
+    ApplicationError,
+    NetworkError,
+    chat_log::{ChatLog, EntryKind, LogEntry},
+    frontend::{self, ChatView, InputQueue, UserInput},
+    plain,
+};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+/// How long a `/sync` waits on the homeserver for new events before returning empty, in milliseconds.
+const SYNC_TIMEOUT: u64 = 30_000;
+/// How long to wait before trying again after the homeserver couldn't be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Prefix marking messages relayed from Matrix into the group.
+const FROM_MATRIX: &str = "[matrix]";
+/// Prefix marking messages relayed from the group into Matrix.
+const FROM_MLS: &str = "[mls_chat]";
+
+/// A Matrix homeserver, reached over the client-server API with an access token.
+///
+/// Only plain `http://` URLs are supported, since there's no TLS in mls_chat's dependencies: point it at a homeserver on
+/// the same machine, or at a local proxy that makes the TLS connection. Messages are end-to-end encrypted only as far
+/// as the bridge; on the Matrix side, they're as private as the Matrix room is.
+#[derive(Clone, Debug)]
+pub struct Homeserver {
+    /// `host:port` to connect to
+    address: String,
+    host: String,
+    token: String,
+}
+
+impl Homeserver {
+    /// Parses the homeserver's `url`, e.g. `http://localhost:8008`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Bridge` if the URL isn't a plain `http://` one.
+    pub fn new(url: &str, token: String) -> Result<Homeserver, ApplicationError> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(NetworkError::Bridge(format!(
+                "{} isn't an http:// URL. For https, run a local proxy that makes the TLS connection.", url,
+            )).into());
+        };
+        let host = rest.trim_end_matches('/').to_string();
+        if host.is_empty() || host.contains('/') {
+            return Err(NetworkError::Bridge(format!("{} isn't a homeserver URL like http://localhost:8008", url)).into());
+        }
+        let address = if host.contains(':') { host.clone() } else { format!("{}:80", host) };
+
+        Ok(Homeserver { address, host, token })
+    }
+
+    /// Sends a request to the client-server API and returns the JSON it answers with.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Bridge` if the homeserver can't be reached, or answers with an error or
+    /// something other than JSON.
+    fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value, ApplicationError> {
+        let error = |e: &dyn std::fmt::Display| ApplicationError::Network(NetworkError::Bridge(format!("{} {}: {}", method, path, e)));
+
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut stream = TcpStream::connect(&self.address).map_err(|e| error(&e))?;
+        stream.set_read_timeout(Some(Duration::from_millis(SYNC_TIMEOUT) * 2)).map_err(|e| error(&e))?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method, path, self.host, self.token, body.len(), body,
+        ).map_err(|e| error(&e))?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).map_err(|e| error(&e))?;
+        let code = status.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+        let mut chunked = false;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).map_err(|e| error(&e))? == 0 {
+                break;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                chunked |= name.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked");
+            }
+        }
+
+        let mut content = vec![];
+        reader.read_to_end(&mut content).map_err(|e| error(&e))?;
+        if chunked {
+            content = decode_chunked(&content).ok_or_else(|| error(&"malformed chunked response"))?;
+        }
+        let value: Value = serde_json::from_slice(&content).map_err(|e| error(&e))?;
+        if code != "200" {
+            let reason = value["error"].as_str().unwrap_or("no reason given");
+            return Err(error(&format!("the homeserver answered {}: {}", code, reason)));
+        }
+        Ok(value)
+    }
+
+    /// Returns the Matrix user ID the access token belongs to.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by the request.
+    pub fn whoami(&self) -> Result<String, ApplicationError> {
+        let value = self.request("GET", "/_matrix/client/v3/account/whoami", None)?;
+        value["user_id"].as_str()
+            .map(str::to_string)
+            .ok_or_else(|| ApplicationError::Network(NetworkError::Bridge(String::from("whoami didn't return a user_id"))))
+    }
+
+    /// Fetches new events since the batch token `since`, waiting up to `SYNC_TIMEOUT` for some to arrive.
+    /// With no token, returns straight away with just a token to continue from, so history isn't bridged.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by the request.
+    pub fn sync(&self, since: Option<&str>) -> Result<Value, ApplicationError> {
+        let path = match since {
+            Some(since) => format!("/_matrix/client/v3/sync?since={}&timeout={}", encode(since), SYNC_TIMEOUT),
+            None => String::from("/_matrix/client/v3/sync?timeout=0&filter=%7B%22room%22%3A%7B%22timeline%22%3A%7B%22limit%22%3A0%7D%7D%7D"),
+        };
+        self.request("GET", &path, None)
+    }
+
+    /// Sends a plain text message to `room`. `txn` must be unique for each message, so a retried request isn't sent
+    /// twice.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by the request.
+    pub fn send(&self, room: &str, txn: &str, text: &str) -> Result<(), ApplicationError> {
+        let path = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/{}", encode(room), encode(txn));
+        self.request("PUT", &path, Some(&json!({ "msgtype": "m.text", "body": text }))).map(|_| ())
+    }
+
+    /// Returns the display name of the Matrix user `user`, if they've set one.
+    pub fn display_name(&self, user: &str) -> Option<String> {
+        let value = self.request("GET", &format!("/_matrix/client/v3/profile/{}/displayname", encode(user)), None).ok()?;
+        value["displayname"].as_str().map(str::to_string)
+    }
+}
+
+/// Returns the sender and text of each message event in `room` in the `/sync` response `sync`, oldest first, leaving
+/// out those sent by `own`, the bridge's own user.
+pub fn messages(sync: &Value, room: &str, own: &str) -> Vec<(String, String)> {
+    let Some(events) = sync["rooms"]["join"][room]["timeline"]["events"].as_array() else { return vec![] };
+    events.iter()
+        .filter(|event| event["type"] == "m.room.message" && event["sender"] != own)
+        .filter_map(|event| {
+            let sender = event["sender"].as_str()?;
+            let text = event["content"]["body"].as_str()?;
+            let text = match event["content"]["msgtype"].as_str()? {
+                "m.text" | "m.notice" => text.to_string(),
+                "m.emote" => format!("* {}", text),
+                _ => return None,
+            };
+            Some((sender.to_string(), text))
+        })
+        .collect()
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`, returning None if it's malformed.
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for putting IDs and tokens in a URL.
+fn encode(s: &str) -> String {
+    s.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// A view that bridges the group with a Matrix room: chat messages from other members are sent to the room, marked
+/// `[mls_chat]` and with their sender's name, and messages from the room are sent to the group, marked `[matrix]` and
+/// with the Matrix user's display name. Log entries are printed to stdout like `PlainView` does.
+pub struct BridgeView {
+    incoming: InputQueue,
+    outgoing: Sender<String>,
+}
+
+impl BridgeView {
+    /// Starts bridging with `room` on `homeserver`, spawning threads to follow the room and to send to it without
+    /// blocking the `Controller`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Homeserver::whoami()` or the first `Homeserver::sync()`, so that a
+    /// wrong URL or token is reported straight away.
+    pub fn start(homeserver: Homeserver, room: String) -> Result<BridgeView, ApplicationError> {
+        let own = homeserver.whoami()?;
+        let mut since = homeserver.sync(None)?["next_batch"].as_str().map(str::to_string);
+
+        let (incoming_tx, incoming) = frontend::input_queue();
+        let follower = homeserver.clone();
+        let followed = room.clone();
+        thread::spawn(move || {
+            let mut names: HashMap<String, String> = HashMap::new();
+            loop {
+                let sync = match follower.sync(since.as_deref()) {
+                    Ok(sync) => sync,
+                    Err(e) => {
+                        eprintln!("Matrix sync failed, retrying: {:?}", e);
+                        thread::sleep(RETRY_DELAY);
+                        continue;
+                    }
+                };
+                for (sender, text) in messages(&sync, &followed, &own) {
+                    let name = names.entry(sender.clone())
+                        .or_insert_with(|| follower.display_name(&sender).unwrap_or_else(|| sender.clone()));
+                    let line = format!("{} {}: {}", FROM_MATRIX, name, text.lines().collect::<Vec<_>>().join(" "));
+                    if incoming_tx.send(line).is_err() {
+                        return;
+                    }
+                }
+                since = sync["next_batch"].as_str().map(str::to_string).or(since);
+            }
+        });
+
+        let (outgoing, outgoing_rx) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            // transaction ids only have to be unique per access token, so the time the bridge started tells runs apart
+            let run = Utc::now().timestamp_millis();
+            for (n, text) in outgoing_rx.into_iter().enumerate() {
+                if let Err(e) = homeserver.send(&room, &format!("mls_chat-{}-{}", run, n), &text) {
+                    eprintln!("Couldn't send to Matrix: {:?}", e);
+                }
+            }
+        });
+
+        Ok(BridgeView { incoming, outgoing })
+    }
+}
+
+impl ChatView for BridgeView {
+    /// Entries are printed by `BridgeView::push_message()` as they arrive, so there's nothing to redraw.
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.incoming.poll())
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        let mut stdout = io::stdout().lock();
+        // stdout going away (e.g. a closed pipe) leaves nothing to report the error to
+        let _ = writeln!(stdout, "{}", plain::format_entry(entry)).and_then(|_| stdout.flush());
+
+        // the bridge's own messages are what it relayed from Matrix, so only other members' go the other way
+        if let EntryKind::Chat { sender, author: Some(_), .. } = &entry.kind {
+            if !entry.deleted {
+                let _ = self.outgoing.send(format!("{} {}: {}", FROM_MLS, sender, entry.text));
+            }
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        self.incoming.ready()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sync_responses() {
+        let sync = json!({
+            "next_batch": "s2",
+            "rooms": { "join": { "!room:example.org": { "timeline": { "events": [
+                { "type": "m.room.message", "sender": "@alice:example.org", "content": { "msgtype": "m.text", "body": "hi" } },
+                { "type": "m.room.message", "sender": "@bridge:example.org", "content": { "msgtype": "m.text", "body": "echo" } },
+                { "type": "m.room.member", "sender": "@bob:example.org", "content": { "membership": "join" } },
+                { "type": "m.room.message", "sender": "@bob:example.org", "content": { "msgtype": "m.emote", "body": "waves" } },
+            ] } } } },
+        });
+
+        assert_eq!(messages(&sync, "!room:example.org", "@bridge:example.org"), vec![
+            (String::from("@alice:example.org"), String::from("hi")),
+            (String::from("@bob:example.org"), String::from("* waves")),
+        ]);
+        assert!(messages(&sync, "!other:example.org", "@bridge:example.org").is_empty());
+    }
+
+    #[test]
+    fn decode_http_details() {
+        assert_eq!(decode_chunked(b"4\r\n{\"a\"\r\n3;ext\r\n:1}\r\n0\r\n\r\n"), Some(b"{\"a\":1}".to_vec()));
+        assert_eq!(decode_chunked(b"9\r\nshort\r\n"), None);
+        assert_eq!(encode("!room:example.org"), "%21room%3Aexample.org");
+        assert!(Homeserver::new("https://matrix.org", String::new()).is_err());
+        assert_eq!(Homeserver::new("http://localhost", String::new()).unwrap().address, "localhost:80");
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{ApplicationError, MlsError};
+
+use std::{collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque}, hash::BuildHasher};
+use serde::{Deserialize, Serialize};
+
+/// Randomly generated identifier of a chat message, unique within the group.
+pub type MessageId = u64;
+
+/// Longest chat message or topic shown, in characters. Anything past it is cut off.
+pub const MAX_TEXT_CHARS: usize = 4000;
+/// Longest name or reaction shown, in characters. Anything past it is cut off.
+pub const MAX_NAME_CHARS: usize = 32;
+
+/// The plaintext carried inside every MLS application message, serialized as JSON before encryption.
+/// Chat text and the application's own control messages share the same envelope, so they're all
+/// equally authenticated and encrypted by the group.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// A chat message, with the time it was sent as a Unix timestamp (in seconds). `seq` counts up from 1 with each
+    /// chat message the sender sends, so receivers can tell when some of them went missing; 0 means the sender
+    /// doesn't number its messages.
+    Chat {
+        id: MessageId,
+        #[serde(default)]
+        seq: u64,
+        time: i64,
+        text: String,
+    },
+    /// The sender has changed the name they want to be displayed under.
+    Nick { name: String },
+    /// The sender reacted to the chat message `target` with an emoji.
+    Reaction { target: MessageId, emoji: String },
+    /// The sender replaced the text of their own chat message `target`.
+    Edit { target: MessageId, text: String },
+    /// The sender deleted their own chat message `target`.
+    Delete { target: MessageId },
+    /// The sender just joined and asks the other members for the recent chat history.
+    HistoryRequest,
+    /// Recent chat messages, oldest first, shared with the member at leaf index `to` after they asked for them with a
+    /// `HistoryRequest`. Sent to the whole group like everything else, and ignored by everyone but `to`.
+    History { to: u32, entries: Vec<SharedEntry> },
+    /// Dummy traffic sent in paranoid mode, carrying random filler so that it isn't always the same length.
+    /// Receivers discard it.
+    Cover { filler: String },
+    /// The sender set the group's topic.
+    ///
+    /// Ideally the topic would live in a group context extension, committed so that every member (including later
+    /// joiners) agrees on it as part of the group state. openmls 0.5 doesn't support that: creating
+    /// GroupContextExtensions proposals is test-only, and they're never applied when a commit is merged. Nor can it
+    /// be sent as a custom proposal type, which would at least order it with the group's commits: 0.5's `Proposal`
+    /// enum has no variant for one, so a commit carrying it fails to decode. Until the dependency is upgraded, the
+    /// topic is carried as an application message instead, which is still authenticated as coming from a member of
+    /// the group, but isn't ordered against commits or kept for later joiners.
+    ///
+    /// # TODO
+    ///
+    /// Send topic changes as a custom proposal type once openmls is upgraded to a version that has them (0.6 or
+    /// later), so they're committed like any other change to the group.
+    Topic { text: String },
+    /// The sender's presence: away with a message, or back if `away` is None. `connection` is the id the server knows
+    /// the sender's connection by, so that receivers can tell when it goes offline from the server's
+    /// `Frame::Disconnected`. Sent on joining, when a new member joins, and on `/away`. `moderation` is the digest of
+    /// the sender's moderation settings (see `User::note_moderation()`).
+    Presence {
+        #[serde(default)]
+        connection: Option<u64>,
+        #[serde(default)]
+        away: Option<String>,
+        #[serde(default)]
+        moderation: Option<Vec<u8>>,
+    },
+    /// The sender, a moderator, is moving the group to a successor with the ciphersuite `ciphersuite` (see
+    /// `Group::reinit()`), whose ID is `group_id`, and asks every member for a key package for it in a
+    /// `Payload::Successor`.
+    ReInit { group_id: Vec<u8>, ciphersuite: u16 },
+    /// A key package for the successor `group_id`, for the member moving the group to add the sender with.
+    Successor { group_id: Vec<u8>, key_package: Vec<u8> },
+    /// The successor `group_id` is ready: the Welcome into it for the members who answered the `Payload::ReInit`, with
+    /// its ratchet tree if the Welcome leaves it out. Everyone it welcomes moves over, leaving the old group behind.
+    Switch { group_id: Vec<u8>, welcome: Vec<u8>, ratchet_tree: Option<Vec<u8>> },
+}
+
+impl Payload {
+    /// Serializes the payload into the bytes to be encrypted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::InvalidMessage` if serialization fails.
+    pub fn encode(&self) -> Result<Vec<u8>, ApplicationError> {
+        serde_json::to_vec(self).map_err(|_| MlsError::InvalidMessage.into())
+    }
+
+    /// Deserializes a payload from decrypted bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::InvalidMessage` if the bytes aren't a valid payload.
+    pub fn decode(bytes: &[u8]) -> Result<Payload, ApplicationError> {
+        serde_json::from_slice(bytes).map_err(|_| MlsError::InvalidMessage.into())
+    }
+
+    /// Returns the payload with every piece of text passed through `sanitize()`, so that it's safe to show in the
+    /// terminal. Applied to everything received from other members before it reaches the log.
+    pub fn sanitized(self) -> Payload {
+        match self {
+            Payload::Chat { id, seq, time, text } => Payload::Chat { id, seq, time, text: sanitize(&text, MAX_TEXT_CHARS) },
+            Payload::Nick { name } => Payload::Nick { name: sanitize(&name, MAX_NAME_CHARS) },
+            Payload::Reaction { target, emoji } => Payload::Reaction { target, emoji: sanitize(&emoji, MAX_NAME_CHARS) },
+            Payload::Edit { target, text } => Payload::Edit { target, text: sanitize(&text, MAX_TEXT_CHARS) },
+            Payload::Delete { target } => Payload::Delete { target },
+            Payload::HistoryRequest => Payload::HistoryRequest,
+            // never shown, so there's nothing to sanitize
+            Payload::Cover { filler } => Payload::Cover { filler },
+            Payload::History { to, entries } => Payload::History {
+                to,
+                entries: entries.into_iter().map(|entry| SharedEntry {
+                    sender: sanitize(&entry.sender, MAX_NAME_CHARS),
+                    text: sanitize(&entry.text, MAX_TEXT_CHARS),
+                    ..entry
+                }).collect(),
+            },
+            Payload::Topic { text } => Payload::Topic { text: sanitize(&text, MAX_TEXT_CHARS) },
+            Payload::Presence { connection, away, moderation } => Payload::Presence {
+                connection,
+                away: away.map(|away| sanitize(&away, MAX_TEXT_CHARS)),
+                moderation,
+            },
+            // never shown either
+            payload @ (Payload::ReInit { .. } | Payload::Successor { .. } | Payload::Switch { .. }) => payload,
+        }
+    }
+}
+
+/// Makes text from another member safe to print: strips terminal escape sequences, control characters, and
+/// bidirectional overrides (which could hide or reorder what's shown), turns line breaks and tabs into spaces so a
+/// message can't fake extra log lines, and cuts the text off at `max_chars` characters, marking it with an ellipsis.
+pub fn sanitize(text: &str, max_chars: usize) -> String {
+    let mut sanitized = String::new();
+    let mut count = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\x1b' => {
+                skip_escape(&mut chars);
+                continue;
+            }
+            '\n' | '\r' | '\t' => ' ',
+            // C0 and C1 controls, DEL, and the bidirectional embedding, override, and isolate characters
+            c if c.is_control() || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}') => continue,
+            c => c,
+        };
+
+        if count == max_chars {
+            sanitized.push('…');
+            break;
+        }
+        sanitized.push(c);
+        count += 1;
+    }
+
+    sanitized
+}
+
+/// Consumes the rest of an escape sequence after its ESC: a CSI sequence up to its final byte, a string sequence
+/// (OSC, DCS, APC, PM, or SOS) up to its BEL or ST terminator, or otherwise the single character following the ESC.
+fn skip_escape(chars: &mut std::str::Chars) {
+    match chars.next() {
+        Some('[') => {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+        Some(']' | 'P' | '_' | '^' | 'X') => {
+            while let Some(c) = chars.next() {
+                if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                    break;
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// A chat message from the log, as shared with a member who joined after it was sent. It's only as trustworthy as
+/// the member sharing it, since the original sender's signature isn't carried over.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedEntry {
+    pub id: MessageId,
+    /// Leaf index of the member who originally sent the message, as the member sharing it says. The member it's
+    /// shared with never attributes the message by it; see `ChatLog::push_shared()`.
+    pub author: u32,
+    /// The name the message was shown under.
+    pub sender: String,
+    pub time: i64,
+    pub text: String,
+}
+
+/// A decrypted application message, along with the group member who sent it.
+#[derive(Debug)]
+pub struct Received {
+    /// Leaf index of the sender in the group's ratchet tree.
+    pub sender: u32,
+    /// Identity from the sender's credential.
+    pub identity: String,
+    pub content: Vec<u8>,
+}
+
+/// Tracks the sequence numbers of each member's chat messages to spot the ones that never arrived, e.g. because the
+/// server dropped them from a full queue.
+#[derive(Debug, Default)]
+pub struct Sequences {
+    /// the last sequence number seen from each member, by leaf index
+    last: HashMap<u32, u64>,
+}
+
+impl Sequences {
+    /// Records chat message `seq` from the member at leaf index `sender`, returning how many of their messages were
+    /// skipped since the last one seen. A number at or below the last one seen means the sender started counting
+    /// over (they rejoined, or a new member took over their leaf), so it becomes the new baseline.
+    pub fn record(&mut self, sender: u32, seq: u64) -> u64 {
+        if seq == 0 {
+            return 0;
+        }
+
+        match self.last.insert(sender, seq) {
+            Some(last) if seq > last => seq - last - 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Number of the user's own outgoing messages `SentIds` remembers.
+const SENT_IDS: usize = 256;
+
+/// Remembers an id for each of the user's most recent outgoing MLS messages, so that one coming back from the server
+/// (e.g. one that echoes messages to their sender, or replays them after a reconnect) can be dropped instead of
+/// processed, which would have the client try to add itself or process its own commit. Ids are hashes of the
+/// serialized message keyed with a secret only this client knows, so nobody else can make a message look like one of
+/// the user's own.
+#[derive(Debug, Default)]
+pub struct SentIds {
+    key: RandomState,
+    ids: VecDeque<u64>,
+}
+
+impl SentIds {
+    /// Records a message the user is sending, forgetting the oldest one remembered if there are already `SENT_IDS`.
+    pub fn record(&mut self, data: &[u8]) {
+        if self.ids.len() == SENT_IDS {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(self.key.hash_one(data));
+    }
+
+    /// Returns whether `data` is one of the messages the user sent.
+    pub fn contains(&self, data: &[u8]) -> bool {
+        self.ids.contains(&self.key.hash_one(data))
+    }
+}
+
+/// Number of epochs `SeenIds` remembers messages from, counting back from the newest it's seen one from.
+const SEEN_EPOCHS: usize = 4;
+/// Number of messages `SeenIds` remembers from any one epoch.
+const SEEN_PER_EPOCH: usize = 1024;
+
+/// Remembers an id for each MLS message processed in the group's last few epochs, so that an exact duplicate (e.g.
+/// replayed again after a reconnect, or forwarded twice) is dropped quietly instead of failing to be processed a
+/// second time. Ids are keyed hashes of the serialized message, as in `SentIds`.
+#[derive(Debug, Default)]
+pub struct SeenIds {
+    key: RandomState,
+    epochs: BTreeMap<u64, HashSet<u64>>,
+}
+
+impl SeenIds {
+    /// Records a message sent in `epoch` that's been processed, forgetting the oldest epoch remembered if there are
+    /// more than `SEEN_EPOCHS`. Past `SEEN_PER_EPOCH` messages, the rest of an epoch's aren't remembered.
+    pub fn record(&mut self, epoch: u64, data: &[u8]) {
+        let ids = self.epochs.entry(epoch).or_default();
+        if ids.len() < SEEN_PER_EPOCH {
+            ids.insert(self.key.hash_one(data));
+        }
+        while self.epochs.len() > SEEN_EPOCHS {
+            self.epochs.pop_first();
+        }
+    }
+
+    /// Returns whether `data`, sent in `epoch`, has been processed already.
+    pub fn contains(&self, epoch: u64, data: &[u8]) -> bool {
+        self.epochs.get(&epoch).is_some_and(|ids| ids.contains(&self.key.hash_one(data)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let payloads = [
+            Payload::Chat { id: 1, seq: 1, time: 1_700_000_000, text: String::from("hi") },
+            Payload::Nick { name: String::from("bobby") },
+            Payload::Reaction { target: 1, emoji: String::from("🎉") },
+            Payload::Edit { target: 1, text: String::from("hi!") },
+            Payload::Delete { target: 1 },
+            Payload::HistoryRequest,
+            Payload::Cover { filler: String::from("x7Qa") },
+            Payload::History {
+                to: 2,
+                entries: vec![SharedEntry {
+                    id: 1,
+                    author: 0,
+                    sender: String::from("alice"),
+                    time: 1_700_000_000,
+                    text: String::from("hi"),
+                }],
+            },
+            Payload::Topic { text: String::from("book club") },
+        ];
+
+        for payload in payloads {
+            let decoded = Payload::decode(&payload.encode().unwrap());
+            assert_eq!(decoded.ok(), Some(payload));
+        }
+    }
+
+    #[test]
+    fn unnumbered_chat_decodes() {
+        let decoded = Payload::decode(br#"{"type":"chat","id":1,"time":1700000000,"text":"hi"}"#);
+        assert_eq!(decoded.ok(), Some(Payload::Chat { id: 1, seq: 0, time: 1_700_000_000, text: String::from("hi") }));
+    }
+
+    #[test]
+    fn sent_ids_remember_recent_messages() {
+        let mut sent = SentIds::default();
+        for i in 0..=SENT_IDS {
+            sent.record(&i.to_be_bytes());
+        }
+
+        assert!(sent.contains(&SENT_IDS.to_be_bytes()));
+        assert!(sent.contains(&1usize.to_be_bytes()));
+        assert!(!sent.contains(&0usize.to_be_bytes()));
+    }
+
+    #[test]
+    fn seen_ids_are_kept_per_epoch() {
+        let mut seen = SeenIds::default();
+        seen.record(1, b"commit");
+        assert!(seen.contains(1, b"commit"));
+        assert!(!seen.contains(2, b"commit"));
+
+        for epoch in 2..=SEEN_EPOCHS as u64 + 1 {
+            seen.record(epoch, b"hello");
+        }
+        assert!(!seen.contains(1, b"commit"));
+        assert!(seen.contains(2, b"hello"));
+    }
+
+    #[test]
+    fn sequence_gaps() {
+        let mut sequences = Sequences::default();
+        assert_eq!(sequences.record(1, 4), 0);
+        assert_eq!(sequences.record(1, 5), 0);
+        assert_eq!(sequences.record(1, 8), 2);
+        assert_eq!(sequences.record(2, 1), 0);
+        assert_eq!(sequences.record(1, 1), 0);
+        assert_eq!(sequences.record(1, 3), 1);
+        assert_eq!(sequences.record(1, 0), 0);
+    }
+
+    #[test]
+    fn sanitize_strips_escapes() {
+        assert_eq!(sanitize("\x1b[2J\x1b[31mred\x1b[0m", 100), "red");
+        assert_eq!(sanitize("\x1b]0;new title\x07hi\x1b]8;;http://x\x1b\\link", 100), "hilink");
+        assert_eq!(sanitize("a\nb\tc\x00\u{9b}d", 100), "a b cd");
+        assert_eq!(sanitize("abc\u{202e}def", 100), "abcdef");
+        assert_eq!(sanitize("hello world", 5), "hello…");
+        assert_eq!(sanitize("hello", 5), "hello");
+    }
+}
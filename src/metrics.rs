@@ -0,0 +1,204 @@
+use std::{
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long throughput is averaged over.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Counters for the local session, shown with `/stats` and in the status line. The `Client` counts the bytes it
+/// writes and reads, the round trips of its pings, and the times it reconnects, and the `Controller` counts the rest,
+/// so the two share it behind an `Arc`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    commits: AtomicU64,
+    reconnects: AtomicU64,
+    /// sum of every send latency recorded, in microseconds
+    latency_total: AtomicU64,
+    latency_samples: AtomicU64,
+    /// smoothed round trip time to the server, in microseconds, or 0 before the first is measured
+    round_trip: AtomicU64,
+    throughput: Mutex<ThroughputWindow>,
+}
+
+/// Bytes sent and received when the current throughput window started, along with the rates over the last one.
+#[derive(Debug, Default)]
+struct ThroughputWindow {
+    started: Option<Instant>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// bytes per second sent and received over the last full window
+    rates: Option<(u64, u64)>,
+}
+
+/// The values of the `Metrics` counters at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// MLS messages the user sent to the group, including commits.
+    pub messages_sent: u64,
+    /// MLS messages relayed to the user from the rest of the group.
+    pub messages_received: u64,
+    /// Bytes of frames written to the server, including their length prefixes and checksums.
+    pub bytes_sent: u64,
+    /// Bytes of frames read from the server, including their length prefixes and checksums.
+    pub bytes_received: u64,
+    /// Commits merged into the user's group, their own included.
+    pub commits: u64,
+    /// Times the connection to the server was lost and made again.
+    pub reconnects: u64,
+    /// Average time between sending a message and the server sequencing it, or None if none have been yet.
+    pub average_latency: Option<Duration>,
+    /// Smoothed time between sending the server a ping and its answer, or None if none have been answered yet.
+    pub round_trip: Option<Duration>,
+    /// Bytes per second written to the server over the last few seconds, or None until that's been measured.
+    pub upload_rate: Option<u64>,
+    /// Bytes per second read from the server over the last few seconds, or None until that's been measured.
+    pub download_rate: Option<u64>,
+}
+
+impl Metrics {
+    /// Counts an MLS message sent to the group.
+    pub fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an MLS message relayed from another member.
+    pub fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds `bytes` written to the server.
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Adds `bytes` read from the server.
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a commit merged into the group.
+    pub fn commit_merged(&self) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a successful reconnection to the server.
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long the server took to sequence a message the user sent.
+    pub fn record_latency(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.latency_total.fetch_add(micros, Ordering::Relaxed);
+        self.latency_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the round trip of a ping to the server, smoothing it with the ones before as TCP does (RFC 6298), so a
+    /// single slow answer doesn't throw it off.
+    pub fn record_round_trip(&self, round_trip: Duration) {
+        let sample = u64::try_from(round_trip.as_micros()).unwrap_or(u64::MAX).max(1);
+        // only the Client's reading task records round trips, so nothing races the update
+        let smoothed = match self.round_trip.load(Ordering::Relaxed) {
+            0 => sample,
+            smoothed => smoothed - smoothed / 8 + sample / 8,
+        };
+        self.round_trip.store(smoothed, Ordering::Relaxed);
+    }
+
+    /// Works out throughput over the window ending at `now`, if it's gone on for `THROUGHPUT_WINDOW`, and starts the
+    /// next one. Called regularly, e.g. whenever the status line is refreshed.
+    pub fn sample_throughput(&self, now: Instant) {
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        let bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        // a poisoned lock only means another thread panicked partway through sampling
+        let mut window = self.throughput.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let elapsed = match window.started {
+            Some(started) => now.saturating_duration_since(started),
+            None => {
+                *window = ThroughputWindow { started: Some(now), bytes_sent, bytes_received, rates: None };
+                return;
+            }
+        };
+        if elapsed < THROUGHPUT_WINDOW {
+            return;
+        }
+
+        let rate = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64()) as u64;
+        let rates = (rate(bytes_sent - window.bytes_sent), rate(bytes_received - window.bytes_received));
+        *window = ThroughputWindow { started: Some(now), bytes_sent, bytes_received, rates: Some(rates) };
+    }
+
+    /// Returns the current value of every counter.
+    pub fn stats(&self) -> Stats {
+        let samples = self.latency_samples.load(Ordering::Relaxed);
+        let round_trip = self.round_trip.load(Ordering::Relaxed);
+        let rates = self.throughput.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).rates;
+        Stats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            average_latency: (samples > 0)
+                .then(|| Duration::from_micros(self.latency_total.load(Ordering::Relaxed) / samples)),
+            round_trip: (round_trip > 0).then(|| Duration::from_micros(round_trip)),
+            upload_rate: rates.map(|(sent, _)| sent),
+            download_rate: rates.map(|(_, received)| received),
+        }
+    }
+}
+
+/// Formats a number of bytes for `/stats` and the status line, in KiB or MiB once there are enough of them, e.g.
+/// "1.5 KiB".
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_latency() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.stats().average_latency, None);
+
+        metrics.record_latency(Duration::from_millis(10));
+        metrics.record_latency(Duration::from_millis(30));
+        metrics.message_sent();
+        metrics.add_bytes_sent(100);
+        metrics.add_bytes_sent(20);
+        let stats = metrics.stats();
+        assert_eq!(stats.average_latency, Some(Duration::from_millis(20)));
+        assert_eq!((stats.messages_sent, stats.bytes_sent, stats.messages_received), (1, 120, 0));
+    }
+
+    #[test]
+    fn round_trip_and_throughput() {
+        let metrics = Metrics::default();
+        metrics.record_round_trip(Duration::from_millis(80));
+        metrics.record_round_trip(Duration::from_millis(160));
+        assert_eq!(metrics.stats().round_trip, Some(Duration::from_millis(90)));
+
+        let start = Instant::now();
+        metrics.sample_throughput(start);
+        metrics.add_bytes_sent(5000);
+        metrics.add_bytes_received(50_000);
+        metrics.sample_throughput(start + Duration::from_secs(1));
+        assert_eq!(metrics.stats().upload_rate, None);
+        metrics.sample_throughput(start + Duration::from_secs(10));
+        let stats = metrics.stats();
+        assert_eq!((stats.upload_rate, stats.download_rate), (Some(500), Some(5000)));
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use crate::{config::AccessLogConfig, ApplicationError, StorageError};
+
+use chrono::{SecondsFormat, Utc};
+
+/// A record of every connection to the server, for operators who need to account for who used a shared host: when
+/// it was accepted or turned away, the identity it registered, and when and why it closed, with the frames, bytes,
+/// and MLS messages it was relayed. Each line is a UTC timestamp, the event's name, and its details as `key=value`
+/// pairs, with identities quoted and escaped, as in the client's `AuditLog`. The file is rotated once it grows past
+/// `AccessLogConfig::max_size`.
+#[derive(Default)]
+pub struct AccessLog {
+    /// None if the log is turned off
+    file: Mutex<Option<LogFile>>,
+}
+
+struct LogFile {
+    file: File,
+    path: PathBuf,
+    /// bytes written to the file so far
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl AccessLog {
+    /// Opens the access log configured in `config` for appending, creating it and its parent directories if needed.
+    /// With no path configured, returns a log that records nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file can't be opened.
+    pub fn open(config: &AccessLogConfig) -> Result<AccessLog, ApplicationError> {
+        let Some(path) = &config.path else { return Ok(AccessLog::default()) };
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| StorageError::Config(format!("{}: {}", parent.display(), e)))?;
+        }
+        let file = open_append(path).map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)))?;
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+
+        let file = LogFile { file, path: path.clone(), size, max_size: config.max_size, keep: config.keep };
+        Ok(AccessLog { file: Mutex::new(Some(file)) })
+    }
+
+    /// Appends an event to the log, rotating it first if the line would take it past its maximum size. `details` is
+    /// written as is, and should already be in `key=value` form. A failure to write is ignored, since the server
+    /// carries on either way.
+    pub fn record(&self, event: &str, details: &str) {
+        // a poisoned lock only means another thread panicked partway through a write
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(file) = file.as_mut() else { return };
+
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let line = format!("{} {} {}\n", time, event, details);
+        let full = file.max_size > 0 && file.size > 0 && file.size + line.len() as u64 > file.max_size;
+        if full && file.rotate().is_err() {
+            return;
+        }
+        if file.file.write_all(line.as_bytes()).is_ok() {
+            file.size += line.len() as u64;
+        }
+    }
+}
+
+impl LogFile {
+    /// Moves the log to `<path>.1`, and each older one up by one, dropping any past `keep`, then starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(&self.path, self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1));
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Returns `path` with `.n` on the end, e.g. `access.log.2`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_past_max_size() {
+        let dir = std::env::temp_dir().join(format!("mls_chat_access_log_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("access.log");
+        let config = AccessLogConfig { path: Some(path.clone()), max_size: 100, keep: 2 };
+
+        let log = AccessLog::open(&config).unwrap();
+        for id in 1..=4 {
+            // each line is over half the maximum size, so every one after the first rotates the log
+            log.record("connect", &format!("id={} address=127.0.0.1:4000", id));
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap_or_default();
+        assert!(read(&path).contains(" connect id=4 "), "{}", read(&path));
+        assert!(read(&numbered(&path, 1)).contains(" connect id=3 "));
+        assert!(read(&numbered(&path, 2)).contains(" connect id=2 "));
+        assert!(!numbered(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fmt, net::{IpAddr, SocketAddr}, str::FromStr};
+use crate::errors::{ApplicationError, NetworkError};
+
+use tokio::net;
+
+/// Address of a server for a `Client` to connect to: a hostname or IP address, and a port. Hostnames are resolved
+/// each time the client connects, with `ServerAddr::resolve()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerAddr {
+    /// Hostname or IP address of the server. IPv6 addresses are stored without brackets.
+    pub host: String,
+    pub port: u16,
+}
+
+impl ServerAddr {
+    pub fn new(host: String, port: u16) -> ServerAddr {
+        ServerAddr { host, port }
+    }
+
+    /// Resolves the host into the socket addresses to try connecting to, in the order to try them: alternating
+    /// between IPv6 and IPv4, starting with the family of the first address the resolver returned, so that a family
+    /// that's broken on the network only ever holds up every other attempt (as RFC 8305's "happy eyeballs" does).
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::HostNotFound` if the host can't be resolved to any address.
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, ApplicationError> {
+        let Ok(addresses) = net::lookup_host((self.host.as_str(), self.port)).await else {
+            return Err(NetworkError::HostNotFound.into());
+        };
+        let addresses = interleave(addresses.collect());
+        if addresses.is_empty() {
+            return Err(NetworkError::HostNotFound.into());
+        }
+        Ok(addresses)
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(address: SocketAddr) -> Self {
+        ServerAddr::new(address.ip().to_string(), address.port())
+    }
+}
+
+/// A host to connect to as given on the command line with `join --target`, with the port if one was given along with
+/// it: `chat.example.org`, `chat.example.org:4000`, `192.0.2.7:4000`, `2001:db8::7`, or `[2001:db8::7]:4000`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a bare IPv6 address is full of colons, none of them before a port
+        if let Ok(ip @ IpAddr::V6(_)) = s.parse::<IpAddr>() {
+            return Ok(Target { host: ip.to_string(), port: None });
+        }
+
+        let (host, port) = if let Some(bracketed) = s.strip_prefix('[') {
+            match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(format!("unexpected \"{}\" after the IPv6 address", port)),
+                },
+                None => return Err(String::from("missing ] after the IPv6 address")),
+            }
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(String::from("the host is missing"));
+        }
+        if host.contains(|c: char| c.is_whitespace() || "/?#@[]".contains(c)) {
+            return Err(format!("\"{}\" isn't a hostname or IP address", host));
+        }
+        let port = match port {
+            Some(port) => Some(port.parse().map_err(|_| format!("\"{}\" isn't a valid port", port))?),
+            None => None,
+        };
+        Ok(Target { host: host.to_string(), port })
+    }
+}
+
+/// Helper function for `ServerAddr::resolve()`. Reorders `addresses` to alternate between families, starting with
+/// the family of the first, keeping the order within each family.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(ipv6) = addresses.first().map(SocketAddr::is_ipv6) else { return addresses };
+    let mut interleaved = Vec::with_capacity(addresses.len());
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses.into_iter()
+        .partition(|address| address.is_ipv6() == ipv6);
+
+    let mut other = other.into_iter();
+    for address in preferred {
+        interleaved.push(address);
+        interleaved.extend(other.next());
+    }
+    interleaved.extend(other);
+    interleaved
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_targets() {
+        let target = |host: &str, port| Target { host: host.to_string(), port };
+        assert_eq!("chat.example.org".parse(), Ok(target("chat.example.org", None)));
+        assert_eq!("chat.example.org:4000".parse(), Ok(target("chat.example.org", Some(4000))));
+        assert_eq!("192.0.2.7:4000".parse(), Ok(target("192.0.2.7", Some(4000))));
+        assert_eq!("2001:db8::7".parse(), Ok(target("2001:db8::7", None)));
+        assert_eq!("[2001:db8::7]:4000".parse(), Ok(target("2001:db8::7", Some(4000))));
+
+        assert!("chat.example.org:port".parse::<Target>().is_err());
+        assert!(":4000".parse::<Target>().is_err());
+        assert!("[2001:db8::7".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn format_v4_and_v6() {
+        for address in ["192.0.2.7:4000", "[2001:db8::7]:4000", "[::1]:4000"] {
+            let socket: SocketAddr = address.parse().unwrap();
+            assert_eq!(ServerAddr::from(socket).to_string(), address);
+        }
+        assert_eq!(ServerAddr::new(String::from("chat.example.org"), 4000).to_string(), "chat.example.org:4000");
+    }
+
+    #[test]
+    fn interleave_alternates_families() {
+        let v4: Vec<SocketAddr> = ["192.0.2.1:1", "192.0.2.2:1"].iter().map(|s| s.parse().unwrap()).collect();
+        let v6: Vec<SocketAddr> = ["[2001:db8::1]:1", "[2001:db8::2]:1", "[2001:db8::3]:1"].iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+
+        let addresses = v6.iter().chain(&v4).copied().collect();
+        assert_eq!(interleave(addresses), vec![v6[0], v4[0], v6[1], v4[1], v6[2]]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fs, io::ErrorKind, net::{IpAddr, SocketAddr}, os::unix::fs::{FileTypeExt, PermissionsExt}, path::Path};
+use crate::{
+    errors::{ApplicationError, NetworkError},
+    network::registry::{ConnectionId, ConnectionInfo, ConnectionStats},
+};
+
+use chrono::SecondsFormat;
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}};
+
+/// A request to a server's admin socket. On the socket, each request is a line of JSON naming the operation in `op`,
+/// e.g. `{"op":"kick","id":3}`, and is answered with a line holding an `AdminResponse`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// List every open connection.
+    List,
+    /// Close the connection `id`, telling its client not to reconnect.
+    Kick { id: ConnectionId },
+    /// Close every connection from `address`, and turn away any it opens from now on.
+    Ban { address: IpAddr },
+    /// Let `address` connect again.
+    Unban { address: IpAddr },
+    /// Show `text` to everyone connected.
+    Announce { text: String },
+    /// Report on the server as a whole.
+    Stats,
+    /// Shut the server down, as Ctrl+C would.
+    Shutdown,
+}
+
+/// The answer to an `AdminRequest`, naming what it holds in `result`, e.g. `{"result":"done"}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AdminResponse {
+    /// The request was carried out.
+    Done,
+    /// The answer to an `AdminRequest::List`.
+    Connections { connections: Vec<AdminConnection> },
+    /// The answer to an `AdminRequest::Stats`.
+    Stats { stats: ServerStats },
+    /// The request couldn't be carried out, e.g. because there's no such connection.
+    Error { message: String },
+}
+
+/// A connection as listed over the admin socket. Room codes are all it takes to join a room, so they're left out,
+/// as they are from the server's output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminConnection {
+    pub id: ConnectionId,
+    pub address: SocketAddr,
+    pub identity: Option<String>,
+    /// When the connection was accepted, in RFC 3339 format.
+    pub connected_at: String,
+    pub stats: ConnectionStats,
+}
+
+impl From<ConnectionInfo> for AdminConnection {
+    fn from(info: ConnectionInfo) -> Self {
+        AdminConnection {
+            id: info.id,
+            address: info.address,
+            identity: info.identity,
+            connected_at: info.connected_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            stats: info.stats,
+        }
+    }
+}
+
+/// The server as a whole, as reported over the admin socket.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub uptime_secs: u64,
+    pub connections: usize,
+    /// Rooms open, counting the one every connection is put in outside rendezvous mode.
+    pub rooms: usize,
+    /// Rooms created with `/create`, whether or not anyone's in them.
+    pub created_rooms: usize,
+    pub banned: Vec<IpAddr>,
+    /// Traffic on every connection since the server started, open or not.
+    pub traffic: ConnectionStats,
+}
+
+/// Opens the admin socket at `path`, replacing a socket left there by a server that didn't shut down cleanly. Only
+/// the user running the server can connect to it.
+///
+/// # Errors
+///
+/// Returns a `NetworkError::Admin` if something other than a socket is already at `path`, or the socket can't be
+/// opened.
+pub fn bind(path: &Path) -> Result<UnixListener, ApplicationError> {
+    let error = |e: &dyn std::fmt::Display| ApplicationError::Network(NetworkError::Admin(format!("{}: {}", path.display(), e)));
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path).map_err(|e| error(&e))?,
+        Ok(_) => return Err(error(&"already exists, and isn't a socket")),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(error(&e)),
+    }
+    let listener = UnixListener::bind(path).map_err(|e| error(&e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| error(&e))?;
+    Ok(listener)
+}
+
+/// Reads the next request from a connection to the admin socket, or None once it's closed. A line that isn't a valid
+/// request is returned as the `Err` to answer it with.
+pub async fn read_request<R>(reader: &mut R) -> Option<Result<AdminRequest, AdminResponse>>
+where R: AsyncBufReadExt + Unpin {
+    let mut line = String::new();
+    match reader.read_line(&mut line).await {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(serde_json::from_str(&line).map_err(|e| AdminResponse::Error { message: format!("invalid request: {}", e) })),
+    }
+}
+
+/// Writes a response to a connection to the admin socket.
+///
+/// # Errors
+///
+/// Returns a `NetworkError::Io` if writing fails.
+pub async fn write_response<W>(writer: &mut W, response: &AdminResponse) -> Result<(), ApplicationError>
+where W: AsyncWriteExt + Unpin {
+    // nothing in a response can fail to serialize
+    let mut line = serde_json::to_string(response).unwrap_or_default();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await.map_err(|_| NetworkError::Io.into())
+}
+
+/// Sends `request` to the admin socket at `path` and waits for the answer, for `mls_chat admin`.
+///
+/// # Errors
+///
+/// Returns a `NetworkError::Admin` if nothing's listening at `path`, or it doesn't answer with an `AdminResponse`.
+pub async fn request(path: &Path, request: &AdminRequest) -> Result<AdminResponse, ApplicationError> {
+    let error = |e: &dyn std::fmt::Display| ApplicationError::Network(NetworkError::Admin(format!("{}: {}", path.display(), e)));
+
+    let stream = UnixStream::connect(path).await.map_err(|e| error(&e))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request).map_err(|e| error(&e))?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await.map_err(|e| error(&e))?;
+
+    let mut answer = String::new();
+    BufReader::new(reader).read_line(&mut answer).await.map_err(|e| error(&e))?;
+    serde_json::from_str(&answer).map_err(|_| error(&"the server's answer couldn't be read"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_json_lines() {
+        let request = AdminRequest::Kick { id: 3 };
+        assert_eq!(serde_json::to_string(&request).unwrap(), r#"{"op":"kick","id":3}"#);
+        let parsed: AdminRequest = serde_json::from_str(r#"{"op":"ban","address":"203.0.113.7"}"#).unwrap();
+        assert_eq!(parsed, AdminRequest::Ban { address: IpAddr::from([203, 0, 113, 7]) });
+        assert_eq!(serde_json::to_string(&AdminResponse::Done).unwrap(), r#"{"result":"done"}"#);
+    }
+}
@@ -1,87 +1,591 @@
-use std::sync::Arc;
-use crate::ApplicationError;
-
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-    sync::Mutex,
-    task::JoinHandle,
-};
-
-pub struct Client {
-    input: Arc<Mutex<Vec<Vec<u8>>>>,
-    output: Arc<Mutex<Vec<Vec<u8>>>>,
-    stream: Option<TcpStream>,
-}
-
-impl Client {
-    /// Builds a new `Client`. Takes in the IP address (as a `String`) of the `Server` to connect to.
-    /// 
-    /// # Error
-    /// 
-    /// Returns an `ApplicationError::ConnectionFailed` if `TcpStream::connect()` can't connect
-    /// to the given address.
-    pub async fn build(address: String) -> Result<Client, ApplicationError> {
-        let input = Arc::new(Mutex::new(vec![]));
-        let output = Arc::new(Mutex::new(vec![]));
-        let Ok(stream) = TcpStream::connect(&address).await else {
-            return Err(ApplicationError::ConnectionFailed);
-        };
-
-        Ok(Client {
-            input,
-            output,
-            stream: Some(stream),
-        })
-    }
-
-    /// Returns a `Vec` of all messages received from the stream since it was last drained.
-    /// Removes the returned messages.
-    pub async fn get_input(&mut self) -> Vec<Vec<u8>> {
-        self.input.lock().await.drain(0..).collect()
-    }
-
-
-    /// Spawns a `tokio::task` to repeatedly send out any outgoing messages and read in incoming messages
-    /// from the `Server`. Returns the `JoinHandle<()>` of the task.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::ConnectionFailed` if this method was called on a `Client` whose
-    /// stream is None.
-    /// 
-    /// # TODO
-    /// 
-    /// Check if this function is erroneously sending an EOF byte when it shouldn't. Also, generally
-    /// doesn't work. Rethink how to concurrently read and write on the stream.
-    pub async fn handle_stream(&mut self) -> Result<JoinHandle<()>, ApplicationError> {
-        let input = Arc::clone(&self.input);
-        let output = Arc::clone(&self.output);
-        let Some(mut stream) = self.stream.take() else { return Err(ApplicationError::ConnectionFailed) };
-
-        Ok(tokio::spawn(async move { loop{
-            while let Some(msg) = output.lock().await.pop() {
-                match stream.write_all(&msg).await {
-                    Ok(_) => (),
-                    Err(_) => println!("Failed writing a message to the network stream."),
-                }
-            }
-
-            let mut buf_reader = BufReader::new(&mut stream);
-            let mut buffer = vec![];
-            match buf_reader.read(&mut buffer).await {
-                Ok(0) => { break; } // EOF
-                Ok(_) => input.lock().await.push(buffer),
-                _ => (),
-            }
-        }}))
-    }
-
-    /// Adds a newline to the end of a message, then pushes it onto the `Vec` of outgoing
-    /// messages. The newline is added due to the `Server` using `AsyncBufReadExt::read_until()` to
-    /// separate messages by newlines.
-    pub async fn send(&mut self, mut msg: Vec<u8>) {
-        msg.push(b'\n');
-        self.output.lock().await.push(msg);
-    }
-}
\ No newline at end of file
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::ErrorKind,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc},
+    time::Duration,
+};
+use crate::{
+    ApplicationError, NetworkError,
+    config::ClientTuning,
+    metrics::Metrics,
+    network::{address::ServerAddr, frame::{Frame, DEFLATE, FRAME_OVERHEAD}, wire::{self, Feature}},
+};
+
+use rand::Rng;
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+    sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, Mutex, Notify},
+    task::{JoinHandle, JoinSet},
+    time::{self, Instant},
+};
+
+/// How much longer `Client::build()` waits before each retry than before the one ahead of it.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long `Client::build()` waits on an attempt to connect to one of the server's addresses before it starts on the
+/// next alongside it, as RFC 8305 recommends.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long the server gets to answer the handshake in `Client::handshake()`.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the client goes without sending anything before it sends a `Frame::Ping`, so the server doesn't close the
+/// connection for being idle.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the `Client` is still connected to the `Server`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+pub struct Client {
+    address: String,
+    connected: Arc<AtomicBool>,
+    /// how long each attempt to connect gets, kept for `Client::reconnect()`
+    connect_timeout: Duration,
+    /// whether frames written to the current connection are compressed, as negotiated in `register()`
+    compress: bool,
+    /// whether compression is offered to the server when registering
+    compression: bool,
+    /// number of frames from the server dropped since `Client::take_corrupted()` last took them, for not matching
+    /// their checksum
+    corrupted: Arc<AtomicUsize>,
+    /// notified whenever the writing task finishes writing frames, or either task stops
+    drained: Arc<Notify>,
+    /// number of frames from the server skipped since `Client::take_dropped()` last took them, for not decoding
+    dropped: Arc<AtomicUsize>,
+    /// notified whenever the reading task adds frames to `input`, or stops
+    incoming: Arc<Notify>,
+    input: Arc<Mutex<Vec<Frame>>>,
+    /// longest random delay added before writing each outgoing frame
+    max_delay: Duration,
+    /// counters for `/stats`, shared with the `Controller`
+    metrics: Arc<Metrics>,
+    output: UnboundedSender<Frame>,
+    output_rx: Option<UnboundedReceiver<Frame>>,
+    /// number of frames queued with `Client::send()` that haven't been written to the stream yet
+    pending: Arc<AtomicUsize>,
+    /// attempt to connect again started by `Client::reconnect()`, until `Client::finish_reconnect()` takes its result
+    reconnecting: Option<JoinHandle<Result<(TcpStream, Registration), ApplicationError>>>,
+    /// where to connect again, or None for a `Client` that was never connected
+    server: Option<ServerAddr>,
+    stream: Option<TcpStream>,
+    /// notified whenever `Client::get_input()` takes the frames read so far
+    taken: Arc<Notify>,
+    tuning: ClientTuning,
+    /// protocol version spoken over the current connection, as negotiated in `register()`
+    version: u16,
+}
+
+impl Client {
+    /// Builds a new `Client`. Takes in the address of the `Server` to connect to, how long each attempt
+    /// to connect gets, and how many more attempts are made after the first fails, each after waiting `RETRY_DELAY`
+    /// longer than the last.
+    ///
+    /// # Error
+    ///
+    /// Returns a `NetworkError::HostNotFound` if the host can't be resolved, which isn't retried, or else
+    /// whichever error the last attempt failed with: a `NetworkError::ConnectTimedOut` if the server didn't accept
+    /// the connection within `timeout`, a `NetworkError::ConnectionRefused` if nothing is listening at the address,
+    /// or a `NetworkError::ConnectionFailed` if connecting failed any other way.
+    pub async fn build(address: ServerAddr, timeout: Duration, retries: u32) -> Result<Client, ApplicationError> {
+        let input = Arc::new(Mutex::new(vec![]));
+        let (output, output_rx) = mpsc::unbounded_channel();
+        let mut attempts = 0;
+        let stream = loop {
+            match Self::connect(&address, timeout).await {
+                Ok(stream) => break stream,
+                Err(err @ ApplicationError::Network(NetworkError::HostNotFound)) => return Err(err),
+                Err(err) if attempts >= retries => return Err(err),
+                Err(_) => {
+                    attempts += 1;
+                    tokio::time::sleep(RETRY_DELAY * attempts).await;
+                }
+            }
+        };
+
+        Ok(Client {
+            address: address.to_string(),
+            connected: Arc::new(AtomicBool::new(true)),
+            connect_timeout: timeout,
+            compress: false,
+            compression: true,
+            corrupted: Arc::new(AtomicUsize::new(0)),
+            drained: Arc::new(Notify::new()),
+            dropped: Arc::new(AtomicUsize::new(0)),
+            incoming: Arc::new(Notify::new()),
+            input,
+            max_delay: Duration::ZERO,
+            metrics: Arc::default(),
+            output,
+            output_rx: Some(output_rx),
+            pending: Arc::new(AtomicUsize::new(0)),
+            reconnecting: None,
+            server: Some(address),
+            stream: Some(stream),
+            taken: Arc::new(Notify::new()),
+            tuning: ClientTuning::default(),
+            version: wire::LEGACY_VERSION,
+        })
+    }
+
+    /// Helper function for `Client::build()`. Makes one attempt to connect to `address`, going through the socket
+    /// addresses it resolves to in the order `ServerAddr::resolve()` gives them. The next is started whenever the last
+    /// one fails, or has been going for `ATTEMPT_DELAY` without connecting, and the first connection made is used
+    /// (dropping the rest). If none are made, returns the error the last attempt failed with.
+    async fn connect(address: &ServerAddr, timeout: Duration) -> Result<TcpStream, ApplicationError> {
+        let mut addresses = address.resolve().await?.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut error = ApplicationError::Network(NetworkError::HostNotFound);
+        loop {
+            if let Some(address) = addresses.next() {
+                attempts.spawn(tokio::time::timeout(timeout, TcpStream::connect(address)));
+            }
+            let finished = if addresses.len() > 0 {
+                tokio::select! {
+                    finished = attempts.join_next() => finished,
+                    _ = tokio::time::sleep(ATTEMPT_DELAY) => continue,
+                }
+            } else {
+                attempts.join_next().await
+            };
+
+            error = match finished {
+                None => return Err(error),
+                Some(Ok(Ok(Ok(stream)))) => return Ok(stream),
+                Some(Ok(Ok(Err(e)))) if e.kind() == ErrorKind::ConnectionRefused => ApplicationError::Network(NetworkError::ConnectionRefused),
+                Some(Ok(Err(_))) => ApplicationError::Network(NetworkError::ConnectTimedOut),
+                Some(_) => ApplicationError::Network(NetworkError::ConnectionFailed),
+            };
+        }
+    }
+
+    /// Builds a `Client` with no connection behind it, for driving a `Controller` without a `Server`, e.g. in a
+    /// simulation. Returns it along with the receiver of every frame it sends and the buffer its received frames are
+    /// taken from, which `Client::get_input()` drains. `Client::handle_stream()` fails on it, so the frames sent are
+    /// only ever counted as pending.
+    pub fn in_memory(address: String) -> (Client, UnboundedReceiver<Frame>, Arc<Mutex<Vec<Frame>>>) {
+        let input = Arc::new(Mutex::new(vec![]));
+        let (output, output_rx) = mpsc::unbounded_channel();
+
+        let client = Client {
+            address,
+            connected: Arc::new(AtomicBool::new(true)),
+            connect_timeout: Duration::ZERO,
+            compress: false,
+            compression: false,
+            corrupted: Arc::new(AtomicUsize::new(0)),
+            drained: Arc::new(Notify::new()),
+            dropped: Arc::new(AtomicUsize::new(0)),
+            incoming: Arc::new(Notify::new()),
+            input: Arc::clone(&input),
+            max_delay: Duration::ZERO,
+            metrics: Arc::default(),
+            output,
+            output_rx: None,
+            pending: Arc::new(AtomicUsize::new(0)),
+            reconnecting: None,
+            server: None,
+            stream: None,
+            taken: Arc::new(Notify::new()),
+            // nothing drains the queues of a client with no connection, so they're left unbounded
+            tuning: ClientTuning { outbound_queue: 0, inbound_queue: 0, ..ClientTuning::default() },
+            // as if negotiated with a server as new as the client
+            version: wire::CURRENT_VERSION,
+        };
+        (client, output_rx, input)
+    }
+
+    /// Returns the address of the `Server` the client connected to.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns whether the connection to the `Server` is still open.
+    pub fn state(&self) -> ConnectionState {
+        if self.connected.load(Ordering::Relaxed) {
+            ConnectionState::Connected
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    /// Returns the protocol version spoken with the server, as negotiated by `Client::handshake()` or
+    /// `Client::reconnect()`. It's `wire::LEGACY_VERSION` with a server from before versions were negotiated.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Returns the number of outgoing frames still waiting to be written to the stream.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames from the server that were skipped for not decoding since this was last called.
+    pub fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames from the server that were dropped for arriving corrupted since this was last
+    /// called.
+    pub fn take_corrupted(&self) -> usize {
+        self.corrupted.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the session's `Metrics`, which the `Client` adds the bytes it writes and reads and its reconnections to.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Returns the `Notify` woken whenever frames arrive for `Client::get_input()`, and when the connection closes,
+    /// for the `Controller` to wait on between passes of its loop.
+    pub fn incoming(&self) -> Arc<Notify> {
+        Arc::clone(&self.incoming)
+    }
+
+    /// Waits until every frame queued so far has been written to the stream, the connection has closed, or `timeout`
+    /// has passed, whichever comes first, so frames queued just before the client exits aren't lost. Returns whether
+    /// every frame was written.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.pending() > 0 && self.state() == ConnectionState::Connected {
+            let drained = self.drained.notified();
+            // checked again now the notification can't be missed
+            if self.pending() == 0 {
+                break;
+            }
+            if time::timeout_at(deadline, drained).await.is_err() {
+                break;
+            }
+        }
+        self.pending() == 0
+    }
+
+    /// Delays each outgoing frame by a random amount of time up to `max_delay` before it's written, so that the
+    /// timing of the client's traffic says less about when the user acted. Frames are still sent in order. Only
+    /// takes effect if called before `Client::handle_stream()`.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
+    /// Sets the capacities of the client's queues and of the buffers its connection is read and written through. The
+    /// buffers only take effect if this is called before `Client::handle_stream()`.
+    pub fn set_tuning(&mut self, tuning: ClientTuning) {
+        self.tuning = tuning;
+    }
+
+    /// Registers the id `id` with the server, first entering the room with the code `room` if there is one (see
+    /// `Server::set_rendezvous()`). Returns the id the server registered the connection under, which has a suffix added
+    /// if another connection in the room already goes by `id`. Frames the server sends ahead of its answer are kept
+    /// for `Client::get_input()`. Only works if called before `Client::handle_stream()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::ServerFull` if the server has no room for the connection, a
+    /// `NetworkError::ConnectionFailed` if the client has no stream, or if the server closes the connection or
+    /// doesn't answer within `HANDSHAKE_TIMEOUT`, or any `ApplicationError` types returned by `Frame::write()` or
+    /// `Frame::read()`.
+    pub async fn handshake(&mut self, room: Option<&str>, id: &str) -> Result<String, ApplicationError> {
+        let Some(stream) = self.stream.as_mut() else { return Err(NetworkError::ConnectionFailed.into()) };
+        let registration = register(stream, &self.input, room, id, self.compression).await?;
+        self.compress = registration.compress;
+        self.version = registration.version;
+        Ok(registration.id)
+    }
+
+    /// Sets whether compression of large frames is offered to the server when registering, which it is by default.
+    /// Only takes effect from the next `Client::handshake()` or `Client::reconnect()`.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Starts connecting to the `Server` again in the background after the connection was lost, registering `id` (in
+    /// `room`, if there is one) as `Client::handshake()` does. The `Notify` from `Client::incoming()` is woken once the
+    /// attempt finishes, and `Client::finish_reconnect()` takes it from there. Does nothing if an attempt is already
+    /// under way, or if the `Client` was never connected.
+    pub fn reconnect(&mut self, room: Option<String>, id: String) {
+        let Some(address) = self.server.clone() else { return };
+        if self.reconnecting.is_some() {
+            return;
+        }
+        let (input, incoming, timeout) = (Arc::clone(&self.input), Arc::clone(&self.incoming), self.connect_timeout);
+        let compression = self.compression;
+        self.reconnecting = Some(tokio::spawn(async move {
+            let attempt = async {
+                let mut stream = Self::connect(&address, timeout).await?;
+                let registration = register(&mut stream, &input, room.as_deref(), &id, compression).await?;
+                Ok((stream, registration))
+            }.await;
+            incoming.notify_one();
+            attempt
+        }));
+    }
+
+    /// Returns whether an attempt started by `Client::reconnect()` is still under way.
+    pub fn reconnecting(&self) -> bool {
+        self.reconnecting.as_ref().is_some_and(|attempt| !attempt.is_finished())
+    }
+
+    /// Takes the result of the attempt started by `Client::reconnect()` once it's finished, returning None until then.
+    /// If it connected, the stream is handled as `Client::handle_stream()` does, with a fresh outgoing queue: frames
+    /// queued while the connection was down are dropped, since the server would take them for the new connection's.
+    ///
+    /// # Errors
+    ///
+    /// Returns whatever `ApplicationError` the attempt failed with, as `Client::build()` and `Client::handshake()`
+    /// would.
+    pub async fn finish_reconnect(&mut self) -> Option<Result<(), ApplicationError>> {
+        if self.reconnecting() {
+            return None;
+        }
+        let attempt = self.reconnecting.take()?;
+        let stream = match attempt.await {
+            Ok(Ok((stream, registration))) => {
+                self.compress = registration.compress;
+                self.version = registration.version;
+                stream
+            }
+            Ok(Err(err)) => return Some(Err(err)),
+            Err(_) => return Some(Err(NetworkError::ConnectionFailed.into())),
+        };
+
+        // the old tasks may still hold the old counters, so the new ones start from scratch
+        let (output, output_rx) = mpsc::unbounded_channel();
+        self.output = output;
+        self.output_rx = Some(output_rx);
+        self.pending = Arc::new(AtomicUsize::new(0));
+        self.connected = Arc::new(AtomicBool::new(true));
+        self.stream = Some(stream);
+        self.metrics.reconnected();
+        Some(self.handle_stream().await.map(|_| ()))
+    }
+
+    /// Returns a `Vec` of all frames received from the stream since it was last drained.
+    /// Removes the returned frames.
+    pub async fn get_input(&mut self) -> Vec<Frame> {
+        let input = self.input.lock().await.drain(0..).collect();
+        self.taken.notify_waiters();
+        input
+    }
+
+
+    /// Splits the stream and spawns a `tokio::task` for each half: one to send out outgoing frames as they're queued,
+    /// and one to read in incoming frames from the `Server`. Both halves are buffered, and frames that pile up while
+    /// earlier ones are being written go out together in a single flush. Returns the `JoinHandle<()>` of the reading
+    /// task, which finishes when the server closes the connection. Whenever `KEEPALIVE_INTERVAL` passes without a frame
+    /// to send, a `Frame::Ping` is sent instead. The time until each ping is answered is recorded in the `Metrics` as
+    /// the round trip to the server. Once `ClientTuning::inbound_queue` frames are waiting for `Client::get_input()`,
+    /// reading stops until they've been taken.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::ConnectionFailed` if this method was called on a `Client` whose
+    /// stream is None.
+    pub async fn handle_stream(&mut self) -> Result<JoinHandle<()>, ApplicationError> {
+        let input = Arc::clone(&self.input);
+        let Some(stream) = self.stream.take() else { return Err(NetworkError::ConnectionFailed.into()) };
+        let Some(mut output) = self.output_rx.take() else { return Err(NetworkError::ConnectionFailed.into()) };
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::with_capacity(self.tuning.read_buffer, reader);
+        let mut writer = BufWriter::with_capacity(self.tuning.write_buffer, writer);
+        let pending = Arc::clone(&self.pending);
+        let connected = Arc::clone(&self.connected);
+        let (drained, incoming) = (Arc::clone(&self.drained), Arc::clone(&self.incoming));
+        let (dropped, corrupted) = (Arc::clone(&self.dropped), Arc::clone(&self.corrupted));
+        let (metrics, read_metrics) = (Arc::clone(&self.metrics), Arc::clone(&self.metrics));
+        let (max_delay, compress) = (self.max_delay, self.compress);
+        let (taken, inbound_queue, closed) = (Arc::clone(&self.taken), self.tuning.inbound_queue, Arc::clone(&self.drained));
+        // when each ping still unanswered was sent, oldest first, which the server answers in order
+        let pings = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+        let answered = Arc::clone(&pings);
+
+        tokio::spawn(async move {
+            loop {
+                let frame = match tokio::time::timeout(KEEPALIVE_INTERVAL, output.recv()).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(_) => {
+                        if Frame::Ping.write(&mut writer).await.is_err() || writer.flush().await.is_err() {
+                            break;
+                        }
+                        metrics.add_bytes_sent(Frame::Ping.encoded_len());
+                        pings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push_back(Instant::now());
+                        continue;
+                    }
+                };
+                if !max_delay.is_zero() {
+                    let delay = rand::thread_rng().gen_range(Duration::ZERO..=max_delay);
+                    tokio::time::sleep(delay).await;
+                }
+                let mut pinged = usize::from(frame == Frame::Ping);
+                let mut res = frame.write_with(&mut writer, compress).await;
+                let (mut written, mut bytes) = (1, res.as_ref().map_or(0, |len| *len));
+                // frames delayed one by one have to be flushed one by one
+                while res.is_ok() && max_delay.is_zero() {
+                    let Ok(frame) = output.try_recv() else { break };
+                    pinged += usize::from(frame == Frame::Ping);
+                    res = frame.write_with(&mut writer, compress).await;
+                    written += 1;
+                    bytes += res.as_ref().map_or(0, |len| *len);
+                }
+                if res.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+                pending.fetch_sub(written, Ordering::Relaxed);
+                metrics.add_bytes_sent(bytes);
+                let sent = Instant::now();
+                pings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).extend((0..pinged).map(|_| sent));
+                drained.notify_waiters();
+            }
+            drained.notify_waiters();
+        });
+
+        Ok(tokio::spawn(async move {
+            // a frame that's corrupted or doesn't decode is skipped, since the stream is still in step after it;
+            // anything else ends it
+            loop {
+                match Frame::read_body(&mut reader).await {
+                    Ok(Some(body)) => {
+                        read_metrics.add_bytes_received(FRAME_OVERHEAD + body.len());
+                        match Frame::decode(&body) {
+                            Ok(frame) => {
+                                if matches!(frame, Frame::Pong { .. }) {
+                                    let sent = answered.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).pop_front();
+                                    if let Some(sent) = sent {
+                                        read_metrics.record_round_trip(sent.elapsed());
+                                    }
+                                }
+                                loop {
+                                    let mut input = input.lock().await;
+                                    if inbound_queue == 0 || input.len() < inbound_queue {
+                                        input.push(frame);
+                                        break;
+                                    }
+                                    // created while the lock is held, so the frames can't be taken in between
+                                    let taken = taken.notified();
+                                    drop(input);
+                                    incoming.notify_one();
+                                    taken.await;
+                                }
+                            }
+                            Err(_) => {
+                                dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    Err(ApplicationError::Network(NetworkError::CorruptFrame)) => {
+                        corrupted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => break,
+                }
+                incoming.notify_one();
+            }
+            connected.store(false, Ordering::Relaxed);
+            incoming.notify_one();
+            closed.notify_waiters();
+        }))
+    }
+
+    /// Wraps a serialized MLS message in a `Frame` and queues it to be sent to the `Server`.
+    pub async fn send(&mut self, msg: Vec<u8>) {
+        self.send_frame(Frame::Mls(msg)).await;
+    }
+
+    /// Queues a frame to be sent to the `Server`, first waiting for room if `ClientTuning::outbound_queue` frames are
+    /// already waiting to be written.
+    pub async fn send_frame(&mut self, frame: Frame) {
+        // waits for room in the queue, unless the connection's gone and it'll never be drained
+        let limit = self.tuning.outbound_queue;
+        while limit > 0 && self.pending() >= limit && self.state() == ConnectionState::Connected {
+            let drained = self.drained.notified();
+            if self.pending() < limit || self.state() == ConnectionState::Disconnected {
+                break;
+            }
+            drained.await;
+        }
+        // counted before queueing so the writing task can't finish the frame first
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        // an Err only means the writing task has already stopped because the connection closed
+        if self.output.send(frame).is_err() {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// What `register()` settled with the server.
+struct Registration {
+    /// id the server registered the connection under
+    id: String,
+    /// whether the server took up compression
+    compress: bool,
+    /// protocol version picked from those the server offered
+    version: u16,
+}
+
+/// Helper function for `Client::handshake()` and `Client::reconnect()`. Enters `room` if there is one, picks a protocol
+/// version from those the server offers in its `Frame::Connected`, offers it compression if `compression` is set and
+/// the version has it, and registers `id`, keeping frames the server sends ahead of its answer (the `Connected` among
+/// them) in `input`.
+async fn register(
+    stream: &mut TcpStream,
+    input: &Mutex<Vec<Frame>>,
+    room: Option<&str>,
+    id: &str,
+    compression: bool,
+) -> Result<Registration, ApplicationError> {
+    if let Some(room) = room {
+        Frame::Enter { room: room.to_string() }.write(stream).await?;
+    }
+
+    let answer = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let offered = loop {
+            match Frame::read(stream).await? {
+                Some(Frame::Connected { id, versions }) => {
+                    input.lock().await.push(Frame::Connected { id, versions: versions.clone() });
+                    break versions;
+                }
+                Some(Frame::ServerFull) => return Err(NetworkError::ServerFull.into()),
+                Some(frame) => input.lock().await.push(frame),
+                None => return Err(NetworkError::ConnectionFailed.into()),
+            }
+        };
+        // a server that offered nothing doesn't know the frame, and speaks the legacy version
+        let version = match wire::pick_version(&offered) {
+            Some(version) => {
+                Frame::Version { version }.write(stream).await?;
+                version
+            }
+            None => wire::LEGACY_VERSION,
+        };
+        if compression && Feature::Compression.available(version) {
+            Frame::Compression { codecs: vec![String::from(DEFLATE)] }.write(stream).await?;
+        }
+        Frame::Register { id: id.to_string() }.write(stream).await?;
+
+        // the server answers the offer ahead of the `Register`
+        let mut compress = false;
+        loop {
+            match Frame::read(stream).await? {
+                Some(Frame::Registered { id }) => return Ok(Registration { id, compress, version }),
+                Some(Frame::Compression { codecs }) => compress = codecs.iter().any(|codec| codec == DEFLATE),
+                Some(Frame::ServerFull) => return Err(NetworkError::ServerFull.into()),
+                Some(frame) => input.lock().await.push(frame),
+                None => return Err(NetworkError::ConnectionFailed.into()),
+            }
+        }
+    }).await;
+    answer.unwrap_or(Err(NetworkError::ConnectionFailed.into()))
+}
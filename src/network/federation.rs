@@ -0,0 +1,145 @@
+use std::str::FromStr;
+use crate::{
+    errors::{ApplicationError, NetworkError},
+    invite::{self, Invite},
+    network::frame::Frame,
+};
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Most servers a connection can be forwarded through before it's taken for a loop and closed.
+pub const MAX_HOPS: usize = 8;
+
+/// A room hosted on another server, its home, which a server forwards its own connections to, so that members who
+/// joined either server share the one group. The home server orders every message in the room, so members on both
+/// servers always agree on the order of the group's commits.
+///
+/// Parses from the `[<room>=]<invite>` form `host --federate` takes: `room` is the code of the room on this server
+/// (left out for the single room of a server outside rendezvous mode), and the invite says where the room's home is,
+/// including its code there if the home is a rendezvous server. The two codes don't have to match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Federation {
+    pub room: String,
+    pub home: Invite,
+}
+
+impl FromStr for Federation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // an invite can't contain =, outside its query
+        let (room, uri) = match s.split_once('=') {
+            Some((room, uri)) if !room.contains('?') => (room, uri),
+            _ => ("", s),
+        };
+        if !room.is_empty() {
+            invite::check_room_code(room)?;
+        }
+
+        let home: Invite = uri.parse()?;
+        if home.tls {
+            return Err(String::from("federating with a server over TLS isn't supported yet"));
+        }
+        if let Some(room) = &home.room {
+            invite::check_room_code(room)?;
+        }
+        Ok(Federation { room: room.to_string(), home })
+    }
+}
+
+/// Forwards a connection to the room's home server, relaying frames between them in both directions until either
+/// side closes or `cancel` is cancelled, in which case the connection is sent a `Frame::ServerClosing` first. `name`
+/// is this server's name, which the home server is told the connection is forwarded through with a
+/// `Frame::Forwarded`.
+///
+/// The home server is only connected to once the connection's first frame has arrived, so that a connection already
+/// forwarded by another server is checked for a loop before it's forwarded any further.
+///
+/// # Errors
+///
+/// Returns a `NetworkError::ConnectionFailed` if the home server can't be reached, a
+/// `NetworkError::FederationLoop` if the connection has already been forwarded through this server or through
+/// `MAX_HOPS` servers, or any `ApplicationError` types returned by `Frame::read()` or `Frame::write()`.
+pub async fn forward(
+    mut reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    federation: &Federation,
+    name: &str,
+    cancel: CancellationToken,
+) -> Result<(), ApplicationError> {
+    let mut writer = BufWriter::new(writer);
+    let first = tokio::select! {
+        frame = Frame::read(&mut reader) => frame?,
+        _ = cancel.cancelled() => return Ok(()),
+    };
+    let Some(first) = first else { return Ok(()) };
+    let (via, first) = match first {
+        Frame::Forwarded { via } => (via, None),
+        frame => (Vec::new(), Some(frame)),
+    };
+    if via.iter().any(|server| server == name) || via.len() >= MAX_HOPS {
+        return Err(NetworkError::FederationLoop.into());
+    }
+
+    let home = TcpStream::connect((federation.home.host.as_str(), federation.home.port)).await
+        .map_err(|_| NetworkError::ConnectionFailed)?;
+    let (home_reader, home_writer) = home.into_split();
+    let (mut home_reader, mut home_writer) = (BufReader::new(home_reader), BufWriter::new(home_writer));
+
+    if let Some(room) = &federation.home.room {
+        Frame::Enter { room: room.clone() }.write(&mut home_writer).await?;
+    }
+    Frame::Forwarded { via: via.into_iter().chain([name.to_string()]).collect() }.write(&mut home_writer).await?;
+    if let Some(frame) = first {
+        frame.write(&mut home_writer).await?;
+    }
+    flush(&mut home_writer).await?;
+
+    let closing = tokio::select! {
+        res = relay(&mut reader, &mut home_writer) => res.map(|_| false),
+        res = relay(&mut home_reader, &mut writer) => res.map(|_| false),
+        _ = cancel.cancelled() => Ok(true),
+    }?;
+
+    if closing {
+        Frame::ServerClosing.write(&mut writer).await?;
+        flush(&mut writer).await?;
+    }
+    Ok(())
+}
+
+/// Helper function for `forward()`. Relays every frame from `reader` to `writer` as it arrives.
+async fn relay(reader: &mut BufReader<OwnedReadHalf>, writer: &mut BufWriter<OwnedWriteHalf>) -> Result<(), ApplicationError> {
+    while let Some(frame) = Frame::read(reader).await? {
+        frame.write(writer).await?;
+        flush(writer).await?;
+    }
+    Ok(())
+}
+
+async fn flush(writer: &mut BufWriter<OwnedWriteHalf>) -> Result<(), ApplicationError> {
+    writer.flush().await.map_err(|_| NetworkError::Io.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_federations() {
+        let federation: Federation = "book-club=mls-chat://198.51.100.7:4000/bookclub".parse().unwrap();
+        assert_eq!(federation.room, "book-club");
+        assert_eq!(federation.home.room.as_deref(), Some("bookclub"));
+
+        let federation: Federation = "mls-chat://198.51.100.7:4000/".parse().unwrap();
+        assert_eq!(federation.room, "");
+        assert_eq!(federation.home.room, None);
+
+        assert!("room=mls-chat://198.51.100.7:4000/?tls=1".parse::<Federation>().is_err());
+    }
+}
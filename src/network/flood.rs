@@ -0,0 +1,163 @@
+use std::{collections::HashMap, time::Duration};
+use crate::{config::RateLimitConfig, trace};
+
+use tokio::time::Instant;
+
+/// Flood protection for a server's rooms. Each identity in a room gets a token bucket holding up to
+/// `RateLimitConfig::burst` chat messages and refilling at `RateLimitConfig::rate` a second, shared by every
+/// connection registered under it, so reconnecting doesn't get around it. A message sent with the bucket empty is
+/// held back until it refills, which throttles the connection as a whole, and an identity that has
+/// `RateLimitConfig::mute_after` messages held back in a row is muted for `RateLimitConfig::mute_secs`, during which its
+/// chat messages are dropped. Only application messages count: handshake messages are always relayed, since dropping
+/// one would break the group for everyone.
+#[derive(Debug, Default)]
+pub struct FloodControl {
+    limits: RateLimitConfig,
+    /// by room code and identity
+    senders: HashMap<(String, String), SenderState>,
+}
+
+#[derive(Debug)]
+struct SenderState {
+    bucket: TokenBucket,
+    /// messages held back in a row, since the last one the bucket had a token for straight away
+    held: u32,
+    muted_until: Option<Instant>,
+}
+
+/// What the server does with a chat message, as decided by `FloodControl::check()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Relay the message.
+    Relay,
+    /// Hold the message back this long, then check again.
+    Wait(Duration),
+    /// The sender has just been muted for this long; drop the message and tell the room.
+    Mute(Duration),
+    /// The sender is still muted for this long; drop the message.
+    Muted(Duration),
+}
+
+/// A token bucket holding up to `capacity` tokens, refilling at `rate` a second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Returns a full bucket.
+    pub fn new(capacity: u32, rate: f64, now: Instant) -> TokenBucket {
+        let capacity = f64::from(capacity.max(1));
+        TokenBucket { capacity, rate, tokens: capacity, updated: now }
+    }
+
+    /// Takes a token if there is one, or returns how long it'll be until there is.
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+impl FloodControl {
+    pub fn new(limits: RateLimitConfig) -> FloodControl {
+        FloodControl { limits, senders: HashMap::new() }
+    }
+
+    /// Decides what to do with a chat message `identity` sent to `room`. A message that has to `Verdict::Wait` is
+    /// only counted as held back once, however many times it's checked again.
+    pub fn check(&mut self, room: &str, identity: &str, waited: bool, now: Instant) -> Verdict {
+        if self.limits.rate <= 0.0 {
+            return Verdict::Relay;
+        }
+        let limits = self.limits;
+        let sender = self.senders.entry((room.to_string(), identity.to_string())).or_insert_with(|| SenderState {
+            bucket: TokenBucket::new(limits.burst, limits.rate, now),
+            held: 0,
+            muted_until: None,
+        });
+
+        if let Some(until) = sender.muted_until {
+            if until > now {
+                return Verdict::Muted(until - now);
+            }
+            sender.muted_until = None;
+        }
+        match sender.bucket.take(now) {
+            Ok(()) => {
+                if !waited {
+                    sender.held = 0;
+                }
+                Verdict::Relay
+            }
+            Err(wait) if waited => Verdict::Wait(wait),
+            Err(wait) => {
+                sender.held += 1;
+                if limits.mute_after > 0 && sender.held >= limits.mute_after {
+                    let mute = Duration::from_secs(limits.mute_secs);
+                    sender.held = 0;
+                    sender.muted_until = Some(now + mute);
+                    // a fresh start once the mute is over
+                    sender.bucket = TokenBucket::new(limits.burst, limits.rate, now + mute);
+                    Verdict::Mute(mute)
+                } else {
+                    Verdict::Wait(wait)
+                }
+            }
+        }
+    }
+
+    /// Forgets every identity that has neither been muted nor sent anything for long enough that its bucket would
+    /// have refilled, so that the state kept doesn't grow with every identity that's ever sent a message.
+    pub fn prune(&mut self, now: Instant) {
+        let refill = Duration::from_secs_f64(f64::from(self.limits.burst.max(1)) / self.limits.rate.max(f64::EPSILON));
+        self.senders.retain(|_, sender| {
+            sender.muted_until.is_some_and(|until| until > now)
+                || now.saturating_duration_since(sender.bucket.updated) < refill
+        });
+    }
+}
+
+/// Returns whether `data`, the contents of a `Frame::Mls`, is a chat message, which is all flood protection applies
+/// to. Only its content type is read, which is sent in the clear even in a `PrivateMessage`.
+pub fn is_chat_message(data: &[u8]) -> bool {
+    trace::describe(data).kind.ends_with("_application")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_then_mutes_floods() {
+        let limits = RateLimitConfig { rate: 1.0, burst: 2, mute_after: 3, mute_secs: 60 };
+        let mut flood = FloodControl::new(limits);
+        let start = Instant::now();
+        let check = |flood: &mut FloodControl, identity: &str, secs: f64| {
+            flood.check("room", identity, false, start + Duration::from_secs_f64(secs))
+        };
+
+        assert_eq!(check(&mut flood, "alice", 0.0), Verdict::Relay);
+        assert_eq!(check(&mut flood, "alice", 0.0), Verdict::Relay);
+        assert_eq!(check(&mut flood, "alice", 0.0), Verdict::Wait(Duration::from_secs(1)));
+        // others aren't held back on alice's account
+        assert_eq!(check(&mut flood, "bob", 0.0), Verdict::Relay);
+        assert_eq!(check(&mut flood, "alice", 0.5), Verdict::Wait(Duration::from_millis(500)));
+        assert_eq!(check(&mut flood, "alice", 0.5), Verdict::Mute(Duration::from_secs(60)));
+        assert_eq!(check(&mut flood, "alice", 30.5), Verdict::Muted(Duration::from_secs(30)));
+        assert_eq!(check(&mut flood, "alice", 61.0), Verdict::Relay);
+
+        flood.prune(start + Duration::from_secs(100));
+        assert!(flood.senders.is_empty());
+    }
+}
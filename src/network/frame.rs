@@ -0,0 +1,738 @@
+use crate::{ApplicationError, NetworkError};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound on the length of a single frame, to keep a misbehaving peer from making the reader
+/// allocate arbitrarily large buffers.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Bytes each frame takes on the wire on top of its body: the length prefix before it, and the checksum after it.
+pub const FRAME_OVERHEAD: usize = 8;
+
+/// Name of the compression codec offered and accepted in a `Frame::Compression`: raw DEFLATE.
+pub const DEFLATE: &str = "deflate";
+
+/// Bodies at least this long are compressed once compression's been negotiated; anything shorter wouldn't gain
+/// enough to be worth it.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Bit set in a frame's length prefix if its body is compressed. `MAX_FRAME_LEN` is well below it, so it can't be
+/// mistaken for part of a length.
+const COMPRESSED: u32 = 1 << 31;
+
+/// CRC-32 lookup table for the reflected IEEE polynomial, as used by Ethernet and zlib.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+};
+
+const MLS: u8 = 0;
+const RELAY: u8 = 1;
+const LAGGED: u8 = 2;
+const RESYNC: u8 = 3;
+const SERVER_CLOSING: u8 = 4;
+const PUBLISH_KEY_PACKAGE: u8 = 5;
+const KEY_PACKAGES_AVAILABLE: u8 = 6;
+const CLAIM_KEY_PACKAGE: u8 = 7;
+const KEY_PACKAGE: u8 = 8;
+const KEY_PACKAGE_CLAIMED: u8 = 9;
+const SEQUENCED: u8 = 10;
+const RESUME: u8 = 11;
+const CONNECTED: u8 = 12;
+const DISCONNECTED: u8 = 13;
+const ENTER: u8 = 14;
+const FORWARDED: u8 = 15;
+const REGISTER: u8 = 16;
+const REGISTERED: u8 = 17;
+const SERVER_FULL: u8 = 18;
+const PING: u8 = 19;
+const PONG: u8 = 20;
+const CREATE_ROOM: u8 = 21;
+const ROOM_CREATED: u8 = 22;
+const ROOM_REFUSED: u8 = 23;
+const ROOM_OWNER: u8 = 24;
+const KICKED: u8 = 25;
+const ANNOUNCEMENT: u8 = 26;
+const MUTED: u8 = 27;
+const RETRANSMIT: u8 = 28;
+const COMPRESSION: u8 = 29;
+const PUBLISH_RATCHET_TREE: u8 = 30;
+const FETCH_RATCHET_TREE: u8 = 31;
+const RATCHET_TREE: u8 = 32;
+const VERSION: u8 = 33;
+
+/// The unit of communication between a `Client` and the `Server`. On the wire, every frame is a
+/// big-endian `u32` length followed by a one-byte tag and the tag's body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// A serialized MLS message sent by a client, to be relayed to every other connection.
+    Mls(Vec<u8>),
+    /// A serialized MLS message relayed by the server, tagged with its sequence number in the
+    /// server's relay history.
+    Relay { seq: u64, data: Vec<u8> },
+    /// Sent by the server when a connection fell behind and `missed` relayed messages were dropped
+    /// for it. Followed by a replay of the dropped messages, or by a `Resync` if they're no longer
+    /// in the server's history.
+    Lagged { missed: u64 },
+    /// Sent by the server when messages a connection missed couldn't be replayed, meaning the
+    /// client's MLS group state can no longer be trusted to match the rest of the group.
+    Resync,
+    /// Sent by the server to every connection right before it shuts down.
+    ServerClosing,
+    /// A serialized key package sent by a client to be kept in the server's directory, for other members to claim
+    /// when adding the client to the group. The `last_resort` package is handed out once the rest have all been
+    /// claimed, and is never used up. Publishing a new last resort drops every package published before it.
+    PublishKeyPackage { last_resort: bool, data: Vec<u8> },
+    /// Sent by the server to every other connection when the connection `owner` publishes its first key package
+    /// after a last resort.
+    KeyPackagesAvailable { owner: u64 },
+    /// Sent by a client to claim one of the key packages the connection `owner` published.
+    ClaimKeyPackage { owner: u64 },
+    /// Sent by the server in answer to a `ClaimKeyPackage`, with the key package claimed.
+    KeyPackage { owner: u64, data: Vec<u8> },
+    /// Sent by the server to a connection when one of its key packages is claimed, with the number it has left
+    /// (not counting its last resort), so it can publish more before they run out.
+    KeyPackageClaimed { remaining: u64 },
+    /// Sent by the server to a connection in place of relaying its own MLS message back to it, at the same point in
+    /// the stream, so the client knows every message relayed before this was ordered ahead of its own. `seq` is the
+    /// message's sequence number in the relay history.
+    Sequenced { seq: u64 },
+    /// Sent by a client resuming a saved session, as its first frame, to have the server replay every message relayed
+    /// after `after`: the last one it handled before it went away. Answered with a `Resync` if some of them are no
+    /// longer in the server's history.
+    Resume { after: u64 },
+    /// Sent by the server to a connection as soon as it's accepted, with the id other connections know it by, e.g. in
+    /// `KeyPackagesAvailable` and `Disconnected`, and the protocol versions the server speaks, for the client to pick
+    /// one of with a `Version`. Servers from before versions were negotiated send none, and clients from before then
+    /// don't read past the id.
+    Connected { id: u64, versions: Vec<u16> },
+    /// Sent by the server to every other connection when the connection `id` closes.
+    Disconnected { id: u64 },
+    /// Sent by a client as its very first frame to a server in rendezvous mode, to be put in the room with the given
+    /// code along with every other connection that entered it. Only connections in the same room see each other's
+    /// messages and key packages.
+    Enter { room: String },
+    /// Sent by a server forwarding a connection to the home server of a federated room, right after the connection's
+    /// `Enter` if it needs one, with the name of every server the connection has been forwarded through so far. A
+    /// server forwarding it on again sends its own in place of it, with its name added, and closes the connection
+    /// instead if its name is already there, so a loop of federated servers can't keep forwarding it around.
+    Forwarded { via: Vec<String> },
+    /// Sent by a client right after connecting (and entering its room, on a rendezvous server) to register the id it
+    /// goes by. Answered with a `Registered`.
+    Register { id: String },
+    /// Sent by the server in answer to a `Register`, with the id the connection is registered under: the one it asked
+    /// for, or if another connection in the room already goes by that, the same with the first free suffix of `_2`,
+    /// `_3`, and so on.
+    Registered { id: String },
+    /// Sent by the server to a connection it has no room for, since it already has as many as it allows, right
+    /// before closing it.
+    ServerFull,
+    /// Sent by a client that hasn't sent anything else for a while, so the server doesn't take it for gone and close
+    /// the connection (see `Server::set_idle_timeout()`), or to measure the round trip. Answered with a `Pong`.
+    Ping,
+    /// Sent by the server in answer to a `Ping`, with the time it was sent by the server's clock, in milliseconds since
+    /// the Unix epoch.
+    Pong { time: u64 },
+    /// Sent by a client to a server in rendezvous mode to create the room with the given code, owned by the identity
+    /// the connection registered. In an `invite_only` room, only the owner can claim key packages, so only they can
+    /// add anyone to the group. Answered with a `RoomCreated`, or a `RoomRefused` if the code isn't valid or is
+    /// already taken.
+    CreateRoom { room: String, invite_only: bool },
+    /// Sent by the server in answer to a `CreateRoom` that created the room.
+    RoomCreated { room: String },
+    /// Sent by the server in answer to a `CreateRoom` it turned down.
+    RoomRefused { room: String },
+    /// Sent by the server to a connection entering a room created with `CreateRoom`, right after its `Connected`,
+    /// with the identity of the room's owner and whether it's invite-only.
+    RoomOwner { owner: String, invite_only: bool },
+    /// Sent by the server to a connection its host closed, or banned the address of, right before closing it. The
+    /// client doesn't try to reconnect.
+    Kicked,
+    /// Sent by the server to every connection with a message from its host, e.g. about upcoming maintenance.
+    Announcement { text: String },
+    /// Sent by the server in place of a `Sequenced` for an application message it dropped instead of relaying,
+    /// because the sender's identity is muted for flooding its room for another `secs` seconds. `data` is the message
+    /// dropped, so the client can tell which of the ones it has in flight it was.
+    Muted { secs: u64, data: Vec<u8> },
+    /// Sent by the server when a frame from the client arrived corrupted and was dropped. The client asks for a replay
+    /// with a `Resume` and sends again whatever the replay shows never reached the server, as it would on reconnecting.
+    Retransmit,
+    /// Sent by a client right after its `Enter`, if it has one, to offer the compression `codecs` it can read, and
+    /// answered by the server with the one it picked, or none to turn them all down. Once the server's answered,
+    /// frames either way whose body is over `COMPRESSION_THRESHOLD` may be compressed with the codec picked, which
+    /// their length prefix marks them as. Only `DEFLATE` is supported.
+    Compression { codecs: Vec<String> },
+    /// Sent by a client right before a Welcome it sent without the group's ratchet tree in it, for the server to keep
+    /// in the room's store until the new member asks for it. `key_package` is the reference to the new member's key
+    /// package the Welcome is for, and `tree` the group's TLS-serialized ratchet tree as of the Welcome's epoch.
+    PublishRatchetTree { key_package: Vec<u8>, tree: Vec<u8> },
+    /// Sent by a client given a Welcome for its key package `key_package`, before opening it, for the ratchet tree
+    /// published along with it. Answered with a `RatchetTree`.
+    FetchRatchetTree { key_package: Vec<u8> },
+    /// Sent by the server in answer to a `FetchRatchetTree`, with the tree published for `key_package`, or an empty
+    /// one if none was, in which case the Welcome carries the tree itself.
+    RatchetTree { key_package: Vec<u8>, tree: Vec<u8> },
+    /// Sent by a client right after its `Enter`, if it has one, with the protocol version it picked from those the
+    /// server's `Connected` offered. Never sent to a server that offered none, which wouldn't know the frame; until
+    /// it's sent, both sides speak `wire::LEGACY_VERSION`.
+    Version { version: u16 },
+}
+
+impl Frame {
+    /// Encodes the frame into its wire format: the length of its body, the body (its tag followed by its fields), and
+    /// a CRC-32 checksum of the body, so that a frame corrupted on the way is caught before anything is made of it.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(false)
+    }
+
+    /// Encodes the frame as `Frame::encode()` does, but if `compress` is set and its body is long enough, with the
+    /// body compressed and the length prefix marking it as such. The checksum is of the body as sent, so corruption is
+    /// caught before decompressing it.
+    pub fn encode_with(&self, compress: bool) -> Vec<u8> {
+        let body = self.body();
+        let (body, flag) = match compress && body.len() >= COMPRESSION_THRESHOLD {
+            true => {
+                let compressed = miniz_oxide::deflate::compress_to_vec(&body, 6);
+                if compressed.len() < body.len() { (compressed, COMPRESSED) } else { (body, 0) }
+            }
+            false => (body, 0),
+        };
+
+        let mut buf = Vec::with_capacity(body.len() + FRAME_OVERHEAD);
+        buf.extend_from_slice(&(body.len() as u32 | flag).to_be_bytes());
+        buf.extend_from_slice(&body);
+        buf.extend_from_slice(&crc32(&body).to_be_bytes());
+        buf
+    }
+
+    /// Returns the frame's body: its tag followed by its fields.
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Frame::Mls(data) => {
+                body.push(MLS);
+                body.extend_from_slice(data);
+            }
+            Frame::Relay { seq, data } => {
+                body.push(RELAY);
+                body.extend_from_slice(&seq.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Frame::Lagged { missed } => {
+                body.push(LAGGED);
+                body.extend_from_slice(&missed.to_be_bytes());
+            }
+            Frame::Resync => body.push(RESYNC),
+            Frame::ServerClosing => body.push(SERVER_CLOSING),
+            Frame::ServerFull => body.push(SERVER_FULL),
+            Frame::Ping => body.push(PING),
+            Frame::Kicked => body.push(KICKED),
+            Frame::Retransmit => body.push(RETRANSMIT),
+            Frame::Compression { codecs } => {
+                body.push(COMPRESSION);
+                body.extend_from_slice(codecs.join("\n").as_bytes());
+            }
+            Frame::PublishRatchetTree { key_package, tree } => {
+                body.push(PUBLISH_RATCHET_TREE);
+                push_prefixed(&mut body, key_package);
+                body.extend_from_slice(tree);
+            }
+            Frame::FetchRatchetTree { key_package } => {
+                body.push(FETCH_RATCHET_TREE);
+                body.extend_from_slice(key_package);
+            }
+            Frame::RatchetTree { key_package, tree } => {
+                body.push(RATCHET_TREE);
+                push_prefixed(&mut body, key_package);
+                body.extend_from_slice(tree);
+            }
+            Frame::Announcement { text } => {
+                body.push(ANNOUNCEMENT);
+                body.extend_from_slice(text.as_bytes());
+            }
+            Frame::Muted { secs, data } => {
+                body.push(MUTED);
+                body.extend_from_slice(&secs.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Frame::Pong { time } => {
+                body.push(PONG);
+                body.extend_from_slice(&time.to_be_bytes());
+            }
+            Frame::PublishKeyPackage { last_resort, data } => {
+                body.push(PUBLISH_KEY_PACKAGE);
+                body.push(u8::from(*last_resort));
+                body.extend_from_slice(data);
+            }
+            Frame::KeyPackagesAvailable { owner } => {
+                body.push(KEY_PACKAGES_AVAILABLE);
+                body.extend_from_slice(&owner.to_be_bytes());
+            }
+            Frame::ClaimKeyPackage { owner } => {
+                body.push(CLAIM_KEY_PACKAGE);
+                body.extend_from_slice(&owner.to_be_bytes());
+            }
+            Frame::KeyPackage { owner, data } => {
+                body.push(KEY_PACKAGE);
+                body.extend_from_slice(&owner.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Frame::KeyPackageClaimed { remaining } => {
+                body.push(KEY_PACKAGE_CLAIMED);
+                body.extend_from_slice(&remaining.to_be_bytes());
+            }
+            Frame::Sequenced { seq } => {
+                body.push(SEQUENCED);
+                body.extend_from_slice(&seq.to_be_bytes());
+            }
+            Frame::Resume { after } => {
+                body.push(RESUME);
+                body.extend_from_slice(&after.to_be_bytes());
+            }
+            Frame::Connected { id, versions } => {
+                body.push(CONNECTED);
+                body.extend_from_slice(&id.to_be_bytes());
+                for version in versions {
+                    body.extend_from_slice(&version.to_be_bytes());
+                }
+            }
+            Frame::Version { version } => {
+                body.push(VERSION);
+                body.extend_from_slice(&version.to_be_bytes());
+            }
+            Frame::Disconnected { id } => {
+                body.push(DISCONNECTED);
+                body.extend_from_slice(&id.to_be_bytes());
+            }
+            Frame::Enter { room } => {
+                body.push(ENTER);
+                body.extend_from_slice(room.as_bytes());
+            }
+            Frame::Forwarded { via } => {
+                body.push(FORWARDED);
+                body.extend_from_slice(via.join("\n").as_bytes());
+            }
+            Frame::Register { id } => {
+                body.push(REGISTER);
+                body.extend_from_slice(id.as_bytes());
+            }
+            Frame::Registered { id } => {
+                body.push(REGISTERED);
+                body.extend_from_slice(id.as_bytes());
+            }
+            Frame::CreateRoom { room, invite_only } => {
+                body.push(CREATE_ROOM);
+                body.push(u8::from(*invite_only));
+                body.extend_from_slice(room.as_bytes());
+            }
+            Frame::RoomCreated { room } => {
+                body.push(ROOM_CREATED);
+                body.extend_from_slice(room.as_bytes());
+            }
+            Frame::RoomRefused { room } => {
+                body.push(ROOM_REFUSED);
+                body.extend_from_slice(room.as_bytes());
+            }
+            Frame::RoomOwner { owner, invite_only } => {
+                body.push(ROOM_OWNER);
+                body.push(u8::from(*invite_only));
+                body.extend_from_slice(owner.as_bytes());
+            }
+        }
+
+        body
+    }
+
+    /// Returns the length of the frame's wire format, including the length prefix and checksum, without encoding it.
+    /// Compression isn't taken into account.
+    pub fn encoded_len(&self) -> usize {
+        let fields = match self {
+            Frame::Mls(data) => data.len(),
+            Frame::Relay { data, .. } | Frame::KeyPackage { data, .. } | Frame::Muted { data, .. } => 8 + data.len(),
+            Frame::PublishKeyPackage { data, .. } => 1 + data.len(),
+            Frame::PublishRatchetTree { key_package, tree } | Frame::RatchetTree { key_package, tree } => {
+                1 + key_package.len() + tree.len()
+            }
+            Frame::FetchRatchetTree { key_package } => key_package.len(),
+            Frame::Connected { versions, .. } => 8 + 2 * versions.len(),
+            Frame::Version { .. } => 2,
+            Frame::Enter { room: text }
+            | Frame::Register { id: text }
+            | Frame::Registered { id: text }
+            | Frame::RoomCreated { room: text }
+            | Frame::RoomRefused { room: text }
+            | Frame::Announcement { text } => text.len(),
+            Frame::CreateRoom { room: text, .. } | Frame::RoomOwner { owner: text, .. } => 1 + text.len(),
+            Frame::Forwarded { via: lines } | Frame::Compression { codecs: lines } => {
+                lines.iter().map(|line| line.len() + 1).sum::<usize>().saturating_sub(1)
+            }
+            Frame::Resync
+            | Frame::ServerClosing
+            | Frame::ServerFull
+            | Frame::Ping
+            | Frame::Kicked
+            | Frame::Retransmit => 0,
+            Frame::Lagged { .. }
+            | Frame::KeyPackagesAvailable { .. }
+            | Frame::ClaimKeyPackage { .. }
+            | Frame::KeyPackageClaimed { .. }
+            | Frame::Sequenced { .. }
+            | Frame::Resume { .. }
+            | Frame::Disconnected { .. }
+            | Frame::Pong { .. } => 8,
+        };
+        FRAME_OVERHEAD + 1 + fields
+    }
+
+    /// Decodes a frame from its body (its tag and fields, between the length prefix and the checksum).
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::InvalidFrame` if the body is empty, the tag is unknown, the
+    /// body is too short for the tag's fields, or a room code, server name, or id isn't valid UTF-8.
+    pub fn decode(body: &[u8]) -> Result<Frame, ApplicationError> {
+        let Some((&tag, rest)) = body.split_first() else { return Err(NetworkError::InvalidFrame.into()) };
+
+        match tag {
+            MLS => Ok(Frame::Mls(rest.to_vec())),
+            RELAY => {
+                let (seq, data) = split_u64(rest)?;
+                Ok(Frame::Relay { seq, data: data.to_vec() })
+            }
+            LAGGED => {
+                let (missed, _) = split_u64(rest)?;
+                Ok(Frame::Lagged { missed })
+            }
+            RESYNC => Ok(Frame::Resync),
+            SERVER_CLOSING => Ok(Frame::ServerClosing),
+            SERVER_FULL => Ok(Frame::ServerFull),
+            PING => Ok(Frame::Ping),
+            KICKED => Ok(Frame::Kicked),
+            RETRANSMIT => Ok(Frame::Retransmit),
+            COMPRESSION => Ok(Frame::Compression { codecs: decode_lines(rest)? }),
+            ANNOUNCEMENT => Ok(Frame::Announcement { text: decode_string(rest)? }),
+            MUTED => {
+                let (secs, data) = split_u64(rest)?;
+                Ok(Frame::Muted { secs, data: data.to_vec() })
+            }
+            PONG => {
+                let (time, _) = split_u64(rest)?;
+                Ok(Frame::Pong { time })
+            }
+            PUBLISH_KEY_PACKAGE => match rest.split_first() {
+                Some((&flag @ (0 | 1), data)) => Ok(Frame::PublishKeyPackage { last_resort: flag == 1, data: data.to_vec() }),
+                _ => Err(NetworkError::InvalidFrame.into()),
+            }
+            KEY_PACKAGES_AVAILABLE => {
+                let (owner, _) = split_u64(rest)?;
+                Ok(Frame::KeyPackagesAvailable { owner })
+            }
+            CLAIM_KEY_PACKAGE => {
+                let (owner, _) = split_u64(rest)?;
+                Ok(Frame::ClaimKeyPackage { owner })
+            }
+            KEY_PACKAGE => {
+                let (owner, data) = split_u64(rest)?;
+                Ok(Frame::KeyPackage { owner, data: data.to_vec() })
+            }
+            KEY_PACKAGE_CLAIMED => {
+                let (remaining, _) = split_u64(rest)?;
+                Ok(Frame::KeyPackageClaimed { remaining })
+            }
+            SEQUENCED => {
+                let (seq, _) = split_u64(rest)?;
+                Ok(Frame::Sequenced { seq })
+            }
+            RESUME => {
+                let (after, _) = split_u64(rest)?;
+                Ok(Frame::Resume { after })
+            }
+            CONNECTED => {
+                let (id, versions) = split_u64(rest)?;
+                if versions.len() % 2 != 0 {
+                    return Err(NetworkError::InvalidFrame.into());
+                }
+                let versions = versions.chunks_exact(2).map(|version| u16::from_be_bytes([version[0], version[1]])).collect();
+                Ok(Frame::Connected { id, versions })
+            }
+            VERSION => match rest {
+                &[high, low] => Ok(Frame::Version { version: u16::from_be_bytes([high, low]) }),
+                _ => Err(NetworkError::InvalidFrame.into()),
+            }
+            DISCONNECTED => {
+                let (id, _) = split_u64(rest)?;
+                Ok(Frame::Disconnected { id })
+            }
+            ENTER => Ok(Frame::Enter { room: decode_string(rest)? }),
+            REGISTER => Ok(Frame::Register { id: decode_string(rest)? }),
+            REGISTERED => Ok(Frame::Registered { id: decode_string(rest)? }),
+            ROOM_CREATED => Ok(Frame::RoomCreated { room: decode_string(rest)? }),
+            ROOM_REFUSED => Ok(Frame::RoomRefused { room: decode_string(rest)? }),
+            CREATE_ROOM => match rest.split_first() {
+                Some((&flag @ (0 | 1), room)) => Ok(Frame::CreateRoom { room: decode_string(room)?, invite_only: flag == 1 }),
+                _ => Err(NetworkError::InvalidFrame.into()),
+            }
+            ROOM_OWNER => match rest.split_first() {
+                Some((&flag @ (0 | 1), owner)) => Ok(Frame::RoomOwner { owner: decode_string(owner)?, invite_only: flag == 1 }),
+                _ => Err(NetworkError::InvalidFrame.into()),
+            }
+            FORWARDED => Ok(Frame::Forwarded { via: decode_lines(rest)? }),
+            PUBLISH_RATCHET_TREE => {
+                let (key_package, tree) = split_prefixed(rest)?;
+                Ok(Frame::PublishRatchetTree { key_package: key_package.to_vec(), tree: tree.to_vec() })
+            }
+            FETCH_RATCHET_TREE => Ok(Frame::FetchRatchetTree { key_package: rest.to_vec() }),
+            RATCHET_TREE => {
+                let (key_package, tree) = split_prefixed(rest)?;
+                Ok(Frame::RatchetTree { key_package: key_package.to_vec(), tree: tree.to_vec() })
+            }
+            _ => Err(NetworkError::InvalidFrame.into()),
+        }
+    }
+
+    /// Reads a single frame from the stream. Returns `Ok(None)` if the stream reached EOF cleanly
+    /// before the start of a frame.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if reading fails or the stream ends partway through a
+    /// frame, a `NetworkError::CorruptFrame` if it doesn't match its checksum, or a `NetworkError::InvalidFrame` if the
+    /// frame is oversized or can't be decoded.
+    pub async fn read<R>(reader: &mut R) -> Result<Option<Frame>, ApplicationError>
+    where R: AsyncRead + Unpin {
+        match Self::read_body(reader).await? {
+            Some(body) => Self::decode(&body).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the body of a single frame from the stream without decoding it, so that a reader can skip a frame it
+    /// can't decode and carry on with the next. Returns `Ok(None)` if the stream reached EOF cleanly before the start
+    /// of a frame.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if reading fails or the stream ends partway through a frame, a
+    /// `NetworkError::CorruptFrame` if the body doesn't match its checksum, after which the stream is still in step
+    /// if only the body was corrupted, or a `NetworkError::InvalidFrame` if the frame is oversized, which leaves the
+    /// stream out of step.
+    pub async fn read_body<R>(reader: &mut R) -> Result<Option<Vec<u8>>, ApplicationError>
+    where R: AsyncRead + Unpin {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(_) => return Err(NetworkError::Io.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+        let (compressed, len) = (len & COMPRESSED != 0, len & !COMPRESSED);
+        if len > MAX_FRAME_LEN {
+            return Err(NetworkError::InvalidFrame.into());
+        }
+
+        let mut body = vec![0u8; len as usize + 4];
+        let Ok(_) = reader.read_exact(&mut body).await else { return Err(NetworkError::Io.into()) };
+        let checksum = body.split_off(len as usize);
+        if crc32(&body).to_be_bytes() != checksum[..] {
+            return Err(NetworkError::CorruptFrame.into());
+        }
+        if compressed {
+            // bounded, so a small frame can't be made to decompress into an arbitrarily large one
+            body = miniz_oxide::inflate::decompress_to_vec_with_limit(&body, MAX_FRAME_LEN as usize)
+                .map_err(|_| NetworkError::InvalidFrame)?;
+        }
+        Ok(Some(body))
+    }
+
+    /// Writes the encoded frame to the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if writing fails.
+    pub async fn write<W>(&self, writer: &mut W) -> Result<(), ApplicationError>
+    where W: AsyncWrite + Unpin {
+        self.write_with(writer, false).await.map(|_| ())
+    }
+
+    /// Writes the frame to the stream as encoded by `Frame::encode_with()`, returning the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if writing fails.
+    pub async fn write_with<W>(&self, writer: &mut W, compress: bool) -> Result<usize, ApplicationError>
+    where W: AsyncWrite + Unpin {
+        let encoded = self.encode_with(compress);
+        match writer.write_all(&encoded).await {
+            Ok(_) => Ok(encoded.len()),
+            Err(_) => Err(NetworkError::Io.into()),
+        }
+    }
+}
+
+/// Returns the CRC-32 checksum of `bytes`, as a frame's trailer holds for its body.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn decode_string(bytes: &[u8]) -> Result<String, ApplicationError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| NetworkError::InvalidFrame.into())
+}
+
+/// Decodes newline-separated strings, e.g. the servers in a `Frame::Forwarded`.
+fn decode_lines(bytes: &[u8]) -> Result<Vec<String>, ApplicationError> {
+    match std::str::from_utf8(bytes) {
+        Ok("") => Ok(Vec::new()),
+        Ok(lines) => Ok(lines.split('\n').map(str::to_string).collect()),
+        Err(_) => Err(NetworkError::InvalidFrame.into()),
+    }
+}
+
+fn split_u64(bytes: &[u8]) -> Result<(u64, &[u8]), ApplicationError> {
+    if bytes.len() < 8 {
+        return Err(NetworkError::InvalidFrame.into());
+    }
+    let (int, rest) = bytes.split_at(8);
+    Ok((u64::from_be_bytes(int.try_into().unwrap()), rest))
+}
+
+/// Appends `bytes` preceded by its length in a single byte, for a field followed by another of any length. Only for
+/// fields that always fit, e.g. key package references, which are a hash.
+fn push_prefixed(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.push(bytes.len().min(usize::from(u8::MAX)) as u8);
+    body.extend_from_slice(&bytes[..bytes.len().min(usize::from(u8::MAX))]);
+}
+
+/// Splits off a field written with `push_prefixed()`, returning it along with what follows it.
+fn split_prefixed(bytes: &[u8]) -> Result<(&[u8], &[u8]), ApplicationError> {
+    let Some((&len, rest)) = bytes.split_first() else { return Err(NetworkError::InvalidFrame.into()) };
+    if rest.len() < usize::from(len) {
+        return Err(NetworkError::InvalidFrame.into());
+    }
+    Ok(rest.split_at(usize::from(len)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let frames = [
+            Frame::Mls(vec![1, 2, 3]),
+            Frame::Relay { seq: 42, data: vec![b'\n', 0, 255] },
+            Frame::Lagged { missed: 7 },
+            Frame::Resync,
+            Frame::ServerClosing,
+            Frame::PublishKeyPackage { last_resort: true, data: vec![4, 5] },
+            Frame::KeyPackagesAvailable { owner: 3 },
+            Frame::ClaimKeyPackage { owner: 3 },
+            Frame::KeyPackage { owner: 3, data: vec![4, 5] },
+            Frame::KeyPackageClaimed { remaining: 2 },
+            Frame::Sequenced { seq: 42 },
+            Frame::Resume { after: 41 },
+            Frame::Connected { id: 3, versions: vec![1, 2] },
+            Frame::Connected { id: 3, versions: Vec::new() },
+            Frame::Version { version: 2 },
+            Frame::Disconnected { id: 3 },
+            Frame::Enter { room: String::from("q7rk-2mfx") },
+            Frame::Forwarded { via: vec![String::from("3f2a9c1e"), String::from("b71d04e6")] },
+            Frame::Forwarded { via: Vec::new() },
+            Frame::Register { id: String::from("bob") },
+            Frame::Registered { id: String::from("bob_2") },
+            Frame::ServerFull,
+            Frame::Ping,
+            Frame::Pong { time: 1_700_000_000_000 },
+            Frame::CreateRoom { room: String::from("q7rk-2mfx"), invite_only: true },
+            Frame::RoomCreated { room: String::from("q7rk-2mfx") },
+            Frame::RoomRefused { room: String::from("q7rk-2mfx") },
+            Frame::RoomOwner { owner: String::from("alice"), invite_only: false },
+            Frame::Kicked,
+            Frame::Announcement { text: String::from("Restarting at noon.") },
+            Frame::Muted { secs: 60, data: vec![1, 2, 3] },
+            Frame::Retransmit,
+            Frame::Compression { codecs: vec![String::from(DEFLATE)] },
+            Frame::PublishRatchetTree { key_package: vec![9; 32], tree: vec![1, 2, 3] },
+            Frame::FetchRatchetTree { key_package: vec![9; 32] },
+            Frame::RatchetTree { key_package: vec![9; 32], tree: Vec::new() },
+        ];
+
+        for frame in frames {
+            let encoded = frame.encode();
+            assert_eq!(frame.encoded_len(), encoded.len());
+            let decoded = Frame::decode(&encoded[4..encoded.len() - 4]);
+            assert_eq!(decoded.ok(), Some(frame));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_truncated_body() {
+        let res = Frame::decode(&[RELAY, 0, 0, 1]);
+        assert!(res.is_err(), "Truncated relay frame decoded: {:?}", res);
+    }
+
+    #[tokio::test]
+    async fn read_stops_at_clean_eof() {
+        let mut bytes = Frame::Lagged { missed: 3 }.encode();
+        bytes.extend(Frame::Resync.encode());
+        let mut reader = bytes.as_slice();
+
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(Frame::Lagged { missed: 3 })));
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(Frame::Resync)));
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn read_body_stays_in_step_past_undecodable_frame() {
+        // a well-formed length around an unknown tag
+        let mut bytes = vec![0, 0, 0, 2, 0xff, 0];
+        bytes.extend(crc32(&[0xff, 0]).to_be_bytes());
+        bytes.extend(Frame::Resync.encode());
+        let mut reader = bytes.as_slice();
+
+        let body = Frame::read_body(&mut reader).await.unwrap().unwrap();
+        assert!(Frame::decode(&body).is_err());
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(Frame::Resync)));
+    }
+
+    #[tokio::test]
+    async fn read_drops_corrupted_frame() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut bytes = Frame::Relay { seq: 1, data: vec![1, 2, 3] }.encode();
+        bytes[9] ^= 0x40;
+        bytes.extend(Frame::Resync.encode());
+        let mut reader = bytes.as_slice();
+
+        let res = Frame::read(&mut reader).await;
+        assert!(matches!(res, Err(ApplicationError::Network(NetworkError::CorruptFrame))), "{:?}", res);
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(Frame::Resync)));
+    }
+
+    #[tokio::test]
+    async fn compresses_large_frames() {
+        let welcome = Frame::Mls(vec![7; 4096]);
+        let small = Frame::Mls(vec![7; 16]);
+        let compressed = welcome.encode_with(true);
+        assert!(compressed.len() < welcome.encoded_len() / 4);
+        assert_eq!(small.encode_with(true), small.encode());
+
+        let mut bytes = compressed;
+        bytes.extend(small.encode_with(true));
+        let mut reader = bytes.as_slice();
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(welcome)));
+        assert_eq!(Frame::read(&mut reader).await.ok(), Some(Some(small)));
+    }
+}
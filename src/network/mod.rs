@@ -1,2 +1,12 @@
-pub mod client;
-pub mod server;
\ No newline at end of file
+pub mod access_log;
+pub mod address;
+#[cfg(unix)]
+pub mod admin;
+pub mod client;
+pub mod federation;
+pub mod flood;
+pub mod frame;
+pub mod registry;
+pub mod server;
+pub mod state;
+pub mod wire;
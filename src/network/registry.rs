@@ -0,0 +1,563 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+use crate::{
+    config::RateLimitConfig,
+    network::{access_log::AccessLog, flood::{FloodControl, Verdict}, frame::Frame, wire},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::{mpsc, Mutex}, task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Identifier the server gives a connection, unique for as long as the server runs. It's what clients know each
+/// other's connections by, e.g. in `Frame::KeyPackagesAvailable` and `Frame::Disconnected`.
+pub type ConnectionId = u64;
+
+/// What the server knows about an open connection, as listed by `Registry::list()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub address: SocketAddr,
+    /// Code of the room the connection entered, or an empty string outside rendezvous mode.
+    pub room: String,
+    /// Identity from the credential in the connection's published key packages. The server doesn't verify it, so
+    /// it's only what the client claims to be.
+    pub identity: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub stats: ConnectionStats,
+}
+
+/// Traffic on a connection since it was accepted, counting whole frames including their length prefix and checksum.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    /// MLS messages the connection sent to be relayed to the rest of its room.
+    #[serde(default)]
+    pub relayed: u64,
+}
+
+/// A room created at runtime with a `Frame::CreateRoom`, as the registry records it for as long as the server runs, or
+/// across restarts with `Server::set_state_path()`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    /// Identity the creating connection was registered under when it created the room.
+    pub owner: String,
+    /// Whether only the owner may claim key packages in the room.
+    pub invite_only: bool,
+}
+
+/// Counters behind a connection's `ConnectionStats`, updated by its reader and writer threads without going through
+/// the registry's lock, along with when the connection last sent anything.
+#[derive(Debug)]
+pub struct Counters {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    relayed: AtomicU64,
+    /// when the counters were made, which `last_read` is measured from
+    started: Instant,
+    /// milliseconds after `started` that the last frame was read
+    last_read: AtomicU64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            frames_in: AtomicU64::default(),
+            bytes_in: AtomicU64::default(),
+            frames_out: AtomicU64::default(),
+            bytes_out: AtomicU64::default(),
+            relayed: AtomicU64::default(),
+            started: Instant::now(),
+            last_read: AtomicU64::default(),
+        }
+    }
+}
+
+impl Counters {
+    /// Counts a frame of `len` bytes read from the connection.
+    pub fn read(&self, len: usize) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_read.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns how long it's been since a frame was last read from the connection, or since the counters were made
+    /// if none has been.
+    pub fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last_read.load(Ordering::Relaxed)))
+    }
+
+    /// Counts an MLS message read from the connection to be relayed to its room.
+    pub fn relayed(&self) {
+        self.relayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a frame of `len` bytes written to the connection.
+    pub fn wrote(&self, len: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            relayed: self.relayed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An open connection: what's known about it, the `Sender` for frames addressed only to it, which are written out by
+/// its writer thread, and the `JoinHandle` of that thread. The writer thread stops once the `Connection` is dropped.
+pub struct Connection {
+    pub id: ConnectionId,
+    pub address: SocketAddr,
+    pub room: String,
+    pub identity: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// protocol version the connection picked, or `wire::LEGACY_VERSION` until it picks one
+    pub version: u16,
+    pub counters: Arc<Counters>,
+    pub direct: mpsc::Sender<Frame>,
+    pub writer: JoinHandle<()>,
+    /// cancelled to close the connection when it's kicked
+    pub kick: CancellationToken,
+}
+
+impl Connection {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            address: self.address,
+            room: self.room.clone(),
+            identity: self.identity.clone(),
+            connected_at: self.connected_at,
+            stats: self.counters.snapshot(),
+        }
+    }
+}
+
+/// Every open connection, by id, and every room created at runtime, by code, shared between the listener, each
+/// connection's threads, and anything that routes frames to connections or reports on them.
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<ConnectionId, Connection>>,
+    rooms: Mutex<HashMap<String, RoomInfo>>,
+    /// addresses whose connections are turned away
+    banned: Mutex<HashSet<IpAddr>>,
+    /// traffic on every connection that's been removed, for `Registry::traffic()`
+    closed: Mutex<ConnectionStats>,
+    access_log: AccessLog,
+    flood: Mutex<FloodControl>,
+}
+
+impl Registry {
+    /// Records connections opening and closing in `access_log` from now on.
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = access_log;
+    }
+
+    /// Holds each identity in a room to `limits` when sending chat messages, from now on. See `FloodControl`.
+    pub fn set_rate_limit(&mut self, limits: RateLimitConfig) {
+        self.flood = Mutex::new(FloodControl::new(limits));
+    }
+
+    /// Returns the registry's access log, e.g. to record connections turned away before they're inserted.
+    pub fn access_log(&self) -> &AccessLog {
+        &self.access_log
+    }
+
+    /// Returns a fresh connection id. Ids start at 1 and are never reused.
+    pub fn next_id(&self) -> ConnectionId {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Adds an open connection, making it reachable by `Registry::send()`.
+    pub async fn insert(&self, connection: Connection) {
+        self.access_log.record("connect", &format!("id={} address={}", connection.id, connection.address));
+        self.connections.lock().await.insert(connection.id, connection);
+    }
+
+    /// Removes the connection `id` once it closes, e.g. with `reason` "closed" or "idle", returning it if it was still
+    /// registered.
+    pub async fn remove(&self, id: ConnectionId, reason: &str) -> Option<Connection> {
+        let connection = self.connections.lock().await.remove(&id)?;
+        self.closed(&connection, reason).await;
+        Some(connection)
+    }
+
+    /// Adds the traffic on a removed connection to the totals, and records it closing in the access log.
+    async fn closed(&self, connection: &Connection, reason: &str) {
+        let stats = connection.counters.snapshot();
+        let mut closed = self.closed.lock().await;
+        closed.frames_in += stats.frames_in;
+        closed.bytes_in += stats.bytes_in;
+        closed.frames_out += stats.frames_out;
+        closed.bytes_out += stats.bytes_out;
+        closed.relayed += stats.relayed;
+
+        let identity = connection.identity.as_deref().map_or(String::from("none"), |identity| format!("{:?}", identity));
+        self.access_log.record("disconnect", &format!(
+            "id={} address={} identity={} reason={} duration_secs={} frames_in={} bytes_in={} frames_out={} \
+             bytes_out={} relayed={}",
+            connection.id,
+            connection.address,
+            identity,
+            reason,
+            (Utc::now() - connection.connected_at).num_seconds().max(0),
+            stats.frames_in,
+            stats.bytes_in,
+            stats.frames_out,
+            stats.bytes_out,
+            stats.relayed,
+        ));
+    }
+
+    /// Returns the traffic on every connection since the server started, open or not.
+    pub async fn traffic(&self) -> ConnectionStats {
+        let mut total = *self.closed.lock().await;
+        for connection in self.connections.lock().await.values() {
+            let stats = connection.counters.snapshot();
+            total.frames_in += stats.frames_in;
+            total.bytes_in += stats.bytes_in;
+            total.frames_out += stats.frames_out;
+            total.bytes_out += stats.bytes_out;
+            total.relayed += stats.relayed;
+        }
+        total
+    }
+
+    /// Closes the connection `id`, after telling it with a `Frame::Kicked`. Returns false if it isn't open.
+    pub async fn kick(&self, id: ConnectionId) -> bool {
+        let Some((direct, kick)) = self.connections.lock().await.get(&id)
+            .map(|connection| (connection.direct.clone(), connection.kick.clone()))
+        else {
+            return false;
+        };
+        // written ahead of anything queued after it, since the writer thread goes on until its queue is dropped
+        let _ = direct.send(Frame::Kicked).await;
+        kick.cancel();
+        true
+    }
+
+    /// Bans `address`, kicking every connection open from it. Returns the number kicked.
+    pub async fn ban(&self, address: IpAddr) -> usize {
+        self.banned.lock().await.insert(address);
+        let from: Vec<ConnectionId> = self.connections.lock().await.values()
+            .filter(|connection| connection.address.ip() == address)
+            .map(|connection| connection.id)
+            .collect();
+        for &id in &from {
+            self.kick(id).await;
+        }
+        from.len()
+    }
+
+    /// Lifts the ban on `address`. Returns false if it wasn't banned.
+    pub async fn unban(&self, address: IpAddr) -> bool {
+        self.banned.lock().await.remove(&address)
+    }
+
+    /// Returns whether connections from `address` are turned away.
+    pub async fn is_banned(&self, address: IpAddr) -> bool {
+        self.banned.lock().await.contains(&address)
+    }
+
+    /// Returns every banned address, sorted.
+    pub async fn banned(&self) -> Vec<IpAddr> {
+        let mut banned: Vec<IpAddr> = self.banned.lock().await.iter().copied().collect();
+        banned.sort();
+        banned
+    }
+
+    /// Removes and returns every connection, e.g. to close them all on shutdown.
+    pub async fn drain(&self) -> Vec<Connection> {
+        let connections: Vec<Connection> =
+            self.connections.lock().await.drain().map(|(_, connection)| connection).collect();
+        for connection in &connections {
+            self.closed(connection, "shutdown").await;
+        }
+        connections
+    }
+
+    /// Decides what to do with a chat message from connection `id`, going by the identity it registered, or by the
+    /// connection alone if it hasn't, and returns it along with the identity it went by. `waited` is set if the
+    /// message has already been held back once.
+    pub async fn check_flood(&self, id: ConnectionId, waited: bool) -> (Verdict, String) {
+        let Some((room, identity)) = self.connections.lock().await.get(&id).map(|connection| {
+            let identity = connection.identity.clone().unwrap_or_else(|| format!("connection {}", id));
+            (connection.room.clone(), identity)
+        }) else {
+            return (Verdict::Relay, String::new());
+        };
+        let verdict = self.flood.lock().await.check(&room, &identity, waited, Instant::now());
+        (verdict, identity)
+    }
+
+    /// Forgets the flood protection state of identities that haven't sent anything in a while.
+    pub async fn prune_flood(&self) {
+        self.flood.lock().await.prune(Instant::now());
+    }
+
+    /// Records the identity connection `id` presents in its key packages.
+    pub async fn set_identity(&self, id: ConnectionId, identity: String) {
+        if let Some(connection) = self.connections.lock().await.get_mut(&id) {
+            self.access_log.record("identity", &format!("id={} identity={:?}", id, identity));
+            connection.identity = Some(identity);
+        }
+    }
+
+    /// Records the protocol version connection `id` picked.
+    pub async fn set_version(&self, id: ConnectionId, version: u16) {
+        if let Some(connection) = self.connections.lock().await.get_mut(&id) {
+            self.access_log.record("version", &format!("id={} version={}", id, version));
+            connection.version = version;
+        }
+    }
+
+    /// Returns the protocol version connection `id` speaks, or `wire::LEGACY_VERSION` if it's no longer open.
+    pub async fn version(&self, id: ConnectionId) -> u16 {
+        self.connections.lock().await.get(&id).map_or(wire::LEGACY_VERSION, |connection| connection.version)
+    }
+
+    /// Registers `requested` as the identity of connection `id`, or if another connection in the same room already goes
+    /// by it, the first of `requested_2`, `requested_3`, and so on that's free. Returns the identity registered.
+    pub async fn register(&self, id: ConnectionId, requested: &str) -> String {
+        let mut connections = self.connections.lock().await;
+        let Some(room) = connections.get(&id).map(|connection| connection.room.clone()) else {
+            return requested.to_string();
+        };
+
+        let taken = |name: &str| connections.values().any(|other| {
+            other.id != id && other.room == room && other.identity.as_deref() == Some(name)
+        });
+        let assigned = (1..)
+            .map(|n| if n == 1 { requested.to_string() } else { format!("{}_{}", requested, n) })
+            .find(|name| !taken(name))
+            .unwrap_or_default();
+
+        if let Some(connection) = connections.get_mut(&id) {
+            self.access_log.record("identity", &format!("id={} identity={:?}", id, assigned));
+            connection.identity = Some(assigned.clone());
+        }
+        assigned
+    }
+
+    /// Creates the room `code`, owned by the identity connection `id` is registered under. Returns the room's info, or
+    /// None if the connection hasn't registered an identity, or the room was already created or has connections in
+    /// it.
+    pub async fn create_room(&self, id: ConnectionId, code: &str, invite_only: bool) -> Option<RoomInfo> {
+        let connections = self.connections.lock().await;
+        let owner = connections.get(&id)?.identity.clone()?;
+        let mut rooms = self.rooms.lock().await;
+        if rooms.contains_key(code) || connections.values().any(|connection| connection.room == code) {
+            return None;
+        }
+
+        let info = RoomInfo { owner, invite_only };
+        rooms.insert(code.to_string(), info.clone());
+        Some(info)
+    }
+
+    /// Returns the info of the room `code`, if it was created with `Registry::create_room()`.
+    pub async fn room(&self, code: &str) -> Option<RoomInfo> {
+        self.rooms.lock().await.get(code).cloned()
+    }
+
+    /// Returns every room created with `Registry::create_room()`, by code.
+    pub async fn rooms(&self) -> HashMap<String, RoomInfo> {
+        self.rooms.lock().await.clone()
+    }
+
+    /// Adds rooms created before the server last restarted.
+    pub async fn restore_rooms(&self, rooms: HashMap<String, RoomInfo>) {
+        self.rooms.lock().await.extend(rooms);
+    }
+
+    /// Returns whether connection `id` may claim key packages: always, unless it's in an invite-only room whose owner
+    /// it isn't registered as.
+    pub async fn may_invite(&self, id: ConnectionId) -> bool {
+        let connections = self.connections.lock().await;
+        let Some(connection) = connections.get(&id) else { return false };
+        match self.rooms.lock().await.get(&connection.room) {
+            Some(info) if info.invite_only => connection.identity.as_deref() == Some(info.owner.as_str()),
+            _ => true,
+        }
+    }
+
+    /// Returns what's known about every open connection, ordered by id.
+    pub async fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<ConnectionInfo> = self.connections.lock().await.values().map(Connection::info).collect();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Returns the number of open connections.
+    pub async fn len(&self) -> usize {
+        self.connections.lock().await.len()
+    }
+
+    /// Returns whether there are no open connections.
+    pub async fn is_empty(&self) -> bool {
+        self.connections.lock().await.is_empty()
+    }
+
+    /// Queues a frame to be written to the connection `id` only, if it's still open and the protocol version it speaks
+    /// has the frame (see `wire::Feature`).
+    pub async fn send(&self, id: ConnectionId, frame: Frame) {
+        // the lock isn't held while waiting for room in the queue
+        let direct = self.connections.lock().await.get(&id)
+            .filter(|connection| wire::allowed(connection.version, &frame))
+            .map(|connection| connection.direct.clone());
+        if let Some(direct) = direct {
+            // an Err only means the writer thread has already stopped
+            let _ = direct.send(frame).await;
+        }
+    }
+
+    /// Queues a frame to be written to every open connection, whatever room it's in.
+    pub async fn send_to_all(&self, frame: Frame) {
+        let all: Vec<ConnectionId> = self.connections.lock().await.keys().copied().collect();
+        for id in all {
+            self.send(id, frame.clone()).await;
+        }
+    }
+
+    /// Queues a frame to be written to every open connection other than `id` in the same room as it. Once `id` has
+    /// been removed, `room` is what's used instead.
+    pub async fn send_to_others(&self, id: ConnectionId, room: &str, frame: Frame) {
+        let others: Vec<ConnectionId> = self.connections.lock().await.values()
+            .filter(|other| other.id != id && other.room == room)
+            .map(|other| other.id)
+            .collect();
+        for other in others {
+            self.send(other, frame.clone()).await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tracks_connections() {
+        let registry = Registry::default();
+        let (first, second) = (registry.next_id(), registry.next_id());
+        assert_eq!((first, second), (1, 2));
+
+        let third = registry.next_id();
+        let mut queues = vec![];
+        for id in [first, second, third] {
+            let (direct, rx) = mpsc::channel(4);
+            queues.push(rx);
+            registry.insert(Connection {
+                id,
+                address: SocketAddr::from(([127, 0, 0, 1], 4000 + id as u16)),
+                room: String::from(if id == third { "elsewhere" } else { "" }),
+                identity: None,
+                connected_at: Utc::now(),
+                version: if id == first { wire::LEGACY_VERSION } else { 2 },
+                counters: Arc::default(),
+                direct,
+                writer: tokio::spawn(async {}),
+                kick: CancellationToken::new(),
+            }).await;
+        }
+
+        registry.set_identity(second, String::from("bob")).await;
+        assert_eq!(registry.register(first, "bob").await, "bob_2");
+        assert_eq!(registry.register(third, "bob").await, "bob");
+        registry.send_to_others(first, "", Frame::Disconnected { id: first }).await;
+        assert_eq!(queues[1].recv().await, Some(Frame::Disconnected { id: first }));
+        assert!(queues[0].try_recv().is_err());
+        assert!(queues[2].try_recv().is_err());
+        // a connection from before presence never hears of others leaving
+        registry.send(first, Frame::Disconnected { id: second }).await;
+        assert!(queues[0].try_recv().is_err());
+
+        let list = registry.list().await;
+        assert_eq!(list.iter().map(|info| info.id).collect::<Vec<_>>(), vec![first, second, third]);
+        assert_eq!(list[0].identity.as_deref(), Some("bob_2"));
+        assert_eq!(list[1].identity.as_deref(), Some("bob"));
+
+        registry.remove(first, "closed").await;
+        assert_eq!(registry.len().await, 2);
+        assert!(!registry.kick(first).await);
+
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(registry.ban(loopback).await, 2);
+        assert_eq!(queues[1].recv().await, Some(Frame::Kicked));
+        assert_eq!(queues[2].recv().await, Some(Frame::Kicked));
+        assert_eq!(registry.banned().await, vec![loopback]);
+        assert!(registry.unban(loopback).await);
+        assert!(!registry.is_banned(loopback).await);
+    }
+
+    #[tokio::test]
+    async fn records_room_owners() {
+        let registry = Registry::default();
+        let (owner, guest) = (registry.next_id(), registry.next_id());
+        for id in [owner, guest] {
+            let (direct, _) = mpsc::channel(4);
+            registry.insert(Connection {
+                id,
+                address: SocketAddr::from(([127, 0, 0, 1], 4000 + id as u16)),
+                room: String::from(if id == owner { "lobby" } else { "private" }),
+                identity: None,
+                connected_at: Utc::now(),
+                version: 2,
+                counters: Arc::default(),
+                direct,
+                writer: tokio::spawn(async {}),
+                kick: CancellationToken::new(),
+            }).await;
+        }
+
+        // only a registered connection can own a room
+        assert_eq!(registry.create_room(owner, "private", true).await, None);
+        registry.register(owner, "alice").await;
+        registry.register(guest, "bob").await;
+        // a room with connections in it is taken, whether or not it was created
+        assert_eq!(registry.create_room(owner, "lobby", true).await, None);
+        assert_eq!(registry.create_room(guest, "private", true).await, None);
+
+        let info = RoomInfo { owner: String::from("alice"), invite_only: true };
+        assert_eq!(registry.create_room(owner, "book-club", true).await, Some(info.clone()));
+        assert_eq!(registry.create_room(guest, "book-club", false).await, None);
+        assert_eq!(registry.room("book-club").await, Some(info));
+        assert_eq!(registry.room("lobby").await, None);
+
+        registry.remove(guest, "closed").await;
+        let (direct, _) = mpsc::channel(4);
+        registry.insert(Connection {
+            id: guest,
+            address: SocketAddr::from(([127, 0, 0, 1], 4002)),
+            room: String::from("book-club"),
+            identity: None,
+            connected_at: Utc::now(),
+            version: 2,
+            counters: Arc::default(),
+            direct,
+            writer: tokio::spawn(async {}),
+            kick: CancellationToken::new(),
+        }).await;
+        assert!(registry.may_invite(owner).await);
+        registry.register(guest, "bob").await;
+        assert!(!registry.may_invite(guest).await);
+    }
+}
@@ -1,175 +1,1524 @@
-use std::{
-    collections::HashMap,
-    sync::Arc,
-};
-use crate::errors::ApplicationError;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener},
-    sync::{mpsc::{self, Receiver, Sender}, Mutex},
-};
-
-pub struct Server {
-    connections: Arc<Mutex<HashMap<usize, Connection>>>,
-    size: usize,
-    port: u16,
-}
-
-impl Server {
-    pub fn new(port: u16, size: usize) -> Server {
-        Server {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            size,
-            port,
-        }
-    }
-
-    /// Thread-spawning and main functionality loop for the server.
-    /// 
-    /// # Panics
-    /// 
-    /// This function panics if `TcpListener::bind()` returns an Err.
-    /// 
-    /// # TODO
-    /// 
-    /// Implement better shutdown/cleanup procedures.
-    /// 
-    /// Replace `unwrap()` with more robust error handling.
-    pub async fn run(&mut self) -> Result<(), ApplicationError> {
-        let address = format!("127.0.0.1:{}", self.port);
-        let listener = TcpListener::bind(address).await.unwrap();
-
-        println!("Server opened on port {}.", self.port);
-
-        let (tx, rx) = mpsc::channel(self.size);
-
-        let mut handles = Vec::new();
-
-        let connections = Arc::clone(&self.connections);
-        handles.push(tokio::spawn(async move { Self::listen(listener, connections, tx).await }));
-
-        let connections = Arc::clone(&self.connections);
-        handles.push(tokio::spawn(async move { Self::handle_input(connections, rx).await }));
-        
-        for handle in handles {
-            match handle.await {
-                Ok(_) => (),
-                Err(_) => return Err(ApplicationError::JoinError),
-            }
-        }
-        Ok(())
-    }
-
-    /// Sends a received message to all open connections except the one from which it originated. Takes in the
-    /// necessary `MessageIn` and a reference to the server's connections HashMap -- this is passed from
-    /// `Server::handle_input()`, which calls this function and acquires the lock on the connections Mutex.
-    /// 
-    /// # TODO
-    /// 
-    /// Do something to handle errors when the writer fails.
-    async fn fan_out(msg: MessageIn, connections: &mut HashMap<usize, Connection>) {
-        for (k, v) in connections.iter_mut() {
-            if *k != msg.id {
-                match v.writer.write_all(&msg.contents).await {
-                    Ok(_) => println!("Message sent from {} to {}.", msg.id, k),
-                    Err(_) => (),
-                }
-            }
-        }
-    }
-
-    /// Thread for handling incoming `MessageIn`s from each reader thread's `Sender`. Acquires the lock on the
-    /// server's connections HashMap and passes the reference to `Server::fan_out()` to send the message out
-    /// to all other connected clients.
-    async fn handle_input(connections: Arc<Mutex<HashMap<usize, Connection>>>, mut rx: Receiver<MessageIn>) {
-        while let Some(msg) = rx.recv().await {
-            let mut connections = connections.lock().await;
-            Self::fan_out(msg, &mut connections).await;
-        }
-    }
-
-    /// Thread for listening to incoming connections. Takes in a `TcpListener`, a cloned Arc of the server's connections
-    /// HashMap, and a `Sender<MessageIn>` to clone and give to each connection's thread. Also, maintains an iterative
-    /// usize to give as a unique key for each incoming connection added to the HashMap.
-    async fn listen(listener: TcpListener, connections: Arc<Mutex<HashMap<usize, Connection>>>, tx: Sender<MessageIn>) {
-        let mut id_iter = 1;
-    
-        while let Ok((stream, _)) = listener.accept().await {
-            let (reader, writer) = stream.into_split();
-
-            let connection = Connection{ _approved: true, writer };
-            let tx = tx.clone();
-            connections.lock().await.insert(id_iter, connection);
-
-            let connections = Arc::clone(&connections);
-            tokio::spawn(async move {
-                let id = id_iter;
-
-                let address = reader.peer_addr().unwrap();
-                println!("Listening on a connection accepted from {}.", address);
-                match Self::read_stream(id, reader, tx).await {
-                    Ok(_) => println!("Connection to {} closed successfully.", address),
-                    Err(_) => println!("ERROR: Problem reading data received from {}. Connection closed unsuccessfully.", address),
-                }
-                connections.lock().await.remove(&id);
-            });
-    
-            id_iter += 1;
-        }
-    }
-    
-    /// Handles reading the incoming data from the `OwnedReadHalf` of a split `TcpStream`. Takes in the id-number matching
-    /// the key associated entry in the Server.connections HashMap, the `OwnedReadHalf`, and a `Sender<MessageIn>` to send
-    /// messages to the server's `Receiver` to be fanned out to other connections. Returns an `Ok(())` when the thread
-    /// successfully closes, or an `Err(ApplicationError::IOError)` on a failed read from the stream.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if `Sender::send()` returns an Err.
-    /// 
-    /// # TODO
-    /// 
-    /// Currently, either this function is misreading non-EOF input as EOF, or the client-side `Controller` is failing
-    /// or sending on EOF erroneously. Urgently needs investigating.
-    /// 
-    /// Double-check `OwnedReadHalf`, `OwnedWriteHalf`, and `OwnedReadHalf::readable()`, and alternative solutions to ensure
-    /// this function isn't holding a lock on the stream preventing the writer from writing to it while awaiting input.
-    /// 
-    /// Replace calls to `unwrap()` with more robust error handling.
-    async fn read_stream(
-        id: usize,
-        mut reader: OwnedReadHalf,
-        tx: Sender<MessageIn>,
-    ) -> Result<(), ApplicationError> {
-        loop {
-            let Ok(()) = reader.readable().await else { return Err(ApplicationError::IOError) };
-
-            let mut buf = vec![];
-            let mut buf_reader = BufReader::new(&mut reader);
-            match buf_reader.read_until(b'\n',&mut buf).await {
-                Ok(0) => break, // EOF
-                Ok(_) => tx.send(MessageIn{ id, contents: buf.to_owned() }).await.unwrap(),
-                Err(_) => return Err(ApplicationError::IOError),
-            }
-        }
-
-        Ok(())
-    }
-}
-
-/// Holds information the `Server` needs access to about each open connection. Can be expanded
-/// to provide more info or functionality. The `Connection` holds an `OwnedWriteHalf` to allow it access
-/// to write to the connection while other threads hold the corresponding `OwnedReadHalf`.
-struct Connection {
-    /// "approved" field currently unused, but could be used in the future to facilitate
-    /// approving/rejecting users before accepting them into the group
-    _approved: bool,
-    writer: OwnedWriteHalf,
-}
-
-/// The type used by the `Server` threads' `Sender`s and `Receiver`. The "id" refers to the
-/// connection/thread the message was read by.
-struct MessageIn {
-    id: usize,
-    contents: Vec<u8>,
-}
\ No newline at end of file
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+use crate::{
+    config::{RateLimitConfig, ServerTuning},
+    errors::{ApplicationError, NetworkError},
+    invite,
+    network::{
+        access_log::AccessLog,
+        federation::{self, Federation},
+        flood::{self, Verdict},
+        frame::{Frame, DEFLATE},
+        registry::{Connection, ConnectionId, Counters, Registry},
+        state::{RelayState, ServerState},
+        wire::{self, Channel, Feature},
+    },
+};
+#[cfg(unix)]
+use crate::network::admin::{self, AdminConnection, AdminRequest, AdminResponse, ServerStats};
+use openmls::prelude::{KeyPackageIn, TlsDeserializeTrait};
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener, TcpStream},
+    signal,
+    sync::{mpsc::{self, error::TrySendError}, Mutex, Notify, Semaphore},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Number of relayed messages the server keeps around to replay to connections that fall behind.
+const HISTORY_SIZE: usize = 1024;
+
+/// How long a shutting-down server waits for connections to flush the frames queued for them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most key packages kept in the directory for a single connection, not counting its last resort.
+const MAX_POOL_SIZE: usize = 32;
+
+/// Most ratchet trees kept in a room's directory for new members to fetch, the oldest being dropped past it.
+const MAX_RATCHET_TREES: usize = 16;
+
+/// How long a connection to a server in rendezvous mode gets to send its `Frame::Enter`.
+const ENTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection can go without sending anything before it's taken for gone and closed, unless changed with
+/// `Server::set_idle_timeout()`. Clients send a `Frame::Ping` after 30 seconds without sending anything else, so this
+/// leaves room for a couple to go missing.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How often a server hosted with `Server::set_state_path()` saves its rooms, on top of when it shuts down.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// First file descriptor systemd passes a socket-activated process, as in `sd_listen_fds(3)`.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// How often the flood protection state of identities that have stopped sending is forgotten.
+const FLOOD_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Set by `Server::set_quiet()`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a line about what the server is doing, unless it's been quieted.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Returns the listening socket systemd passed in, if it started the server through socket activation, so the server
+/// can be started on demand by the first connection to it. systemd says so with the `LISTEN_PID` and `LISTEN_FDS`
+/// environment variables; only the first socket is used if it passed in more than one. Returns None if the server
+/// wasn't socket-activated, or what was passed in isn't a TCP listener, in which case it binds its address itself.
+#[cfg(unix)]
+fn activated_listener() -> Option<TcpListener> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let count = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id());
+    if count == 0 {
+        return None;
+    }
+    if count > 1 {
+        log!("systemd passed in {} sockets. Only the first is used.", count);
+    }
+
+    // SAFETY: systemd hands the descriptors from SD_LISTEN_FDS_START on to the process LISTEN_PID names, checked above
+    // to be this one, and nothing else in the process opens them
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    match listener.local_addr().and_then(|_| listener.set_nonblocking(true)) {
+        Ok(()) => TcpListener::from_std(listener).ok(),
+        Err(_) => {
+            log!("ERROR: The socket passed in by systemd isn't a TCP listener. Ignoring it.");
+            // left open, since it isn't the server's to close
+            let _ = listener.into_raw_fd();
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Returns how many sockets systemd passed in, going by the values of `LISTEN_PID` and `LISTEN_FDS`: none unless
+/// `LISTEN_PID` names `own_pid`, since a child process can inherit the variables without the sockets.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> usize {
+    match listen_pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == own_pid => listen_fds.and_then(|fds| fds.parse().ok()).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+pub struct Server {
+    registry: Arc<Registry>,
+    /// most connections open at once, counting ones still entering a room or being forwarded
+    size: usize,
+    port: u16,
+    rendezvous: bool,
+    /// random name other servers know this one by when it forwards connections to them
+    name: String,
+    /// rooms hosted on other servers, by their code on this one
+    federations: HashMap<String, Federation>,
+    /// how long a connection can go without sending anything before it's closed, if at all
+    idle_timeout: Option<Duration>,
+    /// file the server's rooms are kept in across restarts, if any
+    state_path: Option<PathBuf>,
+    /// Unix socket the server takes admin requests on, if any
+    admin_socket: Option<PathBuf>,
+    tuning: ServerTuning,
+}
+
+impl Server {
+    pub fn new(port: u16, size: usize) -> Server {
+        Server {
+            registry: Arc::new(Registry::default()),
+            size,
+            port,
+            rendezvous: false,
+            name: format!("{:016x}", rand::random::<u64>()),
+            federations: HashMap::new(),
+            idle_timeout: Some(IDLE_TIMEOUT),
+            state_path: None,
+            admin_socket: None,
+            tuning: ServerTuning::default(),
+        }
+    }
+
+    /// Returns the address `Server::run()` listens on: the server's port on every interface in rendezvous mode, or
+    /// only on loopback otherwise.
+    pub fn address(&self) -> SocketAddr {
+        let ip = if self.rendezvous { Ipv4Addr::UNSPECIFIED } else { Ipv4Addr::LOCALHOST };
+        SocketAddr::from((ip, self.port))
+    }
+
+    /// Returns the name other servers know this one by, e.g. in the `Frame::Forwarded` of connections it forwards.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Makes the room `federation.room` a room hosted on another server: every connection entering it is forwarded
+    /// to its home there with `federation::forward()`, so the members on both servers share the one group. Only takes
+    /// effect if called before `Server::run()`.
+    pub fn federate(&mut self, federation: Federation) {
+        self.federations.insert(federation.room.clone(), federation);
+    }
+
+    /// Sets how long a connection can go without sending the server anything before it's closed, as if it had closed
+    /// itself: it's dropped from the registry and its key packages, and the rest of its room is sent a
+    /// `Frame::Disconnected`. `None` keeps connections open however long they're idle. Only takes effect if called
+    /// before `Server::run()`.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Stops the server printing what it's doing, e.g. when it's hosted by a client whose interface shares the
+    /// terminal. Applies to every server in the process.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        QUIET.store(quiet, Ordering::Relaxed);
+    }
+
+    /// Puts the server in rendezvous mode, as a public relay for clients that can't host a reachable server
+    /// themselves (e.g. behind NATs): it listens on every interface instead of only loopback, and each connection has
+    /// to open with a `Frame::Enter` naming a room code. Connections are paired with the others that entered the same
+    /// code, and never see anything from other rooms. Only takes effect if called before `Server::run()`.
+    pub fn set_rendezvous(&mut self, rendezvous: bool) {
+        self.rendezvous = rendezvous;
+    }
+
+    /// Keeps the server's rooms in the file at `path` across restarts (see `ServerState`): they're loaded from it when
+    /// the server starts, and saved to it every `STATE_SAVE_INTERVAL` and when it shuts down. Only takes effect if
+    /// called before `Server::run()`.
+    pub fn set_state_path(&mut self, path: Option<PathBuf>) {
+        self.state_path = path;
+    }
+
+    /// Takes admin requests (see `AdminRequest`) on a Unix socket at `path`, so that a server running without a
+    /// terminal can still be managed with `mls_chat admin`. Only takes effect if called before `Server::run()`.
+    pub fn set_admin_socket(&mut self, path: Option<PathBuf>) {
+        self.admin_socket = path;
+    }
+
+    /// Records every connection in `access_log`: when it's accepted or turned away, the identity it registers, and
+    /// when and why it closes, with its traffic. Only takes effect if called before `Server::run()`.
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        if let Some(registry) = Arc::get_mut(&mut self.registry) {
+            registry.set_access_log(access_log);
+        }
+    }
+
+    /// Holds each identity in a room to `limits` when sending chat messages, muting it for flooding if it keeps going
+    /// past them (see `FloodControl`). Only takes effect if called before `Server::run()`.
+    pub fn set_rate_limit(&mut self, limits: RateLimitConfig) {
+        if let Some(registry) = Arc::get_mut(&mut self.registry) {
+            registry.set_rate_limit(limits);
+        }
+    }
+
+    /// Sets the capacities of the queues and buffers connections' frames pass through. Only takes effect if called
+    /// before `Server::run()`.
+    pub fn set_tuning(&mut self, tuning: ServerTuning) {
+        self.tuning = tuning;
+    }
+
+    /// Thread-spawning and main functionality loop for the server. Runs until Ctrl+C is received, then shuts down
+    /// gracefully with `Server::shutdown()`. If systemd started the server through socket activation, it serves the
+    /// socket systemd passed in (see `activated_listener()`); otherwise it binds `Server::address()` itself.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `TcpListener::bind()` returns an Err.
+    ///
+    /// # TODO
+    ///
+    /// Replace `unwrap()` with more robust error handling.
+    pub async fn run(&mut self) -> Result<(), ApplicationError> {
+        let listener = match activated_listener() {
+            Some(listener) => {
+                log!("Listening on the socket passed in by systemd.");
+                listener
+            }
+            None => TcpListener::bind(self.address()).await.unwrap(),
+        };
+        self.serve(listener, signal::ctrl_c()).await
+    }
+
+    /// Like `Server::run()`, but serves connections to a `TcpListener` that's already bound, until `shutdown`
+    /// completes instead of until Ctrl+C is received. An `Err` from `shutdown` is returned as a
+    /// `NetworkError::Io` without shutting down gracefully.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::ServerState` if the state file set with `Server::set_state_path()` can't be loaded,
+    /// rather than start over without the rooms in it, a `NetworkError::Admin` if the admin socket set with
+    /// `Server::set_admin_socket()` can't be opened, or a `NetworkError::ListenerFailed` if the listener panics.
+    pub async fn serve(
+        &mut self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = std::io::Result<()>>,
+    ) -> Result<(), ApplicationError> {
+        let mut rooms = Rooms::new(self.tuning.relay_queue.max(1));
+        if let Some(path) = &self.state_path {
+            if let Some(state) = ServerState::load(path)? {
+                log!("Restored {} created rooms and the history of {} rooms.", state.rooms.len(), state.relays.len());
+                self.registry.restore_rooms(state.rooms).await;
+                for address in state.banned {
+                    self.registry.ban(address).await;
+                }
+                rooms.restore(state.relays);
+            }
+        }
+        let rooms = Arc::new(Mutex::new(rooms));
+
+        // a socket passed in by systemd may be on another port than the one the server was given
+        let port = listener.local_addr().map_or(self.port, |address| address.port());
+        if self.rendezvous {
+            log!("Rendezvous server opened on port {}.", port);
+        } else {
+            log!("Server opened on port {}.", port);
+        }
+
+        let slots = Arc::new(Semaphore::new(self.size));
+        let cancel = CancellationToken::new();
+        // cancelled by an admin request to shut down
+        let stop = CancellationToken::new();
+
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_socket {
+            let listener = admin::bind(path)?;
+            log!("Taking admin requests on {}.", path.display());
+            tokio::spawn(Self::admin(listener, Arc::clone(&self.registry), Arc::clone(&rooms), stop.clone(), cancel.clone()));
+        }
+        #[cfg(not(unix))]
+        if self.admin_socket.is_some() {
+            return Err(NetworkError::Admin(String::from("admin sockets are only supported on Unix")).into());
+        }
+
+        let registry = Arc::clone(&self.registry);
+        let routing = Arc::new(Routing {
+            rendezvous: self.rendezvous,
+            name: self.name.clone(),
+            federations: self.federations.clone(),
+            tuning: self.tuning,
+        });
+        let idle_timeout = self.idle_timeout;
+        let token = cancel.clone();
+        let listener_rooms = Arc::clone(&rooms);
+        let mut handle = tokio::spawn(async move {
+            Self::listen(listener, registry, listener_rooms, slots, routing, idle_timeout, token).await
+        });
+        let registry = Arc::clone(&self.registry);
+        let token = cancel.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(FLOOD_PRUNE_INTERVAL) => registry.prune_flood().await,
+                    _ = token.cancelled() => break,
+                }
+            }
+        });
+        if let Some(path) = self.state_path.clone() {
+            let registry = Arc::clone(&self.registry);
+            let rooms = Arc::clone(&rooms);
+            let token = cancel.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = time::sleep(STATE_SAVE_INTERVAL) => Self::save_state(&path, &registry, &rooms).await,
+                        _ = token.cancelled() => break,
+                    }
+                }
+            });
+        }
+
+        tokio::select! {
+            res = &mut handle => return match res {
+                Ok(_) => Ok(()),
+                Err(_) => Err(NetworkError::ListenerFailed.into()),
+            },
+            res = shutdown => if res.is_err() { return Err(NetworkError::Io.into()) },
+            _ = stop.cancelled() => (),
+        }
+
+        log!("Shutting down the server...");
+        self.shutdown().await;
+        cancel.cancel();
+        if let Some(path) = &self.state_path {
+            Self::save_state(path, &self.registry, &rooms).await;
+        }
+        if let Some(path) = &self.admin_socket {
+            let _ = std::fs::remove_file(path);
+        }
+
+        match handle.await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NetworkError::ListenerFailed.into()),
+        }
+    }
+
+    /// Saves the rooms created in the `Registry` and the history of every room in `Rooms` that's kept while empty to
+    /// the file at `path`. A failure is only logged, since the server can carry on without it and try again later.
+    async fn save_state(path: &Path, registry: &Registry, rooms: &Mutex<Rooms>) {
+        let created = registry.rooms().await;
+        let relays = rooms.lock().await.relay_states(|code| code.is_empty() || created.contains_key(code)).await;
+        let state = ServerState { rooms: created, relays, banned: registry.banned().await };
+        if let Err(err) = state.save(path) {
+            log!("ERROR: Couldn't save the server's rooms: {}", err);
+        }
+    }
+
+    /// Thread answering requests on the admin socket, each connection to which may send any number of them, until the
+    /// server shuts down. An `AdminRequest::Shutdown` cancels `stop`.
+    #[cfg(unix)]
+    async fn admin(
+        listener: tokio::net::UnixListener,
+        registry: Arc<Registry>,
+        rooms: Arc<Mutex<Rooms>>,
+        stop: CancellationToken,
+        cancel: CancellationToken,
+    ) {
+        let started = time::Instant::now();
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(_) => break,
+                },
+                _ = cancel.cancelled() => break,
+            };
+
+            let registry = Arc::clone(&registry);
+            let rooms = Arc::clone(&rooms);
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+                while let Some(request) = admin::read_request(&mut reader).await {
+                    let response = match request {
+                        Ok(request) => Self::handle_admin(request, &registry, &rooms, started, &stop).await,
+                        Err(response) => response,
+                    };
+                    if admin::write_response(&mut writer, &response).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Helper function for `Server::admin()`. Carries out a single admin request.
+    #[cfg(unix)]
+    async fn handle_admin(
+        request: AdminRequest,
+        registry: &Registry,
+        rooms: &Mutex<Rooms>,
+        started: time::Instant,
+        stop: &CancellationToken,
+    ) -> AdminResponse {
+        match request {
+            AdminRequest::List => AdminResponse::Connections {
+                connections: registry.list().await.into_iter().map(AdminConnection::from).collect(),
+            },
+            AdminRequest::Kick { id } => {
+                if !registry.kick(id).await {
+                    return AdminResponse::Error { message: format!("there's no connection {}", id) };
+                }
+                log!("Kicked connection {}.", id);
+                AdminResponse::Done
+            }
+            AdminRequest::Ban { address } => {
+                let kicked = registry.ban(address).await;
+                log!("Banned {}, kicking {} connections from it.", address, kicked);
+                AdminResponse::Done
+            }
+            AdminRequest::Unban { address } => {
+                if !registry.unban(address).await {
+                    return AdminResponse::Error { message: format!("{} isn't banned", address) };
+                }
+                log!("Lifted the ban on {}.", address);
+                AdminResponse::Done
+            }
+            AdminRequest::Announce { text } => {
+                registry.send_to_all(Frame::Announcement { text }).await;
+                AdminResponse::Done
+            }
+            AdminRequest::Stats => AdminResponse::Stats {
+                stats: ServerStats {
+                    uptime_secs: started.elapsed().as_secs(),
+                    connections: registry.len().await,
+                    rooms: rooms.lock().await.rooms.len(),
+                    created_rooms: registry.rooms().await.len(),
+                    banned: registry.banned().await,
+                    traffic: registry.traffic().await,
+                },
+            },
+            AdminRequest::Shutdown => {
+                log!("Shutdown requested over the admin socket.");
+                stop.cancel();
+                AdminResponse::Done
+            }
+        }
+    }
+
+    /// Sends a `Frame::ServerClosing` to every open connection, then waits up to `SHUTDOWN_TIMEOUT` for their writer
+    /// threads to flush it along with anything else still queued for them.
+    async fn shutdown(&mut self) {
+        let connections = self.registry.drain().await;
+
+        let mut writers = Vec::new();
+        for connection in connections {
+            // an Err only means the writer thread has already stopped
+            let _ = connection.direct.send(Frame::ServerClosing).await;
+            writers.push(connection.writer);
+        }
+
+        let flushed = time::timeout(SHUTDOWN_TIMEOUT, async {
+            for writer in writers {
+                let _ = writer.await;
+            }
+        }).await;
+
+        if flushed.is_err() {
+            log!("Timed out waiting for connections to flush. Closing them anyway.");
+        }
+    }
+
+    /// Thread for listening to incoming connections. Takes in a `TcpListener`, a cloned Arc of the server's connection
+    /// `Registry`, which gives each incoming connection its id, the shared `Rooms` whose `Relay` and key package
+    /// `Directory` each connection's threads are given, a `Semaphore` with a permit for each connection the server
+    /// allows open at once, the `Routing` that says which room each connection goes to, how long a connection can be
+    /// idle before it's closed, and a `CancellationToken` that stops the listener and every connection's reader thread
+    /// when the server shuts down.
+    ///
+    /// A connection arriving when every permit is taken is sent a `Frame::ServerFull` and closed.
+    ///
+    /// Each connection gets a reader thread (`Server::read_stream()`) and a writer thread (`Server::write_stream()`), so
+    /// a slow client only ever holds up its own writes. In rendezvous mode, a connection only gets them once it's
+    /// entered a room with `Server::enter()`; until then, it's not visible to anyone. Connections to a federated room
+    /// are forwarded to the room's home server instead.
+    async fn listen(
+        listener: TcpListener,
+        registry: Arc<Registry>,
+        rooms: Arc<Mutex<Rooms>>,
+        slots: Arc<Semaphore>,
+        routing: Arc<Routing>,
+        idle_timeout: Option<Duration>,
+        cancel: CancellationToken,
+    ) {
+        loop {
+            let (stream, address) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                _ = cancel.cancelled() => break,
+            };
+            if registry.is_banned(address.ip()).await {
+                log!("Turned away a connection from {}: the address is banned.", address);
+                registry.access_log().record("refused", &format!("address={} reason=banned", address));
+                continue;
+            }
+            let Ok(slot) = Arc::clone(&slots).try_acquire_owned() else {
+                log!("Turned away a connection from {}: the server is full.", address);
+                registry.access_log().record("refused", &format!("address={} reason=full", address));
+                tokio::spawn(Self::turn_away(stream));
+                continue;
+            };
+
+            let registry = Arc::clone(&registry);
+            let rooms = Arc::clone(&rooms);
+            let routing = Arc::clone(&routing);
+            let cancel = cancel.clone();
+            // spawned straight away, so a connection slow to enter a room doesn't hold up the ones behind it
+            tokio::spawn(async move {
+                // held until the connection closes
+                let _slot = slot;
+                let id = registry.next_id();
+                let (reader, writer) = stream.into_split();
+                let mut reader = BufReader::with_capacity(routing.tuning.read_buffer, reader);
+                let counters = Arc::new(Counters::default());
+
+                let room = if routing.rendezvous {
+                    let entered = tokio::select! {
+                        room = Self::enter(&mut reader, &counters) => room,
+                        _ = cancel.cancelled() => return,
+                    };
+                    match entered {
+                        // the code is all it takes to join the room, so it's kept out of the server's output
+                        Some(room) => {
+                            log!("Connection {} accepted from {} entered a room.", id, address);
+                            room
+                        }
+                        None => {
+                            log!("Connection from {} didn't enter a room in time. Closing it.", address);
+                            registry.access_log().record("refused", &format!("address={} reason=no_room", address));
+                            return;
+                        }
+                    }
+                } else {
+                    String::new()
+                };
+
+                if let Some(federation) = routing.federations.get(&room) {
+                    log!("Forwarding connection {} from {} to the room's home server.", id, address);
+                    registry.access_log().record("forwarded", &format!("address={} to={}", address, federation.home.address()));
+                    match federation::forward(reader, writer, federation, &routing.name, cancel).await {
+                        Ok(_) => log!("Forwarded connection from {} closed successfully.", address),
+                        Err(ApplicationError::Network(NetworkError::FederationLoop)) => {
+                            log!("ERROR: Connection from {} was forwarded around a loop of servers. Closing it.", address)
+                        }
+                        Err(_) => log!(
+                            "ERROR: Problem forwarding connection from {} to {}.",
+                            address,
+                            federation.home.address(),
+                        ),
+                    }
+                    return;
+                }
+                let (relay, directory) = rooms.lock().await.enter(&room);
+                let kick = CancellationToken::new();
+
+                // subscribe before the connection is visible to anyone, so it can't miss messages relayed in between
+                let (inbox, last_seq) = relay.lock().await.subscribe(id);
+                let (direct_tx, direct_rx) = mpsc::channel(routing.tuning.direct_queue.max(1));
+                let write_buffer = routing.tuning.write_buffer;
+
+                let writer_relay = Arc::clone(&relay);
+                let writer_counters = Arc::clone(&counters);
+                let writer = tokio::spawn(async move {
+                    let writer = BufWriter::with_capacity(write_buffer, writer);
+                    let stream = Stream { id, writer, counters: writer_counters, compress: false };
+                    if Self::write_stream(stream, writer_relay, inbox, direct_rx, last_seq).await.is_err() {
+                        log!("ERROR: Problem writing to connection {}.", id);
+                    }
+                });
+
+                registry.insert(Connection {
+                    id,
+                    address,
+                    room: room.clone(),
+                    identity: None,
+                    connected_at: chrono::Utc::now(),
+                    version: wire::LEGACY_VERSION,
+                    counters: Arc::clone(&counters),
+                    direct: direct_tx,
+                    writer,
+                    kick: kick.clone(),
+                }).await;
+                registry.send(id, Frame::Connected { id, versions: wire::SUPPORTED_VERSIONS.to_vec() }).await;
+
+                log!("Listening on connection {} accepted from {}.", id, address);
+                let reason = tokio::select! {
+                    res = Self::read_stream(id, &room, reader, &counters, relay, &directory, &registry) => match res {
+                        Ok(_) => {
+                            log!("Connection to {} closed successfully.", address);
+                            "closed"
+                        }
+                        Err(_) => {
+                            log!("ERROR: Problem reading data received from {}. Connection closed unsuccessfully.", address);
+                            "error"
+                        }
+                    },
+                    _ = Self::idle_out(&counters, idle_timeout) => {
+                        log!("Connection {} from {} sent nothing for too long. Closing it.", id, address);
+                        "idle"
+                    }
+                    _ = kick.cancelled() => {
+                        log!("Connection {} from {} was kicked. Closing it.", id, address);
+                        "kicked"
+                    }
+                    _ = cancel.cancelled() => "shutdown",
+                };
+                registry.remove(id, reason).await;
+                directory.lock().await.remove(id);
+                let created = registry.room(&room).await.is_some();
+                rooms.lock().await.leave(&room, created);
+                // on shutdown, everyone is about to be disconnected anyway
+                if !cancel.is_cancelled() {
+                    registry.send_to_others(id, &room, Frame::Disconnected { id }).await;
+                }
+            });
+        }
+    }
+
+    /// Completes once the connection whose frames `counters` counts has gone `idle_timeout` without sending one, or
+    /// never if there's no timeout.
+    async fn idle_out(counters: &Counters, idle_timeout: Option<Duration>) {
+        let Some(idle_timeout) = idle_timeout else { return std::future::pending().await };
+        loop {
+            let idle = counters.idle();
+            if idle >= idle_timeout {
+                return;
+            }
+            time::sleep(idle_timeout - idle).await;
+        }
+    }
+
+    /// Tells a connection there's no room for it with a `Frame::ServerFull`, then closes it.
+    async fn turn_away(stream: TcpStream) {
+        let mut writer = BufWriter::new(stream);
+        // the connection is closed either way
+        if Frame::ServerFull.write(&mut writer).await.is_ok() {
+            let _ = writer.flush().await;
+        }
+    }
+
+    /// Waits up to `ENTER_TIMEOUT` for a connection's first frame, returning the room code it names if it's a
+    /// `Frame::Enter` with a valid one.
+    async fn enter(reader: &mut BufReader<OwnedReadHalf>, counters: &Counters) -> Option<String> {
+        let frame = time::timeout(ENTER_TIMEOUT, Frame::read(reader)).await.ok()?.ok()??;
+        counters.read(frame.encoded_len());
+        match frame {
+            Frame::Enter { room } if invite::check_room_code(&room).is_ok() => Some(room),
+            _ => None,
+        }
+    }
+
+    /// Handles reading the incoming frames from the `OwnedReadHalf` of a split `TcpStream`. Takes in the connection's
+    /// id in the `Registry` and the room it's in, the buffered `OwnedReadHalf`, the `Counters` to record the frames read in, the shared `Relay` that
+    /// MLS messages are pushed into to be fanned out to other connections, and the key package `Directory` along with
+    /// the registry of connections to notify of changes to it. A frame that arrives corrupted is dropped, and the
+    /// client asked to send it again with a `Frame::Retransmit`. Returns an `Ok(())` when the thread successfully
+    /// closes, or an `Err` on a failed read from the stream.
+    async fn read_stream(
+        id: ConnectionId,
+        room: &str,
+        mut reader: BufReader<OwnedReadHalf>,
+        counters: &Counters,
+        relay: Arc<Mutex<Relay>>,
+        directory: &Mutex<Directory>,
+        registry: &Registry,
+    ) -> Result<(), ApplicationError> {
+        loop {
+            let frame = match Frame::read(&mut reader).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                // the stream is still in step, so the client can send it again
+                Err(ApplicationError::Network(NetworkError::CorruptFrame)) => {
+                    log!("WARNING: Dropped a corrupted frame from connection {}. Asking for it again.", id);
+                    registry.send(id, Frame::Retransmit).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            counters.read(frame.encoded_len());
+            match frame {
+                Frame::Mls(contents) => {
+                    if flood::is_chat_message(&contents) && !Self::let_through(id, room, &contents, registry).await {
+                        continue;
+                    }
+                    counters.relayed();
+                    relay.lock().await.push(id, contents);
+                }
+                Frame::PublishKeyPackage { last_resort, data } => {
+                    // whatever the client claims to be, for the registry's listing; nothing here relies on it
+                    if let Ok(key_package) = KeyPackageIn::tls_deserialize(&mut data.as_slice()) {
+                        let credential = key_package.unverified_credential().credential;
+                        registry.set_identity(id, String::from_utf8_lossy(credential.identity()).into_owned()).await;
+                    }
+
+                    let first = directory.lock().await.publish(id, last_resort, data);
+                    if first {
+                        log!("Connection {} published key packages.", id);
+                        registry.send_to_others(id, room, Frame::KeyPackagesAvailable { owner: id }).await;
+                    }
+                }
+                Frame::PublishRatchetTree { key_package, tree } => directory.lock().await.publish_tree(key_package, tree),
+                Frame::FetchRatchetTree { key_package } => {
+                    let tree = directory.lock().await.tree(&key_package).unwrap_or_default();
+                    registry.send(id, Frame::RatchetTree { key_package, tree }).await;
+                }
+                Frame::Forwarded { via } => log!("Connection {} was forwarded through {}.", id, via.join(", ")),
+                Frame::Version { version } if wire::SUPPORTED_VERSIONS.contains(&version) => {
+                    registry.set_version(id, version).await;
+                    // held back until now, since a connection from before rooms wouldn't know what to make of it
+                    if let Some(info) = registry.room(room).await {
+                        registry.send(id, Frame::RoomOwner { owner: info.owner, invite_only: info.invite_only }).await;
+                    }
+                }
+                Frame::Version { version } => {
+                    log!("Connection {} picked protocol version {}, which isn't one offered. Ignoring it.", id, version);
+                }
+                Frame::Compression { codecs } => {
+                    let accepted = codecs.into_iter().filter(|codec| codec == DEFLATE).take(1).collect();
+                    registry.send(id, Frame::Compression { codecs: accepted }).await;
+                }
+                Frame::Ping => {
+                    let time = chrono::Utc::now().timestamp_millis().max(0) as u64;
+                    registry.send(id, Frame::Pong { time }).await;
+                }
+                Frame::Register { id: requested } => {
+                    let assigned = registry.register(id, &requested).await;
+                    if assigned != requested {
+                        log!("Connection {} asked for a name already taken, and was given {}.", id, assigned);
+                    }
+                    registry.send(id, Frame::Registered { id: assigned }).await;
+                }
+                // the writer thread replays from the history, in order with everything else it writes
+                Frame::Resume { after } => registry.send(id, Frame::Resume { after }).await,
+                Frame::ClaimKeyPackage { .. } if !registry.may_invite(id).await => {
+                    log!("Connection {} tried to claim a key package in an invite-only room it doesn't own.", id);
+                }
+                Frame::CreateRoom { .. } if !Feature::Rooms.available(registry.version(id).await) => {
+                    log!("Connection {} tried to create a room without picking a protocol version with rooms.", id);
+                }
+                Frame::CreateRoom { room: code, invite_only } => {
+                    // outside rendezvous mode, there are no rooms to enter
+                    let created = if room.is_empty() || invite::check_room_code(&code).is_err() {
+                        None
+                    } else {
+                        registry.create_room(id, &code, invite_only).await
+                    };
+                    match created {
+                        Some(_) => {
+                            log!("Connection {} created a room.", id);
+                            registry.send(id, Frame::RoomCreated { room: code }).await;
+                        }
+                        None => registry.send(id, Frame::RoomRefused { room: code }).await,
+                    }
+                }
+                Frame::ClaimKeyPackage { owner } => {
+                    let claimed = directory.lock().await.claim(owner);
+                    if let Some((data, remaining)) = claimed {
+                        log!("Connection {} claimed a key package from {}.", id, owner);
+                        registry.send(id, Frame::KeyPackage { owner, data }).await;
+                        registry.send(owner, Frame::KeyPackageClaimed { remaining: remaining as u64 }).await;
+                    }
+                }
+                frame => log!("Ignoring an unexpected {:?} frame from connection {}.", Channel::of(&frame), id),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Helper function for `Server::read_stream()`. Applies flood protection to a chat message from connection `id`,
+    /// holding it back while its identity is over the rate limit, which throttles the connection. Returns false if the
+    /// message is to be dropped instead because the identity is muted, after answering it with a `Frame::Muted` and,
+    /// if it's only just been muted, telling the room why.
+    async fn let_through(id: ConnectionId, room: &str, contents: &[u8], registry: &Registry) -> bool {
+        let mut waited = false;
+        loop {
+            let (verdict, identity) = registry.check_flood(id, waited).await;
+            let muted = match verdict {
+                Verdict::Relay => return true,
+                Verdict::Wait(wait) => {
+                    waited = true;
+                    time::sleep(wait).await;
+                    continue;
+                }
+                Verdict::Mute(muted) => {
+                    log!("Connection {} was muted for flooding its room.", id);
+                    registry.access_log().record("muted", &format!("id={} identity={:?} secs={}", id, identity, muted.as_secs()));
+                    let text = format!("{} was muted for {} seconds for flooding the room.", identity, muted.as_secs());
+                    registry.send_to_others(id, room, Frame::Announcement { text: text.clone() }).await;
+                    registry.send(id, Frame::Announcement { text }).await;
+                    muted
+                }
+                Verdict::Muted(muted) => muted,
+            };
+            // rounded up, so it isn't over before the client's told it is
+            let secs = muted.as_secs() + u64::from(muted.subsec_nanos() > 0);
+            registry.send(id, Frame::Muted { secs, data: contents.to_vec() }).await;
+            return false;
+        }
+    }
+
+    /// Handles writing frames to a connection's `Stream`: frames addressed directly to the connection, every message
+    /// pushed into the `Relay` by other connections, and a `Frame::Sequenced` for each of the connection's own.
+    /// `last_seq` is the sequence number of the last relayed message the connection is considered to have seen.
+    ///
+    /// Frames are buffered and only flushed once neither queue has anything else ready, so that a burst of them (e.g. a
+    /// chat message's `Relay` along with the `Sequenced` for the sender's own) goes out in as few writes as possible.
+    ///
+    /// Writing a `Frame::ServerClosing` ends the thread, dropping the `OwnedWriteHalf` to close the connection.
+    ///
+    /// If the connection falls far enough behind that its `Inbox` fills up, messages relayed meanwhile are dropped for it
+    /// alone, and once it catches up the client is sent a `Frame::Lagged` followed by a replay of the dropped messages
+    /// from the relay history. A `Frame::Resume` from the client is
+    /// answered the same way with a replay of everything after the point it resumes from, even messages already
+    /// written to it since it connected, so that it gets them all in order. See `Server::replay()`.
+    async fn write_stream(
+        mut stream: Stream,
+        relay: Arc<Mutex<Relay>>,
+        mut inbox: Inbox,
+        mut direct: mpsc::Receiver<Frame>,
+        mut last_seq: u64,
+    ) -> Result<(), ApplicationError> {
+        let id = stream.id;
+        loop {
+            let unflushed = stream.unflushed();
+            tokio::select! {
+                // checked in order, so the flush only happens when there's nothing left to write
+                biased;
+                frame = direct.recv() => match frame {
+                    Some(frame @ (Frame::ServerClosing | Frame::Kicked)) => {
+                        stream.write(&frame).await?;
+                        break;
+                    }
+                    Some(Frame::Resume { after }) => {
+                        log!("Connection {} resumed after message {}.", id, after);
+                        Self::replay(&mut stream, &relay, after, &mut last_seq).await?;
+                    }
+                    Some(frame) => stream.write(&frame).await?,
+                    None => break, // connection closed and removed from the registry
+                },
+                msg = inbox.relayed.recv() => match msg {
+                    Some(msg) => {
+                        if msg.seq <= last_seq { continue; } // already delivered by a replay
+                        last_seq = msg.seq;
+                        if msg.id != id {
+                            stream.write(&Frame::Relay { seq: msg.seq, data: msg.contents.to_vec() }).await?;
+                            log!("Message {} sent from {} to {}.", msg.seq, msg.id, id);
+                        } else {
+                            stream.write(&Frame::Sequenced { seq: msg.seq }).await?;
+                        }
+                    }
+                    None => break, // the relay's routing task has stopped
+                },
+                _ = inbox.lag.notify.notified() => {
+                    let missed = inbox.lag.missed.swap(0, Ordering::Relaxed);
+                    log!("Connection {} lagged behind by {} messages.", id, missed);
+                    stream.write(&Frame::Lagged { missed }).await?;
+                    Self::replay(&mut stream, &relay, last_seq, &mut last_seq).await?;
+                }
+                _ = std::future::ready(()), if unflushed => stream.flush().await?,
+            }
+        }
+
+        stream.flush().await
+    }
+
+    /// Helper function for `Server::write_stream()`. Writes every message in the relay history after `after` to
+    /// the connection, and moves `last_seq` past them so they aren't written again when they come through its
+    /// `Inbox`. If the history no longer reaches back far enough, the client is sent a `Frame::Resync`
+    /// instead, since its MLS state can't catch up from what's left.
+    async fn replay(
+        stream: &mut Stream,
+        relay: &Mutex<Relay>,
+        after: u64,
+        last_seq: &mut u64,
+    ) -> Result<(), ApplicationError> {
+        let replay = relay.lock().await.replay(after);
+        match replay {
+            Some(msgs) => {
+                for msg in msgs {
+                    *last_seq = (*last_seq).max(msg.seq);
+                    if msg.id != stream.id {
+                        stream.write(&Frame::Relay { seq: msg.seq, data: msg.contents.to_vec() }).await?;
+                    } else {
+                        stream.write(&Frame::Sequenced { seq: msg.seq }).await?;
+                    }
+                }
+            }
+            None => {
+                *last_seq = relay.lock().await.next_seq - 1;
+                stream.write(&Frame::Resync).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The buffered writing half of a connection, along with its id, the `Counters` every frame written to it is
+/// recorded in, and whether frames written to it are compressed.
+struct Stream {
+    id: ConnectionId,
+    writer: BufWriter<OwnedWriteHalf>,
+    counters: Arc<Counters>,
+    compress: bool,
+}
+
+impl Stream {
+    /// Writes a frame into the buffer, which only goes out to the connection once full or flushed. Once it's written
+    /// the server's answer to the client's offer of compression, every frame after it is compressed if it accepted.
+    async fn write(&mut self, frame: &Frame) -> Result<(), ApplicationError> {
+        let len = frame.write_with(&mut self.writer, self.compress).await?;
+        self.counters.wrote(len);
+        if let Frame::Compression { codecs } = frame {
+            self.compress = codecs.iter().any(|codec| codec == DEFLATE);
+        }
+        Ok(())
+    }
+
+    /// Returns whether any frames are still waiting in the buffer.
+    fn unflushed(&self) -> bool {
+        !self.writer.buffer().is_empty()
+    }
+
+    async fn flush(&mut self) -> Result<(), ApplicationError> {
+        self.writer.flush().await.map_err(|_| NetworkError::Io.into())
+    }
+}
+
+/// The type routed to each connection's `Inbox`. The "id" refers to the connection/thread
+/// the message was read by, and the "seq" is its position in the relay history.
+#[derive(Clone)]
+struct MessageIn {
+    id: ConnectionId,
+    seq: u64,
+    contents: Arc<Vec<u8>>,
+}
+
+/// Assigns sequence numbers to incoming messages, hands them to the `Router` to be queued for every
+/// connection's writer thread, and keeps the most recent `HISTORY_SIZE` of them so connections that
+/// lag behind can be caught up.
+struct Relay {
+    history: VecDeque<MessageIn>,
+    next_seq: u64,
+    routes: mpsc::UnboundedSender<Route>,
+    queue_size: usize,
+}
+
+impl Relay {
+    /// Returns a relay whose connections' queues hold up to `queue_size` messages each, along with the `Router` to
+    /// run alongside it.
+    fn new(queue_size: usize) -> (Relay, Router) {
+        let (routes, rx) = mpsc::unbounded_channel();
+
+        let relay = Relay {
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            next_seq: 1,
+            routes,
+            queue_size,
+        };
+        (relay, Router { routes: rx, queues: HashMap::new() })
+    }
+
+    /// Starts queueing relayed messages for connection `id`. Returns its `Inbox`, along with the sequence number of
+    /// the last message relayed before it, which it won't be sent.
+    fn subscribe(&mut self, id: ConnectionId) -> (Inbox, u64) {
+        let (tx, relayed) = mpsc::channel(self.queue_size);
+        let lag = Arc::new(Lag::default());
+
+        // an Err only means the router has stopped, in which case the inbox is closed straight away
+        let _ = self.routes.send(Route::Subscribe(id, Queue { tx, lag: Arc::clone(&lag) }));
+        (Inbox { relayed, lag }, self.next_seq - 1)
+    }
+
+    /// Sequences a message read from connection `id`, records it in the history, and routes it to every connection.
+    fn push(&mut self, id: ConnectionId, contents: Vec<u8>) {
+        let msg = MessageIn { id, seq: self.next_seq, contents: Arc::new(contents) };
+        self.next_seq += 1;
+
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(msg.clone());
+
+        // an Err only means the router has stopped, which it only does once the server does
+        let _ = self.routes.send(Route::Message(msg));
+    }
+
+    /// Returns every message in the history after `after`, or None if some of them have already
+    /// been dropped from the history, or if `after` is past the last message relayed (e.g. the client
+    /// is resuming from before the server restarted and started counting over).
+    fn replay(&self, after: u64) -> Option<Vec<MessageIn>> {
+        let oldest = self.history.front().map_or(self.next_seq, |msg| msg.seq);
+        if oldest > after + 1 || after >= self.next_seq {
+            return None;
+        }
+
+        Some(self.history.iter().filter(|msg| msg.seq > after).cloned().collect())
+    }
+
+    /// Returns where the relay has got to, to save with the rest of the server's state.
+    fn state(&self) -> RelayState {
+        RelayState {
+            next_seq: self.next_seq,
+            history: self.history.iter().map(|msg| (msg.seq, msg.contents.to_vec())).collect(),
+        }
+    }
+
+    /// Picks up where a relay saved with `Relay::state()` left off. Its messages are put down to connection 0, which
+    /// no connection ever is, since whoever sent them has a different id since the restart.
+    fn restore(&mut self, state: RelayState) {
+        self.next_seq = state.next_seq.max(1);
+        self.history = state.history.into_iter()
+            .rev()
+            .take(HISTORY_SIZE)
+            .rev()
+            .map(|(seq, contents)| MessageIn { id: 0, seq, contents: Arc::new(contents) })
+            .collect();
+    }
+}
+
+/// What the `Relay` hands its `Router`, in the order it happened, so a connection subscribed before a message was
+/// pushed always has it routed to it.
+enum Route {
+    Subscribe(ConnectionId, Queue),
+    Message(MessageIn),
+}
+
+/// The sending end of a connection's `Inbox`, held by the `Router`.
+struct Queue {
+    tx: mpsc::Sender<MessageIn>,
+    lag: Arc<Lag>,
+}
+
+/// The receiving end of a connection's queue of relayed messages, read by its writer thread.
+struct Inbox {
+    relayed: mpsc::Receiver<MessageIn>,
+    lag: Arc<Lag>,
+}
+
+/// Tells a connection's writer thread how many messages were dropped for it because its queue was full.
+#[derive(Default)]
+struct Lag {
+    missed: AtomicU64,
+    notify: Notify,
+}
+
+/// Task that fans each relayed message out to every connection's bounded queue. It never waits on a queue: a message
+/// that doesn't fit is dropped for that connection alone, which is told through its `Lag` so that its writer thread
+/// can replay it from the history, while everyone else carries on at their own pace.
+struct Router {
+    routes: mpsc::UnboundedReceiver<Route>,
+    queues: HashMap<ConnectionId, Queue>,
+}
+
+impl Router {
+    /// Routes everything the `Relay` hands over, until the relay is dropped.
+    async fn run(mut self) {
+        while let Some(route) = self.routes.recv().await {
+            self.route(route);
+        }
+    }
+
+    fn route(&mut self, route: Route) {
+        match route {
+            Route::Subscribe(id, queue) => {
+                self.queues.insert(id, queue);
+            }
+            // queues whose writer thread has stopped are dropped along the way
+            Route::Message(msg) => self.queues.retain(|_, queue| match queue.tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    queue.lag.missed.fetch_add(1, Ordering::Relaxed);
+                    queue.lag.notify.notify_one();
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }),
+        }
+    }
+}
+
+/// What the listener needs to route a connection: whether it has to enter a room first (rendezvous mode), and which
+/// rooms are forwarded to their home on another server, along with the name this server forwards them under. Also
+/// holds the sizes of the queues and buffers set up for each connection.
+struct Routing {
+    rendezvous: bool,
+    name: String,
+    federations: HashMap<String, Federation>,
+    tuning: ServerTuning,
+}
+
+/// The rooms connections are paired in, by code, each with a `Relay` and key package `Directory` of its own so that
+/// connections only ever see the messages and key packages of others in the same room. Outside rendezvous mode,
+/// every connection is put in a single room with an empty code.
+struct Rooms {
+    rooms: HashMap<String, Room>,
+    queue_size: usize,
+}
+
+struct Room {
+    relay: Arc<Mutex<Relay>>,
+    directory: Arc<Mutex<Directory>>,
+    /// number of open connections in the room
+    connections: usize,
+}
+
+impl Rooms {
+    /// Returns an empty set of rooms, whose relays queue up to `queue_size` messages for each connection.
+    fn new(queue_size: usize) -> Rooms {
+        Rooms { rooms: HashMap::new(), queue_size }
+    }
+
+    /// Adds a connection to the room `code`, opening the room and starting its `Router` if it isn't open yet. Returns
+    /// the room's relay and key package directory.
+    fn enter(&mut self, code: &str) -> (Arc<Mutex<Relay>>, Arc<Mutex<Directory>>) {
+        let queue_size = self.queue_size;
+        let room = self.rooms.entry(code.to_string()).or_insert_with(|| {
+            let (relay, router) = Relay::new(queue_size);
+            tokio::spawn(router.run());
+            Room { relay: Arc::new(Mutex::new(relay)), directory: Arc::default(), connections: 0 }
+        });
+        room.connections += 1;
+        (Arc::clone(&room.relay), Arc::clone(&room.directory))
+    }
+
+    /// Takes a connection out of the room `code`. A room with a code is closed once its last connection leaves,
+    /// dropping its history, so rooms that are done with don't pile up on a public relay, unless it was `created`
+    /// with a `Frame::CreateRoom`. Those, and the room every connection is put in outside rendezvous mode, are kept
+    /// for as long as the server runs, so clients can still resume from their history after everyone's been away.
+    fn leave(&mut self, code: &str, created: bool) {
+        let Some(room) = self.rooms.get_mut(code) else { return };
+        room.connections = room.connections.saturating_sub(1);
+        if room.connections == 0 && !code.is_empty() && !created {
+            self.rooms.remove(code);
+        }
+    }
+
+    /// Reopens the rooms whose relays were saved before the server restarted, picking up where each left off.
+    fn restore(&mut self, relays: HashMap<String, RelayState>) {
+        for (code, state) in relays {
+            let (mut relay, router) = Relay::new(self.queue_size);
+            relay.restore(state);
+            tokio::spawn(router.run());
+            self.rooms.insert(code, Room { relay: Arc::new(Mutex::new(relay)), directory: Arc::default(), connections: 0 });
+        }
+    }
+
+    /// Returns the state of the relay of every open room whose code passes `keep`, by code.
+    async fn relay_states(&self, keep: impl Fn(&str) -> bool) -> HashMap<String, RelayState> {
+        let mut states = HashMap::new();
+        for (code, room) in self.rooms.iter().filter(|(code, _)| keep(code)) {
+            states.insert(code.clone(), room.relay.lock().await.state());
+        }
+        states
+    }
+}
+
+/// Key packages published by each connection, kept for other members to claim when they add its owner to the group.
+/// Every member adding the same client gets a different key package, until only the owner's last resort is left.
+/// Also keeps the ratchet trees published for Welcomes sent without one, until the new members fetch them.
+#[derive(Default)]
+struct Directory {
+    pools: HashMap<ConnectionId, KeyPackagePool>,
+    /// the last `MAX_RATCHET_TREES` trees published, oldest first, each with the key package reference it's for
+    trees: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct KeyPackagePool {
+    packages: VecDeque<Vec<u8>>,
+    last_resort: Option<Vec<u8>>,
+    /// whether the other connections have been told about the pool since it was started
+    announced: bool,
+}
+
+impl Directory {
+    /// Adds a key package to the pool of connection `owner`. A last resort starts the pool over, since it means the
+    /// client has new key material (e.g. after a resync) and anything published before it is stale. Packages past
+    /// `MAX_POOL_SIZE` are dropped. Returns true if the other connections should be told about the pool: when the
+    /// first ordinary package since it was started arrives.
+    fn publish(&mut self, owner: ConnectionId, last_resort: bool, data: Vec<u8>) -> bool {
+        if last_resort {
+            self.pools.insert(owner, KeyPackagePool { last_resort: Some(data), ..KeyPackagePool::default() });
+            return false;
+        }
+
+        let pool = self.pools.entry(owner).or_default();
+        if pool.packages.len() < MAX_POOL_SIZE {
+            pool.packages.push_back(data);
+        }
+        !std::mem::replace(&mut pool.announced, true)
+    }
+
+    /// Takes one of connection `owner`'s key packages, falling back on its last resort (which is left in place) once
+    /// the rest are gone. Returns the package along with the number left, not counting the last resort.
+    fn claim(&mut self, owner: ConnectionId) -> Option<(Vec<u8>, usize)> {
+        let pool = self.pools.get_mut(&owner)?;
+        match pool.packages.pop_front() {
+            Some(data) => Some((data, pool.packages.len())),
+            None => pool.last_resort.clone().map(|data| (data, 0)),
+        }
+    }
+
+    /// Forgets connection `owner`'s key packages once it closes.
+    fn remove(&mut self, owner: ConnectionId) {
+        self.pools.remove(&owner);
+    }
+
+    /// Keeps the ratchet tree for the Welcome for `key_package`, replacing any published for it before. Trees aren't
+    /// dropped once they're fetched, since the answer may be lost if the new member's connection drops.
+    fn publish_tree(&mut self, key_package: Vec<u8>, tree: Vec<u8>) {
+        self.trees.retain(|(published, _)| *published != key_package);
+        if self.trees.len() == MAX_RATCHET_TREES {
+            self.trees.pop_front();
+        }
+        self.trees.push_back((key_package, tree));
+    }
+
+    /// Returns the ratchet tree published for the Welcome for `key_package`, if there is one.
+    fn tree(&self, key_package: &[u8]) -> Option<Vec<u8>> {
+        self.trees.iter().find(|(published, _)| published == key_package).map(|(_, tree)| tree.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the server sends a connection it's just accepted as `id`.
+    fn connected(id: u64) -> Option<Frame> {
+        Some(Frame::Connected { id, versions: wire::SUPPORTED_VERSIONS.to_vec() })
+    }
+
+    #[test]
+    fn replay_returns_messages_after_seq() {
+        let (mut relay, _router) = Relay::new(4);
+        for i in 0..3 {
+            relay.push(1, vec![i]);
+        }
+
+        let seqs: Vec<u64> = relay.replay(1).unwrap().iter().map(|msg| msg.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        assert!(relay.replay(3).unwrap().is_empty());
+        assert!(relay.replay(4).is_none());
+    }
+
+    #[test]
+    fn router_drops_only_for_full_queues() {
+        let (mut relay, mut router) = Relay::new(1);
+        let (mut slow, _) = relay.subscribe(1);
+        let (mut fast, _) = relay.subscribe(2);
+        for i in 0..2 {
+            relay.push(3, vec![i]);
+            while let Ok(route) = router.routes.try_recv() {
+                router.route(route);
+            }
+            assert_eq!(fast.relayed.try_recv().map(|msg| msg.seq).ok(), Some(i as u64 + 1));
+        }
+
+        assert_eq!(slow.relayed.try_recv().map(|msg| msg.seq).ok(), Some(1));
+        assert!(slow.relayed.try_recv().is_err());
+        assert_eq!(slow.lag.missed.load(Ordering::Relaxed), 1);
+        assert_eq!(fast.lag.missed.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn directory_hands_out_each_key_package_once() {
+        let mut directory = Directory::default();
+        assert!(!directory.publish(1, true, vec![8]));
+        assert!(directory.publish(1, false, vec![0]));
+        // starting over drops the stale package
+        assert!(!directory.publish(1, true, vec![9]));
+        assert!(directory.publish(1, false, vec![1]));
+        assert!(!directory.publish(1, false, vec![2]));
+
+        assert_eq!(directory.claim(1), Some((vec![1], 1)));
+        assert_eq!(directory.claim(1), Some((vec![2], 0)));
+        assert_eq!(directory.claim(1), Some((vec![9], 0)));
+        assert_eq!(directory.claim(1), Some((vec![9], 0)));
+        assert_eq!(directory.claim(2), None);
+
+        directory.remove(1);
+        assert_eq!(directory.claim(1), None);
+    }
+
+    #[test]
+    fn replay_fails_once_history_is_dropped() {
+        let (mut relay, _router) = Relay::new(4);
+        for i in 0..(HISTORY_SIZE + 2) {
+            relay.push(1, vec![i as u8]);
+        }
+
+        assert!(relay.replay(1).is_none());
+        assert!(relay.replay(2).is_some());
+    }
+
+    #[tokio::test]
+    async fn turns_away_connections_past_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = Server::new(address.port(), 1);
+        server.set_quiet(true);
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+
+        let mut first = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut first).await.unwrap(), connected(1));
+        let mut second = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut second).await.unwrap(), Some(Frame::ServerFull));
+        assert_eq!(Frame::read(&mut second).await.unwrap(), None);
+
+        // the slot is given back once the first connection closes
+        drop(first);
+        time::sleep(Duration::from_millis(100)).await;
+        let mut third = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut third).await.unwrap(), connected(2));
+        serving.abort();
+    }
+
+    #[test]
+    fn counts_sockets_only_for_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("1"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("one"), 42), 0);
+    }
+
+    #[test]
+    fn listens_on_loopback_unless_rendezvous() {
+        let mut server = Server::new(4000, 1);
+        assert_eq!(server.address(), "127.0.0.1:4000".parse().unwrap());
+        server.set_rendezvous(true);
+        assert_eq!(server.address(), "0.0.0.0:4000".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = Server::new(address.port(), 2);
+        server.set_quiet(true);
+        server.set_idle_timeout(Some(Duration::from_millis(300)));
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+
+        let mut idle = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut idle).await.unwrap(), connected(1));
+        let mut pinging = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut pinging).await.unwrap(), connected(2));
+        Frame::Version { version: wire::CURRENT_VERSION }.write(&mut pinging).await.unwrap();
+        for _ in 0..4 {
+            time::sleep(Duration::from_millis(100)).await;
+            Frame::Ping.write(&mut pinging).await.unwrap();
+        }
+
+        assert_eq!(Frame::read(&mut idle).await.unwrap(), None);
+        let mut frame = Frame::read(&mut pinging).await.unwrap();
+        while let Some(Frame::Pong { .. }) = frame {
+            frame = Frame::read(&mut pinging).await.unwrap();
+        }
+        assert_eq!(frame, Some(Frame::Disconnected { id: 1 }));
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn negotiates_versions_with_old_and_new_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = Server::new(address.port(), 3);
+        server.set_quiet(true);
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+        let compression = Frame::Compression { codecs: vec![String::from(DEFLATE)] };
+
+        // a client from before versions were negotiated offers compression, and goes without it
+        let mut old = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut old).await.unwrap(), connected(1));
+        compression.write(&mut old).await.unwrap();
+        Frame::Register { id: String::from("old") }.write(&mut old).await.unwrap();
+        assert_eq!(Frame::read(&mut old).await.unwrap(), Some(Frame::Registered { id: String::from("old") }));
+
+        let mut new = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut new).await.unwrap(), connected(2));
+        Frame::Version { version: wire::CURRENT_VERSION }.write(&mut new).await.unwrap();
+        compression.write(&mut new).await.unwrap();
+        Frame::Register { id: String::from("new") }.write(&mut new).await.unwrap();
+        assert_eq!(Frame::read(&mut new).await.unwrap(), Some(compression));
+        assert_eq!(Frame::read(&mut new).await.unwrap(), Some(Frame::Registered { id: String::from("new") }));
+
+        // only the new client hears of others leaving
+        let mut leaving = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut leaving).await.unwrap(), connected(3));
+        drop(leaving);
+        assert_eq!(Frame::read(&mut new).await.unwrap(), Some(Frame::Disconnected { id: 3 }));
+        Frame::Ping.write(&mut old).await.unwrap();
+        assert!(matches!(Frame::read(&mut old).await.unwrap(), Some(Frame::Pong { .. })));
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn rooms_pair_connections_by_code() {
+        let mut rooms = Rooms::new(4);
+        let (first, _) = rooms.enter("q7rk-2mfx");
+        let (second, _) = rooms.enter("q7rk-2mfx");
+        let (other, _) = rooms.enter("other");
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        rooms.leave("q7rk-2mfx", false);
+        assert!(rooms.rooms.contains_key("q7rk-2mfx"));
+        rooms.leave("q7rk-2mfx", false);
+        assert!(!rooms.rooms.contains_key("q7rk-2mfx"));
+
+        rooms.enter("");
+        rooms.leave("", false);
+        assert!(rooms.rooms.contains_key(""));
+        rooms.enter("book-club");
+        rooms.leave("book-club", true);
+        assert!(rooms.rooms.contains_key("book-club"));
+    }
+
+    #[tokio::test]
+    async fn keeps_history_across_restarts() {
+        let path = std::env::temp_dir().join(format!("mls_chat_server_state_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        for restarted in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let mut server = Server::new(address.port(), 2);
+            server.set_quiet(true);
+            server.set_state_path(Some(path.clone()));
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let serving = tokio::spawn(async move {
+                server.serve(listener, async { stopped.await.map_err(|_| std::io::ErrorKind::Other.into()) }).await
+            });
+
+            let mut connection = BufReader::new(TcpStream::connect(address).await.unwrap());
+            assert_eq!(Frame::read(&mut connection).await.unwrap(), connected(1));
+            if restarted {
+                Frame::Resume { after: 0 }.write(&mut connection).await.unwrap();
+                let replayed = Frame::read(&mut connection).await.unwrap();
+                assert_eq!(replayed, Some(Frame::Relay { seq: 1, data: vec![1, 2, 3] }));
+            } else {
+                Frame::Mls(vec![1, 2, 3]).write(&mut connection).await.unwrap();
+                assert_eq!(Frame::read(&mut connection).await.unwrap(), Some(Frame::Sequenced { seq: 1 }));
+            }
+
+            stop.send(()).unwrap();
+            serving.await.unwrap().unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn takes_admin_requests() {
+        let socket = std::env::temp_dir().join(format!("mls_chat_admin_{}.sock", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = Server::new(address.port(), 2);
+        server.set_quiet(true);
+        server.set_admin_socket(Some(socket.clone()));
+        let serving = tokio::spawn(async move { server.serve(listener, std::future::pending()).await });
+
+        let mut connection = BufReader::new(TcpStream::connect(address).await.unwrap());
+        assert_eq!(Frame::read(&mut connection).await.unwrap(), connected(1));
+        let Ok(AdminResponse::Connections { connections }) = admin::request(&socket, &AdminRequest::List).await else {
+            panic!("no connections listed");
+        };
+        assert_eq!(connections.iter().map(|connection| connection.id).collect::<Vec<_>>(), vec![1]);
+
+        let text = String::from("Restarting at noon.");
+        let announced = admin::request(&socket, &AdminRequest::Announce { text: text.clone() }).await;
+        assert_eq!(announced.ok(), Some(AdminResponse::Done));
+        assert_eq!(Frame::read(&mut connection).await.unwrap(), Some(Frame::Announcement { text }));
+
+        assert_eq!(admin::request(&socket, &AdminRequest::Kick { id: 1 }).await.ok(), Some(AdminResponse::Done));
+        assert_eq!(Frame::read(&mut connection).await.unwrap(), Some(Frame::Kicked));
+        assert_eq!(Frame::read(&mut connection).await.unwrap(), None);
+        assert!(matches!(
+            admin::request(&socket, &AdminRequest::Kick { id: 1 }).await,
+            Ok(AdminResponse::Error { .. }),
+        ));
+
+        assert_eq!(admin::request(&socket, &AdminRequest::Shutdown).await.ok(), Some(AdminResponse::Done));
+        serving.await.unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+}
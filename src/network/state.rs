@@ -0,0 +1,62 @@
+use std::{collections::HashMap, fs, io::ErrorKind, net::IpAddr, path::Path};
+use crate::{errors::{ApplicationError, StorageError}, network::registry::RoomInfo, session};
+
+use serde::{Deserialize, Serialize};
+
+/// The rooms a server keeps across restarts when hosted with `host --state`: the ones created with
+/// `Frame::CreateRoom`, and the relay history of every room that's kept while nobody's in it, so clients resuming
+/// after the restart are still replayed what was relayed while they were away. The addresses banned over the admin
+/// socket are kept along with them. Key packages aren't kept, since they
+/// belong to connections, which don't outlive the server; clients publish new ones when they reconnect.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerState {
+    /// Rooms created at runtime, by code.
+    pub rooms: HashMap<String, RoomInfo>,
+    /// Relay history, by room code.
+    pub relays: HashMap<String, RelayState>,
+    #[serde(default)]
+    pub banned: Vec<IpAddr>,
+}
+
+/// Where a room's relay had got to, and the messages it still kept to replay.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayState {
+    /// Sequence number the next message relayed will get.
+    pub next_seq: u64,
+    /// Sequence number and contents of each message in the history, oldest first.
+    pub history: Vec<(u64, Vec<u8>)>,
+}
+
+impl ServerState {
+    /// Loads the state saved at `path`, or returns None if there isn't any.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::ServerState` if the file exists but can't be read or parsed.
+    pub fn load(path: &Path) -> Result<Option<ServerState>, ApplicationError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StorageError::ServerState(format!("{}: {}", path.display(), e)).into()),
+        };
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| StorageError::ServerState(format!("{}: {}", path.display(), e)).into())
+    }
+
+    /// Saves the state to `path` with `session::write_private()`, so a crash partway through leaves the last state saved
+    /// intact. Room codes are all it takes to join a room, so they're kept from other users on the host.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::ServerState` if serializing or writing fails.
+    pub fn save(&self, path: &Path) -> Result<(), ApplicationError> {
+        let error = |e: &dyn std::fmt::Display| {
+            ApplicationError::Storage(StorageError::ServerState(format!("{}: {}", path.display(), e)))
+        };
+
+        let contents = serde_json::to_vec(self).map_err(|e| error(&e))?;
+        session::write_private(path, &contents).map_err(|e| error(&e))
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{
+    errors::{ApplicationError, MlsError},
+    network::frame::Frame,
+    PROTOCOL_VERSION,
+};
+
+use openmls::prelude::*;
+
+/// Bytes an `MLSMessage` starts with before its body: the protocol version, then the wire format, two bytes each.
+const HEADER_LEN: usize = 4;
+
+/// Version of the frame protocol spoken by clients and servers from before versions were negotiated, which neither
+/// offer nor pick one. A peer is taken to speak it until it picks another.
+pub const LEGACY_VERSION: u16 = 1;
+
+/// Newest version of the frame protocol, which adds every `Feature`.
+pub const CURRENT_VERSION: u16 = 2;
+
+/// Versions of the frame protocol this build speaks, oldest first.
+pub const SUPPORTED_VERSIONS: [u16; 2] = [LEGACY_VERSION, CURRENT_VERSION];
+
+/// What part of the protocol a `Frame` belongs to. Frames of one channel only matter to the part of the client or
+/// server handling it, e.g. presence to the member list, so each can be gated, logged, or counted on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Setting up the connection, before anything is relayed over it: entering a room, registering an id,
+    /// negotiating compression, and resuming a session.
+    Handshake,
+    /// MLS messages relayed between the group's members, and the server sequencing them.
+    Chat,
+    /// The key packages and ratchet trees the server keeps for members adding each other.
+    Directory,
+    /// Keeping the relayed stream in step: catching up on what was missed, keepalives, and shutting down.
+    Control,
+    /// Rooms, their owners, and what the server's host does to connections.
+    Admin,
+    /// Connections coming and going.
+    Presence,
+}
+
+impl Channel {
+    /// Returns the channel `frame` belongs to.
+    pub fn of(frame: &Frame) -> Channel {
+        match frame {
+            Frame::Enter { .. }
+            | Frame::Forwarded { .. }
+            | Frame::Register { .. }
+            | Frame::Registered { .. }
+            | Frame::Compression { .. }
+            | Frame::Resume { .. }
+            | Frame::Version { .. }
+            | Frame::ServerFull => Channel::Handshake,
+            Frame::Mls(_) | Frame::Relay { .. } | Frame::Sequenced { .. } => Channel::Chat,
+            Frame::PublishKeyPackage { .. }
+            | Frame::KeyPackagesAvailable { .. }
+            | Frame::ClaimKeyPackage { .. }
+            | Frame::KeyPackage { .. }
+            | Frame::KeyPackageClaimed { .. }
+            | Frame::PublishRatchetTree { .. }
+            | Frame::FetchRatchetTree { .. }
+            | Frame::RatchetTree { .. } => Channel::Directory,
+            Frame::Lagged { .. }
+            | Frame::Resync
+            | Frame::Retransmit
+            | Frame::Ping
+            | Frame::Pong { .. }
+            | Frame::ServerClosing => Channel::Control,
+            Frame::CreateRoom { .. }
+            | Frame::RoomCreated { .. }
+            | Frame::RoomRefused { .. }
+            | Frame::RoomOwner { .. }
+            | Frame::Kicked
+            | Frame::Announcement { .. }
+            | Frame::Muted { .. } => Channel::Admin,
+            Frame::Connected { .. } | Frame::Disconnected { .. } => Channel::Presence,
+        }
+    }
+}
+
+/// Optional parts of the frame protocol, used only over connections that negotiated a version with them. Frames for a
+/// feature are never sent to a peer whose version lacks it, so one from before it sees nothing it can't make sense of,
+/// and gets by without it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// Compressing large frames, offered and answered with `Frame::Compression`.
+    Compression,
+    /// Rooms created at runtime with `Frame::CreateRoom`, and their owners.
+    Rooms,
+    /// Being told when other connections in the room close, with `Frame::Disconnected`.
+    Presence,
+}
+
+impl Feature {
+    /// Returns the first protocol version with the feature.
+    pub fn since(self) -> u16 {
+        match self {
+            Feature::Compression | Feature::Rooms | Feature::Presence => CURRENT_VERSION,
+        }
+    }
+
+    /// Returns whether a connection speaking `version` has the feature.
+    pub fn available(self, version: u16) -> bool {
+        version >= self.since()
+    }
+
+    /// Returns the feature `frame` is part of, or None if it's part of every version.
+    pub fn of(frame: &Frame) -> Option<Feature> {
+        match frame {
+            Frame::Compression { .. } => Some(Feature::Compression),
+            Frame::CreateRoom { .. } | Frame::RoomCreated { .. } | Frame::RoomRefused { .. } | Frame::RoomOwner { .. } => {
+                Some(Feature::Rooms)
+            }
+            Frame::Disconnected { .. } => Some(Feature::Presence),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether `frame` may be sent to a peer speaking `version`.
+pub fn allowed(version: u16, frame: &Frame) -> bool {
+    Feature::of(frame).is_none_or(|feature| feature.available(version))
+}
+
+/// Picks the newest of the versions a server `offered` in its `Frame::Connected` that this build also speaks, or None
+/// if there's none, as from a server that offered nothing, in which case `LEGACY_VERSION` is spoken without picking it.
+pub fn pick_version(offered: &[u16]) -> Option<u16> {
+    offered.iter().copied().filter(|version| SUPPORTED_VERSIONS.contains(version)).max()
+}
+
+/// An MLS message as carried by a `Frame::Mls` or `Frame::Relay`: an `MLSMessage` (RFC 9420, section 6), whose header
+/// names the protocol version and the wire format of the body that follows. The header is read first, so a message is
+/// only ever deserialized as the one type it says it is.
+#[derive(Debug)]
+pub struct Envelope {
+    /// Protocol version from the header, always `ProtocolVersion::Mls10` once decoded.
+    pub version: ProtocolVersion,
+    pub wire_format: WireFormat,
+    pub body: MlsMessageInBody,
+}
+
+impl Envelope {
+    /// Reads the header of a serialized `MLSMessage`, without deserializing its body.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::InvalidMessage` if `data` is too short to hold a header, an
+    /// `MlsError::UnsupportedVersion` if it's for a protocol version other than the client's, or an
+    /// `MlsError::UnknownWireFormat` if its wire format isn't one of the five MLS defines.
+    pub fn header(data: &[u8]) -> Result<(ProtocolVersion, WireFormat), ApplicationError> {
+        if data.len() < HEADER_LEN {
+            return Err(MlsError::InvalidMessage.into());
+        }
+        let version = u16::from_be_bytes([data[0], data[1]]);
+        if version != PROTOCOL_VERSION as u16 {
+            return Err(MlsError::UnsupportedVersion(version).into());
+        }
+        let wire_format = match u16::from_be_bytes([data[2], data[3]]) {
+            1 => WireFormat::PublicMessage,
+            2 => WireFormat::PrivateMessage,
+            3 => WireFormat::Welcome,
+            4 => WireFormat::GroupInfo,
+            5 => WireFormat::KeyPackage,
+            other => return Err(MlsError::UnknownWireFormat(other).into()),
+        };
+        Ok((PROTOCOL_VERSION, wire_format))
+    }
+
+    /// Decodes a serialized `MLSMessage`, checking its header before its body.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Envelope::header()`, or an `MlsError::InvalidMessage` if the body
+    /// isn't a well-formed message of the wire format the header names, or is followed by anything.
+    pub fn decode(data: &[u8]) -> Result<Envelope, ApplicationError> {
+        let (version, wire_format) = Self::header(data)?;
+        let mut reader = data;
+        let message = MlsMessageIn::tls_deserialize(&mut reader).map_err(|_| MlsError::InvalidMessage)?;
+        if !reader.is_empty() {
+            return Err(MlsError::InvalidMessage.into());
+        }
+        Ok(Envelope { version, wire_format, body: message.extract() })
+    }
+
+    /// Serializes an outgoing MLS message into what `Envelope::decode()` reads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::TlsSerialize` if the message can't be serialized.
+    pub fn encode(message: &MlsMessageOut) -> Result<Vec<u8>, ApplicationError> {
+        message.tls_serialize_detached().map_err(|e| MlsError::TlsSerialize(e.to_string()).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+
+    #[test]
+    fn reads_the_header_before_the_body() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let msg = Envelope::encode(&alice.encrypt_message(b"hi").unwrap()).unwrap();
+        let envelope = Envelope::decode(&msg).unwrap();
+        assert_eq!((envelope.version, envelope.wire_format), (ProtocolVersion::Mls10, WireFormat::PrivateMessage));
+        assert!(matches!(envelope.body, MlsMessageInBody::PrivateMessage(_)));
+        assert_eq!(Channel::of(&Frame::Mls(msg.clone())), Channel::Chat);
+
+        // a bare key package starts with its own version and ciphersuite, which would read as a Welcome's header
+        let key_package = alice.generate_key_package().unwrap();
+        let bare = key_package.tls_serialize_detached().unwrap();
+        assert!(matches!(Envelope::header(&bare), Ok((_, WireFormat::Welcome))));
+        assert!(matches!(Envelope::decode(&bare), Err(ApplicationError::Mls(MlsError::InvalidMessage))));
+        let wrapped = Envelope::encode(&MlsMessageOut::from(key_package)).unwrap();
+        assert!(matches!(Envelope::decode(&wrapped).unwrap().body, MlsMessageInBody::KeyPackage(_)));
+
+        let mut trailing = msg.clone();
+        trailing.push(0);
+        assert!(matches!(Envelope::decode(&trailing), Err(ApplicationError::Mls(MlsError::InvalidMessage))));
+        let mut unknown = msg.clone();
+        unknown[3] = 9;
+        assert!(matches!(Envelope::decode(&unknown), Err(ApplicationError::Mls(MlsError::UnknownWireFormat(9)))));
+        let mut draft = msg;
+        draft[1] = 200;
+        assert!(matches!(Envelope::decode(&draft), Err(ApplicationError::Mls(MlsError::UnsupportedVersion(200)))));
+    }
+}
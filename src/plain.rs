@@ -0,0 +1,105 @@
+use std::{
+    io::{self, BufRead, Write},
+    sync::Arc,
+    thread,
+};
+use crate::{
+    ApplicationError,
+    chat_log::{ChatLog, EntryKind, LogEntry},
+    frontend::{self, ChatView, InputQueue, UserInput},
+};
+
+use tokio::sync::Notify;
+
+/// A line-based view for screen readers, dumb terminals, and piping into other tools. Log entries are printed to
+/// stdout as they're added, and every line read from stdin is handled like a line typed into the chat window.
+/// Reaching the end of stdin quits.
+pub struct PlainView {
+    input: InputQueue,
+    topic: Option<String>,
+}
+
+impl PlainView {
+    /// Builds a new `PlainView`, spawning a thread to read stdin without blocking the `Controller`.
+    pub fn new() -> PlainView {
+        let (tx, input) = frontend::input_queue();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        PlainView {
+            input,
+            topic: None,
+        }
+    }
+}
+
+impl Default for PlainView {
+    fn default() -> Self {
+        PlainView::new()
+    }
+}
+
+impl ChatView for PlainView {
+    /// Entries are printed by `PlainView::push_message()` as they arrive, so there's nothing to redraw.
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.input.poll())
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        let mut stdout = io::stdout().lock();
+        // stdout going away (e.g. a closed pipe) leaves nothing to report the error to
+        let _ = writeln!(stdout, "{}", format_entry(entry)).and_then(|_| stdout.flush());
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn bell(&mut self) {
+        print!("\x07");
+        let _ = io::stdout().flush();
+    }
+
+    fn set_topic(&mut self, topic: Option<String>) {
+        // the topic change is already announced in the log, so only a cleared topic is worth a line
+        if topic.is_none() && self.topic.is_some() {
+            println!("* The topic was cleared.");
+        }
+        self.topic = topic;
+    }
+
+    fn show_keys(&mut self) {
+        println!("* Key bindings only apply to the full-screen interface.");
+    }
+
+    /// There's no status line to show it in, so the notice is printed like a warning, just not kept in the log.
+    fn show_notice(&mut self, notice: &str) {
+        println!("! {}", notice);
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        self.input.ready()
+    }
+}
+
+/// Formats a log entry as the single line `PlainView` prints for it.
+pub fn format_entry(entry: &LogEntry) -> String {
+    match &entry.kind {
+        EntryKind::Chat { number, sender, .. } => {
+            let pending = if entry.failed { " (failed)" } else if entry.pending { " (pending)" } else { "" };
+            format!("#{} [{}] {}: {}{}", number, entry.timestamp(), sender, entry.text, pending)
+        }
+        EntryKind::System => format!("[{}] * {}", entry.timestamp(), entry.text),
+        EntryKind::Warning => format!("[{}] ! {}", entry.timestamp(), entry.text),
+    }
+}
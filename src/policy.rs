@@ -0,0 +1,144 @@
+use crate::{
+    config::{CommitPolicyName, ModerationPolicy},
+    group::CommitSummary,
+};
+
+/// A commit from another member, as it stands before it's merged, for a `CommitPolicy` to judge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProposedCommit<'a> {
+    /// What the commit changes, including who sent it.
+    pub summary: &'a CommitSummary,
+    /// Whether the moderation settings let the committer add and remove members.
+    pub moderator: bool,
+    pub moderation: ModerationPolicy,
+    /// Whether the committer's moderation settings are the same as the user's, or they haven't announced theirs yet.
+    /// See `User::note_moderation()`.
+    pub moderation_agrees: bool,
+    /// Identity of the room's owner, if the room is invite-only. See `User::set_locked_by()`.
+    pub locked_by: Option<&'a str>,
+    /// Identities of the members the commit removes and adds back under a different signature key, other than the
+    /// committer's own. See `Group::swapped_credentials()`.
+    pub swapped: Vec<String>,
+}
+
+impl ProposedCommit<'_> {
+    /// Returns whether the commit adds or removes anyone.
+    pub fn changes_members(&self) -> bool {
+        !self.summary.added.is_empty() || !self.summary.removed.is_empty()
+    }
+}
+
+/// Rules a commit from another member has to pass before it's merged. A refused commit is never merged, so the user
+/// falls out of step with any members who merged it.
+pub trait CommitPolicy: Send {
+    /// Returns why `commit` is refused, worded to follow its committer's name (e.g. "removed members without being a
+    /// moderator"), or None if it may be merged.
+    fn check(&self, commit: &ProposedCommit) -> Option<String>;
+}
+
+/// Refuses only what the moderation settings do: commits adding or removing members from someone who isn't a
+/// moderator, under `ModerationPolicy::Enforce`, or from someone whose settings differ from the user's, since the two
+/// of them would disagree on whether such commits may be merged. Everything else suspicious is merged, and warned
+/// about afterwards.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Permissive;
+
+impl CommitPolicy for Permissive {
+    fn check(&self, commit: &ProposedCommit) -> Option<String> {
+        if commit.changes_members() && !commit.moderation_agrees {
+            return Some(String::from("added or removed members under different moderation settings than yours"));
+        }
+        (commit.changes_members() && !commit.moderator && commit.moderation == ModerationPolicy::Enforce)
+            .then(|| String::from("added or removed members without being a moderator"))
+    }
+}
+
+/// Refuses everything `Permissive` does, and also removals from anyone who isn't a moderator whatever the moderation
+/// policy, additions from anyone but the owner of an invite-only room, and credential swaps.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Strict;
+
+impl CommitPolicy for Strict {
+    fn check(&self, commit: &ProposedCommit) -> Option<String> {
+        if !commit.summary.removed.is_empty() && !commit.moderator {
+            return Some(String::from("removed members without being a moderator"));
+        }
+        if let Some(owner) = commit.locked_by {
+            if !commit.summary.added.is_empty() && commit.summary.committer != owner {
+                return Some(format!("added members to a room only its owner, {}, can add to", owner));
+            }
+        }
+        if !commit.swapped.is_empty() {
+            return Some(format!("replaced the credentials of {}", commit.swapped.join(", ")));
+        }
+        Permissive.check(commit)
+    }
+}
+
+/// Returns the policy `name` selects.
+pub fn named(name: CommitPolicyName) -> Box<dyn CommitPolicy> {
+    match name {
+        CommitPolicyName::Permissive => Box::new(Permissive),
+        CommitPolicyName::Strict => Box::new(Strict),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(committer: &str, added: &[&str], removed: &[&str]) -> CommitSummary {
+        CommitSummary {
+            committer: String::from(committer),
+            epoch: 2,
+            added: added.iter().map(|identity| identity.to_string()).collect(),
+            removed: removed.iter().map(|identity| identity.to_string()).collect(),
+            updated: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn strict_refuses_what_permissive_merges() {
+        let removal = summary("mallory", &[], &["bob"]);
+        let mut commit = ProposedCommit {
+            summary: &removal,
+            moderator: false,
+            moderation: ModerationPolicy::Warn,
+            moderation_agrees: true,
+            locked_by: None,
+            swapped: Vec::new(),
+        };
+        assert_eq!(Permissive.check(&commit), None);
+        assert!(Strict.check(&commit).is_some());
+        commit.moderation = ModerationPolicy::Enforce;
+        assert!(Permissive.check(&commit).is_some());
+
+        let addition = summary("bob", &["carol"], &[]);
+        let mut commit = ProposedCommit {
+            summary: &addition,
+            moderator: true,
+            moderation: ModerationPolicy::Enforce,
+            moderation_agrees: true,
+            locked_by: Some("alice"),
+            swapped: Vec::new(),
+        };
+        assert_eq!(Permissive.check(&commit), None);
+        assert!(Strict.check(&commit).unwrap().contains("alice"));
+        commit.locked_by = None;
+        assert_eq!(Strict.check(&commit), None);
+        commit.moderation_agrees = false;
+        assert!(Permissive.check(&commit).unwrap().contains("moderation settings"));
+
+        let swap = summary("mallory", &["bob"], &["bob"]);
+        let commit = ProposedCommit {
+            summary: &swap,
+            moderator: true,
+            moderation: ModerationPolicy::Open,
+            moderation_agrees: true,
+            locked_by: None,
+            swapped: vec![String::from("bob")],
+        };
+        assert_eq!(Permissive.check(&commit), None);
+        assert_eq!(Strict.check(&commit).unwrap(), "replaced the credentials of bob");
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fs, io::{self, ErrorKind, Write}, mem, path::Path};
+use crate::{ApplicationError, StorageError, PROVIDER, chat_log::LogEntry, message::Payload, user::{User, UserState}};
+
+use openmls_traits::OpenMlsCryptoProvider;
+use serde::{Deserialize, Serialize};
+
+/// What an MLS message the client sent was, kept until the server sequences it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Sent {
+    /// An application message or a Welcome, or a commit that has since been discarded.
+    Message,
+    /// A commit updating the user's own keys.
+    Update,
+    /// A commit adding a member.
+    Add,
+}
+
+/// An MLS message the client sent that the server hasn't sequenced yet.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InFlight {
+    pub sent: Sent,
+    /// The message as it was sent, to recognize it by if the server relays it back to a later connection.
+    pub data: Vec<u8>,
+    /// For a commit adding a member, the Welcome for them, held back until the server sequences the commit so that a
+    /// commit which loses out to another never welcomes anyone into a group nobody else is in.
+    #[serde(default)]
+    pub welcome: Option<Vec<u8>>,
+    /// For a commit adding a member, their key package, to add them again with if the commit loses out.
+    #[serde(default)]
+    pub key_package: Option<Vec<u8>>,
+}
+
+/// Everything a client needs to carry on in its group after a restart, at the same leaf and epoch, instead of being
+/// added back as a new member: the `User`, the key store holding its private keys and group state, and how far
+/// through the server's relay history it had got, so the server can replay whatever was relayed while it was away.
+///
+/// The session holds the user's private keys, so it's only written to a file the user alone can read.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub user: UserState,
+    /// snapshot of the provider's key store, taken right after `User::save()`
+    key_store: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Sequence number of the last relayed message the client handled, or None if it hasn't seen any.
+    pub last_seq: Option<u64>,
+    /// MLS messages sent that the server hadn't sequenced yet, oldest first.
+    pub in_flight: Vec<InFlight>,
+    /// The chat log, oldest entry first, if saving it is on.
+    #[serde(default)]
+    pub log: Vec<LogEntry>,
+    /// Payloads written while disconnected that haven't been sent yet, oldest first.
+    #[serde(default)]
+    pub outbox: Vec<Payload>,
+}
+
+impl Session {
+    /// Captures the current state of `user` along with the provider's key store.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `User::save()`.
+    pub fn capture(user: &mut User, last_seq: Option<u64>, in_flight: Vec<InFlight>) -> Result<Session, ApplicationError> {
+        let user = user.save()?;
+
+        Ok(Session {
+            user,
+            key_store: (*PROVIDER).key_store().snapshot(),
+            last_seq,
+            in_flight,
+            log: Vec::new(),
+            outbox: Vec::new(),
+        })
+    }
+
+    /// Restores the session's key store snapshot into the provider and rebuilds its `User`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `User::resume()`.
+    pub fn resume_user(&mut self) -> Result<User, ApplicationError> {
+        (*PROVIDER).key_store().restore(mem::take(&mut self.key_store));
+        User::resume(self.user.clone())
+    }
+
+    /// Loads the session saved at `path`, or returns None if there isn't one.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if the file exists but can't be read or parsed.
+    pub fn load(path: &Path) -> Result<Option<Session>, ApplicationError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StorageError::Session(format!("{}: {}", path.display(), e)).into()),
+        };
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| StorageError::Session(format!("{}: {}", path.display(), e)).into())
+    }
+
+    /// Saves the session to `path`, creating its parent directories if needed. The session is written to a temporary
+    /// file first and then moved into place, so a crash partway through never leaves a half-written session behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Session` if serializing or writing fails.
+    pub fn save(&self, path: &Path) -> Result<(), ApplicationError> {
+        let error = |e: &dyn std::fmt::Display| ApplicationError::Storage(StorageError::Session(format!("{}: {}", path.display(), e)));
+
+        let contents = serde_json::to_vec(self).map_err(|e| error(&e))?;
+        write_private(path, &contents).map_err(|e| error(&e))
+    }
+}
+
+/// Writes `contents` to `path`, creating its parent directories if needed, readable only by the user on Unix. It's
+/// written to a temporary file beside it first and then moved into place, so a crash partway through leaves whatever
+/// was there before intact. Used for every file holding secrets: the session, identity backups, and a server's rooms.
+///
+/// # Errors
+///
+/// Returns any `io::Error` from creating the directories, or writing or renaming the file.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&temp)?;
+    file.write_all(contents).and_then(|()| file.sync_all())?;
+    fs::rename(&temp, path)
+}
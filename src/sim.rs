@@ -0,0 +1,551 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+use crate::{
+    chat_log::{ChatLog, EntryKind, LogEntry},
+    clock::{Clock, ManualClock},
+    config::ClientConfig,
+    controller::Controller,
+    errors::{ApplicationError, NetworkError},
+    frontend::{ChatView, UserInput},
+    network::{client::Client, frame::Frame, wire},
+};
+
+use chrono::DateTime;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::sync::{mpsc::UnboundedReceiver, Mutex};
+
+/// Most events a simulation handles before it's considered stuck, e.g. in a loop of clients answering each other.
+const MAX_EVENTS: usize = 100_000;
+
+/// How the simulated network treats frames.
+#[derive(Clone, Copy, Debug)]
+pub struct Conditions {
+    /// Shortest and longest time, in milliseconds, a frame takes to arrive. Frames on the same connection arrive in
+    /// the order they were sent, as over TCP, but frames on different connections overtake each other.
+    pub delay: (u64, u64),
+    /// Chance that a relayed message doesn't fit in a connection's queue and is dropped for it, as when the client
+    /// can't keep up. Everything else relayed to it is dropped as well until it catches up, some time later. Clients
+    /// start out with this, and `Simulation::set_loss()` changes it for one.
+    pub loss: f64,
+    /// Number of relayed messages the server keeps to replay to connections that lost some.
+    pub history: usize,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions { delay: (1, 50), loss: 0.0, history: 1024 }
+    }
+}
+
+/// Something that happens at a point in virtual time.
+enum Event {
+    /// A frame from the client at the given index arrives at the server.
+    ToServer(usize, Frame),
+    /// A frame from the server arrives at the client at the given index.
+    ToClient(usize, Frame),
+    /// The user of the client at the given index types a line.
+    Input(usize, String),
+    /// The client at the given index, having lost relayed messages, has room in its queue again.
+    CatchUp(usize),
+}
+
+/// A `ChatView` fed from a queue of lines, which records the text of every chat message pushed to it.
+struct SimView {
+    input: Arc<StdMutex<VecDeque<String>>>,
+    chats: Arc<StdMutex<Vec<String>>>,
+}
+
+impl ChatView for SimView {
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.input.lock().unwrap().pop_front().map(UserInput::Line))
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        if matches!(entry.kind, EntryKind::Chat { .. }) {
+            self.chats.lock().unwrap().push(entry.text.clone());
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+}
+
+/// A `Controller` connected to the simulated server, along with the server's side of its connection.
+struct SimClient {
+    controller: Controller,
+    outgoing: UnboundedReceiver<Frame>,
+    incoming: Arc<Mutex<Vec<Frame>>>,
+    input: Arc<StdMutex<VecDeque<String>>>,
+    chats: Arc<StdMutex<Vec<String>>>,
+    /// chance that a message relayed to the client is dropped; see `Conditions::loss`
+    loss: f64,
+    /// sequence number of the last relayed message the server wrote to the client
+    last_seq: u64,
+    /// relayed messages dropped for the client since it fell behind, if it has
+    missed: u64,
+}
+
+/// A relayed message in the server's history.
+struct Relayed {
+    seq: u64,
+    from: usize,
+    data: Vec<u8>,
+}
+
+/// Key packages a client published, as kept in the server's directory.
+#[derive(Default)]
+struct Pool {
+    packages: VecDeque<Vec<u8>>,
+    last_resort: Option<Vec<u8>>,
+    announced: bool,
+}
+
+/// Runs `Controller`s against a model of the `Server` over a simulated network that delays, reorders, and drops
+/// frames, driven by a `ManualClock` and a seeded random number generator, so any run can be played back exactly.
+/// Every `Controller` reads the simulation's clock, so nothing waits on the real one and their timers fire at the
+/// simulated time. A client waiting to claim a newcomer's key package is woken when it's due, even if nothing else
+/// happens to it.
+/// The server model sequences and relays MLS messages, keeps a history to replay to clients that lost some (or tells
+/// them to resync once it can't), and hands out key packages, like the real one.
+pub struct Simulation {
+    clock: ManualClock,
+    rng: StdRng,
+    conditions: Conditions,
+    /// pending events, by when they happen and then the order they were scheduled in
+    events: BTreeMap<(u64, u64), Event>,
+    scheduled: u64,
+    /// when the last frame sent each way on each connection arrives, keyed by client index and whether it's headed
+    /// for the server, so later frames can't overtake it
+    links: HashMap<(usize, bool), u64>,
+    clients: Vec<SimClient>,
+    history: VecDeque<Relayed>,
+    next_seq: u64,
+    pools: HashMap<usize, Pool>,
+    /// ratchet trees published for Welcomes sent without one, by the key package reference they're for
+    trees: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, conditions: Conditions) -> Simulation {
+        Simulation {
+            clock: ManualClock::new(DateTime::UNIX_EPOCH),
+            rng: StdRng::seed_from_u64(seed),
+            conditions,
+            events: BTreeMap::new(),
+            scheduled: 0,
+            links: HashMap::new(),
+            clients: Vec::new(),
+            history: VecDeque::new(),
+            next_seq: 1,
+            pools: HashMap::new(),
+            trees: HashMap::new(),
+        }
+    }
+
+    /// Returns the simulated time, in milliseconds since the simulation started.
+    pub fn now(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    /// Moves the clock forward to `to`, unless it's already past it.
+    fn advance(&self, to: u64) {
+        self.clock.advance(Duration::from_millis(to.saturating_sub(self.now())));
+    }
+
+    /// Connects a new client for the user `uid`, returning its index.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Controller::with_client()` or `Controller::start()`.
+    pub async fn join(&mut self, uid: &str, config: ClientConfig) -> Result<usize, ApplicationError> {
+        let index = self.clients.len();
+        let (client, outgoing, incoming) = Client::in_memory(format!("simulated server, connection {}", index + 1));
+        let input = Arc::new(StdMutex::new(VecDeque::new()));
+        let chats = Arc::new(StdMutex::new(Vec::new()));
+        let view = SimView { input: Arc::clone(&input), chats: Arc::clone(&chats) };
+
+        let mut controller = Controller::with_client(client, uid.to_string(), config, Box::new(view), Box::new(self.clock.clone()))?;
+        controller.start().await?;
+        self.clients.push(SimClient {
+            controller,
+            outgoing,
+            incoming,
+            input,
+            chats,
+            loss: self.conditions.loss,
+            last_seq: self.next_seq - 1,
+            missed: 0,
+        });
+
+        self.send(index, Frame::Connected { id: index as u64 + 1, versions: wire::SUPPORTED_VERSIONS.to_vec() });
+        self.flush(index);
+        Ok(index)
+    }
+
+    /// Changes the chance that messages relayed to client `client` are dropped.
+    pub fn set_loss(&mut self, client: usize, loss: f64) {
+        self.clients[client].loss = loss;
+    }
+
+    /// Has the user of client `client` type `line` at virtual time `at`.
+    pub fn type_at(&mut self, client: usize, at: u64, line: &str) {
+        self.schedule(at, Event::Input(client, line.to_string()));
+    }
+
+    /// Returns the `Controller` of client `client`.
+    pub fn controller(&self, client: usize) -> &Controller {
+        &self.clients[client].controller
+    }
+
+    /// Returns the text of every chat message client `client` has shown, in the order it showed them.
+    pub fn chats(&self, client: usize) -> Vec<String> {
+        self.clients[client].chats.lock().unwrap().clone()
+    }
+
+    /// Runs until nothing is left to happen.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Simulation::run_until()`.
+    pub async fn settle(&mut self) -> Result<(), ApplicationError> {
+        self.run_until(u64::MAX).await
+    }
+
+    /// Runs everything due to happen up to virtual time `until`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` a client's `Controller` returns, or a `NetworkError::Io` if events
+    /// keep coming past `MAX_EVENTS`.
+    pub async fn run_until(&mut self, until: u64) -> Result<(), ApplicationError> {
+        for _ in 0..MAX_EVENTS {
+            let next = self.events.first_key_value().map(|(key, _)| key.0);
+            if let Some((at, client)) = self.next_claim().filter(|(at, _)| *at <= until && next.is_none_or(|next| *at < next)) {
+                self.advance(at);
+                self.tick(client).await?;
+                continue;
+            }
+
+            let Some(entry) = self.events.first_entry().filter(|entry| entry.key().0 <= until) else {
+                // time still passes when nothing happens, unless nothing's left to wait for
+                if !self.events.is_empty() {
+                    self.advance(until);
+                }
+                return Ok(());
+            };
+            let ((at, _), event) = entry.remove_entry();
+            self.advance(at);
+            match event {
+                Event::ToServer(client, frame) => self.serve(client, frame),
+                Event::ToClient(client, frame) => {
+                    self.clients[client].incoming.lock().await.push(frame);
+                    self.tick(client).await?;
+                }
+                Event::Input(client, line) => {
+                    self.clients[client].input.lock().unwrap().push_back(line);
+                    self.tick(client).await?;
+                }
+                Event::CatchUp(client) => self.catch_up(client),
+            }
+        }
+        Err(NetworkError::Io.into())
+    }
+
+    /// Asserts that every client is in the same epoch of the same group, with every one of them in it.
+    pub fn assert_converged(&self) {
+        let first = self.clients[0].controller.user();
+        for client in &self.clients {
+            let user = client.controller.user();
+            assert_eq!(user.epoch(), first.epoch(), "{} is in a different epoch", user.get_id());
+            assert_eq!(user.verification_code(), first.verification_code(), "{} is in a different group", user.get_id());
+            for other in &self.clients {
+                let id = other.controller.user().get_id();
+                assert!(user.roster().iter().any(|member| member.identity == *id), "{} doesn't have {}", user.get_id(), id);
+            }
+        }
+    }
+
+    /// Returns when the next client is due to claim a newcomer's key package, in virtual time, and which client it is.
+    fn next_claim(&self) -> Option<(u64, usize)> {
+        let now = self.clock.now();
+        self.clients.iter()
+            .enumerate()
+            .filter_map(|(index, client)| client.controller.next_claim().map(|due| (due, index)))
+            .min()
+            .map(|(due, index)| (self.now() + due.saturating_duration_since(now).as_millis() as u64, index))
+    }
+
+    fn schedule(&mut self, at: u64, event: Event) {
+        self.events.insert((at, self.scheduled), event);
+        self.scheduled += 1;
+    }
+
+    /// Sends a frame over the connection of client `client`, to arrive after a random delay, but not before anything
+    /// sent the same way before it.
+    fn transmit(&mut self, client: usize, to_server: bool, frame: Frame) {
+        let (min, max) = self.conditions.delay;
+        let arrival = self.now() + self.rng.gen_range(min..=max);
+        let link = self.links.entry((client, to_server)).or_default();
+        let at = arrival.max(*link);
+        *link = at;
+
+        let event = if to_server { Event::ToServer(client, frame) } else { Event::ToClient(client, frame) };
+        self.schedule(at, event);
+    }
+
+    /// Sends a frame from the server to client `client`.
+    fn send(&mut self, client: usize, frame: Frame) {
+        self.transmit(client, false, frame);
+    }
+
+    /// Goes once through the main loop of client `client`, and again for as long as it has input waiting, then sends
+    /// everything it queued.
+    async fn tick(&mut self, client: usize) -> Result<(), ApplicationError> {
+        loop {
+            self.clients[client].controller.tick().await?;
+            if self.clients[client].input.lock().unwrap().is_empty() {
+                break;
+            }
+        }
+        self.flush(client);
+        Ok(())
+    }
+
+    fn flush(&mut self, client: usize) {
+        while let Ok(frame) = self.clients[client].outgoing.try_recv() {
+            self.transmit(client, true, frame);
+        }
+    }
+
+    /// Handles a frame arriving at the server from client `client`.
+    fn serve(&mut self, client: usize, frame: Frame) {
+        let id = client as u64 + 1;
+        match frame {
+            Frame::Mls(data) => {
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                self.history.push_back(Relayed { seq, from: client, data: data.clone() });
+                while self.history.len() > self.conditions.history {
+                    self.history.pop_front();
+                }
+                for other in 0..self.clients.len() {
+                    self.relay(other, seq, client, &data);
+                }
+            }
+            Frame::PublishKeyPackage { last_resort, data } => {
+                if last_resort {
+                    self.pools.insert(client, Pool { last_resort: Some(data), ..Pool::default() });
+                    return;
+                }
+                let pool = self.pools.entry(client).or_default();
+                pool.packages.push_back(data);
+                if !std::mem::replace(&mut pool.announced, true) {
+                    for other in (0..self.clients.len()).filter(|&other| other != client) {
+                        self.send(other, Frame::KeyPackagesAvailable { owner: id });
+                    }
+                }
+            }
+            Frame::ClaimKeyPackage { owner } => {
+                let Some(pool) = self.pools.get_mut(&(owner as usize - 1)) else { return };
+                let claimed = match pool.packages.pop_front() {
+                    Some(data) => Some((data, pool.packages.len())),
+                    None => pool.last_resort.clone().map(|data| (data, 0)),
+                };
+                if let Some((data, remaining)) = claimed {
+                    self.send(client, Frame::KeyPackage { owner, data });
+                    self.send(owner as usize - 1, Frame::KeyPackageClaimed { remaining: remaining as u64 });
+                }
+            }
+            Frame::PublishRatchetTree { key_package, tree } => {
+                self.trees.insert(key_package, tree);
+            }
+            Frame::FetchRatchetTree { key_package } => {
+                let tree = self.trees.get(&key_package).cloned().unwrap_or_default();
+                self.send(client, Frame::RatchetTree { key_package, tree });
+            }
+            _ => (),
+        }
+    }
+
+    /// Queues relayed message `seq` from client `from` for client `client`, unless it's behind or the message is lost,
+    /// in which case it's dropped until the client catches up.
+    fn relay(&mut self, client: usize, seq: u64, from: usize, data: &[u8]) {
+        if self.clients[client].missed > 0 {
+            self.clients[client].missed += 1;
+            return;
+        }
+        if self.rng.gen_bool(self.clients[client].loss) {
+            self.clients[client].missed = 1;
+            let (min, max) = self.conditions.delay;
+            let at = self.now() + self.rng.gen_range(min..=max) * 4;
+            self.schedule(at, Event::CatchUp(client));
+            return;
+        }
+
+        self.clients[client].last_seq = seq;
+        let frame = if from == client { Frame::Sequenced { seq } } else { Frame::Relay { seq, data: data.to_vec() } };
+        self.send(client, frame);
+    }
+
+    /// Tells client `client` how many relayed messages it lost and replays them from the history, or tells it to
+    /// resync if they're no longer all there.
+    fn catch_up(&mut self, client: usize) {
+        let missed = std::mem::take(&mut self.clients[client].missed);
+        self.send(client, Frame::Lagged { missed });
+
+        let after = self.clients[client].last_seq;
+        if self.history.front().is_some_and(|msg| msg.seq > after + 1) {
+            self.clients[client].last_seq = self.next_seq - 1;
+            self.send(client, Frame::Resync);
+            return;
+        }
+
+        let replay: Vec<Frame> = self.history.iter()
+            .filter(|msg| msg.seq > after)
+            .map(|msg| if msg.from == client {
+                Frame::Sequenced { seq: msg.seq }
+            } else {
+                Frame::Relay { seq: msg.seq, data: msg.data.clone() }
+            })
+            .collect();
+        self.clients[client].last_seq = self.next_seq - 1;
+        for frame in replay {
+            self.send(client, frame);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModerationConfig, ModerationPolicy};
+
+    /// Joins `users` one after another, letting each be added before the next arrives. Only the first adds anyone,
+    /// so that there's never more than one commit adding the same user.
+    async fn join_all(sim: &mut Simulation, users: &[&str]) {
+        for user in users {
+            let moderation = ModerationConfig { policy: ModerationPolicy::Warn, moderators: vec![users[0].to_string()] };
+            sim.join(user, ClientConfig { moderation, ..ClientConfig::default() }).await.unwrap();
+            sim.settle().await.unwrap();
+        }
+        sim.assert_converged();
+    }
+
+    /// Has every client send `count` messages at random times over the next `window` milliseconds, returning them.
+    fn chatter(sim: &mut Simulation, seed: u64, count: usize, window: u64) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut sent = vec![];
+        for client in 0..sim.clients.len() {
+            for i in 0..count {
+                let text = format!("message {} from {}", i, sim.controller(client).user().get_id());
+                let at = sim.now() + rng.gen_range(0..window);
+                sim.type_at(client, at, &text);
+                sent.push(text);
+            }
+        }
+        sent.sort();
+        sent
+    }
+
+    fn assert_saw_everything(sim: &Simulation, sent: &[String]) {
+        for client in 0..sim.clients.len() {
+            let mut chats = sim.chats(client);
+            chats.sort();
+            assert_eq!(chats, sent, "{} missed messages", sim.controller(client).user().get_id());
+        }
+    }
+
+    #[tokio::test]
+    async fn converges_despite_reordering() {
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, Conditions { delay: (1, 80), ..Conditions::default() });
+            join_all(&mut sim, &["alice", "bob", "carol"]).await;
+
+            // every message is followed by a key update, so the members' commits keep racing each other
+            let sent = chatter(&mut sim, seed, 3, 200);
+            sim.settle().await.unwrap();
+            sim.assert_converged();
+            assert_saw_everything(&sim, &sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn joins_with_a_fetched_ratchet_tree() {
+        let mut sim = Simulation::new(0, Conditions { delay: (1, 80), ..Conditions::default() });
+        for user in ["alice", "bob", "carol"] {
+            let mut config = ClientConfig::default();
+            config.tuning.ratchet_tree_in_welcome = false;
+            sim.join(user, config).await.unwrap();
+            sim.settle().await.unwrap();
+        }
+        sim.assert_converged();
+        assert_eq!(sim.controller(0).user().roster().len(), 3);
+
+        let sent = chatter(&mut sim, 0, 2, 200);
+        sim.settle().await.unwrap();
+        assert_saw_everything(&sim, &sent);
+    }
+
+    #[tokio::test]
+    async fn one_member_adds_each_newcomer() {
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, Conditions { delay: (1, 80), ..Conditions::default() });
+            // anyone may add members, so all three are told about each newcomer at once
+            for user in ["alice", "bob", "carol"] {
+                sim.join(user, ClientConfig::default()).await.unwrap();
+                sim.settle().await.unwrap();
+            }
+            sim.assert_converged();
+
+            // the members' key updates keep racing the commits adding the newcomers
+            chatter(&mut sim, seed, 3, 200);
+            sim.join("dave", ClientConfig::default()).await.unwrap();
+            sim.join("erin", ClientConfig::default()).await.unwrap();
+            sim.settle().await.unwrap();
+            sim.assert_converged();
+            assert_eq!(sim.controller(0).user().roster().len(), 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_lost_messages_from_history() {
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, Conditions::default());
+            join_all(&mut sim, &["alice", "bob", "carol"]).await;
+
+            for client in 0..3 {
+                sim.set_loss(client, 0.2);
+            }
+            let sent = chatter(&mut sim, seed, 3, 200);
+            sim.settle().await.unwrap();
+            sim.assert_converged();
+            assert_saw_everything(&sim, &sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejoins_once_history_is_gone() {
+        for seed in 0..4 {
+            let mut sim = Simulation::new(seed, Conditions::default());
+            join_all(&mut sim, &["alice", "bob"]).await;
+
+            // anything bob loses is dropped from the history before he catches up
+            sim.conditions.history = 1;
+            sim.set_loss(1, 0.5);
+            chatter(&mut sim, seed, 3, 200);
+            sim.run_until(sim.now() + 400).await.unwrap();
+            sim.set_loss(1, 0.0);
+            sim.settle().await.unwrap();
+            sim.assert_converged();
+        }
+    }
+}
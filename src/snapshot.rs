@@ -0,0 +1,153 @@
+use std::{fs, io::Write, path::Path};
+use crate::{
+    errors::{ApplicationError, StorageError},
+    group::{Group, TreeNode},
+};
+
+use serde::Serialize;
+
+/// The public state of the user's group at one point, written as JSON by `/dump-state` to attach to bug reports.
+/// Nothing in it is secret: there are no keys other than the members' public signature keys, and none of the chat.
+/// Comparing the dumps of two members in the same epoch shows where their views of the group diverged.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GroupSnapshot {
+    /// Group ID, in hex.
+    pub group_id: String,
+    pub epoch: u64,
+    /// Hash of the ratchet tree, in hex, from `Group::tree_hash()`. Members in the same epoch have the same one unless
+    /// they've diverged.
+    pub tree_hash: String,
+    /// The user's own leaf index.
+    pub own_index: u32,
+    /// Whether one of the user's own commits is waiting for the server to sequence it.
+    pub pending_commit: bool,
+    /// Whether Welcomes into the group include its ratchet tree.
+    pub ratchet_tree_in_welcome: bool,
+    pub members: Vec<SnapshotMember>,
+    /// Proposals received but not yet committed.
+    pub pending_proposals: Vec<SnapshotProposal>,
+    /// Every node of the ratchet tree, blank ones included, as `/tree` lists them.
+    pub tree: Vec<TreeNode>,
+}
+
+/// A member of the group, as listed in a `GroupSnapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotMember {
+    pub index: u32,
+    /// Identity from the member's credential.
+    pub identity: String,
+    /// `Profile::user_id` from the member's leaf, if they published one.
+    pub user_id: Option<String>,
+    /// Public key the member signs with, in hex.
+    pub signature_key: String,
+}
+
+/// A proposal waiting to be committed, as listed in a `GroupSnapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotProposal {
+    /// What the proposal does, e.g. "Add" or "Remove".
+    pub proposal_type: String,
+    /// Leaf index of the member who sent it, or None if it came from outside the group.
+    pub sender: Option<u32>,
+}
+
+impl GroupSnapshot {
+    /// Takes a snapshot of `group` as it is now.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Group::tree_hash()`.
+    pub fn of(group: &Group) -> Result<GroupSnapshot, ApplicationError> {
+        Ok(GroupSnapshot {
+            group_id: hex(group.group_id().as_slice()),
+            epoch: group.epoch(),
+            tree_hash: hex(&group.tree_hash()?),
+            own_index: group.own_index(),
+            pending_commit: group.has_pending_commit(),
+            ratchet_tree_in_welcome: group.includes_ratchet_tree(),
+            members: group.roster().into_iter()
+                .map(|member| SnapshotMember {
+                    index: member.index,
+                    identity: member.identity,
+                    user_id: member.profile.map(|profile| profile.user_id),
+                    signature_key: hex(&member.signature_key),
+                })
+                .collect(),
+            pending_proposals: group.pending_proposals().into_iter()
+                .map(|(proposal_type, sender)| SnapshotProposal { proposal_type, sender })
+                .collect(),
+            tree: group.tree(),
+        })
+    }
+
+    /// Replaces each member's identity with `member-<leaf index>`, and drops their user IDs, so the snapshot can be
+    /// shared without naming anyone. Signature keys are kept, since they're what tells members apart when comparing
+    /// dumps, though anyone who already knows a member's key can still pick them out.
+    pub fn redact(&mut self) {
+        for member in &mut self.members {
+            member.identity = format!("member-{}", member.index);
+            member.user_id = None;
+        }
+        for node in &mut self.tree {
+            if let TreeNode::Leaf { index, identity, .. } = node {
+                *identity = format!("member-{}", index);
+            }
+        }
+    }
+
+    /// Writes the snapshot to `path` as indented JSON. Like `transcript::export()`, it won't replace a file that's
+    /// already there, and only the user can read the file it creates.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Export` if the file already exists or can't be written.
+    pub fn write(&self, path: &Path) -> Result<(), ApplicationError> {
+        let error = |e: &dyn std::fmt::Display| ApplicationError::Storage(StorageError::Export(format!("{}: {}", path.display(), e)));
+
+        let mut json = serde_json::to_string_pretty(self).map_err(|e| error(&e))?;
+        json.push('\n');
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => ApplicationError::Storage(StorageError::Export(format!("{} already exists", path.display()))),
+            _ => error(&e),
+        })?;
+        file.write_all(json.as_bytes()).map_err(|e| error(&e))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+
+    #[test]
+    fn redacts_identities() {
+        let user = User::build(String::from("alice")).unwrap();
+        let mut snapshot = user.snapshot().unwrap().unwrap();
+        assert_eq!(snapshot.members.len(), 1);
+        assert_eq!(snapshot.members[0].identity, "alice");
+        assert_eq!(snapshot.tree_hash.len(), 64);
+
+        snapshot.redact();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(!json.contains("alice"), "{}", json);
+        assert!(json.contains(r#""identity":"member-0""#), "{}", json);
+
+        let path = std::env::temp_dir().join(format!("mls_chat_snapshot_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        snapshot.write(&path).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains(&snapshot.tree_hash));
+        assert!(snapshot.write(&path).is_err(), "replaced a file that was already there");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use openmls_rust_crypto::{MemoryKeyStoreError, RustCrypto};
+use openmls_traits::{key_store::{MlsEntity, OpenMlsKeyStore}, OpenMlsCryptoProvider};
+
+/// The crypto provider every group and key package is built with: openmls's default crypto, and a `KeyStore` that
+/// can be saved and restored along with the rest of a session.
+#[derive(Debug, Default)]
+pub struct Provider {
+    crypto: RustCrypto,
+    key_store: KeyStore,
+}
+
+impl OpenMlsCryptoProvider for Provider {
+    type CryptoProvider = RustCrypto;
+    type RandProvider = RustCrypto;
+    type KeyStoreProvider = KeyStore;
+
+    fn crypto(&self) -> &Self::CryptoProvider {
+        &self.crypto
+    }
+
+    fn rand(&self) -> &Self::RandProvider {
+        &self.crypto
+    }
+
+    fn key_store(&self) -> &Self::KeyStoreProvider {
+        &self.key_store
+    }
+}
+
+/// An in-memory key store, like openmls's `MemoryKeyStore`, but whose contents can be copied out and put back. It
+/// holds the private keys behind the user's key packages, signatures, and leaf, as well as the group state itself
+/// once `MlsGroup::save()` is called, so restoring a snapshot brings back everything needed to carry on in a group.
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    values: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KeyStore {
+    /// Returns a copy of every entry in the store.
+    pub fn snapshot(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.values.read().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Adds the entries from a snapshot to the store, replacing any already stored under the same keys.
+    pub fn restore(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) {
+        self.values.write().unwrap().extend(entries);
+    }
+
+    /// Removes every entry from the store.
+    pub fn clear(&self) {
+        self.values.write().unwrap().clear();
+    }
+}
+
+impl OpenMlsKeyStore for KeyStore {
+    type Error = MemoryKeyStoreError;
+
+    fn store<V: MlsEntity>(&self, k: &[u8], v: &V) -> Result<(), Self::Error> {
+        let value = serde_json::to_vec(v).map_err(|_| MemoryKeyStoreError::SerializationError)?;
+        // the lock is only ever held briefly, by code that can't panic while holding it
+        self.values.write().unwrap().insert(k.to_vec(), value);
+        Ok(())
+    }
+
+    fn read<V: MlsEntity>(&self, k: &[u8]) -> Option<V> {
+        self.values.read().unwrap().get(k).and_then(|value| serde_json::from_slice(value).ok())
+    }
+
+    fn delete<V: MlsEntity>(&self, k: &[u8]) -> Result<(), Self::Error> {
+        self.values.write().unwrap().remove(k);
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::str::FromStr;
+
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+
+/// The built-in color themes, chosen with `theme` in the config file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeName {
+    #[default]
+    #[serde(alias = "default")]
+    Dark,
+    Light,
+    Solarized,
+}
+
+/// A color from the config file's `[colors]` table, written as a name (`"red"`, `"lightblue"`), a hex code
+/// (`"#268bd2"`), or a 256-color index (`"33"`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ThemeColor(pub Color);
+
+impl TryFrom<String> for ThemeColor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Color::from_str(&s)
+            .map(ThemeColor)
+            .map_err(|_| format!("unknown color \"{}\"", s))
+    }
+}
+
+/// User-defined colors that override parts of the chosen theme. Each sets the foreground of its element, except
+/// the search and selection highlights, which set the background.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeColors {
+    pub own_message: Option<ThemeColor>,
+    pub mention: Option<ThemeColor>,
+    pub system: Option<ThemeColor>,
+    pub error: Option<ThemeColor>,
+    pub status: Option<ThemeColor>,
+    pub input: Option<ThemeColor>,
+    pub search_match: Option<ThemeColor>,
+    pub current_match: Option<ThemeColor>,
+    pub unread: Option<ThemeColor>,
+    pub online: Option<ThemeColor>,
+    pub away: Option<ThemeColor>,
+    pub selection: Option<ThemeColor>,
+    pub code: Option<ThemeColor>,
+}
+
+/// The styles `ChatWindow` draws with.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    /// the local user's name on their own messages
+    pub own_message: Style,
+    /// lines that mention the local user
+    pub mention: Style,
+    /// notices from the application
+    pub system: Style,
+    /// errors, security warnings, and the connection state once disconnected
+    pub error: Style,
+    /// the status line
+    pub status: Style,
+    /// the input box while typing
+    pub input: Style,
+    /// matches of the current search
+    pub search_match: Style,
+    /// the match last jumped to
+    pub current_match: Style,
+    /// the unread divider and new message count
+    pub unread: Style,
+    /// presence markers and the connection state while connected
+    pub online: Style,
+    /// presence markers of members who are away
+    pub away: Style,
+    /// the entry selected for copying
+    pub selection: Style,
+    /// `code` spans in messages
+    pub code: Style,
+}
+
+impl Theme {
+    /// Builds the named theme, with any user-defined colors applied on top.
+    pub fn new(name: ThemeName, colors: &ThemeColors) -> Theme {
+        let mut theme = match name {
+            ThemeName::Dark => Theme::dark(),
+            ThemeName::Light => Theme::light(),
+            ThemeName::Solarized => Theme::solarized(),
+        };
+
+        let fg = |style: &mut Style, color: Option<ThemeColor>| {
+            if let Some(ThemeColor(color)) = color { *style = style.fg(color) }
+        };
+        fg(&mut theme.own_message, colors.own_message);
+        fg(&mut theme.mention, colors.mention);
+        fg(&mut theme.system, colors.system);
+        fg(&mut theme.error, colors.error);
+        fg(&mut theme.status, colors.status);
+        fg(&mut theme.input, colors.input);
+        fg(&mut theme.unread, colors.unread);
+        fg(&mut theme.online, colors.online);
+        fg(&mut theme.away, colors.away);
+        fg(&mut theme.code, colors.code);
+
+        let bg = |style: &mut Style, color: Option<ThemeColor>| {
+            if let Some(ThemeColor(color)) = color { *style = style.bg(color).remove_modifier(Modifier::REVERSED) }
+        };
+        bg(&mut theme.search_match, colors.search_match);
+        bg(&mut theme.current_match, colors.current_match);
+        bg(&mut theme.selection, colors.selection);
+
+        theme
+    }
+
+    fn dark() -> Theme {
+        Theme {
+            own_message: Style::default().fg(Color::Cyan),
+            mention: Style::default().fg(Color::Yellow),
+            system: Style::default().fg(Color::DarkGray),
+            error: Style::default().fg(Color::Red),
+            status: Style::default().add_modifier(Modifier::DIM),
+            input: Style::default().fg(Color::Yellow),
+            search_match: Style::default().add_modifier(Modifier::REVERSED),
+            current_match: Style::default().fg(Color::Black).bg(Color::Yellow),
+            unread: Style::default().fg(Color::Red),
+            online: Style::default().fg(Color::Green),
+            away: Style::default().fg(Color::Yellow),
+            selection: Style::default().bg(Color::DarkGray),
+            code: Style::default().fg(Color::LightGreen),
+        }
+    }
+
+    fn light() -> Theme {
+        Theme {
+            own_message: Style::default().fg(Color::Blue),
+            mention: Style::default().fg(Color::Magenta),
+            system: Style::default().fg(Color::Gray),
+            error: Style::default().fg(Color::Red),
+            status: Style::default().fg(Color::Gray),
+            input: Style::default().fg(Color::Blue),
+            search_match: Style::default().bg(Color::LightCyan),
+            current_match: Style::default().bg(Color::LightYellow),
+            unread: Style::default().fg(Color::Red),
+            online: Style::default().fg(Color::Green),
+            away: Style::default().fg(Color::Rgb(0xb0, 0x80, 0x00)),
+            selection: Style::default().bg(Color::Gray),
+            code: Style::default().fg(Color::Green),
+        }
+    }
+
+    fn solarized() -> Theme {
+        const BASE01: Color = Color::Rgb(0x58, 0x6e, 0x75);
+        const BASE02: Color = Color::Rgb(0x07, 0x36, 0x42);
+        const BASE03: Color = Color::Rgb(0x00, 0x2b, 0x36);
+        const YELLOW: Color = Color::Rgb(0xb5, 0x89, 0x00);
+        const ORANGE: Color = Color::Rgb(0xcb, 0x4b, 0x16);
+        const RED: Color = Color::Rgb(0xdc, 0x32, 0x2f);
+        const MAGENTA: Color = Color::Rgb(0xd3, 0x36, 0x82);
+        const BLUE: Color = Color::Rgb(0x26, 0x8b, 0xd2);
+        const CYAN: Color = Color::Rgb(0x2a, 0xa1, 0x98);
+        const GREEN: Color = Color::Rgb(0x85, 0x99, 0x00);
+
+        Theme {
+            own_message: Style::default().fg(BLUE),
+            mention: Style::default().fg(YELLOW),
+            system: Style::default().fg(BASE01),
+            error: Style::default().fg(RED),
+            status: Style::default().fg(BASE01),
+            input: Style::default().fg(CYAN),
+            search_match: Style::default().fg(BASE03).bg(CYAN),
+            current_match: Style::default().fg(BASE03).bg(YELLOW),
+            unread: Style::default().fg(ORANGE),
+            online: Style::default().fg(GREEN),
+            away: Style::default().fg(YELLOW),
+            selection: Style::default().bg(BASE02),
+            code: Style::default().fg(MAGENTA),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::dark()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_override_theme() {
+        let colors = ThemeColors {
+            mention: Some(ThemeColor(Color::Rgb(0x12, 0x34, 0x56))),
+            search_match: Some(ThemeColor(Color::Blue)),
+            ..ThemeColors::default()
+        };
+        let theme = Theme::new(ThemeName::Dark, &colors);
+
+        assert_eq!(theme.mention.fg, Some(Color::Rgb(0x12, 0x34, 0x56)));
+        assert_eq!(theme.search_match.bg, Some(Color::Blue));
+        assert!(!theme.search_match.add_modifier.contains(Modifier::REVERSED));
+        assert_eq!(theme.system, Theme::dark().system);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fmt, fs::{self, File, OpenOptions}, io::Write, path::Path};
+use crate::{network::wire::Envelope, ApplicationError, StorageError};
+
+use chrono::{SecondsFormat, Utc};
+use openmls::prelude::*;
+
+/// A trace of the MLS messages the client sends and receives, and of what it decides to do with each one it
+/// receives, written to a local file with `join --debug-mls` so that protocol bugs can be diagnosed after the fact.
+/// Each line is a UTC timestamp, `in`, `out` or `decision`, and details as `key=value` pairs. Only what can be read
+/// without decrypting a message is written, never its content.
+pub struct MlsTrace {
+    /// None if tracing is turned off
+    file: Option<File>,
+}
+
+/// What can be told about a serialized MLS message without decrypting it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Description {
+    /// The kind of message, e.g. "private_commit" or "welcome".
+    pub kind: &'static str,
+    pub epoch: Option<u64>,
+    /// Leaf index of the sender, or None if it's encrypted or the message has none.
+    pub sender: Option<u32>,
+    pub size: usize,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "type={}", self.kind)?;
+        if let Some(epoch) = self.epoch {
+            write!(f, " epoch={}", epoch)?;
+        }
+        match self.sender {
+            Some(sender) => write!(f, " sender={}", sender)?,
+            None if self.kind.starts_with("private_") => write!(f, " sender=encrypted")?,
+            None => (),
+        }
+        write!(f, " size={}", self.size)
+    }
+}
+
+impl MlsTrace {
+    /// Opens the trace at `path` for appending, creating it and its parent directories if needed. With no path,
+    /// returns a trace that records nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `StorageError::Config` if the file can't be opened.
+    pub fn open(path: Option<&Path>) -> Result<MlsTrace, ApplicationError> {
+        let Some(path) = path else { return Ok(MlsTrace::disabled()) };
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| StorageError::Config(format!("{}: {}", parent.display(), e)))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| StorageError::Config(format!("{}: {}", path.display(), e)))?;
+
+        Ok(MlsTrace { file: Some(file) })
+    }
+
+    /// Returns a trace that records nothing.
+    pub fn disabled() -> MlsTrace {
+        MlsTrace { file: None }
+    }
+
+    /// Records an MLS message relayed by the server with sequence number `seq`.
+    pub fn inbound(&mut self, seq: u64, data: &[u8]) {
+        if self.file.is_some() {
+            self.write("in", &format!("seq={} {}", seq, describe(data)));
+        }
+    }
+
+    /// Records an MLS message the user sent from leaf `own_index`, which is its sender when that's encrypted.
+    pub fn outbound(&mut self, data: &[u8], own_index: Option<u32>) {
+        if self.file.is_some() {
+            let mut description = describe(data);
+            if description.kind.starts_with("private_") {
+                description.sender = own_index;
+            }
+            self.write("out", &description.to_string());
+        }
+    }
+
+    /// Records what the client decided to do with the last message received. `details` is written as is, and should
+    /// already be in `key=value` form.
+    pub fn decision(&mut self, decision: &str, details: &str) {
+        let line = if details.is_empty() { decision.to_string() } else { format!("{} {}", decision, details) };
+        self.write("decision", &line);
+    }
+
+    fn write(&mut self, direction: &str, details: &str) {
+        let Some(file) = &mut self.file else { return };
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        // a debugging aid failing to write isn't worth interrupting the chat over
+        let _ = file.write_all(format!("{} {} {}\n", time, direction, details).as_bytes());
+    }
+}
+
+/// Describes a serialized MLS message by what can be read of it without decrypting it.
+pub fn describe(data: &[u8]) -> Description {
+    let (kind, epoch, sender) = match Envelope::decode(data).map(|envelope| envelope.body) {
+        Ok(MlsMessageInBody::PublicMessage(msg)) => {
+            let sender = match msg.sender() {
+                Sender::Member(index) => Some(index.u32()),
+                _ => None,
+            };
+            let kind = match msg.content_type() {
+                ContentType::Application => "public_application",
+                ContentType::Proposal => "public_proposal",
+                ContentType::Commit => "public_commit",
+            };
+            (kind, Some(msg.epoch().as_u64()), sender)
+        }
+        Ok(MlsMessageInBody::PrivateMessage(msg)) => {
+            let msg = ProtocolMessage::from(msg);
+            let kind = match msg.content_type() {
+                ContentType::Application => "private_application",
+                ContentType::Proposal => "private_proposal",
+                ContentType::Commit => "private_commit",
+            };
+            (kind, Some(msg.epoch().as_u64()), None)
+        }
+        Ok(MlsMessageInBody::Welcome(_)) => ("welcome", None, None),
+        Ok(MlsMessageInBody::GroupInfo(_)) => ("group_info", None, None),
+        Ok(MlsMessageInBody::KeyPackage(_)) => ("key_package", None, None),
+        Err(_) => ("unreadable", None, None),
+    };
+    Description { kind, epoch, sender, size: data.len() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+
+    #[test]
+    fn describes_messages_without_decrypting_them() {
+        let mut alice = User::build(String::from("alice")).unwrap();
+        let msg = alice.encrypt_message(b"secret").unwrap().tls_serialize_detached().unwrap();
+        let description = describe(&msg);
+        assert_eq!(description, Description { kind: "private_application", epoch: Some(0), sender: None, size: msg.len() });
+        assert_eq!(description.to_string(), format!("type=private_application epoch=0 sender=encrypted size={}", msg.len()));
+
+        let key_package = MlsMessageOut::from(alice.generate_key_package().unwrap()).tls_serialize_detached().unwrap();
+        assert_eq!(describe(&key_package).kind, "key_package");
+        assert_eq!(describe(&[0xff; 8]).to_string(), "type=unreadable size=8");
+
+        let path = std::env::temp_dir().join(format!("mls_chat_trace_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut trace = MlsTrace::open(Some(&path)).unwrap();
+        trace.outbound(&msg, Some(0));
+        trace.decision("dropped", "reason=duplicate epoch=0");
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].ends_with(&format!(" out type=private_application epoch=0 sender=0 size={}", msg.len())), "{}", contents);
+        assert!(lines[1].ends_with(" decision dropped reason=duplicate epoch=0"), "{}", contents);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt::Write as _, fs, io::Write, path::Path, str::FromStr};
+use crate::{ApplicationError, StorageError, chat_log::{EntryKind, LogEntry}};
+
+use chrono::{DateTime, SecondsFormat};
+use serde::Serialize;
+
+/// What `/export` and `mls_chat export-log` write the chat log as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// An array of objects, one per entry, for other tools to read.
+    Json,
+    /// A list with each sender in bold, for pasting into notes or meeting minutes.
+    Markdown,
+    /// One line per entry, as the chat window shows them but with the full date.
+    Text,
+}
+
+impl Format {
+    /// Picks the format from the extension of `path`: `.json`, `.md` or `.markdown`, or plain text for anything else.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Format::Json,
+            Some("md" | "markdown") => Format::Markdown,
+            _ => Format::Text,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "text" | "txt" => Ok(Format::Text),
+            _ => Err(format!("unknown transcript format \"{}\": use json, markdown, or text", s)),
+        }
+    }
+}
+
+/// An entry as it's written to a JSON transcript.
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<&'a str>,
+    text: &'a str,
+    #[serde(skip_serializing_if = "is_false")]
+    edited: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    deleted: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<(&'a str, usize)>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Renders `entries` as a transcript in the given format. Deleted messages are kept as tombstones, so the transcript
+/// shows that something was said, and reactions are summarized by count.
+pub fn render<'a>(entries: impl IntoIterator<Item = &'a LogEntry>, format: Format) -> String {
+    let lines = entries.into_iter().map(|entry| Line {
+        time: DateTime::from_timestamp(entry.time, 0).unwrap_or_default().to_rfc3339_opts(SecondsFormat::Secs, true),
+        kind: match entry.kind {
+            EntryKind::Chat { .. } => "chat",
+            EntryKind::System => "system",
+            EntryKind::Warning => "warning",
+        },
+        sender: match &entry.kind {
+            EntryKind::Chat { sender, .. } => Some(sender),
+            _ => None,
+        },
+        text: &entry.text,
+        edited: entry.edited,
+        deleted: entry.deleted,
+        reactions: entry.reactions.iter().map(|(emoji, reactors)| (emoji.as_str(), reactors.len())).collect(),
+    });
+
+    if format == Format::Json {
+        let lines: Vec<Line> = lines.collect();
+        // nothing in a `Line` can fail to serialize
+        return serde_json::to_string_pretty(&lines).unwrap_or_default() + "\n";
+    }
+
+    let mut out = String::new();
+    for line in lines {
+        let text = if line.deleted { "(deleted)" } else { line.text };
+        let edited = if line.edited && !line.deleted { " (edited)" } else { "" };
+        let reactions: Vec<String> = line.reactions.iter().map(|(emoji, count)| format!("{} {}", emoji, count)).collect();
+        let reactions = if reactions.is_empty() { String::new() } else { format!(" [{}]", reactions.join(", ")) };
+        let time = line.time.replacen('T', " ", 1).replacen('Z', " UTC", 1);
+
+        let _ = match (format, line.sender) {
+            (Format::Markdown, Some(sender)) => writeln!(out, "- `{}` **{}**: {}{}{}", time, sender, text, edited, reactions),
+            (Format::Markdown, None) => writeln!(out, "- `{}` *{}*", time, text),
+            (_, Some(sender)) => writeln!(out, "[{}] <{}> {}{}{}", time, sender, text, edited, reactions),
+            (_, None) => writeln!(out, "[{}] * {}", time, text),
+        };
+    }
+    out
+}
+
+/// Writes `entries` to `path` as a transcript in the given format, refusing to replace an existing file unless `force`
+/// is set. The transcript holds the decrypted chat, so it's only readable by the user.
+///
+/// # Errors
+///
+/// Returns a `StorageError::Export` if the file exists and `force` isn't set, or if writing fails.
+pub fn export<'a>(
+    entries: impl IntoIterator<Item = &'a LogEntry>,
+    format: Format,
+    path: &Path,
+    force: bool,
+) -> Result<(), ApplicationError> {
+    let error = |e: &dyn std::fmt::Display| ApplicationError::Storage(StorageError::Export(format!("{}: {}", path.display(), e)));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => ApplicationError::Storage(StorageError::Export(format!("{} already exists", path.display()))),
+        _ => error(&e),
+    })?;
+    file.write_all(render(entries, format).as_bytes()).map_err(|e| error(&e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_log::ChatLog;
+
+    #[test]
+    fn render_each_format() {
+        let mut log = ChatLog::new(10);
+        log.push_system(1_700_000_000, String::from("You joined the group."));
+        log.push_chat(7, 1_700_000_060, String::from("alice"), Some(1), "bob", String::from("agenda?"));
+        log.react(7, String::from("👍"), String::from("bob"));
+
+        assert_eq!(
+            render(log.iter(), Format::Text),
+            "[2023-11-14 22:13:20 UTC] * You joined the group.\n[2023-11-14 22:14:20 UTC] <alice> agenda? [👍 1]\n",
+        );
+        assert_eq!(
+            render(log.iter(), Format::Markdown),
+            "- `2023-11-14 22:13:20 UTC` *You joined the group.*\n- `2023-11-14 22:14:20 UTC` **alice**: agenda? [👍 1]\n",
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&render(log.iter(), Format::Json)).unwrap();
+        assert_eq!(json[1]["time"], "2023-11-14T22:14:20Z");
+        assert_eq!(json[1]["sender"], "alice");
+        assert!(json[0].get("sender").is_none());
+
+        assert_eq!(Format::from_path(Path::new("minutes.MD")), Format::Markdown);
+        assert_eq!("txt".parse(), Ok(Format::Text));
+    }
+}
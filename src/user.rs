@@ -1,208 +1,837 @@
-use errors::ApplicationError;
-
-use super::*;
-use crate::group::Group;
-
-pub struct User {
-    id: String,
-    credential_with_key: Option<CredentialWithKey>,
-    signer: SignatureKeyPair,
-    group: Option<Group>,
-}
-
-impl User {
-    /// Builds a new `User`, taking in an id/username `String`.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns any `ApplicationError`s returned by `User::generate_credential()`.
-    pub fn build(id: String) -> Result<User, ApplicationError> {
-        let (credential_with_key, signer) =
-            Self::generate_credential(id.clone().into_bytes(), CredentialType::Basic)?;
-
-        let mut user = User {
-            id,
-            credential_with_key: Some(credential_with_key),
-            signer,
-            group: None,
-        };
-
-        user.generate_group();
-        let (credential_with_key, signer) =
-            Self::generate_credential(user.id.clone().into_bytes(), CredentialType::Basic)?;
-        user.credential_with_key = Some(credential_with_key);
-        user.signer = signer;
-
-        Ok(user)
-    }
-
-    /// Used as a helper for `User::build()`, or to update key material after it's used to encrypt a message. 
-    /// Generates a `CredentialWithKey` and stores the intermediary `SignatureKeyPair` into the provider's key store.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::CryptoError` if `SignatureKeyPair::new()` fails, or an `ApplicationError::MlsKeyStoreError` 
-    /// if `SignatureKeyPair::store()` fails.
-    fn generate_credential(
-        identity: Vec<u8>,
-        credential_type: CredentialType,
-    ) -> Result<(CredentialWithKey, SignatureKeyPair), ApplicationError> {
-        let credential = Credential::new(identity, credential_type).expect("Hardcoded credential type should be supported.");
-        let Ok(signature_keys) = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()) else {
-            return Err(ApplicationError::CryptoError);
-        };
-
-        match signature_keys.store((*PROVIDER).key_store()) {
-            Ok(_) => (),
-            Err(_) => return Err(ApplicationError::MlsKeyStoreError),
-        }
-
-        Ok((
-            CredentialWithKey {
-                credential: credential.into(),
-                signature_key: signature_keys.public().into(),
-            },
-            signature_keys,
-        ))
-    }
-
-    /// Returns an `Ok(MlsMessageOut, MlsMessageOut)`, with the first being a Commit to send to existing members of the group
-    /// and the second being a Welcome for the new member. Takes in the `KeyPackageIn` corresponding to the new member.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::GroupDNE` if the `User`'s group is None, or an `ApplicationError::AddMembersError` if
-    /// returned by `Group::add_member()`.
-    pub fn add_member(&mut self, key_package: KeyPackageIn) -> Result<(MlsMessageOut, MlsMessageOut), ApplicationError> {
-        if let Some(ref mut group) = self.group {
-            Ok(group.add_member(&self.signer, key_package)?)
-        } else { Err(ApplicationError::GroupDNE) }
-    }
-
-    /// Uses the user's key material to encrypt a plaintext message. Returns an `Ok(MlsMessageOut)` if successful.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::GroupDNE` on failure.
-    /// 
-    /// # TODO
-    /// 
-    /// Review error types, refactor to cover other error causes if needed.
-    pub fn encrypt_message(&mut self, msg: &str) -> Result<MlsMessageOut, ApplicationError> {
-        match &mut self.group {
-            Some(g) =>
-                match g.create_message(&self.signer, msg) {
-                    Ok(result) => Ok(result),
-                    Err(_) => Err(ApplicationError::GroupDNE),
-                }
-            None => Err(ApplicationError::GroupDNE),
-        }
-    }
-
-    /// Generates and returns a user's `KeyPackage` from their `SignatureKeyPair` and `CredentialWithKey`.
-    /// Takes ownership of the data within the user's `CredentialWithKey` and replaces it with None.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if the `KeyPackageBuilder::build()` returns an error, or if the user doesn't have a `CredentialWithKey`.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace instances of `unwrap()` with more robust error handling.
-    pub fn generate_key_package(
-        &mut self,
-    ) -> KeyPackage {
-        KeyPackage::builder()
-            .build(
-                CryptoConfig::with_default_version(CIPHERSUITE),
-                &(*PROVIDER),
-                &self.signer,
-                self.credential_with_key.take().unwrap(),
-            ).unwrap()
-    }
-
-    /// Generates a new `MlsGroup` (with the user as the initiator).
-    /// Takes ownership of the data within the user's `CredentialWithKey` and replaces it with None.
-    /// 
-    /// # Panics
-    /// 
-    /// Panics if the user doesn't currently have a `CredentialWithKey`, or if `MlsGroup::new()` fails.
-    /// 
-    /// # TODO
-    /// 
-    /// Replace instances of `unwrap()` with more robust error handling.
-    pub fn generate_group(&mut self) {
-        self.group = Some(
-            Group::build_new(
-                &self.signer,
-                self.credential_with_key.to_owned().unwrap()
-            )
-        );
-    }
-
-    /// Returns true if the User's group is Some() or false if it's None.
-    pub fn has_group(&self) -> bool {
-        match &self.group {
-            Some(_) => true,
-            None => false
-        }
-    }
-
-    /// Returns the User's ID string.
-    pub fn get_id(&self) -> &String {
-        &self.id
-    }
-
-    /// Sets the user's group to one created from a Welcome message.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns an `ApplicationError::KeyPackageDNE` if no `KeyPackage` can be found.
-    pub fn join_group(&mut self, welcome: Welcome) -> Result<(), ApplicationError> {
-        if let Ok(group) = Group::build_join(welcome) {
-            self.group = Some(group);
-            Ok(())
-        } else {
-            Err(ApplicationError::KeyPackageDNE)
-        }
-    }
-
-    /// Processes a `ProtocolMessage`. If it's an `ApplicationMessage`, returns an `Ok(Some(Vec<u8>))` with the decrypted message.
-    /// Otherwise, returns an `Ok(None)` if successful.
-    /// 
-    /// # Errors
-    /// 
-    /// Returns a `ProcessMessageError(err)` or a `GroupDNE` error on failure.
-    pub fn process_message(&mut self, msg: ProtocolMessage) -> Result<Option<Vec<u8>>, ApplicationError> {
-        if let Some(ref mut group) = self.group {
-            let processed_message = group.process_message(msg)?;
-            match processed_message.into_content() {
-                ProcessedMessageContent::ApplicationMessage(app_msg) => Ok(Some(app_msg.into_bytes())),
-                ProcessedMessageContent::StagedCommitMessage(commit) => {
-                    group.merge_commit(*commit);
-                    Ok(None)
-                }
-                _ => Ok(None), // application isn't currently built to send the other remaining message content types in any scenario
-            }
-        } else { Err(ApplicationError::GroupDNE) }
-    }
-
-    /// Updates a `User`'s key material and returns an `Ok(MlsMessageOut)` with the resulting update message
-    /// to be sent to other members of the group.
-    /// 
-    /// # Errors
-    /// 
-    /// Retuns an `ApplicationError::GroupDNE` if called on a `User` whose group is None.
-    pub fn update_keys(&mut self) -> Result<MlsMessageOut, ApplicationError> {
-        let (credential_with_key, signer) =
-            Self::generate_credential(self.id.clone().into_bytes(), CredentialType::Basic)?;
-        self.credential_with_key = Some(credential_with_key);
-        self.signer = signer;
-
-        if let Some(ref mut group) = self.group {
-            Ok(group.update_keys(&self.signer)?)
-        } else {
-            Err(ApplicationError::GroupDNE)
-        }
-    } 
+use errors::{ApplicationError, MlsError};
+
+use super::*;
+use crate::{
+    config::ModerationConfig,
+    group::{self, CommitSummary, Group, KeyChange, Profile, RosterEntry, TreeNode},
+    link::LinkCode,
+    message::Received,
+    policy::{CommitPolicy, Permissive, ProposedCommit},
+    snapshot::GroupSnapshot,
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What came of a message processed by `User::process_message()`.
+#[derive(Debug)]
+pub enum Processed {
+    /// A decrypted application message.
+    Application(Received),
+    /// A commit was merged, changing what `summary` lists. Lists the members whose key material it changed
+    /// unexpectedly, the leaves it added that claim to be another member's device (see `group::impostor_devices()`),
+    /// and if the commit added or removed members without a moderator's say-so under `ModerationPolicy::Warn`, the
+    /// identity of its sender. `superseded` is set if the user's own pending commit was discarded because this one was
+    /// ordered first.
+    Commit {
+        summary: CommitSummary,
+        key_changes: Vec<KeyChange>,
+        impostors: Vec<u32>,
+        unmoderated: Option<String>,
+        superseded: bool,
+    },
+    /// A commit from the member with the identity `committer` was refused by the User's `CommitPolicy`, for the given
+    /// reason.
+    Refused { committer: String, reason: String },
+    /// The member with the given identity proposed re-initializing the group, e.g. to move it to a new ciphersuite or
+    /// protocol version.
+    ///
+    /// openmls 0.5 can't follow one through: it keeps the proposed version and ciphersuite private, and doesn't expose
+    /// the proposals a commit covers, so a commit acting on one can't even be recognized. All the application can do
+    /// is let the user know it was asked for. Such a proposal only ever comes from another implementation's clients,
+    /// since this application moves groups with `User::reinit()` instead.
+    ReInit(String),
+    /// Nothing the application acts on.
+    Other,
+}
+
+/// What's needed to pick a `User` back up after a restart, besides a snapshot of the provider's key store taken at
+/// the same time. See `User::save()`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserState {
+    id: String,
+    /// identity in the User's credential, which `id` no longer matches once it's changed with `User::set_id()`
+    #[serde(default)]
+    identity: Option<String>,
+    /// public half of the User's signature key pair, which the whole pair is stored under in the key store
+    signature_key: Vec<u8>,
+    profile: Profile,
+    /// ID of the User's group, which its state is stored under in the key store
+    group_id: Option<Vec<u8>>,
+    /// references to the key packages the User generated since they were last welcomed into a group
+    #[serde(default)]
+    key_packages: Vec<KeyPackageRef>,
+}
+
+impl UserState {
+    /// Returns the ID of the User the state was saved from.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+pub struct User {
+    id: String,
+    credential_with_key: CredentialWithKey,
+    signer: SignatureKeyPair,
+    group: Option<Group>,
+    /// capabilities advertised in the leaf nodes of the User's key packages
+    capabilities: Capabilities,
+    /// ciphersuite of the User's key packages and the groups they create: `CIPHERSUITE`, unless their group has moved
+    /// to another
+    ciphersuite: Ciphersuite,
+    /// group the User is moving their group's members into, from `User::reinit()` until `User::switch_to_successor()`
+    successor: Option<Group>,
+    /// group the User's group moved out of, kept to read what members still send in it until
+    /// `User::drop_predecessor()`
+    predecessor: Option<Group>,
+    moderation: ModerationConfig,
+    /// digest of the moderation settings each member last announced, by signature key (see
+    /// `User::note_moderation()`)
+    member_moderation: HashMap<Vec<u8>, Vec<u8>>,
+    /// identity of the owner of the room, if the server says it's invite-only
+    locked_by: Option<String>,
+    /// rules the commits from other members have to pass before they're merged
+    commit_policy: Box<dyn CommitPolicy>,
+    profile: Profile,
+    /// block size application messages are padded to, or 0 for none
+    padding: usize,
+    /// whether the Welcomes sent into the groups the User creates include the ratchet tree
+    ratchet_tree_extension: bool,
+    /// epoch the User was welcomed into their group at, since anything sent before it was never theirs to read
+    joined_epoch: u64,
+    /// references to the key packages the User generated since they were last welcomed into a group, to tell the
+    /// Welcomes meant for them from anyone else's
+    key_packages: Vec<KeyPackageRef>,
+}
+
+impl User {
+    /// Builds a new `User`, taking in an id/username `String`.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns any `ApplicationError`s returned by `User::generate_credential()`.
+    pub fn build(id: String) -> Result<User, ApplicationError> {
+        let (credential_with_key, signer) =
+            Self::generate_credential(id.clone().into_bytes(), CredentialType::Basic)?;
+
+        let profile = Profile {
+            user_id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            display_name: Some(id.clone()),
+            link: None,
+        };
+
+        let mut user = User {
+            id,
+            credential_with_key,
+            signer,
+            group: None,
+            capabilities: Capabilities::default(),
+            ciphersuite: CIPHERSUITE,
+            successor: None,
+            predecessor: None,
+            moderation: ModerationConfig::default(),
+            member_moderation: HashMap::new(),
+            locked_by: None,
+            commit_policy: Box::new(Permissive),
+            profile,
+            padding: 0,
+            ratchet_tree_extension: true,
+            joined_epoch: 0,
+            key_packages: Vec::new(),
+        };
+
+        user.generate_group();
+
+        Ok(user)
+    }
+
+    /// Builds a new `User` like `User::build()`, but as another device of the user `code` was shown to: its key
+    /// packages carry that user's `user_id`, along with the proof the device holding `code` checks before adding it.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError`s returned by `User::build()`.
+    pub fn build_linked(id: String, code: &LinkCode) -> Result<User, ApplicationError> {
+        let mut user = Self::build(id)?;
+        user.profile.user_id = code.user_id.clone();
+        user.profile.link = Some(code.proof(user.signer.public()));
+        Ok(user)
+    }
+
+    /// Stores the User's group state in the provider's key store and returns the rest of what `User::resume()` needs
+    /// to pick up where the User left off. Only valid along with a snapshot of the key store taken afterwards, and
+    /// before anything else changes the group.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Group::save()`.
+    pub fn save(&mut self) -> Result<UserState, ApplicationError> {
+        let group_id = match &mut self.group {
+            Some(group) => {
+                group.save()?;
+                Some(group.group_id().as_slice().to_vec())
+            }
+            None => None,
+        };
+
+        Ok(UserState {
+            id: self.id.clone(),
+            identity: Some(self.identity()),
+            signature_key: self.signer.public().to_vec(),
+            profile: self.profile.clone(),
+            group_id,
+            key_packages: self.key_packages.clone(),
+        })
+    }
+
+    /// Rebuilds a `User` saved with `User::save()`, in the same group at the same leaf and epoch, once the key store
+    /// snapshot taken with it has been restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::KeyStore` if the User's signature key pair isn't in the key store, or an
+    /// `MlsError::GroupDNE` if their group isn't.
+    pub fn resume(state: UserState) -> Result<User, ApplicationError> {
+        let Some(signer) = SignatureKeyPair::read(
+            (*PROVIDER).key_store(),
+            &state.signature_key,
+            CIPHERSUITE.signature_algorithm(),
+        ) else {
+            return Err(MlsError::KeyStore(String::from("the signature key pair isn't in the key store")).into());
+        };
+        let group = match state.group_id {
+            Some(group_id) => Some(Group::load(&GroupId::from_slice(&group_id)).ok_or(ApplicationError::Mls(MlsError::GroupDNE))?),
+            None => None,
+        };
+        let identity = state.identity.unwrap_or_else(|| state.id.clone());
+        let credential = Credential::new(identity.into_bytes(), CredentialType::Basic)
+            .expect("Hardcoded credential type should be supported.");
+
+        Ok(User {
+            credential_with_key: CredentialWithKey { credential, signature_key: signer.public().into() },
+            id: state.id,
+            signer,
+            ciphersuite: group.as_ref().map_or(CIPHERSUITE, Group::ciphersuite),
+            successor: None,
+            predecessor: None,
+            group,
+            capabilities: Capabilities::default(),
+            moderation: ModerationConfig::default(),
+            member_moderation: HashMap::new(),
+            locked_by: None,
+            commit_policy: Box::new(Permissive),
+            profile: state.profile,
+            padding: 0,
+            ratchet_tree_extension: true,
+            joined_epoch: 0,
+            key_packages: state.key_packages,
+        })
+    }
+
+    /// Used as a helper for `User::build()`. Generates a `CredentialWithKey` and stores the intermediary
+    /// `SignatureKeyPair` into the provider's key store.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::Crypto` if `SignatureKeyPair::new()` fails, or an `MlsError::KeyStore` 
+    /// if `SignatureKeyPair::store()` fails.
+    fn generate_credential(
+        identity: Vec<u8>,
+        credential_type: CredentialType,
+    ) -> Result<(CredentialWithKey, SignatureKeyPair), ApplicationError> {
+        let credential = Credential::new(identity, credential_type).expect("Hardcoded credential type should be supported.");
+        let signature_keys = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(MlsError::Crypto)?;
+        signature_keys.store((*PROVIDER).key_store()).map_err(|e| MlsError::KeyStore(e.to_string()))?;
+
+        Ok((
+            CredentialWithKey {
+                credential,
+                signature_key: signature_keys.public().into(),
+            },
+            signature_keys,
+        ))
+    }
+
+    /// Returns an `Ok(MlsMessageOut, MlsMessageOut)`, with the first being a Commit to send to existing members of the group
+    /// and the second being a Welcome for the new member. Takes in the `KeyPackageIn` corresponding to the new member.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::GroupDNE` if the `User`'s group is None, or any `ApplicationError` types returned by
+    /// `Group::add_member()`.
+    pub fn add_member(&mut self, key_package: KeyPackageIn) -> Result<(MlsMessageOut, MlsMessageOut), ApplicationError> {
+        if let Some(ref mut group) = self.group {
+            Ok(group.add_member(&self.signer, key_package)?)
+        } else { Err(MlsError::GroupDNE.into()) }
+    }
+
+    /// Uses the user's key material to encrypt a plaintext message. Returns an `Ok(MlsMessageOut)` if successful.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::GroupDNE` if the `User`'s group is None, or an `MlsError::CreateMessage` containing any
+    /// errors returned by `Group::create_message()`, e.g. while one of the User's commits is pending.
+    pub fn encrypt_message(&mut self, msg: &[u8]) -> Result<MlsMessageOut, ApplicationError> {
+        match &mut self.group {
+            Some(g) => Ok(g.create_message(&self.signer, msg).map_err(MlsError::CreateMessage)?),
+            None => Err(MlsError::GroupDNE.into()),
+        }
+    }
+
+    /// Generates and returns a user's `KeyPackage` from their `SignatureKeyPair` and `CredentialWithKey`.
+    /// Each call builds a fresh package with its own init key, so several can be published at once. The package is
+    /// valid for `KEY_PACKAGE_LIFETIME` seconds, and its leaf node carries the user's `Profile` and capabilities, so
+    /// they're in the leaf they're given when they're added to a group.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns any `ApplicationError`s returned by `Profile::extension()`, or an `MlsError::KeyPackageBuild()`
+    /// containing any errors returned by `KeyPackageBuilder::build()`.
+    pub fn generate_key_package(
+        &mut self,
+    ) -> Result<KeyPackage, ApplicationError> {
+        self.build_key_package(self.ciphersuite)
+    }
+
+    /// Generates a key package for the successor group another member is moving the User's group into, as
+    /// `User::generate_key_package()` does, but for that group's ciphersuite.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::UnsupportedCiphersuite` if the User's group can't move to `ciphersuite` (see
+    /// `group::successor_ciphersuite()`), or any `ApplicationError`s returned by `User::generate_key_package()`.
+    pub fn successor_key_package(&mut self, ciphersuite: u16) -> Result<KeyPackage, ApplicationError> {
+        let ciphersuite = group::successor_ciphersuite(self.ciphersuite, ciphersuite)?;
+        self.build_key_package(ciphersuite)
+    }
+
+    /// Helper function for `User::generate_key_package()` and `User::successor_key_package()`.
+    fn build_key_package(&mut self, ciphersuite: Ciphersuite) -> Result<KeyPackage, ApplicationError> {
+        let key_package = KeyPackage::builder()
+            .key_package_lifetime(Lifetime::new(KEY_PACKAGE_LIFETIME))
+            .leaf_node_capabilities(self.capabilities.clone())
+            .leaf_node_extensions(Extensions::single(self.profile.extension()?))
+            .build(
+                CryptoConfig::with_default_version(ciphersuite),
+                &(*PROVIDER),
+                &self.signer,
+                self.credential_with_key.clone(),
+            ).map_err(MlsError::KeyPackageBuild)?;
+        if let Ok(hash_ref) = key_package.hash_ref((*PROVIDER).crypto()) {
+            self.key_packages.push(hash_ref);
+        }
+        Ok(key_package)
+    }
+
+    /// Generates a new `MlsGroup` (with the user as the initiator).
+    ///
+    /// openmls 0.5 can't put extensions in the leaf of a group's creator, so the user's `Profile` is missing from
+    /// this group's roster until they join another from a Welcome.
+    /// 
+    /// # Panics
+    /// 
+    /// Panics if `MlsGroup::new()` fails.
+    /// 
+    /// # TODO
+    /// 
+    /// Replace instances of `unwrap()` with more robust error handling.
+    pub fn generate_group(&mut self) {
+        self.group = Some(
+            Group::build_new(
+                &self.signer,
+                self.credential_with_key.clone(),
+                self.ratchet_tree_extension,
+                self.ciphersuite,
+            )
+        );
+    }
+
+    /// Returns the ciphersuite of the User's group, or of the groups they'd create or join.
+    pub fn ciphersuite(&self) -> Ciphersuite {
+        self.ciphersuite
+    }
+
+    /// Sets the ciphersuite of the User's key packages, e.g. to that of the group a resynced User is waiting to be
+    /// added back into, which may have moved off `CIPHERSUITE`.
+    pub fn set_ciphersuite(&mut self, ciphersuite: Ciphersuite) {
+        self.ciphersuite = ciphersuite;
+    }
+
+    /// Starts moving the User's group to the ciphersuite with the code point `ciphersuite`, creating its successor
+    /// with `Group::reinit()`, and returns the successor's group ID. The User stays in their current group until
+    /// `User::switch_to_successor()`, and a successor from an earlier call that never got that far is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if the User has no group, or an `MlsError::UnsupportedCiphersuite` if it can't
+    /// move to `ciphersuite` (see `group::successor_ciphersuite()`).
+    pub fn reinit(&mut self, ciphersuite: u16) -> Result<Vec<u8>, ApplicationError> {
+        let group = self.group.as_ref().ok_or(MlsError::GroupDNE)?;
+        let ciphersuite = group::successor_ciphersuite(group.ciphersuite(), ciphersuite)?;
+        let mut successor = group.reinit(&self.signer, self.credential_with_key.clone(), ciphersuite);
+        successor.set_padding(self.padding);
+        let group_id = successor.group_id().as_slice().to_vec();
+        self.successor = Some(successor);
+        Ok(group_id)
+    }
+
+    /// Returns the group ID of the successor from `User::reinit()`, if the move is still underway.
+    pub fn successor_id(&self) -> Option<Vec<u8>> {
+        self.successor.as_ref().map(|successor| successor.group_id().as_slice().to_vec())
+    }
+
+    /// Adds the members whose key packages are given to the successor from `User::reinit()`. Nobody else is in it
+    /// yet, so the commit adding them is merged straight away rather than sent. Returns the Welcome for the members,
+    /// and the successor's ratchet tree if the Welcome leaves it out, to send them in the current group before
+    /// `User::switch_to_successor()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if no move is underway, or any `ApplicationError` returned by
+    /// `Group::add_members()` or `Group::merge_pending_commit()`, in which case the move is dropped.
+    pub fn welcome_to_successor(
+        &mut self,
+        key_packages: Vec<KeyPackageIn>,
+    ) -> Result<(MlsMessageOut, Option<Vec<u8>>), ApplicationError> {
+        let successor = self.successor.as_mut().ok_or(MlsError::GroupDNE)?;
+        let added = successor.add_members(&self.signer, key_packages).and_then(|(_commit, welcome)| {
+            successor.merge_pending_commit()?;
+            let ratchet_tree = match successor.includes_ratchet_tree() {
+                true => None,
+                false => Some(successor.export_ratchet_tree()?),
+            };
+            Ok((welcome, ratchet_tree))
+        });
+        if added.is_err() {
+            self.successor = None;
+        }
+        added
+    }
+
+    /// Makes the successor from `User::reinit()` the User's group, in place of the one they were in. The old group is
+    /// kept as its predecessor, to read what the other members send in it before they follow (see
+    /// `User::process_message()`).
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if no move is underway.
+    pub fn switch_to_successor(&mut self) -> Result<(), ApplicationError> {
+        let successor = self.successor.take().ok_or(MlsError::GroupDNE)?;
+        self.ciphersuite = successor.ciphersuite();
+        self.joined_epoch = successor.epoch();
+        self.predecessor = self.group.replace(successor);
+        Ok(())
+    }
+
+    /// Joins the successor another member moved the User's group into, with the Welcome they sent in it, as
+    /// `User::join_group()` does. The old group is kept as its predecessor, as with `User::switch_to_successor()`, and
+    /// stays the User's group if the successor can't be joined.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `User::join_group()`.
+    pub fn join_successor(&mut self, welcome: Welcome, ratchet_tree: Option<&[u8]>) -> Result<(), ApplicationError> {
+        let predecessor = self.group.take();
+        match self.join_group(welcome, ratchet_tree) {
+            Ok(()) => {
+                self.predecessor = predecessor;
+                Ok(())
+            }
+            Err(e) => {
+                self.group = predecessor;
+                Err(e)
+            }
+        }
+    }
+
+    /// Stops reading messages sent in the group the User's group moved out of, once the other members have had time
+    /// to follow.
+    pub fn drop_predecessor(&mut self) {
+        self.predecessor = None;
+    }
+
+    /// Drops the successor from `User::reinit()`, leaving the User in their current group.
+    pub fn cancel_reinit(&mut self) {
+        self.successor = None;
+    }
+
+    /// Returns true if the User's group is Some() or false if it's None.
+    pub fn has_group(&self) -> bool {
+        self.group.is_some()
+    }
+
+    /// Returns true if one of the User's own commits is waiting to be merged. See `Group::has_pending_commit()`.
+    pub fn has_pending_commit(&self) -> bool {
+        self.group.as_ref().is_some_and(Group::has_pending_commit)
+    }
+
+    /// Merges the User's own pending commit, once the server has sequenced it, and returns what it changed. See
+    /// `Group::merge_pending_commit()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `Group::merge_pending_commit()`.
+    pub fn merge_pending_commit(&mut self) -> Result<Option<CommitSummary>, ApplicationError> {
+        match &mut self.group {
+            Some(group) => group.merge_pending_commit(),
+            None => Ok(None),
+        }
+    }
+
+    /// Derives secret key material bound to the current epoch of the User's group. See `Group::export_secret()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if the User has no group, or any `ApplicationError` returned by
+    /// `Group::export_secret()`.
+    pub fn export_secret(&self, label: &str, context: &[u8], length: usize) -> Result<Vec<u8>, ApplicationError> {
+        match &self.group {
+            Some(group) => group.export_secret(label, context, length),
+            None => Err(MlsError::GroupDNE.into()),
+        }
+    }
+
+    /// Returns the verification code for the current epoch of the User's group, or None if the User has no group.
+    /// See `Group::verification_code()`.
+    pub fn verification_code(&self) -> Option<String> {
+        self.group.as_ref().map(Group::verification_code)
+    }
+
+    /// Returns the current epoch of the User's group, or None if the User has no group.
+    pub fn epoch(&self) -> Option<u64> {
+        self.group.as_ref().map(Group::epoch)
+    }
+
+    /// Returns the ID of the User's group, if they have one.
+    pub fn group_id(&self) -> Option<&GroupId> {
+        self.group.as_ref().map(Group::group_id)
+    }
+
+    /// Returns the User's own leaf index in their group, if they have one.
+    pub fn own_index(&self) -> Option<u32> {
+        self.group.as_ref().map(Group::own_index)
+    }
+
+    /// Returns the nodes of the User's group's ratchet tree, or an empty list if the User has no group.
+    pub fn tree(&self) -> Vec<TreeNode> {
+        self.group.as_ref().map(Group::tree).unwrap_or_default()
+    }
+
+    /// Returns a snapshot of the User's group's public state, or None if the User has no group. See `GroupSnapshot`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any `ApplicationError` returned by `GroupSnapshot::of()`.
+    pub fn snapshot(&self) -> Result<Option<GroupSnapshot>, ApplicationError> {
+        self.group.as_ref().map(GroupSnapshot::of).transpose()
+    }
+
+    /// Returns the members of the User's group, or an empty list if the User has no group.
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        self.group.as_ref().map(Group::roster).unwrap_or_default()
+    }
+
+    /// Sets the capabilities advertised in the key packages generated from now on. See `CapabilitiesConfig`.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Sets which members' commits may add and remove members. Everyone may until this is called.
+    pub fn set_moderation(&mut self, moderation: ModerationConfig) {
+        self.moderation = moderation;
+    }
+
+    /// Locks the User's room to the member with the identity `owner`, as the server says when the room is
+    /// invite-only, or unlocks it with None. Only `policy::Strict` refuses anyone else's additions to a locked room.
+    pub fn set_locked_by(&mut self, owner: Option<String>) {
+        self.locked_by = owner;
+    }
+
+    /// Sets the rules the commits from other members have to pass before they're merged. `policy::Permissive` until
+    /// this is called.
+    pub fn set_commit_policy(&mut self, policy: Box<dyn CommitPolicy>) {
+        self.commit_policy = policy;
+    }
+
+    /// Pads the User's application messages to a multiple of `padding` bytes, in their current group and any they
+    /// join later. See `Group::set_padding()`.
+    pub fn set_padding(&mut self, padding: usize) {
+        self.padding = padding;
+        if let Some(group) = &mut self.group {
+            group.set_padding(padding);
+        }
+    }
+
+    /// Sets whether the Welcomes sent into the groups the User creates from now on include the ratchet tree. A group
+    /// the User is still alone in is created again to match; any other keeps to whatever it was created with, as does
+    /// every group the User is welcomed into. See `Group::includes_ratchet_tree()`.
+    pub fn set_ratchet_tree_extension(&mut self, ratchet_tree: bool) {
+        self.ratchet_tree_extension = ratchet_tree;
+        if self.includes_ratchet_tree() != ratchet_tree && self.roster().len() <= 1 {
+            self.generate_group();
+        }
+    }
+
+    /// Returns whether the Welcomes the User sends include the ratchet tree, so the new member needn't fetch it.
+    pub fn includes_ratchet_tree(&self) -> bool {
+        self.group.as_ref().map_or(self.ratchet_tree_extension, Group::includes_ratchet_tree)
+    }
+
+    /// Returns the User's group's ratchet tree, serialized for a member joining from a Welcome sent without one. See
+    /// `Group::export_ratchet_tree()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if the User has no group, or an `MlsError::TlsSerialize` if serializing fails.
+    pub fn ratchet_tree(&self) -> Result<Vec<u8>, ApplicationError> {
+        self.group.as_ref().ok_or(MlsError::GroupDNE)?.export_ratchet_tree()
+    }
+
+    /// Returns the reference of the User's key package that `welcome` is for, to fetch the ratchet tree for it by, or
+    /// None if the Welcome is for someone else. With no record of the key packages the User generated, assumes it's
+    /// for the first new member it names, as `User::join_group()` would try it.
+    pub fn welcome_for(&self, welcome: &Welcome) -> Option<Vec<u8>> {
+        let secrets = welcome.secrets();
+        let own = match self.key_packages.is_empty() {
+            true => secrets.first(),
+            false => secrets.iter().find(|secrets| self.key_packages.contains(&secrets.new_member())),
+        };
+        own.map(|secrets| secrets.new_member().as_slice().to_vec())
+    }
+
+    /// Returns true if the User's own commits adding or removing members would be accepted by clients with the same
+    /// moderation settings, which judge them by the identity in the User's credential rather than their ID.
+    pub fn is_moderator(&self) -> bool {
+        self.moderation.allows(&self.identity())
+    }
+
+    /// Returns the identity in the User's credential, which the other members know them by. It stays the same when
+    /// their ID changes with `User::set_id()`.
+    pub fn identity(&self) -> String {
+        String::from_utf8_lossy(self.credential_with_key.credential.identity()).into_owned()
+    }
+
+    /// Returns the digest of the User's moderation settings, for the other members to compare with their own. See
+    /// `group::moderation_digest()`.
+    pub fn moderation_digest(&self) -> Vec<u8> {
+        group::moderation_digest(&self.moderation)
+    }
+
+    /// Records the digest of the moderation settings announced by the member at leaf `index`. Their commits adding or
+    /// removing members are refused while it differs from the User's own, since members with different settings
+    /// would disagree on whether to merge them. Returns true if it differs, and didn't already.
+    pub fn note_moderation(&mut self, index: u32, digest: Vec<u8>) -> bool {
+        let Some(member) = self.roster().into_iter().find(|member| member.index == index) else { return false };
+        let differs = digest != self.moderation_digest();
+        let previous = self.member_moderation.insert(member.signature_key, digest.clone());
+        differs && previous != Some(digest)
+    }
+
+    /// Returns the User's ID string.
+    pub fn get_id(&self) -> &String {
+        &self.id
+    }
+
+    /// Changes the User's ID string. Takes effect as the display name in the key packages generated from now on.
+    ///
+    /// The identity in the User's credential stays the same, so the other members show the new name next to it (see
+    /// `Payload::Nick`) rather than in its place. In openmls 0.5, `MlsGroup::self_update()` only rekeys the leaf. A
+    /// replacement credential can be proposed with `MlsGroup::propose_self_update()`, but openmls leaves a member's
+    /// own update proposals out of their commits, so it would wait on another member to commit it. Moderation and
+    /// room ownership are also keyed to the identity, and would be lost with it.
+    pub fn set_id(&mut self, id: String) {
+        self.profile.display_name = Some(id.clone());
+        self.id = id;
+    }
+
+    /// Returns the profile the User publishes in their leaf node. Its `user_id` is generated when the User is built
+    /// and never changes.
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Sets the user's group to one created from a Welcome message, replacing their current group along with any
+    /// commit of theirs still pending in it. `ratchet_tree` is the group's ratchet tree as serialized by
+    /// `User::ratchet_tree()`, for a Welcome sent without one.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::KeyPackageDNE` if no `KeyPackage` can be found, e.g. because the Welcome was
+    /// for someone else. That includes a Welcome for a key package of the `User` this one replaced after a resync,
+    /// whose private keys are still in the key store but whose leaf it couldn't sign for. Returns an
+    /// `MlsError::Welcome` if the Welcome is for one of the User's key packages but the group can't be joined with it,
+    /// or an `MlsError::InvalidMessage` if `ratchet_tree` can't be deserialized.
+    pub fn join_group(&mut self, welcome: Welcome, ratchet_tree: Option<&[u8]>) -> Result<(), ApplicationError> {
+        // openmls uses up whichever key package in the key store a Welcome is for, even one of another User sharing
+        // the provider (e.g. in a simulation), so a Welcome for none of the User's own isn't opened at all
+        let own = welcome.secrets().iter().any(|secrets| self.key_packages.contains(&secrets.new_member()));
+        if !own && !self.key_packages.is_empty() {
+            return Err(MlsError::KeyPackageDNE.into());
+        }
+        let ratchet_tree = ratchet_tree
+            .map(|mut tree| RatchetTreeIn::tls_deserialize(&mut tree).map_err(|_| MlsError::InvalidMessage))
+            .transpose()?;
+        let mut group = Group::build_join(welcome, ratchet_tree)?;
+        let own_index = group.own_index();
+        let own_key = group.roster().into_iter().find(|member| member.index == own_index).map(|member| member.signature_key);
+        if own_key.as_deref() != Some(self.signer.public()) {
+            return Err(MlsError::KeyPackageDNE.into());
+        }
+        group.set_padding(self.padding);
+        self.joined_epoch = group.epoch();
+        self.ciphersuite = group.ciphersuite();
+        self.key_packages.clear();
+        self.successor = None;
+        self.group = Some(group);
+        Ok(())
+    }
+
+    /// Processes a `ProtocolMessage`. If it's an `ApplicationMessage`, returns a `Processed::Application` with the
+    /// decrypted message and its sender. Commits are checked against the User's `CommitPolicy` first, along with the
+    /// moderation settings, and returned as a `Processed::Refused` without being merged if it refuses them. Commits
+    /// that pass are merged, and checked with `group::unexpected_key_changes()` for members whose keys were replaced by
+    /// someone other than themselves.
+    ///
+    /// Since the server relays every message in one order, a commit arriving while one of the user's own is pending
+    /// was ordered ahead of it, so the user's is discarded (see `Group::has_pending_commit()`). A commit from an
+    /// earlier epoch lost out the same way to one already merged, and is ignored, as is anything sent to a group other
+    /// than the user's (e.g. by the group that's about to welcome them) or sent to it before the epoch they were
+    /// welcomed in. Messages sent in the group the User's group moved out of are read from its predecessor (see
+    /// `User::process_predecessor_message()`).
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an `MlsError::ProcessMessage(err)` or a `GroupDNE` error on failure, an `InvalidMessage` error if an
+    /// application message wasn't sent by a member of the group, or an `MlsError::MergeCommit(err)` if a commit can't
+    /// be merged.
+    pub fn process_message(&mut self, msg: ProtocolMessage) -> Result<Processed, ApplicationError> {
+        if self.predecessor.as_ref().is_some_and(|predecessor| msg.group_id() == predecessor.group_id()) {
+            return self.process_predecessor_message(msg);
+        }
+        if let Some(ref mut group) = self.group {
+            if msg.group_id() != group.group_id() {
+                return Ok(Processed::Other);
+            }
+            if msg.content_type() == ContentType::Commit && msg.epoch().as_u64() < group.epoch() {
+                return Ok(Processed::Other);
+            }
+            if msg.epoch().as_u64() < self.joined_epoch {
+                return Ok(Processed::Other);
+            }
+
+            let processed_message = group.process_message(msg)?;
+            let sender = match processed_message.sender() {
+                Sender::Member(index) => Some(index.u32()),
+                _ => None,
+            };
+            let identity = String::from_utf8_lossy(processed_message.credential().identity()).into_owned();
+
+            match processed_message.into_content() {
+                ProcessedMessageContent::ApplicationMessage(app_msg) => {
+                    let Some(sender) = sender else { return Err(MlsError::InvalidMessage.into()) };
+                    Ok(Processed::Application(Received { sender, identity, content: app_msg.into_bytes() }))
+                }
+                ProcessedMessageContent::StagedCommitMessage(commit) => {
+                    let summary = group.summarize(&commit, &identity);
+                    let announced = group.roster().into_iter()
+                        .find(|member| Some(member.index) == sender)
+                        .and_then(|member| self.member_moderation.get(&member.signature_key));
+                    let proposed = ProposedCommit {
+                        summary: &summary,
+                        moderator: self.moderation.allows(&identity),
+                        moderation: self.moderation.policy,
+                        moderation_agrees: announced.is_none_or(|digest| *digest == group::moderation_digest(&self.moderation)),
+                        locked_by: self.locked_by.as_deref(),
+                        swapped: group.swapped_credentials(&commit, &identity),
+                    };
+                    if let Some(reason) = self.commit_policy.check(&proposed) {
+                        return Ok(Processed::Refused { committer: identity, reason });
+                    }
+                    let unmoderated = (proposed.changes_members() && !proposed.moderator).then_some(identity);
+
+                    let mut expected: Vec<u32> = commit.remove_proposals()
+                        .map(|proposal| proposal.remove_proposal().removed().u32())
+                        .collect();
+                    expected.extend(commit.update_proposals().filter_map(|proposal| match proposal.sender() {
+                        Sender::Member(index) => Some(index.u32()),
+                        _ => None,
+                    }));
+                    expected.extend(sender);
+
+                    let superseded = group.has_pending_commit();
+                    group.discard_pending_commit();
+
+                    let before = group.roster();
+                    group.merge_commit(*commit)?;
+                    let after = group.roster();
+                    let key_changes = group::unexpected_key_changes(&before, &after, &expected);
+                    let impostors = group::impostor_devices(&before, &after, sender);
+                    Ok(Processed::Commit { summary, key_changes, impostors, unmoderated, superseded })
+                }
+                ProcessedMessageContent::ProposalMessage(proposal) if matches!(proposal.proposal(), Proposal::ReInit(_)) =>
+                    Ok(Processed::ReInit(identity)),
+                _ => Ok(Processed::Other), // application isn't currently built to send the other remaining message content types in any scenario
+            }
+        } else { Err(MlsError::GroupDNE.into()) }
+    }
+
+    /// Helper function for `User::process_message()`. Reads an application message sent in the group the User's group
+    /// moved out of by a member who hadn't followed yet, attributing it to their leaf in the successor, found by their
+    /// signature key. Messages from members who were left behind, and anything other than application messages, are
+    /// ignored, since the predecessor is only kept to read what was already on its way when the group moved.
+    fn process_predecessor_message(&mut self, msg: ProtocolMessage) -> Result<Processed, ApplicationError> {
+        let (Some(predecessor), Some(group)) = (self.predecessor.as_mut(), self.group.as_ref()) else {
+            return Ok(Processed::Other);
+        };
+        if msg.content_type() != ContentType::Application {
+            return Ok(Processed::Other);
+        }
+
+        let processed_message = predecessor.process_message(msg)?;
+        let Sender::Member(index) = processed_message.sender() else { return Err(MlsError::InvalidMessage.into()) };
+        let signature_key = predecessor.roster().into_iter()
+            .find(|member| member.index == index.u32())
+            .map(|member| member.signature_key);
+        let sender = group.roster().into_iter()
+            .find(|member| Some(&member.signature_key) == signature_key.as_ref())
+            .map(|member| member.index);
+        let identity = String::from_utf8_lossy(processed_message.credential().identity()).into_owned();
+
+        match (processed_message.into_content(), sender) {
+            (ProcessedMessageContent::ApplicationMessage(app_msg), Some(sender)) =>
+                Ok(Processed::Application(Received { sender, identity, content: app_msg.into_bytes() })),
+            _ => Ok(Processed::Other),
+        }
+    }
+
+    /// Updates a `User`'s encryption keys and returns an `Ok(MlsMessageOut)` with the resulting commit to be sent to
+    /// other members of the group. The commit stays pending until `User::merge_pending_commit()`.
+    /// 
+    /// # Errors
+    /// 
+    /// Retuns an `MlsError::GroupDNE` if called on a `User` whose group is None, or any `ApplicationError`
+    /// returned by `Group::update_keys()`.
+    pub fn update_keys(&mut self) -> Result<MlsMessageOut, ApplicationError> {
+        if let Some(ref mut group) = self.group {
+            Ok(group.update_keys(&self.signer)?)
+        } else {
+            Err(MlsError::GroupDNE.into())
+        }
+    } 
+
+    /// Removes the member at leaf `member_index` from the User's group, returning the commit to be sent to the other
+    /// members. The commit stays pending until `User::merge_pending_commit()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `MlsError::GroupDNE` if called on a `User` whose group is None, or any `ApplicationError` returned
+    /// by `Group::remove_member()`.
+    pub fn remove_member(&mut self, member_index: u32) -> Result<MlsMessageOut, ApplicationError> {
+        match &mut self.group {
+            Some(group) => group.remove_member(&self.signer, member_index),
+            None => Err(MlsError::GroupDNE.into()),
+        }
+    }
 }
\ No newline at end of file
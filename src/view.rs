@@ -1,212 +1,1057 @@
-use std::{ io::{self, Stdout}, time::Duration, };
-use crate::ApplicationError;
-
-
-use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute,
-    terminal::{
-        disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
-    },
-};
-use ratatui::{
-    backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Modifier, Style},
-    text::{Line, Span, Text},
-    widgets::{Block, Borders, Paragraph, Wrap},
-};
-use tui_input::backend::crossterm::EventHandler;
-use tui_input::Input;
-
-
-enum InputMode {
-    Normal,
-    Editing,
-}
-
-type Terminal = ratatui::Terminal<CrosstermBackend<Stdout>>;
-pub struct ChatWindow {
-    input: Input,
-    input_mode: InputMode,
-    output: Vec<String>,
-    terminal: Terminal,
-}
-
-impl ChatWindow {
-    pub fn build() -> Result<ChatWindow, ApplicationError> {
-        let terminal = Self::build_terminal()?;
-        
-        Ok(ChatWindow {
-            input: Input::default(),
-            input_mode: InputMode::Normal,
-            output: Vec::new(),
-            terminal,
-        })
-    }
-
-    fn build_terminal() -> Result<Terminal, ApplicationError> {
-        match enable_raw_mode() {
-            Ok(_) => (),
-            Err(_) => return Err(ApplicationError::TerminalError),
-        }
-
-        let mut stdout = io::stdout();
-        match execute!(stdout, EnterAlternateScreen, EnableMouseCapture) {
-            Ok(_) => (),
-            Err(_) => return Err(ApplicationError::TerminalError),
-        }
-
-        let backend = CrosstermBackend::new(stdout);
-        match Terminal::new(backend) {
-            Ok(terminal) => Ok(terminal),
-            Err(_) => Err(ApplicationError::TerminalError),
-        }
-    }
-
-    pub fn close_terminal(&mut self) -> Result<(), ApplicationError> {
-        match disable_raw_mode() {
-            Ok(_) => (),
-            Err(_) => return Err(ApplicationError::TerminalError),
-        }
-
-        match execute!(
-            self.terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        ) {
-            Ok(_) => (),
-            Err(_) => return Err(ApplicationError::TerminalError),
-        }
-        match self.terminal.show_cursor() {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ApplicationError::TerminalError)
-        }
-    }
-
-    pub fn draw(&mut self, log: &Vec<String>) -> Result<(), ApplicationError> {
-        match self.terminal.draw(|f| {
-            let rects = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(2)
-                .constraints(
-                    [
-                        Constraint::Min(1),
-                        Constraint::Length(3),
-                        Constraint::Length(1),
-                    ]
-                    .as_ref(),
-                )
-                .split(f.size());
-
-            let (msg, style) = match self.input_mode {
-                InputMode::Normal => (
-                    vec![
-                        Span::raw("Press "),
-                        Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                        Span::raw(" to exit, "),
-                        Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                        Span::raw(" to type in the chat."),
-                    ],
-                    Style::default().add_modifier(Modifier::RAPID_BLINK),
-                ),
-                InputMode::Editing => (
-                    vec![
-                        Span::raw("Press "),
-                        Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                        Span::raw(" to stop editing, "),
-                        Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                        Span::raw(" to send the message."),
-                    ],
-                    Style::default(),
-                ),
-            };
-
-            let mut text = Text::from(Line::from(msg));
-            text = text.patch_style(style);
-            let help_message = Paragraph::new(text);
-            f.render_widget(help_message, rects[2]);
-
-            let width = rects[0].width.max(3) - 3; // 2 width reserved for borders, 1 for cursor
-
-            let scroll = self.input.visual_scroll(width as usize);
-            let input = Paragraph::new(self.input.value())
-                .style(match self.input_mode {
-                    InputMode::Normal => Style::default(),
-                    InputMode::Editing => Style::default().fg(Color::Yellow),
-                })
-                .scroll((0, scroll as u16))
-                .block(Block::default().borders(Borders::ALL).title("Input"));
-            f.render_widget(input, rects[1]);
-
-            match self.input_mode {
-                InputMode::Normal => {}
-                InputMode::Editing => {
-                    f.set_cursor(
-                        // place cursor past end of input text
-                        rects[1].x
-                            + ((self.input.visual_cursor()).max(scroll) - scroll) as u16
-                            + 1,
-                            // move cursor from the border to the input line
-                            rects[1].y + 1,
-                    )
-                }
-            }
-
-            let mut lines = vec![];
-
-            for msg in log {
-                lines.push(Line::raw(msg));
-            }
-            let chat = Paragraph::new(Text::from(lines))
-                .wrap(Wrap { trim: true })
-                .block(Block::default().borders(Borders::ALL).title("Chat Log"));
-
-            f.render_widget(chat, rects[0]);
-        }) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ApplicationError::TerminalError),
-        }
-    }
-
-    pub fn get_output(&mut self) -> Option<String> {
-        self.output.pop()
-    }
-
-    pub fn run(&mut self) -> Result<bool, ApplicationError> {
-        if !event::poll(Duration::from_millis(100)).unwrap() {
-            return Ok(true);
-        }
-
-        if let Ok(Event::Key(key)) = event::read() {
-            match self.input_mode {
-                InputMode::Normal => match key.code {
-                    KeyCode::Enter => {
-                        self.input_mode = InputMode::Editing;
-                        return Ok(true);
-                    }
-                    KeyCode::Esc => {
-                        return Ok(false);
-                    }
-                    _ => { return Ok(true); }
-                }
-                InputMode::Editing => match key.code {
-                    KeyCode::Enter => {
-                        let msg = self.input.value().into();
-                        self.output.push(msg);
-                        self.input.reset();
-                        return Ok(true);
-                    }
-                    KeyCode::Esc => {
-                        self.input_mode = InputMode::Normal;
-                        return Ok(true);
-                    }
-                    _ => {
-                        self.input.handle_event(&Event::Key(key));
-                        return Ok(true);
-                    }
-                }
-            }
-        } else { Ok(true) }
-    }
-}
\ No newline at end of file
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Stdout, Write},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, TryRecvError}, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+use crate::{
+    ApplicationError,
+    UiError,
+    chat_log::{find_matches, mention_spans, ChatLog, EntryKind, LogEntry},
+    config::{DisplayConfig, KeyBindings},
+    frontend::{ChatView, UserInput},
+    markup::{self, MarkupKind},
+    metrics::format_bytes,
+    network::client::ConnectionState,
+    theme::Theme,
+};
+
+
+use crossterm::{
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
+    execute,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+    },
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Clear, Paragraph, Tabs, Wrap},
+};
+use tui_input::backend::crossterm::EventHandler;
+use tui_input::Input;
+use tokio::sync::Notify;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+
+enum InputMode {
+    Normal,
+    Editing,
+    Search,
+}
+
+/// A search of the chat log, entered with the search key in Normal mode.
+struct Search {
+    query: String,
+    /// indices (as counted by `ChatLog::total()`) of the entries matching the query, as of the last draw
+    matches: Vec<usize>,
+    /// the match last jumped to
+    current: Option<usize>,
+    /// set when the view should scroll to the current match on the next draw
+    jump: bool,
+}
+
+/// Width of the member list pane, including its borders.
+const MEMBER_PANE_WIDTH: u16 = 24;
+
+/// Longest the thread reading the terminal blocks at a time, and so how long it takes to notice the window closing.
+const EVENT_POLL: Duration = Duration::from_millis(250);
+
+/// A line of the member list: the name to display, the user ID from the member's profile if they published one,
+/// whether it's the local user, how many devices they're in the group from, and their presence.
+pub struct MemberLine {
+    pub name: String,
+    pub user_id: Option<String>,
+    pub own: bool,
+    pub devices: usize,
+    pub presence: Presence,
+}
+
+/// Whether a member is around, as shown by the dot next to them in the member list.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Presence {
+    /// Connected, and not away. Members are taken to be online until they say or the server shows otherwise.
+    #[default]
+    Online,
+    /// Connected, but marked away with `/away`, with the message they gave.
+    Away(String),
+    /// Their connection to the server closed.
+    Offline,
+}
+
+impl Presence {
+    /// Combines the presence of two devices of the same user: online if either is, else away if either is.
+    pub fn or(self, other: Presence) -> Presence {
+        match (self, other) {
+            (Presence::Online, _) | (_, Presence::Online) => Presence::Online,
+            (away @ Presence::Away(_), _) | (_, away @ Presence::Away(_)) => away,
+            _ => Presence::Offline,
+        }
+    }
+}
+
+/// A group in the tab bar, along with the number of messages it's received since it was last shown.
+pub struct Tab {
+    pub name: String,
+    pub unread: usize,
+}
+
+/// The session details shown in the status line under the chat log.
+pub struct Status {
+    pub address: String,
+    pub state: ConnectionState,
+    /// Epoch of the local user's group, if they have one.
+    pub epoch: Option<u64>,
+    /// The local user's own leaf index in the group, if they have one.
+    pub leaf: Option<u32>,
+    pub members: usize,
+    /// Number of outgoing messages not yet written to the connection.
+    pub pending: usize,
+    /// What the client is busy with before the chat can go on, if anything.
+    pub progress: Option<Progress>,
+    /// Smoothed round trip to the server, once it's been measured.
+    pub round_trip: Option<Duration>,
+    /// Bytes per second written to the server lately, once it's been measured.
+    pub upload_rate: Option<u64>,
+    /// Bytes per second read from the server lately, once it's been measured.
+    pub download_rate: Option<u64>,
+}
+
+/// Something the user is left waiting on before they can chat, shown with a spinner in place of a blank screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// Connecting, or reconnecting, to the server.
+    Connecting,
+    /// Connected, but not yet added to a group with anybody else.
+    AwaitingWelcome,
+    /// Reconnected, and catching up on what was sent in the meantime.
+    Syncing,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Progress::Connecting => write!(f, "Connecting to the server"),
+            Progress::AwaitingWelcome => write!(f, "Waiting to be added to the group"),
+            Progress::Syncing => write!(f, "Catching up on missed messages"),
+        }
+    }
+}
+
+/// How long a notice passed to `ChatWindow::show_notice()` stays in the status line.
+const NOTICE_LIFETIME: Duration = Duration::from_secs(8);
+
+/// How often the spinner shown with a `Progress` turns.
+const SPINNER_INTERVAL: Duration = Duration::from_millis(120);
+
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+type Terminal = ratatui::Terminal<CrosstermBackend<Stdout>>;
+pub struct ChatWindow {
+    /// index into `tabs` of the group being shown
+    active_tab: usize,
+    /// width of the chat log's area as of the last draw
+    chat_width: u16,
+    display: DisplayConfig,
+    /// terminal events read by the thread `ChatWindow::read_events()` spawns
+    events: Receiver<Event>,
+    /// notified as each event is read
+    events_ready: Arc<Notify>,
+    input: Input,
+    input_mode: InputMode,
+    keys: KeyBindings,
+    /// index (as counted by `ChatLog::total()`) of the oldest entry still in the log as of the last draw
+    log_first: usize,
+    /// `ChatLog::total()` as of the last draw
+    log_total: usize,
+    /// largest scroll position of the chat log as of the last draw
+    max_scroll: u16,
+    members: Vec<MemberLine>,
+    /// the latest notice from `ChatWindow::show_notice()` and when it was shown, until it expires
+    notice: Option<(String, Instant)>,
+    output: Vec<UserInput>,
+    /// height of the chat log's text area as of the last draw
+    page: u16,
+    /// cleared to stop the thread reading the terminal
+    reading: Arc<AtomicBool>,
+    /// when the window was built, which the spinner's frame is counted from
+    started: Instant,
+    /// top line shown while the user has scrolled up, or None to follow new messages
+    scroll: Option<u16>,
+    search: Option<Search>,
+    search_input: Input,
+    /// index (as counted by `ChatLog::total()`) of the entry selected for copying
+    selection: Option<usize>,
+    /// set when the view should scroll to the selected entry on the next draw
+    selection_jump: bool,
+    show_keys: bool,
+    show_members: bool,
+    status: Option<Status>,
+    /// tab the user switched to, waiting to be taken by `ChatWindow::take_tab_switch()`
+    switched_tab: Option<usize>,
+    tabs: Vec<Tab>,
+    terminal: Terminal,
+    theme: Theme,
+    topic: Option<String>,
+    /// index (as counted by `ChatLog::total()`) of the first entry added since the user scrolled away from the bottom
+    unread_from: Option<usize>,
+}
+
+impl ChatWindow {
+    pub fn build(keys: KeyBindings, theme: Theme, display: DisplayConfig) -> Result<ChatWindow, ApplicationError> {
+        let terminal = Self::build_terminal()?;
+        let events_ready = Arc::new(Notify::new());
+        let reading = Arc::new(AtomicBool::new(true));
+        let events = Self::read_events(Arc::clone(&events_ready), Arc::clone(&reading));
+
+        Ok(ChatWindow {
+            active_tab: 0,
+            chat_width: 0,
+            display,
+            events,
+            events_ready,
+            input: Input::default(),
+            input_mode: InputMode::Normal,
+            keys,
+            log_first: 0,
+            log_total: 0,
+            max_scroll: 0,
+            members: Vec::new(),
+            notice: None,
+            output: Vec::new(),
+            page: 0,
+            reading,
+            started: Instant::now(),
+            scroll: None,
+            search: None,
+            search_input: Input::default(),
+            selection: None,
+            selection_jump: false,
+            show_keys: false,
+            show_members: true,
+            status: None,
+            switched_tab: None,
+            tabs: Vec::new(),
+            terminal,
+            theme,
+            topic: None,
+            unread_from: None,
+        })
+    }
+
+    fn build_terminal() -> Result<Terminal, ApplicationError> {
+        match enable_raw_mode() {
+            Ok(_) => (),
+            Err(_) => return Err(UiError::Terminal.into()),
+        }
+
+        let mut stdout = io::stdout();
+        match execute!(stdout, EnterAlternateScreen, EnableMouseCapture) {
+            Ok(_) => (),
+            Err(_) => return Err(UiError::Terminal.into()),
+        }
+
+        let backend = CrosstermBackend::new(stdout);
+        match Terminal::new(backend) {
+            Ok(terminal) => Ok(terminal),
+            Err(_) => Err(UiError::Terminal.into()),
+        }
+    }
+
+    /// Spawns a thread to read events from the terminal without blocking the `Controller`, passing each to the
+    /// returned `Receiver` and notifying `ready`, until `reading` is cleared or the terminal can't be read.
+    fn read_events(ready: Arc<Notify>, reading: Arc<AtomicBool>) -> Receiver<Event> {
+        let (tx, events) = mpsc::channel();
+        thread::spawn(move || {
+            while reading.load(Ordering::Relaxed) {
+                match event::poll(EVENT_POLL) {
+                    Ok(false) => continue,
+                    Ok(true) => (),
+                    Err(_) => break,
+                }
+                let Ok(event) = event::read() else { break };
+                if tx.send(event).is_err() {
+                    break;
+                }
+                ready.notify_one();
+            }
+            // wakes the Controller to find the channel closed, if it's still waiting
+            drop(tx);
+            ready.notify_one();
+        });
+        events
+    }
+
+    /// Sets the groups shown in the tab bar and which of them is active. The bar is hidden unless there's more
+    /// than one group.
+    ///
+    /// # TODO
+    ///
+    /// The client only joins a single group so far, so `Controller` never shows the bar yet.
+    pub fn set_tabs(&mut self, tabs: Vec<Tab>, active: usize) {
+        self.active_tab = active.min(tabs.len().saturating_sub(1));
+        self.tabs = tabs;
+    }
+
+    /// Returns the index of the tab the user switched to since the last call, if they switched. The caller is
+    /// expected to swap in that group's log and update the tabs with `ChatWindow::set_tabs()`.
+    pub fn take_tab_switch(&mut self) -> Option<usize> {
+        self.switched_tab.take()
+    }
+
+    /// Makes the tab at `index` active, if it exists, and flags the switch for the caller. The scroll position,
+    /// search, and unread divider belong to the previous group's log, so they're reset.
+    fn switch_tab(&mut self, index: usize) {
+        if index >= self.tabs.len() || index == self.active_tab {
+            return;
+        }
+        self.active_tab = index;
+        self.switched_tab = Some(index);
+        self.scroll = None;
+        self.search = None;
+        self.unread_from = None;
+    }
+
+    /// Scrolls the chat log back by half a page. Leaving the bottom of the log marks every entry added from then on
+    /// as unread.
+    fn scroll_up(&mut self) {
+        if self.max_scroll == 0 {
+            return;
+        }
+        if self.scroll.is_none() {
+            self.unread_from = Some(self.log_total);
+        }
+        let top = self.scroll.unwrap_or(self.max_scroll);
+        self.scroll = Some(top.saturating_sub((self.page / 2).max(1)));
+    }
+
+    /// Scrolls the chat log forward by half a page, following new messages again once the bottom is reached.
+    fn scroll_down(&mut self) {
+        if let Some(top) = self.scroll {
+            let top = top.saturating_add((self.page / 2).max(1));
+            self.scroll = (top < self.max_scroll).then_some(top);
+        }
+    }
+
+    /// Moves the selection to the next older entry if `older`, or else the next newer one. Selecting starts from the
+    /// newest entry, and moving past it clears the selection.
+    fn select(&mut self, older: bool) {
+        self.selection = match (self.selection, older) {
+            (None, true) => self.log_total.checked_sub(1).filter(|&last| last >= self.log_first),
+            (None, false) => None,
+            (Some(index), true) => Some(index.saturating_sub(1).max(self.log_first)),
+            (Some(index), false) => Some(index + 1).filter(|&next| next < self.log_total),
+        };
+        self.selection_jump = true;
+    }
+
+    /// Moves the current search match to the next older match if `older`, or else the next newer one, wrapping
+    /// around at either end of the log. With no current match, starts from the newest one.
+    fn step_search(&mut self, older: bool) {
+        let Some(search) = &mut self.search else { return };
+        search.jump = true;
+        if search.matches.is_empty() {
+            // matches are only found on draw, which starts from the newest one
+            return;
+        }
+
+        let last = search.matches.len() - 1;
+        let next = match search.current.and_then(|current| search.matches.iter().position(|&m| m == current)) {
+            None => last,
+            Some(0) if older => last,
+            Some(pos) if older => pos - 1,
+            Some(pos) => if pos == last { 0 } else { pos + 1 },
+        };
+        search.current = Some(search.matches[next]);
+    }
+
+    /// Handles an event read from the terminal. Returns false if the user pressed the quit key.
+    fn handle_event(&mut self, event: Event) -> Result<bool, ApplicationError> {
+        if let Event::Key(key) = event {
+            if self.show_keys {
+                self.show_keys = false;
+                return Ok(true);
+            }
+
+            if let InputMode::Search = self.input_mode {
+                if self.keys.send.matches(&key) {
+                    let query = self.search_input.value().trim().to_string();
+                    self.search = (!query.is_empty()).then_some(Search { query, matches: vec![], current: None, jump: false });
+                    self.input_mode = InputMode::Normal;
+                    // start from the newest match
+                    self.step_search(false);
+                } else if self.keys.normal.matches(&key) {
+                    self.input_mode = InputMode::Normal;
+                } else {
+                    self.search_input.handle_event(&Event::Key(key));
+                }
+                return Ok(true);
+            }
+
+            // Alt+1 through Alt+9 jump straight to a tab from either mode, since they can't be typed anyway
+            if let (KeyCode::Char(c @ '1'..='9'), KeyModifiers::ALT) = (key.code, key.modifiers) {
+                self.switch_tab(c as usize - '1' as usize);
+                return Ok(true);
+            }
+
+            if self.keys.scroll_up.matches(&key) {
+                self.scroll_up();
+                return Ok(true);
+            } else if self.keys.scroll_down.matches(&key) {
+                self.scroll_down();
+                return Ok(true);
+            }
+
+            match self.input_mode {
+                InputMode::Normal => {
+                    if self.keys.edit.matches(&key) {
+                        self.input_mode = InputMode::Editing;
+                        Ok(true)
+                    } else if self.keys.quit.matches(&key) {
+                        Ok(false)
+                    } else if self.keys.members.matches(&key) {
+                        self.show_members = !self.show_members;
+                        Ok(true)
+                    } else if self.keys.search.matches(&key) {
+                        self.search_input.reset();
+                        self.input_mode = InputMode::Search;
+                        Ok(true)
+                    } else if self.keys.search_next.matches(&key) {
+                        self.step_search(true);
+                        Ok(true)
+                    } else if self.keys.search_prev.matches(&key) {
+                        self.step_search(false);
+                        Ok(true)
+                    } else if self.keys.select_prev.matches(&key) {
+                        self.select(true);
+                        Ok(true)
+                    } else if self.keys.select_next.matches(&key) {
+                        self.select(false);
+                        Ok(true)
+                    } else if self.keys.retry.matches(&key) {
+                        self.output.push(UserInput::Retry);
+                        Ok(true)
+                    } else if self.keys.copy.matches(&key) {
+                        if let Some(index) = self.selection {
+                            self.output.push(UserInput::Copy(index));
+                        }
+                        Ok(true)
+                    } else if self.keys.next_group.matches(&key) && !self.tabs.is_empty() {
+                        self.switch_tab((self.active_tab + 1) % self.tabs.len());
+                        Ok(true)
+                    } else if self.keys.prev_group.matches(&key) && !self.tabs.is_empty() {
+                        self.switch_tab((self.active_tab + self.tabs.len() - 1) % self.tabs.len());
+                        Ok(true)
+                    } else {
+                        Ok(true)
+                    }
+                }
+                InputMode::Editing => {
+                    if self.keys.send.matches(&key) {
+                        let msg = self.input.value().into();
+                        self.output.push(UserInput::Line(msg));
+                        self.input.reset();
+                        // sending jumps back to the newest messages and clears the unread divider
+                        self.scroll = None;
+                        self.unread_from = None;
+                    } else if self.keys.normal.matches(&key) {
+                        self.input_mode = InputMode::Normal;
+                    } else {
+                        self.input.handle_event(&Event::Key(key));
+                    }
+                    Ok(true)
+                }
+                InputMode::Search => Ok(true), // handled above
+            }
+        } else if let Event::Resize(..) = event {
+            // resized before the next draw, so nothing's drawn against the old size in the meantime
+            self.terminal.autoresize().map_err(|_| UiError::Terminal)?;
+            Ok(true)
+        } else { Ok(true) }
+    }
+}
+
+impl ChatView for ChatWindow {
+    fn draw(&mut self, log: &ChatLog) -> Result<(), ApplicationError> {
+        match self.terminal.draw(|f| {
+            let rects = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints(
+                    [
+                        Constraint::Min(1),
+                        Constraint::Length(1),
+                        Constraint::Length(3),
+                        Constraint::Length(1),
+                    ]
+                    .as_ref(),
+                )
+                .split(f.size());
+
+            let (msg, style) = match self.input_mode {
+                InputMode::Normal => (
+                    vec![
+                        Span::raw("Press "),
+                        Span::styled(self.keys.quit.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to exit, "),
+                        Span::styled(self.keys.edit.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to type in the chat, "),
+                        Span::styled(self.keys.members.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to toggle the member list, "),
+                        Span::styled(self.keys.search.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to search."),
+                    ],
+                    Style::default().add_modifier(Modifier::RAPID_BLINK),
+                ),
+                InputMode::Search => (
+                    vec![
+                        Span::raw("Press "),
+                        Span::styled(self.keys.send.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to search (an empty search clears it), "),
+                        Span::styled(self.keys.normal.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to cancel, then "),
+                        Span::styled(self.keys.search_next.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw("/"),
+                        Span::styled(self.keys.search_prev.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" for older/newer matches."),
+                    ],
+                    Style::default(),
+                ),
+                InputMode::Editing => (
+                    vec![
+                        Span::raw("Press "),
+                        Span::styled(self.keys.normal.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to stop editing, "),
+                        Span::styled(self.keys.send.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(" to send the message."),
+                    ],
+                    Style::default(),
+                ),
+            };
+
+            let mut text = Text::from(Line::from(msg));
+            text = text.patch_style(style);
+            let help_message = Paragraph::new(text);
+            f.render_widget(help_message, rects[3]);
+
+            let spinner = SPINNER[(self.started.elapsed().as_millis() / SPINNER_INTERVAL.as_millis()) as usize % SPINNER.len()];
+            let progress = self.status.as_ref().and_then(|status| status.progress);
+
+            if let Some(status) = &self.status {
+                let state_style = match status.state {
+                    ConnectionState::Connected => self.theme.online,
+                    ConnectionState::Disconnected => self.theme.error,
+                };
+                let epoch = match (status.epoch, status.leaf) {
+                    (Some(epoch), Some(leaf)) => format!("epoch {} | leaf {}", epoch, leaf),
+                    (Some(epoch), None) => format!("epoch {}", epoch),
+                    _ => String::from("no group"),
+                };
+                let mut status_line = Line::from(vec![
+                    Span::raw(format!("{} | ", status.address)),
+                    Span::styled(status.state.to_string(), state_style),
+                    Span::raw(format!(" | {} | {} members | {} pending", epoch, status.members, status.pending)),
+                    Span::raw(link(status)),
+                ]);
+                if let Some(progress) = progress {
+                    status_line.spans.push(Span::raw(format!(" | {} {}...", spinner, progress)));
+                }
+                self.notice = self.notice.take().filter(|(_, shown)| shown.elapsed() < NOTICE_LIFETIME);
+                if let Some((notice, _)) = &self.notice {
+                    status_line.spans.push(Span::raw(" | "));
+                    status_line.spans.push(Span::styled(format!("! {}", notice), self.theme.error));
+                }
+                f.render_widget(Paragraph::new(status_line).style(self.theme.status), rects[1]);
+            }
+
+            let width = rects[0].width.max(3) - 3; // 2 width reserved for borders, 1 for cursor
+
+            let (active_input, title) = match self.input_mode {
+                InputMode::Search => (&self.search_input, "Search"),
+                _ => (&self.input, "Input"),
+            };
+            let scroll = active_input.visual_scroll(width as usize);
+            let input = Paragraph::new(active_input.value())
+                .style(match self.input_mode {
+                    InputMode::Normal => Style::default(),
+                    InputMode::Editing | InputMode::Search => self.theme.input,
+                })
+                .scroll((0, scroll as u16))
+                .block(Block::default().borders(Borders::ALL).title(title));
+            f.render_widget(input, rects[2]);
+
+            match self.input_mode {
+                InputMode::Normal => {}
+                InputMode::Editing | InputMode::Search => {
+                    f.set_cursor(
+                        // place cursor past end of input text
+                        rects[2].x
+                            + ((active_input.visual_cursor()).max(scroll) - scroll) as u16
+                            + 1,
+                            // move cursor from the border to the input line
+                            rects[2].y + 1,
+                    )
+                }
+            }
+
+            // the tab bar is only worth its line once there's more than one group to switch between
+            let main_rect = if self.tabs.len() > 1 {
+                let areas = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(1), Constraint::Min(1)].as_ref())
+                    .split(rects[0]);
+
+                let titles: Vec<Line> = self.tabs.iter().enumerate()
+                    .map(|(i, tab)| match tab.unread {
+                        0 => Line::raw(format!("{} {}", i + 1, tab.name)),
+                        unread => Line::from(vec![
+                            Span::raw(format!("{} {} ", i + 1, tab.name)),
+                            Span::styled(format!("({})", unread), self.theme.unread),
+                        ]),
+                    })
+                    .collect();
+                let tabs = Tabs::new(titles)
+                    .select(self.active_tab)
+                    .highlight_style(Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED));
+                f.render_widget(tabs, areas[0]);
+
+                areas[1]
+            } else {
+                rects[0]
+            };
+
+            let chat_rect = if self.show_members && main_rect.width > MEMBER_PANE_WIDTH * 2 {
+                let panes = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Min(1), Constraint::Length(MEMBER_PANE_WIDTH)].as_ref())
+                    .split(main_rect);
+
+                let members: Vec<Line> = self.members.iter()
+                    .map(|member| {
+                        let marker = match member.presence {
+                            Presence::Online => Span::styled("● ", self.theme.online),
+                            Presence::Away(_) => Span::styled("◐ ", self.theme.away),
+                            Presence::Offline => Span::styled("○ ", self.theme.system),
+                        };
+                        let mut suffixes = vec![];
+                        // enough of the user ID to tell apart members going by the same name
+                        if let Some(user_id) = &member.user_id {
+                            let short: String = user_id.chars().take(4).collect();
+                            suffixes.push(Span::styled(format!(" #{}", short), Style::default().add_modifier(Modifier::DIM)));
+                        }
+                        if member.devices > 1 {
+                            suffixes.push(Span::styled(format!(" ×{}", member.devices), Style::default().add_modifier(Modifier::DIM)));
+                        }
+                        // the name gives way to the marker and suffixes, so a long or wide one can't push them off the pane
+                        let own = if member.own { " (you)" } else { "" };
+                        let room = (MEMBER_PANE_WIDTH as usize - 2)
+                            .saturating_sub(marker.width() + own.width() + suffixes.iter().map(Span::width).sum::<usize>());
+                        let name = fit_width(&member.name, room);
+                        let mut line = if member.own {
+                            Line::from(vec![marker, Span::styled(format!("{}{}", name, own), Style::default().add_modifier(Modifier::BOLD))])
+                        } else {
+                            Line::from(vec![marker, Span::raw(name)])
+                        };
+                        line.spans.extend(suffixes);
+                        line
+                    })
+                    .collect();
+                let member_list = Paragraph::new(Text::from(members))
+                    .block(Block::default().borders(Borders::ALL).title(format!("Members ({})", self.members.len())));
+                f.render_widget(member_list, panes[1]);
+
+                panes[0]
+            } else {
+                main_rect
+            };
+
+            let mut lines = vec![];
+            let first_index = log.total() - log.len();
+            if self.selection.is_some_and(|selection| selection < first_index) {
+                self.selection = None;
+            }
+            if let Some(search) = &mut self.search {
+                search.matches = log.iter().enumerate()
+                    .filter(|(_, entry)| !find_matches(&entry.text, &search.query).is_empty())
+                    .map(|(i, _)| first_index + i)
+                    .collect();
+                if search.jump && !search.current.is_some_and(|current| search.matches.contains(&current)) {
+                    search.current = search.matches.last().copied();
+                }
+            }
+            // entry the view needs to scroll to, and the number of unwrapped lines before it once found
+            let jump_target = match &self.search {
+                Some(search) if search.jump => search.current,
+                _ if self.selection_jump => self.selection,
+                _ => None,
+            };
+            let mut jump_line = None;
+
+            for (i, entry) in log.iter().enumerate() {
+                let index = first_index + i;
+                let found = match &self.search {
+                    Some(search) => find_matches(&entry.text, &search.query),
+                    None => vec![],
+                };
+                let match_style = if self.search.as_ref().is_some_and(|s| s.current == Some(index)) {
+                    self.theme.current_match
+                } else {
+                    self.theme.search_match
+                };
+                let mut ranges: Vec<(usize, usize, Style)> = found.into_iter()
+                    .map(|(start, end)| (start, end, match_style))
+                    .collect();
+
+                if self.unread_from == Some(index) {
+                    lines.push(Line::styled(
+                        format!("{:─^1$}", " new messages ", chat_rect.width.saturating_sub(2) as usize),
+                        self.theme.unread,
+                    ));
+                }
+                if jump_target == Some(index) {
+                    jump_line = Some(lines.len());
+                }
+
+                let line = match &entry.kind {
+                    EntryKind::Chat { number, sender, author, mentioned, .. } => {
+                        let mut spans = vec![Span::raw(format!("#{} [{}] ", number, entry.timestamp()))];
+                        spans.push(match author {
+                            None => Span::styled(sender.as_str(), self.theme.own_message),
+                            Some(_) => Span::raw(sender.as_str()),
+                        });
+                        spans.push(Span::raw(": "));
+                        if entry.deleted {
+                            spans.push(Span::styled("(message deleted)", self.theme.system.add_modifier(Modifier::ITALIC)));
+                        }
+                        ranges.extend(mention_spans(&entry.text)
+                            .map(|(start, end)| (start, end, Style::default().add_modifier(Modifier::BOLD))));
+                        let mut hidden = vec![];
+                        if self.display.formatting {
+                            for span in markup::parse(&entry.text) {
+                                let (start, end) = span.content();
+                                ranges.push((start, end, match span.kind {
+                                    MarkupKind::Bold => Style::default().add_modifier(Modifier::BOLD),
+                                    MarkupKind::Italic => Style::default().add_modifier(Modifier::ITALIC),
+                                    MarkupKind::Code => self.theme.code,
+                                }));
+                                hidden.extend([(span.start, start), (end, span.end)]);
+                            }
+                        }
+                        spans.extend(highlight(&entry.text, &ranges, &hidden));
+                        if entry.edited && !entry.deleted {
+                            spans.push(Span::styled(" (edited)", Style::default().add_modifier(Modifier::DIM)));
+                        }
+                        if entry.failed {
+                            spans.push(Span::styled(format!(" (failed, press {} to retry)", self.keys.retry), self.theme.error));
+                        } else if entry.pending {
+                            spans.push(Span::styled(" (pending)", Style::default().add_modifier(Modifier::DIM)));
+                        }
+
+                        let line = Line::from(spans);
+                        if *mentioned { line.style(self.theme.mention) } else { line }
+                    }
+                    EntryKind::System => {
+                        let mut spans = vec![Span::raw(format!("[{}] * ", entry.timestamp()))];
+                        spans.extend(highlight(&entry.text, &ranges, &[]));
+                        Line::from(spans).style(self.theme.system)
+                    }
+                    EntryKind::Warning => {
+                        let mut spans = vec![Span::raw(format!("[{}] ! ", entry.timestamp()))];
+                        spans.extend(highlight(&entry.text, &ranges, &[]));
+                        Line::from(spans).style(self.theme.error.add_modifier(Modifier::BOLD))
+                    }
+                };
+                lines.push(if self.selection == Some(index) { line.patch_style(self.theme.selection) } else { line });
+
+                if !entry.reactions.is_empty() {
+                    let reactions: Vec<String> = entry.reactions.iter()
+                        .map(|(emoji, reactors)| format!("{} {}", emoji, reactors.len()))
+                        .collect();
+                    lines.push(Line::styled(
+                        format!("    {}", reactions.join("  ")),
+                        Style::default().add_modifier(Modifier::DIM),
+                    ));
+                }
+            }
+            // until there's something to read, say what's being waited on rather than leaving the log blank
+            if let Some(progress) = progress {
+                if !log.iter().any(|entry| matches!(entry.kind, EntryKind::Chat { .. })) {
+                    lines.push(Line::styled(format!("{} {}...", spinner, progress), self.theme.system));
+                }
+            }
+            // the log rewraps when its width changes (a resized terminal, or the member list toggled), so a view scrolled
+            // up is kept on the line it had at the top rather than on the same wrapped line number
+            if let Some(top) = self.scroll.filter(|_| self.chat_width != 0 && self.chat_width != chat_rect.width) {
+                let old_width = self.chat_width.saturating_sub(2);
+                let mut wrapped = 0;
+                let top_line = lines.iter().position(|line| {
+                    wrapped += Paragraph::new(line.clone()).wrap(Wrap { trim: true }).line_count(old_width);
+                    wrapped > top as usize
+                });
+                jump_line = jump_line.or(top_line);
+            }
+            self.chat_width = chat_rect.width;
+            // wrapped line the jump target starts on
+            let jump_to = jump_line.map(|line| {
+                Paragraph::new(Text::from(lines[..line].to_vec()))
+                    .wrap(Wrap { trim: true })
+                    .line_count(chat_rect.width.saturating_sub(2)) as u16
+            });
+            let mut chat = Paragraph::new(Text::from(lines)).wrap(Wrap { trim: true });
+
+            // scroll position is kept as the top line shown, so the view stays put while new messages arrive
+            let page = chat_rect.height.saturating_sub(2);
+            let max_scroll = (chat.line_count(chat_rect.width.saturating_sub(2)) as u16).saturating_sub(page);
+            if let Some(search) = &mut self.search {
+                search.jump = false;
+            }
+            self.selection_jump = false;
+            if let Some(line) = jump_to {
+                if self.scroll.is_none() && line < max_scroll {
+                    self.unread_from = Some(log.total());
+                }
+                self.scroll = Some(line);
+            }
+            let top = match self.scroll {
+                Some(top) if top < max_scroll => top,
+                _ => {
+                    self.scroll = None;
+                    max_scroll
+                }
+            };
+            self.page = page;
+            self.max_scroll = max_scroll;
+            self.log_first = first_index;
+            self.log_total = log.total();
+
+            let mut block = Block::default().borders(Borders::ALL).title(match &self.topic {
+                Some(topic) => format!("Chat Log - {}", topic),
+                None => String::from("Chat Log"),
+            });
+            let unread = self.unread_from.map_or(0, |from| log.total().saturating_sub(from));
+            if self.scroll.is_some() && unread > 0 {
+                block = block.title_bottom(Line::styled(
+                    format!(" {} new message{} ", unread, if unread == 1 { "" } else { "s" }),
+                    self.theme.unread,
+                ));
+            }
+            if let Some(search) = &self.search {
+                let position = search.current
+                    .and_then(|current| search.matches.iter().position(|&m| m == current))
+                    .map_or(String::from("-"), |pos| (pos + 1).to_string());
+                block = block.title_bottom(Line::from(format!(
+                    " \"{}\": match {}/{} ", search.query, position, search.matches.len()
+                )).right_aligned());
+            }
+            chat = chat.scroll((top, 0)).block(block);
+
+            f.render_widget(chat, chat_rect);
+
+            if self.show_keys {
+                let bindings = self.keys.list();
+                let key_width = bindings.iter().map(|info| info.key.to_string().width()).max().unwrap_or(0);
+                let lines: Vec<Line> = bindings.iter()
+                    .map(|info| Line::from(vec![
+                        Span::styled(format!("{}  ", pad_width(&info.key.to_string(), key_width)), Style::default().add_modifier(Modifier::BOLD)),
+                        Span::raw(format!("{:<12} {}", info.name, info.description)),
+                    ]))
+                    .collect();
+
+                let area = centered(f.size(), 64, lines.len() as u16 + 2);
+                f.render_widget(Clear, area);
+                f.render_widget(
+                    Paragraph::new(Text::from(lines))
+                        .block(Block::default().borders(Borders::ALL).title("Key bindings").title_bottom(" press any key to close ")),
+                    area,
+                );
+            }
+        }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(UiError::Terminal.into()),
+        }
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        while self.output.is_empty() {
+            let event = match self.events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err(UiError::Terminal.into()),
+            };
+            if !self.handle_event(event)? {
+                return Ok(Some(UserInput::Quit));
+            }
+        }
+        Ok(self.output.pop())
+    }
+
+    /// The whole log is redrawn on every `ChatWindow::draw()`, so there's nothing to do here.
+    fn push_message(&mut self, _entry: &LogEntry) {}
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        self.reading.store(false, Ordering::Relaxed);
+        match disable_raw_mode() {
+            Ok(_) => (),
+            Err(_) => return Err(UiError::Terminal.into()),
+        }
+
+        match execute!(
+            self.terminal.backend_mut(),
+            LeaveAlternateScreen,
+            DisableMouseCapture
+        ) {
+            Ok(_) => (),
+            Err(_) => return Err(UiError::Terminal.into()),
+        }
+        match self.terminal.show_cursor() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(UiError::Terminal.into())
+        }
+    }
+
+    /// Rings the terminal bell.
+    fn bell(&mut self) {
+        let backend = self.terminal.backend_mut();
+        let _ = backend.write_all(b"\x07").and_then(|_| backend.flush());
+    }
+
+    /// Sets the group topic shown in the chat log's title bar.
+    fn set_topic(&mut self, topic: Option<String>) {
+        self.topic = topic;
+    }
+
+    /// Sets the members shown in the member list pane.
+    fn set_members(&mut self, members: Vec<MemberLine>) {
+        self.members = members;
+    }
+
+    /// Sets the session details shown in the status line.
+    fn set_status(&mut self, status: Status) {
+        self.status = Some(status);
+    }
+
+    /// Opens an overlay listing the active key bindings, which closes on the next key press.
+    fn show_keys(&mut self) {
+        self.show_keys = true;
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        Arc::clone(&self.events_ready)
+    }
+
+    /// Shows the notice in the status line until `NOTICE_LIFETIME` passes, or a newer notice replaces it.
+    fn show_notice(&mut self, notice: &str) {
+        self.notice = Some((notice.to_string(), Instant::now()));
+    }
+
+    /// Asks to be redrawn often enough to turn the spinner while there's a `Progress` to show, and to clear a notice
+    /// once it expires.
+    fn redraw_interval(&self) -> Option<Duration> {
+        let spinner = self.status.as_ref()
+            .and_then(|status| status.progress)
+            .map(|_| SPINNER_INTERVAL);
+        let notice = self.notice.as_ref()
+            .map(|(_, shown)| NOTICE_LIFETIME.saturating_sub(shown.elapsed()));
+        spinner.into_iter().chain(notice).min()
+    }
+}
+
+/// Describes the link to the server for the status line, e.g. " | 42 ms | ↑ 1.2 KiB/s ↓ 3.4 KiB/s", leaving out
+/// whatever hasn't been measured yet.
+fn link(status: &Status) -> String {
+    let mut link = String::new();
+    if let Some(round_trip) = status.round_trip {
+        link.push_str(&format!(" | {} ms", round_trip.as_millis()));
+    }
+    if let (Some(up), Some(down)) = (status.upload_rate, status.download_rate) {
+        link.push_str(&format!(" | ↑ {}/s ↓ {}/s", format_bytes(up), format_bytes(down)));
+    }
+    link
+}
+
+/// Cuts `text` down to at most `width` terminal columns, ending it with an ellipsis if anything was cut. Wide
+/// characters like CJK and most emoji count as the two columns they take up.
+fn fit_width(text: &str, width: usize) -> Cow<'_, str> {
+    if text.width() <= width {
+        return Cow::Borrowed(text);
+    }
+    let mut fitted = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        // leaves a column for the ellipsis
+        if used + char_width >= width {
+            break;
+        }
+        used += char_width;
+        fitted.push(c);
+    }
+    if width > 0 {
+        fitted.push('…');
+    }
+    Cow::Owned(fitted)
+}
+
+/// Pads `text` with spaces out to `width` terminal columns, which `format!`'s padding can't do as it counts chars.
+fn pad_width(text: &str, width: usize) -> String {
+    format!("{}{}", text, " ".repeat(width.saturating_sub(text.width())))
+}
+
+/// Returns a rectangle of at most `width` by `height` centered in `area`.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+/// Splits `text` into spans, styling each part with every range in `ranges` that covers it and leaving out the
+/// parts covered by `hidden`. Ranges are byte offsets into `text` and may overlap.
+fn highlight<'a>(text: &'a str, ranges: &[(usize, usize, Style)], hidden: &[(usize, usize)]) -> Vec<Span<'a>> {
+    let mut bounds: Vec<usize> = ranges.iter()
+        .map(|&(start, end, _)| (start, end))
+        .chain(hidden.iter().copied())
+        .flat_map(|(start, end)| [start, end])
+        .chain([0, text.len()])
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    bounds.windows(2)
+        .filter(|bound| !hidden.iter().any(|&(start, end)| start <= bound[0] && bound[1] <= end))
+        .map(|bound| {
+            let style = ranges.iter()
+                .filter(|&&(start, end, _)| start <= bound[0] && bound[1] <= end)
+                .fold(Style::default(), |style, &(_, _, range_style)| style.patch(range_style));
+            Span::styled(&text[bound[0]..bound[1]], style)
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_wide_characters_by_columns() {
+        assert_eq!(fit_width("alice", 5), "alice");
+        assert_eq!(fit_width("alice", 4), "ali…");
+        // each of these takes two columns, so only one fits before the ellipsis
+        assert_eq!(fit_width("日本語", 4), "日…");
+        assert_eq!(fit_width("日本語", 6), "日本語");
+        assert_eq!(fit_width("🦀🦀", 3), "🦀…");
+        assert_eq!(fit_width("alice", 0), "");
+    }
+
+    #[test]
+    fn pads_wide_characters_by_columns() {
+        assert_eq!(pad_width("←", 3), "←  ");
+        assert_eq!(pad_width("日", 3), "日 ");
+        assert_eq!(pad_width("Ctrl+q", 3), "Ctrl+q");
+    }
+}
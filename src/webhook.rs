@@ -0,0 +1,179 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use crate::{
+    ApplicationError,
+    NetworkError,
+    chat_log::{ChatLog, LogEntry},
+    frontend::{self, ChatView, InputQueue, InputSender, UserInput},
+    plain,
+};
+
+use tokio::sync::Notify;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 64 * 1024;
+/// How long a connection gets to send its whole request before it's dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A view for running the client as a bot that other programs post into: every line of the body of each `POST` to
+/// the webhook's address is sent to the group as a message from the bot's user, so CI systems and scripts can post
+/// notifications without holding any keys themselves. Lines are always sent as messages, never run as commands. Log
+/// entries are printed to stdout like `PlainView` does.
+///
+/// There's no authentication, so anyone who can reach the address can post as the bot. Keep it on a loopback address
+/// unless something in front of it checks who's posting.
+pub struct WebhookView {
+    address: SocketAddr,
+    input: InputQueue,
+}
+
+impl WebhookView {
+    /// Starts listening for requests on `address`, spawning a thread to answer them without blocking the
+    /// `Controller`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NetworkError::Io` if the address can't be listened on.
+    pub fn bind(address: SocketAddr) -> Result<WebhookView, ApplicationError> {
+        let listener = TcpListener::bind(address).map_err(|_| NetworkError::Io)?;
+        let address = listener.local_addr().map_err(|_| NetworkError::Io)?;
+        let (tx, input) = frontend::input_queue();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                // a request that fails partway through only loses that request
+                if let Err(Closed) = handle_request(stream, &tx) {
+                    break;
+                }
+            }
+        });
+
+        Ok(WebhookView { address, input })
+    }
+
+    /// Returns the address the webhook is listening on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+/// The `WebhookView` has been dropped, so there's nobody left to pass messages to.
+struct Closed;
+
+/// Reads one request from `stream`, passes the lines of its body to `tx` if it's a `POST`, and answers it.
+fn handle_request(stream: TcpStream, tx: &InputSender) -> Result<(), Closed> {
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let Ok(mut writer) = stream.try_clone() else { return Ok(()) };
+    let status = match read_request(BufReader::new(stream)) {
+        Ok(body) => {
+            let lines = body.lines().map(str::trim_end).filter(|line| !line.is_empty());
+            for line in lines {
+                // a leading / would otherwise be run as a command; // sends it as a message starting with /
+                let line = if line.starts_with('/') { format!("/{}", line) } else { line.to_string() };
+                tx.send(line).map_err(|_| Closed)?;
+            }
+            "202 Accepted"
+        }
+        Err(status) => status,
+    };
+
+    let _ = write!(writer, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    Ok(())
+}
+
+/// Reads a request, returning its body if it's a `POST` with a UTF-8 body of a reasonable size, or else the status
+/// line to answer it with.
+fn read_request(mut reader: impl BufRead) -> Result<String, &'static str> {
+    let bad_request = |_: io::Error| "400 Bad Request";
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(bad_request)?;
+    let method = request_line.split_whitespace().next().ok_or("400 Bad Request")?.to_string();
+
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(bad_request)? == 0 {
+            return Err("400 Bad Request");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>().map_err(|_| "400 Bad Request")?);
+            }
+        }
+    }
+
+    if method != "POST" {
+        return Err("405 Method Not Allowed");
+    }
+    let length = length.ok_or("411 Length Required")?;
+    if length > MAX_BODY {
+        return Err("413 Content Too Large");
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(bad_request)?;
+    String::from_utf8(body).map_err(|_| "400 Bad Request")
+}
+
+impl ChatView for WebhookView {
+    /// Entries are printed by `WebhookView::push_message()` as they arrive, so there's nothing to redraw.
+    fn draw(&mut self, _log: &ChatLog) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<UserInput>, ApplicationError> {
+        Ok(self.input.poll())
+    }
+
+    fn push_message(&mut self, entry: &LogEntry) {
+        let mut stdout = io::stdout().lock();
+        // stdout going away (e.g. a closed pipe) leaves nothing to report the error to
+        let _ = writeln!(stdout, "{}", plain::format_entry(entry)).and_then(|_| stdout.flush());
+    }
+
+    fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn input_ready(&self) -> Arc<Notify> {
+        self.input.ready()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn posts_become_messages() {
+        let mut view = WebhookView::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let body = "build #12 passed\n\n/wipe confirm\n";
+        let response = request(view.address(), &format!("POST /hook HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body));
+        assert!(response.starts_with("HTTP/1.1 202"));
+        assert!(request(view.address(), "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+
+        assert_eq!(view.poll_input().unwrap(), Some(UserInput::Line(String::from("build #12 passed"))));
+        assert_eq!(view.poll_input().unwrap(), Some(UserInput::Line(String::from("//wipe confirm"))));
+        assert_eq!(view.poll_input().unwrap(), None);
+    }
+}
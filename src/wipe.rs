@@ -0,0 +1,87 @@
+use std::{fs::{self, OpenOptions}, io::{self, ErrorKind, Write}, path::{Path, PathBuf}};
+use crate::config::ClientConfig;
+
+use rand::RngCore;
+
+/// Size of the chunks a file is overwritten in.
+const CHUNK: usize = 64 * 1024;
+
+/// Returns the local files that give away the user's participation, for `/wipe` and `mls_chat wipe`: the saved
+/// session (holding their identity, private keys, and group state) and its temporary file, the audit log, and the
+/// config file itself, whichever of them are set.
+pub fn targets(config: &ClientConfig) -> Vec<PathBuf> {
+    let mut targets = vec![];
+    if let Some(path) = &config.session.path {
+        targets.push(path.clone());
+        targets.push(path.with_extension("tmp"));
+    }
+    targets.extend(config.audit.path.clone());
+    targets.extend(config.source.clone());
+    targets
+}
+
+/// Overwrites the file at `path` with random bytes, flushes it to disk, and deletes it. Returns false if there was no
+/// file to delete.
+///
+/// On SSDs, and on journaling or copy-on-write filesystems, the old contents can survive elsewhere on the disk despite
+/// the overwrite. Only full-disk encryption reliably protects against that.
+///
+/// # Errors
+///
+/// Returns any `io::Error` from opening, overwriting, or deleting the file.
+pub fn shred(path: &Path) -> io::Result<bool> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    let mut remaining = file.metadata()?.len() as usize;
+    let mut noise = vec![0; CHUNK.min(remaining)];
+    while remaining > 0 {
+        let len = CHUNK.min(remaining);
+        rand::thread_rng().fill_bytes(&mut noise[..len]);
+        file.write_all(&noise[..len])?;
+        remaining -= len;
+    }
+    file.sync_all()?;
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::remove_file(path)?;
+    Ok(true)
+}
+
+/// Shreds every file in `paths`, carrying on past failures. Returns the files that were deleted, and those that
+/// couldn't be along with why.
+pub fn wipe(paths: &[PathBuf]) -> (Vec<PathBuf>, Vec<(PathBuf, io::Error)>) {
+    let mut deleted = vec![];
+    let mut failed = vec![];
+    for path in paths {
+        match shred(path) {
+            Ok(true) => deleted.push(path.clone()),
+            Ok(false) => (),
+            Err(e) => failed.push((path.clone(), e)),
+        }
+    }
+    (deleted, failed)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shred_files() {
+        let path = std::env::temp_dir().join(format!("mls_chat_wipe_{}", std::process::id()));
+        fs::write(&path, vec![b'x'; CHUNK + 10]).unwrap();
+
+        let (deleted, failed) = wipe(&[path.clone(), path.with_extension("missing")]);
+        assert_eq!(deleted, vec![path.clone()]);
+        assert!(failed.is_empty());
+        assert!(!path.exists());
+        assert!(!shred(&path).unwrap());
+    }
+}